@@ -0,0 +1,75 @@
+//! Hashing throughput of a file read with buffers of different sizes, which
+//! `io_buffer_size` was picked from. Run with `cargo bench --bench io_buffer`,
+//! `GIDRIVE_BENCH_FILE` to hash an existing file on the storage of interest
+//! instead of a generated one in the temp dir.
+
+use gidrive::models::ChecksumAlgo;
+use gidrive::utils::Hasher;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::PathBuf;
+use std::time::Instant;
+
+const SIZES: &[usize] = &[
+    16 * 1024,
+    64 * 1024,
+    128 * 1024,
+    256 * 1024,
+    1024 * 1024,
+    2 * 1024 * 1024,
+];
+const GENERATED_BYTES: usize = 256 * 1024 * 1024;
+const ROUNDS: usize = 3;
+
+fn main() {
+    let (path, generated) = match std::env::var_os("GIDRIVE_BENCH_FILE") {
+        Some(path) => (PathBuf::from(path), false),
+        None => {
+            let path = std::env::temp_dir().join("gidrive-bench-io-buffer");
+            let mut file = File::create(&path).expect("create bench file");
+            let block: Vec<u8> = (0..1024 * 1024).map(|i| (i * 31 % 251) as u8).collect();
+            for _ in 0..GENERATED_BYTES / block.len() {
+                file.write_all(&block).expect("write bench file");
+            }
+            (path, true)
+        }
+    };
+    for algo in [ChecksumAlgo::Sha256, ChecksumAlgo::Blake3] {
+        for &size in SIZES {
+            // best of a few rounds, the first ones also warm the page cache
+            let mut best = f64::MAX;
+            let mut bytes = 0;
+            for _ in 0..ROUNDS {
+                let started = Instant::now();
+                bytes = hash(&path, algo, size);
+                best = best.min(started.elapsed().as_secs_f64());
+            }
+            println!(
+                "{:<7} {:>5} KiB  {:>8.1} MiB/s",
+                algo,
+                size / 1024,
+                bytes as f64 / best / (1024.0 * 1024.0)
+            );
+        }
+    }
+    if generated {
+        let _ = std::fs::remove_file(&path);
+    }
+}
+
+fn hash(path: &PathBuf, algo: ChecksumAlgo, size: usize) -> u64 {
+    let mut file = File::open(path).expect("open bench file");
+    let mut hasher = Hasher::new(algo);
+    let mut buf = vec![0u8; size];
+    let mut total = 0;
+    loop {
+        let n = file.read(&mut buf).expect("read bench file");
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+        total += n as u64;
+    }
+    std::hint::black_box(hasher.finalize_hex());
+    total
+}
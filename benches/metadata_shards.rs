@@ -0,0 +1,158 @@
+//! Latency of metadata operations on a metadata repo of many files, with
+//! fs/ as one tree and split into shards, which `METADATA_SHARDS` and
+//! `METADATA_SHARD_THRESHOLD` were picked from. Run with
+//! `cargo bench --bench metadata_shards`, `GIDRIVE_BENCH_FILES` for another
+//! number of files than 50000.
+
+use gidrive::constants::METADATA_SHARDS;
+use gidrive::metadata::{
+    list_file_metadata, load_file_metadata, move_to_shards, save_file_metadata, save_version,
+};
+use gidrive::models::{ChecksumAlgo, ChunkInfo, FileMetadata};
+use std::path::Path;
+use std::process::Command;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+const DIRS: usize = 10;
+const LOOKUPS: usize = 1000;
+
+fn main() {
+    let files = std::env::var("GIDRIVE_BENCH_FILES")
+        .ok()
+        .and_then(|n| n.parse().ok())
+        .unwrap_or(50_000);
+    let dir = std::env::temp_dir().join("gidrive-bench-metadata-shards");
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).expect("create bench repo");
+    git(&dir, &["init", "-q"]);
+    save_version(&dir, gidrive::constants::VERSION).expect("write version.txt");
+    for i in 0..files {
+        save_file_metadata(&dir, &remote(i), &meta(i)).expect("write metadata");
+    }
+    git(&dir, &["add", "-A"]);
+    git(&dir, &["commit", "-qm", "files"]);
+    println!(
+        "{} files in {} dirs, best of 3 rounds",
+        files,
+        DIRS.min(files)
+    );
+    println!(
+        "{:<12} {:>10} {:>10} {:>10} {:>12}",
+        "layout", "lookup", "list", "commit", "tree bytes"
+    );
+    measure(&dir, "one tree", files);
+    move_to_shards(&dir, METADATA_SHARDS).expect("reshard");
+    git(&dir, &["add", "-A"]);
+    git(&dir, &["commit", "-qm", "reshard"]);
+    measure(&dir, &format!("{} shards", METADATA_SHARDS), files);
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+fn remote(i: usize) -> String {
+    format!("photos/{:03}/IMG_{:06}.jpg", i % DIRS, i)
+}
+
+fn meta(i: usize) -> FileMetadata {
+    let checksum = format!("{:064x}", i);
+    FileMetadata {
+        checksum: checksum.clone(),
+        checksum_algo: ChecksumAlgo::Sha256,
+        size: 3 * 1024 * 1024,
+        chunks: vec![ChunkInfo {
+            repo: "storage-00000001".to_string(),
+            path: format!("{}_0", checksum),
+            size: 3 * 1024 * 1024,
+            index: 0,
+            offset: 0,
+            checksum: Some(checksum),
+            owner: None,
+        }],
+        public: false,
+        mtime: Some(1_700_000_000),
+        archive: None,
+        symlink_target: None,
+        xattrs: None,
+        source_url: None,
+    }
+}
+
+/// Prints the best of a few rounds of looking up `LOOKUPS` files, listing
+/// all of them, and committing a change of one, with the bytes of the
+/// trees that commit wrote.
+fn measure(dir: &Path, layout: &str, files: usize) {
+    let mut lookup = Duration::MAX;
+    let mut list = Duration::MAX;
+    let mut commit = Duration::MAX;
+    let mut tree_bytes = 0;
+    for round in 0..3 {
+        let started = Instant::now();
+        for n in 0..LOOKUPS {
+            let i = n * 7919 % files;
+            std::hint::black_box(load_file_metadata(dir, &remote(i)).expect("lookup"));
+        }
+        lookup = lookup.min(started.elapsed() / LOOKUPS as u32);
+
+        let started = Instant::now();
+        let listed = list_file_metadata(dir, "").expect("list");
+        assert_eq!(listed.len(), files);
+        list = list.min(started.elapsed());
+
+        let mut changed = meta(round);
+        // a new mtime every round of every layout, so there is a change
+        changed.mtime = Some(
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .expect("clock after 1970")
+                .as_nanos() as u64,
+        );
+        let started = Instant::now();
+        save_file_metadata(dir, &remote(round), &changed).expect("write metadata");
+        git(dir, &["add", "-A"]);
+        git(dir, &["commit", "-qm", "change"]);
+        commit = commit.min(started.elapsed());
+        tree_bytes = written_tree_bytes(dir);
+    }
+    println!(
+        "{:<12} {:>8.0}us {:>8.0}ms {:>8.0}ms {:>12}",
+        layout,
+        lookup.as_secs_f64() * 1e6,
+        list.as_secs_f64() * 1e3,
+        commit.as_secs_f64() * 1e3,
+        tree_bytes
+    );
+}
+
+/// Bytes of the tree objects the last commit added, what a push sends
+/// besides the changed file.
+fn written_tree_bytes(dir: &Path) -> u64 {
+    let trees = git(
+        dir,
+        &["diff-tree", "-r", "-t", "--no-renames", "HEAD~1", "HEAD"],
+    );
+    trees
+        .lines()
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            (fields.get(1) == Some(&"040000")).then(|| fields[3].to_string())
+        })
+        .chain([git(dir, &["rev-parse", "HEAD^{tree}"]).trim().to_string()])
+        .map(|tree| {
+            git(dir, &["cat-file", "-s", &tree])
+                .trim()
+                .parse::<u64>()
+                .unwrap_or(0)
+        })
+        .sum()
+}
+
+fn git(dir: &Path, args: &[&str]) -> String {
+    let output = Command::new("git")
+        .arg("-C")
+        .arg(dir)
+        .args(["-c", "user.name=bench", "-c", "user.email=bench@localhost"])
+        .args(args)
+        .output()
+        .expect("run git");
+    assert!(output.status.success(), "git {:?} failed", args);
+    String::from_utf8_lossy(&output.stdout).into_owned()
+}
@@ -0,0 +1,117 @@
+//! Chunking a file for upload: the two passes of before `split_into_chunks`,
+//! hashing the whole file and then copying it into chunk files, against the
+//! fused pass doing both from one read. The middle variant adds the
+//! per-chunk hashes the fused pass computes to the two passes, so it does
+//! the same work. Run with `cargo bench --bench single_pass`,
+//! `GIDRIVE_BENCH_FILE` to chunk an existing file on the storage of
+//! interest instead of a generated 1 GB one in the temp dir.
+
+use gidrive::chunks::split_into_chunks;
+use gidrive::constants::DEFAULT_CHUNK_SIZE;
+use gidrive::models::ChecksumAlgo;
+use gidrive::utils::{checksum_hex, get_file_checksum, read_full, Hasher};
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+const GENERATED_BYTES: usize = 1024 * 1024 * 1024;
+const ROUNDS: usize = 3;
+
+fn main() {
+    let (path, generated) = match std::env::var_os("GIDRIVE_BENCH_FILE") {
+        Some(path) => (PathBuf::from(path), false),
+        None => {
+            let path = std::env::temp_dir().join("gidrive-bench-single-pass");
+            let mut file = File::create(&path).expect("create bench file");
+            let block: Vec<u8> = (0..1024 * 1024).map(|i| (i * 31 % 251) as u8).collect();
+            for _ in 0..GENERATED_BYTES / block.len() {
+                file.write_all(&block).expect("write bench file");
+            }
+            (path, true)
+        }
+    };
+    let size = std::fs::metadata(&path).expect("stat bench file").len();
+    let chunk_sizes: Vec<u64> = (0..size.div_ceil(DEFAULT_CHUNK_SIZE))
+        .map(|i| DEFAULT_CHUNK_SIZE.min(size - i * DEFAULT_CHUNK_SIZE))
+        .collect();
+    let dir = std::env::temp_dir().join("gidrive-bench-single-pass-chunks");
+    std::fs::create_dir_all(&dir).expect("create chunk dir");
+    println!(
+        "{} MiB in {} chunks, {} threads, best of {} rounds",
+        size >> 20,
+        chunk_sizes.len(),
+        rayon::current_num_threads(),
+        ROUNDS
+    );
+    for algo in [ChecksumAlgo::Sha256, ChecksumAlgo::Blake3] {
+        let variants: [(&str, &dyn Fn() -> String); 3] = [
+            ("two passes", &|| {
+                two_passes(&path, &chunk_sizes, &dir, algo, false)
+            }),
+            ("two passes + chunk hashes", &|| {
+                two_passes(&path, &chunk_sizes, &dir, algo, true)
+            }),
+            ("fused", &|| fused(&path, &chunk_sizes, &dir, algo)),
+        ];
+        let mut checksums = Vec::new();
+        for (name, run) in variants {
+            // the first rounds also warm the page cache
+            let mut best = f64::MAX;
+            for _ in 0..ROUNDS {
+                let started = Instant::now();
+                checksums.push(run());
+                best = best.min(started.elapsed().as_secs_f64());
+            }
+            println!(
+                "{:<7} {:<26} {:>6.2}s {:>8.1} MiB/s",
+                algo,
+                name,
+                best,
+                size as f64 / best / (1024.0 * 1024.0)
+            );
+        }
+        assert!(checksums.windows(2).all(|w| w[0] == w[1]));
+    }
+    let _ = std::fs::remove_dir_all(&dir);
+    if generated {
+        let _ = std::fs::remove_file(&path);
+    }
+}
+
+/// Hashes the file, then reads it again into chunk files, hashing each one
+/// with `chunk_hashes`.
+fn two_passes(
+    path: &Path,
+    chunk_sizes: &[u64],
+    dir: &Path,
+    algo: ChecksumAlgo,
+    chunk_hashes: bool,
+) -> String {
+    let checksum = get_file_checksum(path, algo).expect("hash bench file");
+    let mut file = File::open(path).expect("open bench file");
+    let mut buf = vec![0u8; DEFAULT_CHUNK_SIZE as usize];
+    for (index, size) in chunk_sizes.iter().enumerate() {
+        let data = &mut buf[..*size as usize];
+        read_full(&mut file, data).expect("read bench file");
+        let chunk = dir.join(format!("chunk_u_{}", index));
+        std::fs::write(&chunk, &data).expect("write chunk");
+        if chunk_hashes {
+            std::hint::black_box(checksum_hex(algo, data));
+        }
+        let _ = std::fs::remove_file(chunk);
+    }
+    checksum
+}
+
+fn fused(path: &Path, chunk_sizes: &[u64], dir: &Path, algo: ChecksumAlgo) -> String {
+    let mut file = File::open(path).expect("open bench file");
+    let mut whole = Hasher::new(algo);
+    split_into_chunks(&mut file, chunk_sizes, dir, algo, &mut whole, |chunk| {
+        std::hint::black_box(chunk.checksum);
+        let _ = std::fs::remove_file(chunk.path);
+        Ok(())
+    })
+    .expect("chunk bench file");
+    whole.finalize_hex()
+}
@@ -1,290 +1,3233 @@
-use anyhow::{anyhow, Context, Result};
+use anyhow::{bail, Context, Result};
 use rayon::prelude::*;
 use rayon::ThreadPoolBuilder;
-use serde_json;
-use std::collections::HashMap;
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
+use std::fmt;
 use std::fs::{self, File};
-use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::io::{self, BufWriter, Read, Write};
+use std::ops::Range;
 use std::path::{Path, PathBuf};
-use walkdir::WalkDir;
+use std::sync::atomic::{AtomicBool, Ordering as AtomicOrdering};
+use std::sync::{mpsc, Mutex};
+use std::thread;
+use std::time::Instant;
 
-use crate::chunks::{download_chunks_from_repo, upload_chunks_to_repo};
-use crate::constants::{
-    CHUNK_SIZE, METADATA_REPO_URL, NUM_PUSH_THREADS, SSH_KEY_PATH, TMPFS_DIR, VERSION,
+use crate::archive;
+use crate::cache;
+use crate::chunks::{
+    chunk_dest_path, copy_staged, download_chunks_from_repo, remove_chunks_from_repo,
+    upload_pipelined, ShortChunk,
 };
+use crate::config::{settings, Config};
+use crate::constants::{DEFAULT_NAMESPACE, METADATA_SHARD_THRESHOLD, VERSION};
+use crate::exclude::{special_kind, ExcludeOptions, Excludes, LinkPolicy};
+use crate::export::{restore_script, MapFormat, MapWriter, ScriptTransport};
+use crate::fetch::{self, HttpSource};
 use crate::git::{
-    clone_repo, create_repo, delete_repo, git_add_commit_push, list_repos, repo_exists, ssh_agent,
+    check_reachable, check_writable, clone_repo, create_repo, delete_repo, list_account_repos,
+    list_repos, metadata_repo_url, offline, owner, qualified_repo, read_only, remote_overridden,
+    repo_exists, repo_slug, repo_url, require_scope, setup_auth,
 };
+use crate::hooks::{self, Hook, HookEnv};
+use crate::journal::{self, Entry, Intent, Journal};
+use crate::listing;
+use crate::manifest;
 use crate::metadata::{
-    find_or_create_repo_for_chunk, get_metadata_dir, load_repos_metadata, load_version,
-    save_repos_metadata,
+    bootstrap, build_chunk_index, check_remote_name, check_write_version, clone_file_metadata,
+    clone_metadata, commit_metadata, commit_metadata_for, create_planned_repos, defer_deletes,
+    execute_plan, fall_back_offline, file_meta_path, for_each_file_metadata, get_metadata_dir,
+    has_content, list_all_file_metadata, list_all_file_metadata_tolerant, list_file_metadata,
+    list_file_metadata_tolerant, load_chunk_index, load_clients_log, load_file_metadata,
+    load_namespace_stats, load_pending_deletes, load_repos_metadata, load_version,
+    migrate_to_namespaces, move_to_shards, namespace, plan_upload, reference_chunks,
+    release_chunks, release_plan, repos_allowed, revision, save_chunk_index, save_file_metadata,
+    save_pending_deletes, save_repos_metadata, shard_count, split_pending_deletes, stored_chunks,
+    update_namespace_stats, FileNotFound, PlanOptions, UploadPlan,
 };
-use crate::models::{ChunkInfo, FileMetadata, ReposMetadata};
+use crate::metrics;
+use crate::models::{
+    ArchiveInfo, ChecksumAlgo, ChunkInfo, FileEntry, FileMetadata, PendingDelete, RepoInfo,
+    ReposMetadata,
+};
+use crate::progress::{self, Event};
+use crate::report::{RepoTimings, TransferReport};
+use crate::status;
+use crate::usage;
 use crate::utils::{
-    ensure_tmpfs_dir, get_file_sha256, human_size, retry, sleep, versions_are_compatible,
+    checksum_hex, confirm, ensure_free_space, ensure_temp_dirs, explain_no_space, format_utc,
+    get_file_checksum, human_size, is_no_space, move_file, read_full, set_transfer_concurrency,
+    temp_dirs, transfer_concurrency, transfer_slot, unix_now, verbose, very_verbose, Hasher,
+    HashingWriter,
 };
+use crate::xattrs;
+
+#[derive(Default)]
+pub struct UploadOptions {
+    /// Only plan and print the summary, nothing is written or created
+    pub dry_run: bool,
+    /// Algorithm for the file and chunk checksums
+    pub checksum_algo: ChecksumAlgo,
+    /// Store the chunks in public repos so anyone can download the file
+    pub public: bool,
+    /// Skip the upload when `remote` already has the same content
+    pub if_changed: bool,
+    /// Recorded in the metadata, set by `upload_archive`
+    pub archive: Option<ArchiveInfo>,
+    /// `Preserve` stores a symlink `local` as the link, otherwise its
+    /// target is uploaded
+    pub links: LinkPolicy,
+    /// Recorded in the metadata, set for a preserved symlink
+    pub symlink_target: Option<String>,
+    /// Store the extended attributes of the local file with its metadata
+    pub xattrs: bool,
+    /// Recorded in the metadata, set by `fetch`
+    pub source_url: Option<String>,
+}
+
+pub fn upload(remote: &str, local: &str, opts: &UploadOptions) -> Result<TransferReport> {
+    if opts.dry_run {
+        return upload_file(remote, local, opts);
+    }
+    with_hooks("upload", remote, local, || upload_file(remote, local, opts))
+}
+
+static ALLOW_LARGE: AtomicBool = AtomicBool::new(false);
+static RESTORE_XATTRS: AtomicBool = AtomicBool::new(false);
+
+/// Lets uploads past `max_upload_size` through, for `--allow-large`.
+pub fn allow_large() {
+    ALLOW_LARGE.store(true, AtomicOrdering::Relaxed);
+}
+
+/// Makes downloads to a path set the extended attributes stored with the
+/// file, for `download --xattrs`.
+pub fn restore_xattrs() {
+    RESTORE_XATTRS.store(true, AtomicOrdering::Relaxed);
+}
+
+/// An upload of more than `max_upload_size` without `--allow-large`, with
+/// what it would have taken.
+#[derive(Debug)]
+pub struct UploadTooLarge {
+    /// The remote file, or the files of a batch
+    pub what: String,
+    pub size: u64,
+    pub limit: u64,
+    pub chunks: usize,
+    pub new_repos: usize,
+    pub pushes: usize,
+}
+
+impl fmt::Display for UploadTooLarge {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} is {}, over max_upload_size {}: it would take {} chunks, {} new repos and \
+             {} git pushes; pass --allow-large to upload it anyway",
+            self.what,
+            human_size(self.size),
+            human_size(self.limit),
+            self.chunks,
+            self.new_repos,
+            self.pushes
+        )
+    }
+}
+
+impl std::error::Error for UploadTooLarge {}
+
+/// Fails with `UploadTooLarge` when `size` bytes for `what` are more than
+/// `max_upload_size` and `--allow-large` was not passed, projecting the
+/// upload onto `repos_meta` without changing anything.
+fn check_upload_size(
+    what: &str,
+    size: u64,
+    repos_meta: &ReposMetadata,
+    public: bool,
+) -> Result<()> {
+    let limit = settings().max_upload_size();
+    if limit == 0 || size <= limit || ALLOW_LARGE.load(AtomicOrdering::Relaxed) {
+        return Ok(());
+    }
+    let plan = plan_upload(size, repos_meta, &PlanOptions::new(public))?;
+    Err(UploadTooLarge {
+        what: what.to_string(),
+        size,
+        limit,
+        chunks: plan.assignments.len(),
+        new_repos: plan.new_repos.len(),
+        // as in print_upload_summary
+        pushes: plan.repos_used() + 2,
+    }
+    .into())
+}
+
+/// `check_upload_size` for the `files` of a batch together, reading
+/// repos.json only when they are over the limit.
+pub fn check_batch_size(files: usize, size: u64) -> Result<()> {
+    let limit = settings().max_upload_size();
+    if limit == 0 || size <= limit || ALLOW_LARGE.load(AtomicOrdering::Relaxed) {
+        return Ok(());
+    }
+    let metadata_clone_dir = clone_metadata()?;
+    let repos_meta = load_repos_metadata(&metadata_clone_dir);
+    fs::remove_dir_all(&metadata_clone_dir)?;
+    check_upload_size(
+        &format!("the batch of {} files", files),
+        size,
+        &repos_meta?,
+        false,
+    )
+}
+
+fn upload_file(remote: &str, local: &str, opts: &UploadOptions) -> Result<TransferReport> {
+    let local_path = Path::new(local);
+    if opts.links == LinkPolicy::Preserve && fs::symlink_metadata(local_path)?.is_symlink() {
+        return upload_symlink(remote, local_path, opts);
+    }
+    let local_meta = fs::metadata(local_path)?;
+    // reading a fifo or a device would block or never end
+    if let Some(kind) = special_kind(local_meta.file_type()) {
+        bail!("{} is a {}, not a regular file", local, kind);
+    }
+    if local_meta.is_dir() {
+        bail!("{} is a directory, upload it with --archive", local);
+    }
+    let file_size = local_meta.len();
+    if opts.if_changed && is_unchanged(remote, local_path, file_size)? {
+        println!("{} unchanged, skipping", remote);
+        return Ok(TransferReport::default());
+    }
+    let mut file = File::open(local_path)?;
+    let local_path = local_path.canonicalize()?;
+    upload_journaled(remote, &mut file, file_size, opts, Some(&local_path), None)
+}
+
+/// Stores the symlink `local_path` as a file without chunks recording its
+/// target, which download recreates as a link.
+fn upload_symlink(remote: &str, local_path: &Path, opts: &UploadOptions) -> Result<TransferReport> {
+    let target = fs::read_link(local_path)?;
+    let target = target
+        .to_str()
+        .with_context(|| format!("symlink target {} is not UTF-8", target.display()))?;
+    println!(
+        "{} is a symlink to {}, storing the link",
+        local_path.display(),
+        target
+    );
+    let opts = UploadOptions {
+        archive: None,
+        symlink_target: Some(target.to_string()),
+        source_url: None,
+        ..*opts
+    };
+    upload_from_reader(remote, &mut io::empty(), 0, &opts)
+}
+
+/// Uploads the directory `dir` as one zstd-compressed tar file `remote`.
+/// The archive is built in the temp dir first, the upload needs its size.
+pub fn upload_archive(
+    remote: &str,
+    dir: &str,
+    opts: &UploadOptions,
+    excludes: &ExcludeOptions,
+) -> Result<TransferReport> {
+    if !Path::new(dir).is_dir() {
+        bail!("{} is not a directory", dir);
+    }
+    if opts.dry_run {
+        return archive_and_upload(remote, dir, opts, excludes);
+    }
+    with_hooks("upload", remote, dir, || {
+        archive_and_upload(remote, dir, opts, excludes)
+    })
+}
+
+fn archive_and_upload(
+    remote: &str,
+    dir: &str,
+    opts: &UploadOptions,
+    excludes: &ExcludeOptions,
+) -> Result<TransferReport> {
+    ensure_temp_dirs()?;
+    let excludes = Excludes::load(Path::new(dir), excludes)?;
+    let spool = temp_dirs().staging.join("archive.tar.zst");
+    let res = archive::build(Path::new(dir), &spool, &excludes).and_then(|info| {
+        println!(
+            "archived {} entries of {} into {}",
+            info.entries,
+            dir,
+            human_size(fs::metadata(&spool)?.len())
+        );
+        let opts = UploadOptions {
+            archive: Some(info),
+            symlink_target: None,
+            source_url: None,
+            ..*opts
+        };
+        upload_file(remote, &spool.to_string_lossy(), &opts)
+    });
+    let _ = fs::remove_file(&spool);
+    res
+}
+
+/// Streams the archive `remote` into `dir`, unpacking it while it downloads.
+pub fn download_extract(remote: &str, dir: &str) -> Result<TransferReport> {
+    with_hooks("download", remote, dir, || {
+        let (report, entries) =
+            with_archive_reader(remote, |reader| archive::extract(reader, Path::new(dir)))?;
+        println!("extracted {} entries into {}", entries, dir);
+        Ok(report)
+    })
+}
+
+/// Runs the `operation` ("upload" or "download") of `remote` from or to
+/// `local` between its hooks: pre_upload before an upload, then
+/// post_upload or post_download once it succeeded, or on_error.
+fn with_hooks(
+    operation: &str,
+    remote: &str,
+    local: &str,
+    transfer: impl FnOnce() -> Result<TransferReport>,
+) -> Result<TransferReport> {
+    let env = HookEnv {
+        operation,
+        remote,
+        local,
+        ..Default::default()
+    };
+    let _in_flight = metrics::transfer_started();
+    // a long `watch` writes its usage after each transfer
+    let _usage = usage::FlushOnDrop;
+    let res = if operation == "upload" {
+        let size = fs::metadata(local).ok().filter(|m| m.is_file());
+        hooks::run(
+            Hook::PreUpload,
+            &HookEnv {
+                size: size.map(|m| m.len()),
+                ..env
+            },
+        )
+        .and_then(|_| transfer())
+    } else {
+        transfer()
+    };
+    match res {
+        Ok(report) => {
+            metrics::transfer_succeeded(operation, report.bytes);
+            let hook = if operation == "upload" {
+                Hook::PostUpload
+            } else {
+                Hook::PostDownload
+            };
+            hooks::run(
+                hook,
+                &HookEnv {
+                    size: Some(report.bytes),
+                    checksum: report.checksum.as_deref(),
+                    duration: Some(report.total),
+                    ..env
+                },
+            )?;
+            Ok(report)
+        }
+        Err(e) => {
+            metrics::transfer_failed(operation, &e);
+            hooks::run(
+                Hook::OnError,
+                &HookEnv {
+                    error: Some(&format!("{:#}", e)),
+                    ..env
+                },
+            )?;
+            Err(e)
+        }
+    }
+}
+
+/// Prints the entries of the archive `remote` with their sizes.
+pub fn archive_list(remote: &str) -> Result<()> {
+    with_archive_reader(remote, |reader| {
+        archive::list(reader, &mut io::stdout().lock())
+    })?;
+    Ok(())
+}
+
+/// Downloads the archive `remote` through a pipe into `consume`, which runs
+/// on the calling thread while the download writes the other end.
+fn with_archive_reader<T>(
+    remote: &str,
+    consume: impl FnOnce(io::PipeReader) -> Result<T>,
+) -> Result<(TransferReport, T)> {
+    let file_meta = metadata(remote)?;
+    match &file_meta.archive {
+        Some(info) if info.format == archive::FORMAT => {}
+        Some(info) => bail!(
+            "{} is a {} archive, only {} is supported",
+            remote,
+            info.format,
+            archive::FORMAT
+        ),
+        None => bail!(
+            "{} is not an archive, upload directories with --archive",
+            remote
+        ),
+    }
+    let (reader, mut writer) = io::pipe()?;
+    std::thread::scope(|scope| {
+        let download = scope.spawn(move || {
+            let mut output = BufWriter::new(&mut writer);
+            let report = download_to_writer(remote, &mut output)?;
+            output.flush()?;
+            Ok::<_, anyhow::Error>(report)
+        });
+        let consumed = consume(reader);
+        let report = download.join().expect("download thread panicked");
+        match (report, consumed) {
+            (Ok(report), Ok(consumed)) => Ok((report, consumed)),
+            // the consumer stopped reading first, its error is the cause
+            (Err(e), Err(consumer_err))
+                if e.chain().any(|c| {
+                    c.downcast_ref::<io::Error>()
+                        .is_some_and(|e| e.kind() == io::ErrorKind::BrokenPipe)
+                }) =>
+            {
+                Err(consumer_err)
+            }
+            (Err(e), _) | (Ok(_), Err(e)) => Err(e),
+        }
+    })
+}
+
+/// Whether `remote` exists with the content of `local_path`. Sizes are
+/// compared first so a changed file is usually told apart without hashing.
+fn is_unchanged(remote: &str, local_path: &Path, file_size: u64) -> Result<bool> {
+    let meta = match metadata(remote) {
+        Ok(meta) => Some(meta),
+        Err(e) if e.is::<FileNotFound>() => None,
+        Err(e) => return Err(e),
+    };
+    Ok(match meta {
+        Some(meta) if meta.size == file_size => {
+            get_file_checksum(local_path, meta.checksum_algo)? == meta.checksum
+        }
+        _ => false,
+    })
+}
+
+/// Uploads exactly `file_size` bytes read from `reader` to `remote`.
+/// The reader is consumed in a single streaming pass, so it can be a socket
+/// or a pipe as long as its size is known up front.
+pub fn upload_from_reader(
+    remote: &str,
+    reader: &mut (impl Read + Send),
+    file_size: u64,
+    opts: &UploadOptions,
+) -> Result<TransferReport> {
+    upload_journaled(remote, reader, file_size, opts, None, None)
+}
+
+/// Uploads the body of the HTTP(S) `url` to `remote` as it downloads,
+/// recording the URL without its credentials in the metadata. A body whose
+/// size the server does not tell is spooled to the temp dir first. With
+/// `expected_sha256` a body of another checksum fails the upload before its
+/// metadata is written.
+pub fn fetch(
+    url: &str,
+    remote: &str,
+    expected_sha256: Option<&str>,
+    opts: &UploadOptions,
+) -> Result<TransferReport> {
+    let source_url = fetch::redact(url);
+    let opts = UploadOptions {
+        archive: None,
+        symlink_target: None,
+        source_url: Some(source_url.clone()),
+        ..*opts
+    };
+    let transfer = || {
+        check_remote_name(remote)?;
+        let mut source = HttpSource::open(url, expected_sha256)?;
+        match source.size {
+            // nothing is read for an empty file, check its checksum here
+            Some(0) => {
+                io::copy(&mut source, &mut io::sink())?;
+                upload_from_reader(remote, &mut io::empty(), 0, &opts)
+            }
+            Some(size) => upload_from_reader(remote, &mut source, size, &opts),
+            _ => {
+                ensure_temp_dirs()?;
+                let spool = temp_dirs().staging.join("fetch_body");
+                let res = (|| {
+                    let mut file = BufWriter::new(File::create(&spool)?);
+                    io::copy(&mut source, &mut file)
+                        .with_context(|| format!("Failed to fetch {}", source.url()))?;
+                    file.flush()?;
+                    drop(file);
+                    let size = fs::metadata(&spool)?.len();
+                    upload_from_reader(remote, &mut File::open(&spool)?, size, &opts)
+                })();
+                let _ = fs::remove_file(&spool);
+                res
+            }
+        }
+    };
+    // the body fails to read deep in the chunking, tell why at the top
+    let fetch_and_upload = || {
+        transfer().map_err(|e| {
+            match e
+                .chain()
+                .skip(1)
+                .find_map(|c| c.downcast_ref::<io::Error>())
+            {
+                Some(cause) => anyhow::anyhow!("{}: {}", e, cause),
+                None => e,
+            }
+        })
+    };
+    if opts.dry_run {
+        return fetch_and_upload();
+    }
+    with_hooks("upload", remote, &source_url, fetch_and_upload)
+}
+
+/// `upload_from_reader` recording the upload in the journal, with `local`
+/// as the file `resume` reads again. A `resumed` journal with a plan pushes
+/// by that plan, whose space repos.json reserved already.
+fn upload_journaled(
+    remote: &str,
+    reader: &mut (impl Read + Send),
+    file_size: u64,
+    opts: &UploadOptions,
+    local: Option<&Path>,
+    resumed: Option<Journal>,
+) -> Result<TransferReport> {
+    let started = Instant::now();
+    let mut report = TransferReport::default();
+    check_remote_name(remote)?;
+    ensure_temp_dirs()?;
+    // Clone metadata to get repos info
+    let metadata_clone_dir = report.time("metadata clone", clone_metadata)?;
+
+    let repos_meta = load_repos_metadata(&metadata_clone_dir)?;
+    let stored = stored_chunks(
+        &load_chunk_index(&metadata_clone_dir)?,
+        &repos_meta,
+        opts.public,
+    );
+    let plan_opts = PlanOptions::new(opts.public);
+    if opts.dry_run {
+        let plan = plan_upload(file_size, &repos_meta, &plan_opts)?;
+        print_upload_summary(&plan, file_size, None);
+        if let Err(e) = check_upload_size(remote, file_size, &repos_meta, opts.public) {
+            println!("refused:    {:#}", e);
+        }
+        fs::remove_dir_all(&metadata_clone_dir)?;
+        return Ok(report);
+    }
+    check_write_version(&metadata_clone_dir)?;
+    // a resumed upload was let through before
+    if resumed.is_none() {
+        check_upload_size(remote, file_size, &repos_meta, opts.public)?;
+    }
+
+    let journal = match resumed {
+        Some(journal) => journal,
+        None => Journal::begin(Intent::Upload {
+            remote: remote.to_string(),
+            local: local.map(Path::to_path_buf),
+            size: file_size,
+            checksum_algo: opts.checksum_algo,
+            public: opts.public,
+            xattrs: opts.xattrs,
+        })?,
+    };
+    let mut plan = match journal.plan() {
+        Some(plan) => UploadPlan {
+            assignments: plan.assignments,
+            new_repos: plan.new_repos,
+            public: opts.public,
+            repos: repos_meta,
+        },
+        None => report.time("assignment", || {
+            plan_upload(file_size, &repos_meta, &plan_opts)
+        })?,
+    };
+    let (work, staging) = plan.peak_temp_bytes(transfer_concurrency());
+    ensure_free_space(work, staging, &format!("uploading {}", remote))?;
+    let (checksum, chunks, reused, skipped) = push_chunks(
+        &mut report,
+        &metadata_clone_dir,
+        &mut plan,
+        reader,
+        Hasher::new(opts.checksum_algo),
+        opts.checksum_algo,
+        remote,
+        &stored,
+        Some(&journal),
+    )?;
+
+    // Re-clone metadata for fresh state and write file metadata
+    let metadata_clone_dir = report.time("metadata clone", clone_metadata)?;
+    let metadata_write_started = Instant::now();
+    let file_meta = FileMetadata {
+        checksum,
+        checksum_algo: opts.checksum_algo,
+        size: file_size,
+        chunks,
+        public: opts.public,
+        mtime: Some(unix_now()),
+        archive: opts.archive.clone(),
+        symlink_target: opts.symlink_target.clone(),
+        xattrs: local.filter(|_| opts.xattrs).and_then(xattrs::capture),
+        source_url: opts.source_url.clone(),
+    };
+    save_indexed(&metadata_clone_dir, remote, &file_meta, &reused, &skipped)?;
+    update_namespace_stats(&metadata_clone_dir)?;
+    commit_metadata_for(&metadata_clone_dir, "Add metadata for", 1, remote)?;
+    journal.finish();
+    report.add("metadata write", metadata_write_started.elapsed());
+    fs::remove_dir_all(&metadata_clone_dir)?;
+    report.bytes = file_size;
+    report.checksum = Some(file_meta.checksum.clone());
+    report.total = started.elapsed();
+    report.bytes_uncompressed = opts.archive.as_ref().and_then(|info| info.bytes);
+    print_upload_summary(&plan, file_size, Some(&report));
+    Ok(report)
+}
+
+/// Appends the content of `local` (`-` for stdin) to the existing `remote`.
+///
+/// Existing chunks are never rewritten: the new data starts a fresh chunk
+/// with the next index, so a file built by many small appends ends up with
+/// many small chunks. The whole-file checksum is recomputed by downloading
+/// and hashing the current content before the new data is added. When
+/// another client changed `remote` meanwhile, the append fails and the
+/// chunks it pushed are left to `gc`.
+pub fn append(remote: &str, local: &str) -> Result<TransferReport> {
+    let started = Instant::now();
+    let mut report = TransferReport::default();
+    ensure_temp_dirs()?;
+    // stdin has no size to plan with, spool it first
+    let spooled = if local == "-" {
+        // appends running at the same time share the staging dir
+        let path = temp_dirs()
+            .staging
+            .join(format!("append_stdin_{}", std::process::id()));
+        let mut spool = BufWriter::new(File::create(&path)?);
+        io::copy(&mut io::stdin().lock(), &mut spool).context("Failed to read stdin")?;
+        spool.flush()?;
+        Some(path)
+    } else {
+        None
+    };
+    let local_path = spooled.as_deref().unwrap_or(Path::new(local));
+    let res = (|| {
+        let new_size = fs::metadata(local_path)?.len();
+        let metadata_clone_dir = report.time("metadata clone", clone_metadata)?;
+        check_write_version(&metadata_clone_dir)?;
+        let old_meta = load_file_metadata(&metadata_clone_dir, remote)?;
+        if let Some(target) = &old_meta.symlink_target {
+            bail!(
+                "{} is a symlink to {}, there is nothing to append to",
+                remote,
+                target
+            );
+        }
+        ensure_free_space(
+            0,
+            old_meta.size,
+            &format!("hashing the content of {}", remote),
+        )?;
+        let algo = old_meta.checksum_algo;
+        let whole = report.time("hash existing", || -> Result<Hasher> {
+            let temp_dir = temp_dirs()
+                .staging
+                .join(format!("dl_{}", old_meta.checksum));
+            let mut sink = io::sink();
+            let mut chunk_report = TransferReport::default();
+            fetch_to_writer(&old_meta, &temp_dir, &mut sink, &mut chunk_report)
+        })?;
+
+        let repos_meta = load_repos_metadata(&metadata_clone_dir)?;
+        let stored = stored_chunks(
+            &load_chunk_index(&metadata_clone_dir)?,
+            &repos_meta,
+            old_meta.public,
+        );
+        let mut plan = report.time("assignment", || {
+            plan_upload(new_size, &repos_meta, &PlanOptions::new(old_meta.public))
+        })?;
+        let (work, staging) = plan.peak_temp_bytes(transfer_concurrency());
+        ensure_free_space(work, staging, &format!("appending to {}", remote))?;
+        let first_index = old_meta.chunks.last().map_or(0, |c| c.index + 1);
+        for (index, _, _) in plan.assignments.iter_mut() {
+            *index += first_index;
+        }
+        let mut file = File::open(local_path)?;
+        let (checksum, new_chunks, reused, skipped) = push_chunks(
+            &mut report,
+            &metadata_clone_dir,
+            &mut plan,
+            &mut file,
+            whole,
+            algo,
+            remote,
+            &stored,
+            None,
+        )?;
+
+        let metadata_clone_dir = report.time("metadata clone", clone_metadata)?;
+        let metadata_write_started = Instant::now();
+        let mut file_meta = load_file_metadata(&metadata_clone_dir, remote)?;
+        // no metadata lock yet, at least refuse to clobber a concurrent writer
+        if file_meta.checksum != old_meta.checksum {
+            abandon_append(&metadata_clone_dir, remote, new_chunks, &reused, &skipped)?;
+            bail!(
+                "{} was modified while appending, the appended chunks wait for gc",
+                remote
+            );
+        }
+        file_meta.checksum = checksum;
+        file_meta.size += new_size;
+        file_meta.chunks.extend(new_chunks);
+        file_meta.mtime = Some(unix_now());
+        // the content is not the fetched URL's anymore
+        file_meta.source_url = None;
+        save_indexed(&metadata_clone_dir, remote, &file_meta, &reused, &skipped)?;
+        update_namespace_stats(&metadata_clone_dir)?;
+        commit_metadata_for(&metadata_clone_dir, "Append to", 1, remote)?;
+        report.add("metadata write", metadata_write_started.elapsed());
+        fs::remove_dir_all(&metadata_clone_dir)?;
+        report.bytes = new_size;
+        report.total = started.elapsed();
+        print_upload_summary(&plan, new_size, Some(&report));
+        Ok(())
+    })();
+    if let Some(path) = spooled {
+        let _ = fs::remove_file(path);
+    }
+    res.map(|_| report)
+}
+
+/// File checksum, new chunks, (repo, bytes) reserved for chunks that were
+/// stored already, and the chunks their repo had before the push.
+type PushedChunks = (String, Vec<ChunkInfo>, Vec<(String, u64)>, Vec<ChunkInfo>);
+
+/// Creates the repos of `plan`, pushes the space reservations in repos.json,
+/// placing the chunks again if a concurrent upload took the space, and
+/// pipes `reader` through chunking into the storage repos. `whole` is the
+/// hasher state the new data continues. Chunks whose content is in `stored`
+/// reference it instead of being pushed. A `journal` that has the plan
+/// already, resuming an upload, only has the repos created; otherwise the
+/// plan is recorded in it once reserved. Returns the file checksum, the
+/// `ChunkInfo` of every new chunk, the (repo, bytes) reserved for the
+/// chunks that reference stored content, and the chunks the push skipped
+/// because their repo had them at the same path already.
+#[allow(clippy::too_many_arguments)]
+fn push_chunks(
+    report: &mut TransferReport,
+    metadata_clone_dir: &Path,
+    plan: &mut UploadPlan,
+    reader: &mut (impl Read + Send),
+    whole: Hasher,
+    algo: ChecksumAlgo,
+    remote: &str,
+    stored: &HashMap<String, (String, String)>,
+    journal: Option<&Journal>,
+) -> Result<PushedChunks> {
+    if !plan.new_repos.is_empty() {
+        let scope = if plan.public { "public_repo" } else { "repo" };
+        require_scope(scope, "create storage repos")?;
+    }
+    match journal {
+        Some(journal) if journal.plan().is_some() => create_planned_repos(plan)?,
+        _ => {
+            report.time("assignment", || execute_plan(metadata_clone_dir, plan))?;
+            if let Some(journal) = journal {
+                journal.planned(plan)?;
+            }
+        }
+    }
+    let bytes = plan.assignments.iter().map(|(_, _, size)| size).sum();
+    progress::emit(Event::TransferStarted {
+        direction: "upload",
+        remote,
+        bytes,
+        total_chunks: plan.assignments.len(),
+    });
+    let started = Instant::now();
+    // Chunk, hash and push in one pipeline
+    let pipelined = report.time("chunk + upload", || {
+        upload_pipelined(
+            reader,
+            &plan.assignments,
+            &temp_dirs().staging,
+            algo,
+            whole,
+            remote,
+            stored,
+            journal,
+        )
+    });
+    let (checksum, chunk_files, repo_timings) = match pipelined {
+        Ok(pipelined) => pipelined,
+        Err(e) => {
+            match release_reservations(plan, remote) {
+                Ok(()) => {
+                    if let Some(journal) = journal {
+                        journal.unplanned();
+                    }
+                }
+                Err(release_err) => eprintln!(
+                    "--- could not release the space reserved for {}: {:#}",
+                    remote, release_err
+                ),
+            }
+            return Err(explain_no_space(e));
+        }
+    };
+    progress::emit(Event::TransferFinished {
+        direction: "upload",
+        remote,
+        bytes,
+        chunks: plan.assignments.len(),
+        seconds: started.elapsed().as_secs_f64(),
+    });
+    report.chunks_pushed += repo_timings.iter().map(|r| r.chunks).sum::<usize>();
+    report.bytes_pushed += repo_timings.iter().map(|r| r.bytes).sum::<u64>();
+    report.chunks_reused += plan.assignments.len() - report.chunks_pushed;
+    let skipped_indexes: HashSet<usize> = repo_timings
+        .iter()
+        .flat_map(|r| r.skipped.iter().copied())
+        .collect();
+    report.repos = repo_timings;
+    let mut chunks = Vec::new();
+    let mut reused = Vec::new();
+    let mut skipped = Vec::new();
+    for ((i, r, s), chunk) in plan.assignments.iter().zip(chunk_files) {
+        let was_skipped = chunk.stored.is_none() && skipped_indexes.contains(i);
+        let (repo, path) = match chunk.stored {
+            Some(location) => {
+                reused.push((r.clone(), *s));
+                location
+            }
+            None => (r.clone(), chunk_dest_path(&chunk.checksum, *i)),
+        };
+        let repo_owner = plan.repos.repos.get(&repo).and_then(|r| r.owner.clone());
+        let info = ChunkInfo {
+            repo,
+            path,
+            size: *s,
+            index: *i,
+            offset: 0,
+            checksum: Some(chunk.checksum),
+            owner: repo_owner.or_else(|| Some(owner().to_string())),
+        };
+        if was_skipped {
+            skipped.push(info.clone());
+        }
+        chunks.push(info);
+    }
+    Ok((checksum, chunks, reused, skipped))
+}
+
+/// Undoes an append to `remote` that another client's change of it beat:
+/// the space reserved for `reused` chunks and for `skipped` ones a file
+/// references is given back, the chunks it pushed that nothing references
+/// are queued for `gc`, their reservation accounting for them until then.
+fn abandon_append(
+    metadata_clone_dir: &Path,
+    remote: &str,
+    chunks: Vec<ChunkInfo>,
+    reused: &[(String, u64)],
+    skipped: &[ChunkInfo],
+) -> Result<()> {
+    let index = load_chunk_index(metadata_clone_dir)?;
+    let mut repos_meta = load_repos_metadata(metadata_clone_dir)?;
+    let referenced = |c: &ChunkInfo| index.contains_key(&(c.repo.clone(), c.path.clone()));
+    let released = reused.iter().cloned().chain(
+        skipped
+            .iter()
+            .filter(|c| referenced(c))
+            .map(|c| (c.repo.clone(), c.size)),
+    );
+    for (repo, size) in released {
+        if let Some(info) = repos_meta.repos.get_mut(&repo) {
+            info.current_size = info.current_size.saturating_sub(size);
+        }
+    }
+    let orphaned = chunks.into_iter().filter(|c| !referenced(c)).collect();
+    defer_deletes(metadata_clone_dir, &mut repos_meta, orphaned)?;
+    save_repos_metadata(metadata_clone_dir, &repos_meta)?;
+    commit_metadata_for(metadata_clone_dir, "Release space reserved for", 1, remote)?;
+    fs::remove_dir_all(metadata_clone_dir)?;
+    Ok(())
+}
+
+/// Gives back the space reserved in repos.json for the failed upload of
+/// `remote`. Chunks it already pushed stay, a retry reuses them.
+fn release_reservations(plan: &UploadPlan, remote: &str) -> Result<()> {
+    let metadata_clone_dir = clone_metadata()?;
+    let mut repos_meta = load_repos_metadata(&metadata_clone_dir)?;
+    release_plan(&mut repos_meta, plan);
+    save_repos_metadata(&metadata_clone_dir, &repos_meta)?;
+    commit_metadata_for(&metadata_clone_dir, "Release space reserved for", 1, remote)?;
+    fs::remove_dir_all(&metadata_clone_dir)?;
+    Ok(())
+}
+
+/// Saves `file_meta` as `remote` in the metadata clone and moves the
+/// chunks.idx references of the file it replaces, if any, to its chunks.
+/// Chunks of the replaced file nothing uses anymore wait for `gc`. The space
+/// repos.json reserved for the `reused` chunks is released, and that of the
+/// `skipped` chunks a file references already: their bytes were counted when
+/// that file was saved. Skipped chunks no file references, left by a failed
+/// attempt, keep their reservation, which now accounts for them.
+fn save_indexed(
+    metadata_clone_dir: &Path,
+    remote: &str,
+    file_meta: &FileMetadata,
+    reused: &[(String, u64)],
+    skipped: &[ChunkInfo],
+) -> Result<()> {
+    let mut index = load_chunk_index(metadata_clone_dir)?;
+    let mut repos_meta = load_repos_metadata(metadata_clone_dir)?;
+    let mut released = reused.to_vec();
+    for chunk in skipped {
+        if index.contains_key(&(chunk.repo.clone(), chunk.path.clone())) {
+            released.push((chunk.repo.clone(), chunk.size));
+        }
+    }
+    let mut unused = match load_file_metadata(metadata_clone_dir, remote) {
+        Ok(old) => release_chunks(&mut index, &old),
+        Err(_) => Vec::new(),
+    };
+    reference_chunks(&mut index, &repos_meta, file_meta);
+    unused.retain(|c| !index.contains_key(&(c.repo.clone(), c.path.clone())));
+    defer_deletes(metadata_clone_dir, &mut repos_meta, unused)?;
+    for (repo, size) in &released {
+        if let Some(info) = repos_meta.repos.get_mut(repo) {
+            info.current_size = info.current_size.saturating_sub(*size);
+        }
+    }
+    save_file_metadata(metadata_clone_dir, remote, file_meta)?;
+    save_repos_metadata(metadata_clone_dir, &repos_meta)?;
+    save_chunk_index(metadata_clone_dir, &index)
+}
+
+/// Prints what an upload planned, and with the `report` of a finished one
+/// what it actually pushed and how long it took.
+fn print_upload_summary(plan: &UploadPlan, file_size: u64, report: Option<&TransferReport>) {
+    let repos_used = plan.repos_used();
+    println!("file size:  {}", human_size(file_size));
+    println!(
+        "chunks:     {} x {}",
+        plan.assignments.len(),
+        human_size(settings().chunk_size())
+    );
+    println!(
+        "repos:      {} existing, {} new",
+        repos_used - plan.new_repos.len(),
+        plan.new_repos.len()
+    );
+    // one push per storage repo, plus the repos.json and file metadata commits
+    println!(
+        "git pushes: {} ({} storage, 2 metadata)",
+        repos_used + 2,
+        repos_used
+    );
+    if let Some(report) = report {
+        println!("transfer:   {}", report.savings());
+        if let Some(raw) = report.bytes_uncompressed {
+            println!(
+                "compressed: {} of files into {} ({:.2}x)",
+                human_size(raw),
+                human_size(file_size),
+                raw as f64 / file_size.max(1) as f64
+            );
+        }
+        if report.bytes_verified() > 0 {
+            println!(
+                "verified:   {} read back from GitHub",
+                human_size(report.bytes_verified())
+            );
+        }
+        let secs = report.total.as_secs_f64();
+        println!(
+            "took:       {:.1}s ({}/s)",
+            secs,
+            human_size((file_size as f64 / secs.max(0.001)) as u64)
+        );
+    }
+}
+
+/// Downloads `remote` into `local`. The data is assembled in a temporary file
+/// of the staging dir and only moved over `local` once size and checksum
+/// match, so a failed download never touches an existing `local`.
+pub fn download(remote: &str, local: &str) -> Result<TransferReport> {
+    with_hooks("download", remote, local, || download_file(remote, local))
+}
+
+fn download_file(remote: &str, local: &str) -> Result<TransferReport> {
+    let local_path = Path::new(local);
+    let file_name = local_path
+        .file_name()
+        .context("Local path must have a file name")?;
+    let parent = local_path.parent().unwrap_or(Path::new(""));
+    fs::create_dir_all(parent).context("Failed to create local parent dir")?;
+    ensure_temp_dirs()?;
+    let started = Instant::now();
+    let mut report = TransferReport::default();
+    let file_meta = report.time("metadata clone", || metadata(remote))?;
+    if let Some(target) = &file_meta.symlink_target {
+        restore_symlink(target, local_path)?;
+        println!("{} -> {}", local, target);
+        report.total = started.elapsed();
+        return Ok(report);
+    }
+    // restored once the file is in place, a move across filesystems may
+    // lose them
+    let attrs = file_meta
+        .xattrs
+        .clone()
+        .filter(|_| RESTORE_XATTRS.load(AtomicOrdering::Relaxed));
+    let part_path = temp_dirs()
+        .staging
+        .join(format!("{}.gidrive-part", file_name.to_string_lossy()));
+    let res = File::create(&part_path)
+        .context("Failed to create temporary download file")
+        .and_then(|file| {
+            let mut output = BufWriter::new(file);
+            let report = stream_file(remote, file_meta, &mut output, true, report, started)?;
+            let file = output
+                .into_inner()
+                .map_err(|e| e.into_error())
+                .context("Failed to flush output")?;
+            file.sync_all().context("Failed to sync output")?;
+            Ok(report)
+        })
+        .and_then(|report| {
+            move_file(&part_path, local_path).context("Failed to move download in place")?;
+            if let Some(attrs) = &attrs {
+                xattrs::restore(local_path, attrs);
+            }
+            Ok(report)
+        });
+    if res.is_err() {
+        let _ = fs::remove_file(&part_path);
+    }
+    res
+}
+
+/// Replaces `local_path` with a symlink to `target`, created next to it
+/// and renamed over it. An existing dir is left alone.
+fn restore_symlink(target: &str, local_path: &Path) -> Result<()> {
+    if fs::symlink_metadata(local_path).is_ok_and(|m| m.is_dir()) {
+        bail!("{} is a directory", local_path.display());
+    }
+    let file_name = local_path.file_name().unwrap_or_default();
+    let tmp = local_path.with_file_name(format!(".{}.gidrive-link", file_name.to_string_lossy()));
+    let _ = fs::remove_file(&tmp);
+    std::os::unix::fs::symlink(target, &tmp)
+        .with_context(|| format!("Failed to create symlink {}", tmp.display()))?;
+    fs::rename(&tmp, local_path).map_err(|e| {
+        let _ = fs::remove_file(&tmp);
+        anyhow::Error::from(e).context("Failed to move symlink in place")
+    })
+}
+
+/// Streams a remote file to stdout.
+pub fn cat(remote: &str) -> Result<TransferReport> {
+    with_hooks("download", remote, "-", || {
+        let stdout = io::stdout();
+        let mut output = BufWriter::new(stdout.lock());
+        let report = download_to_writer(remote, &mut output)?;
+        output.flush().context("Failed to flush stdout")?;
+        Ok(report)
+    })
+}
+
+/// Metadata of the file `remote`, read without downloading any of its data.
+/// Fails with `FileNotFound` when there is no such file.
+pub fn metadata(remote: &str) -> Result<FileMetadata> {
+    ensure_temp_dirs()?;
+    let metadata_clone_dir = clone_file_metadata(remote)?;
+    let res = load_file_metadata(&metadata_clone_dir, remote);
+    fs::remove_dir_all(&metadata_clone_dir)?;
+    res
+}
+
+/// Whether there is a file `remote`.
+pub fn exists(remote: &str) -> Result<bool> {
+    match metadata(remote) {
+        Ok(_) => Ok(true),
+        Err(e) if e.is::<FileNotFound>() => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+/// Returns a standalone shell script that restores `remote` without gidrive.
+pub fn export_script(remote: &str, transport: ScriptTransport) -> Result<String> {
+    let file_meta = metadata(remote)?;
+    Ok(restore_script(remote, &file_meta, transport))
+}
+
+/// Writes where every chunk of the files under `prefix` is stored to stdout
+/// in `format`, reading only the metadata, one file at a time.
+pub fn export_map(prefix: &str, format: MapFormat) -> Result<()> {
+    ensure_temp_dirs()?;
+    let metadata_clone_dir = clone_metadata()?;
+    let prefix = prefix.trim_matches('/');
+    let res = (|| {
+        let mut map = MapWriter::new(BufWriter::new(io::stdout().lock()), format)?;
+        let corrupt = for_each_file_metadata(&metadata_clone_dir, prefix, |rel, meta| {
+            let remote = if prefix.is_empty() {
+                rel.to_string()
+            } else {
+                format!("{}/{}", prefix, rel)
+            };
+            Ok(map.file(&remote, &meta, owner())?)
+        })?;
+        map.finish()?;
+        for (path, reason) in &corrupt {
+            eprintln!(
+                "--- skipping corrupt metadata {}: {}, see `gidrive fsck`",
+                path.display(),
+                reason
+            );
+        }
+        Ok(())
+    })();
+    fs::remove_dir_all(&metadata_clone_dir)?;
+    res
+}
+
+/// Returns a restore script for a public `remote` that fetches the chunks
+/// over HTTPS without any credentials, for sharing with third parties.
+pub fn share(remote: &str) -> Result<String> {
+    let file_meta = metadata(remote)?;
+    if !file_meta.public {
+        bail!(
+            "{} is stored in private repos, upload it again with --public to share it",
+            remote
+        );
+    }
+    Ok(restore_script(remote, &file_meta, ScriptTransport::Curl))
+}
+
+/// Fetches all chunks of `remote` and writes them in order to `output`,
+/// verifying the total size and checksum of what was written.
+pub fn download_to_writer<W: Write>(remote: &str, output: &mut W) -> Result<TransferReport> {
+    stream_remote(remote, output, true)
+}
+
+/// Fetches `remote` and checks every chunk and the whole file against their
+/// checksums, writing nothing but the staged chunks, which are removed as
+/// they are hashed or after a failure. Prints the result and returns whether
+/// the file is intact; errors other than an `IntegrityError` are returned.
+pub fn verify_remote(remote: &str) -> Result<bool> {
+    ensure_temp_dirs()?;
+    match stream_remote(remote, &mut io::sink(), false) {
+        Ok(report) => {
+            println!("{}: ok, {} verified", remote, human_size(report.bytes));
+            Ok(true)
+        }
+        Err(e) if e.downcast_ref::<IntegrityError>().is_some() => {
+            println!("{}: FAILED, {}", remote, e);
+            Ok(false)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// Checks the files listed in the `sha256sum` manifest at `path` (`-` for
+/// stdin) against their stored checksums, with `deep` also fetching each and
+/// checking its chunks and content. Prints OK or FAILED per line like
+/// `sha256sum -c` and returns whether every listed file matched.
+pub fn verify_manifest(path: &str, deep: bool) -> Result<bool> {
+    let text = if path == "-" {
+        io::read_to_string(io::stdin().lock())
+    } else {
+        fs::read_to_string(path)
+    }
+    .with_context(|| format!("Failed to read the manifest {}", path))?;
+    let (mut listed, mut malformed) = (Vec::new(), 0);
+    for line in text.lines().filter(|line| !line.trim().is_empty()) {
+        match manifest::parse_line(line) {
+            Some(entry) => listed.push(entry),
+            None => malformed += 1,
+        }
+    }
+    if listed.is_empty() {
+        bail!("{} lists no files in the sha256sum format", path);
+    }
+    ensure_temp_dirs()?;
+    let metadata_clone_dir = clone_metadata()?;
+    let (mut mismatched, mut missing) = (0, 0);
+    let res = (|| {
+        for (checksum, remote) in &listed {
+            let shown = manifest::escape(remote);
+            let file_meta = match load_file_metadata(&metadata_clone_dir, remote) {
+                Ok(file_meta) => file_meta,
+                Err(e) if e.is::<FileNotFound>() => {
+                    eprintln!("--- {}: no such file", shown);
+                    println!("{}: FAILED open or read", shown);
+                    missing += 1;
+                    continue;
+                }
+                Err(e) => return Err(e),
+            };
+            let stored_matches =
+                file_meta.checksum_algo == ChecksumAlgo::Sha256 && &file_meta.checksum == checksum;
+            if !stored_matches {
+                println!("{}: FAILED", shown);
+                mismatched += 1;
+                continue;
+            }
+            if deep {
+                let report = TransferReport::default();
+                match stream_file(
+                    remote,
+                    file_meta,
+                    &mut io::sink(),
+                    false,
+                    report,
+                    Instant::now(),
+                ) {
+                    Ok(_) => {}
+                    Err(e) if e.downcast_ref::<IntegrityError>().is_some() => {
+                        eprintln!("--- {}: {}", shown, e);
+                        println!("{}: FAILED", shown);
+                        mismatched += 1;
+                        continue;
+                    }
+                    Err(e) => return Err(e),
+                }
+            }
+            println!("{}: OK", shown);
+        }
+        Ok(())
+    })();
+    fs::remove_dir_all(&metadata_clone_dir)?;
+    res?;
+    let plural =
+        |n: usize, one: &str, many: &str| format!("{} {}", n, if n == 1 { one } else { many });
+    if malformed > 0 {
+        eprintln!(
+            "--- WARNING: {} improperly formatted",
+            plural(malformed, "line is", "lines are")
+        );
+    }
+    if missing > 0 {
+        eprintln!(
+            "--- WARNING: {} could not be read",
+            plural(missing, "listed file", "listed files")
+        );
+    }
+    if mismatched > 0 {
+        eprintln!(
+            "--- WARNING: {} did NOT match",
+            plural(mismatched, "checksum", "checksums")
+        );
+    }
+    Ok(missing == 0 && mismatched == 0)
+}
+
+/// Streams `remote` into `output` in chunk order through the hasher. With
+/// `keep_staged` the chunks fetched before a failure stay staged for a retry.
+fn stream_remote<W: Write>(
+    remote: &str,
+    output: &mut W,
+    keep_staged: bool,
+) -> Result<TransferReport> {
+    let started = Instant::now();
+    let mut report = TransferReport::default();
+    let file_meta = report.time("metadata clone", || metadata(remote))?;
+    stream_file(remote, file_meta, output, keep_staged, report, started)
+}
+
+/// `stream_remote` of the already loaded `file_meta`, the time taken
+/// counted from `started`. A symlink has no content to stream.
+fn stream_file<W: Write>(
+    remote: &str,
+    file_meta: FileMetadata,
+    output: &mut W,
+    keep_staged: bool,
+    mut report: TransferReport,
+    started: Instant,
+) -> Result<TransferReport> {
+    if let Some(target) = &file_meta.symlink_target {
+        bail!(
+            "{} is a symlink to {}, download it to a path to recreate the link",
+            remote,
+            target
+        );
+    }
+    let temp_dir = temp_dirs()
+        .staging
+        .join(format!("dl_{}", file_meta.checksum));
+    // chunks are all staged before they are written out in order
+    ensure_free_space(0, file_meta.size, &format!("downloading {}", remote))?;
+    progress::emit(Event::TransferStarted {
+        direction: "download",
+        remote,
+        bytes: file_meta.size,
+        total_chunks: file_meta.chunks.len(),
+    });
+    if let Err(e) = fetch_to_writer(&file_meta, &temp_dir, output, &mut report) {
+        // staged chunks only help a retry when there was room for them
+        if is_no_space(&e) || !keep_staged {
+            let _ = fs::remove_dir_all(&temp_dir);
+        } else if temp_dir.exists() {
+            eprintln!(
+                "--- staged chunks kept in {} for a retry",
+                temp_dir.display()
+            );
+        }
+        return Err(match (revision(), e.downcast::<IntegrityError>()) {
+            (Some(at), Ok(IntegrityError(reason))) => IntegrityError(format!(
+                "{} existed as of {}, but not all of its data still does: gc deletes \
+                 chunks no current file has used for gc_grace_period. {}",
+                remote, at, reason
+            ))
+            .into(),
+            (_, Ok(e)) => e.into(),
+            (_, Err(e)) => explain_no_space(e),
+        });
+    }
+    progress::emit(Event::TransferFinished {
+        direction: "download",
+        remote,
+        bytes: file_meta.size,
+        chunks: file_meta.chunks.len(),
+        seconds: started.elapsed().as_secs_f64(),
+    });
+    report.bytes = file_meta.size;
+    report.checksum = Some(file_meta.checksum);
+    report.total = started.elapsed();
+    Ok(report)
+}
+
+/// A downloaded file that does not match its metadata: chunks that could
+/// not be fetched intact, or a wrong size or checksum once assembled.
+#[derive(Debug)]
+pub struct IntegrityError(pub String);
+
+impl fmt::Display for IntegrityError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for IntegrityError {}
+
+/// What a fetcher of `fetch_to_writer` reports to the writer.
+enum Fetched {
+    Chunk(usize),
+    Repo(RepoTimings, Vec<(usize, String)>),
+}
+
+/// Downloads the chunks of `file_meta` into `temp_dir` and writes them in
+/// order to `output` while they arrive, verifying the total size and
+/// checksum. Returns the hasher state over the written content.
+/// Repos are started by their lowest chunk index, so with striped placement
+/// the writer trails the fetchers instead of waiting for the repo with chunk
+/// 0 to come last. Staged chunks are only removed once every chunk was
+/// fetched, so a failed download can resume from all of them.
+pub fn fetch_to_writer<W: Write>(
+    file_meta: &FileMetadata,
+    temp_dir: &Path,
+    output: &mut W,
+    report: &mut TransferReport,
+) -> Result<Hasher> {
+    let (total_written, hasher) = fetch_chunks_to_writer(file_meta, temp_dir, output, report)?;
+    if total_written != file_meta.size {
+        return Err(IntegrityError(format!(
+            "Downloaded size mismatch: {} vs {}",
+            total_written, file_meta.size
+        ))
+        .into());
+    }
+    let downloaded_checksum = hasher.clone().finalize_hex();
+    if downloaded_checksum != file_meta.checksum {
+        return Err(IntegrityError(format!(
+            "Checksum mismatch ({}): {} vs {}",
+            file_meta.checksum_algo, downloaded_checksum, file_meta.checksum
+        ))
+        .into());
+    }
+    Ok(hasher)
+}
+
+/// Writes the bytes `range` of `file_meta` to `output`, fetching only the
+/// chunks holding them. Each chunk is checked against its own checksum, the
+/// checksum of the whole file cannot be without reading all of it.
+pub fn fetch_range_to_writer<W: Write>(
+    file_meta: &FileMetadata,
+    range: Range<u64>,
+    temp_dir: &Path,
+    output: &mut W,
+    report: &mut TransferReport,
+) -> Result<()> {
+    let mut chunks = Vec::new();
+    let mut skip = 0;
+    let mut pos = 0;
+    for chunk in &file_meta.chunks {
+        if pos + chunk.size > range.start && pos < range.end {
+            if chunks.is_empty() {
+                skip = range.start - pos;
+            }
+            chunks.push(chunk.clone());
+        }
+        pos += chunk.size;
+    }
+    let part = FileMetadata {
+        size: chunks.iter().map(|c| c.size).sum(),
+        chunks,
+        ..file_meta.clone()
+    };
+    let mut window = RangeWriter {
+        inner: output,
+        skip,
+        remaining: range.end - range.start,
+    };
+    let (written, _) = fetch_chunks_to_writer(&part, temp_dir, &mut window, report)?;
+    if written != part.size || window.remaining > 0 {
+        return Err(IntegrityError(format!(
+            "Downloaded size mismatch: {} bytes of chunks for range {}-{} of a {} byte file",
+            written, range.start, range.end, file_meta.size
+        ))
+        .into());
+    }
+    Ok(())
+}
+
+/// Writer passing on only the `remaining` bytes after the first `skip`.
+struct RangeWriter<'a, W: Write> {
+    inner: &'a mut W,
+    skip: u64,
+    remaining: u64,
+}
+
+impl<W: Write> Write for RangeWriter<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let skipped = buf.len().min(self.skip as usize);
+        self.skip -= skipped as u64;
+        let kept = (buf.len() - skipped).min(self.remaining as usize);
+        self.inner.write_all(&buf[skipped..skipped + kept])?;
+        self.remaining -= kept as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// The fetching and in-order writing of `fetch_to_writer`, returning the
+/// bytes written and the hasher over them unchecked.
+fn fetch_chunks_to_writer<W: Write>(
+    file_meta: &FileMetadata,
+    temp_dir: &Path,
+    output: &mut W,
+    report: &mut TransferReport,
+) -> Result<(u64, Hasher)> {
+    // Group chunks by repo for batched parallel download, each in index order
+    let mut repo_map: HashMap<String, Vec<(usize, ChunkInfo)>> = HashMap::new();
+    for (global_i, chunk) in file_meta.chunks.iter().enumerate() {
+        repo_map
+            .entry(chunk.location())
+            .or_default()
+            .push((global_i, chunk.clone()));
+    }
+    let mut schedule: Vec<(String, Vec<(usize, ChunkInfo)>)> = repo_map.into_iter().collect();
+    schedule.sort_by_key(|(_, chunk_list)| chunk_list[0].0);
+    if very_verbose() {
+        for (repo_name, chunk_list) in &schedule {
+            eprintln!(
+                "schedule: {} from chunk {} ({} chunks)",
+                repo_name,
+                chunk_list[0].0,
+                chunk_list.len()
+            );
+        }
+    }
+    let repo_count = schedule.len();
+    let schedule = Mutex::new(VecDeque::from(schedule));
+    fs::create_dir_all(temp_dir).context("Failed to create dl temp dir")?;
+    let total = file_meta.chunks.len();
+    let fetch_started = Instant::now();
+    let stop = AtomicBool::new(false);
+    let (tx, rx) = mpsc::channel();
+    let mut output = HashingWriter::new(output, file_meta.checksum_algo);
+    let mut failed: Vec<(usize, String)> = Vec::new();
+    let mut written = 0;
+    let write_chunk = |output: &mut HashingWriter<&mut W>, i: usize, remove: bool| -> Result<()> {
+        let chunk_p = temp_dir.join(format!("chunk_{}", i));
+        let chunk = &file_meta.chunks[i];
+        if let Err(e) = copy_staged(&chunk_p, chunk, output) {
+            let Some(short) = e.downcast_ref::<ShortChunk>() else {
+                return Err(e);
+            };
+            // the staged file changed since it was verified, fetch it again
+            eprintln!("--- {}, fetching it again", short);
+            let (_, refetch_failed) = download_chunks_from_repo(
+                &chunk.location(),
+                &[(i, chunk.clone())],
+                temp_dir,
+                file_meta.checksum_algo,
+                |_| {},
+            );
+            if let Some((_, reason)) = refetch_failed.into_iter().next() {
+                return Err(IntegrityError(format!(
+                    "chunk {} ({}): {}",
+                    chunk.index, chunk.repo, reason
+                ))
+                .into());
+            }
+            copy_staged(&chunk_p, chunk, output).map_err(|e| match e.downcast::<ShortChunk>() {
+                Ok(short) => IntegrityError(short.to_string()).into(),
+                Err(e) => e,
+            })?;
+        }
+        if remove {
+            fs::remove_file(&chunk_p).context("Failed to remove temp chunk")?;
+        }
+        Ok(())
+    };
+    thread::scope(|s| -> Result<()> {
+        for _ in 0..transfer_concurrency().min(repo_count) {
+            let tx = tx.clone();
+            let (schedule, stop) = (&schedule, &stop);
+            s.spawn(move || {
+                while !stop.load(AtomicOrdering::Relaxed) {
+                    let Some((repo_name, chunk_list)) = schedule.lock().unwrap().pop_front() else {
+                        break;
+                    };
+                    if very_verbose() {
+                        eprintln!(
+                            "schedule: starting {}, lowest outstanding chunk {}",
+                            repo_name, chunk_list[0].0
+                        );
+                    }
+                    progress::emit(Event::RepoStarted {
+                        repo: &repo_name,
+                        chunks: chunk_list.len(),
+                    });
+                    let started = Instant::now();
+                    let (timings, repo_failed) = download_chunks_from_repo(
+                        &repo_name,
+                        &chunk_list,
+                        temp_dir,
+                        file_meta.checksum_algo,
+                        |global_i| {
+                            let chunk = &file_meta.chunks[global_i];
+                            progress::emit(Event::ChunkDownloaded {
+                                index: chunk.index,
+                                repo: &repo_name,
+                                bytes: chunk.size,
+                                total_chunks: total,
+                            });
+                            let _ = tx.send(Fetched::Chunk(global_i));
+                        },
+                    );
+                    progress::emit(Event::RepoFinished {
+                        repo: &repo_name,
+                        chunks: chunk_list.len() - repo_failed.len(),
+                        seconds: started.elapsed().as_secs_f64(),
+                    });
+                    let _ = tx.send(Fetched::Repo(timings, repo_failed));
+                }
+            });
+        }
+        drop(tx);
+        // write the chunks in order while the fetchers go on
+        let mut staged = vec![false; total];
+        let mut waiting_for = None;
+        for fetched in rx {
+            match fetched {
+                Fetched::Chunk(i) => staged[i] = true,
+                Fetched::Repo(timings, repo_failed) => {
+                    for (global_i, reason) in repo_failed {
+                        let index = file_meta.chunks[global_i].index;
+                        failed.push((
+                            index,
+                            format!("chunk {} ({}): {}", index, timings.repo, reason),
+                        ));
+                    }
+                    report.repos.push(timings);
+                }
+            }
+            if !failed.is_empty() {
+                continue;
+            }
+            while written < total && staged[written] {
+                if let Err(e) = write_chunk(&mut output, written, false) {
+                    stop.store(true, AtomicOrdering::Relaxed);
+                    return Err(e);
+                }
+                written += 1;
+            }
+            if very_verbose() && written < total && waiting_for != Some(written) {
+                eprintln!(
+                    "schedule: writer waiting for chunk {} from {}",
+                    written, file_meta.chunks[written].repo
+                );
+                waiting_for = Some(written);
+            }
+        }
+        Ok(())
+    })?;
+    report.add("chunk download", fetch_started.elapsed());
+    if let Err(e) = cache::evict(settings().repo_cache_size()) {
+        eprintln!("--- could not trim the repo cache: {:#}", e);
+    }
+    if !failed.is_empty() {
+        failed.sort();
+        let lines: Vec<String> = failed.into_iter().map(|(_, line)| line).collect();
+        return Err(IntegrityError(format!(
+            "{} unrecoverable chunks:\n  {}",
+            lines.len(),
+            lines.join("\n  ")
+        ))
+        .into());
+    }
+    // what the writer still trails the fetchers by
+    let assembly_started = Instant::now();
+    for i in 0..written {
+        fs::remove_file(temp_dir.join(format!("chunk_{}", i)))
+            .context("Failed to remove temp chunk")?;
+    }
+    for i in written..total {
+        write_chunk(&mut output, i, true)?;
+    }
+    fs::remove_dir(temp_dir).context("Failed to remove dl temp dir")?;
+    report.add("assembly", assembly_started.elapsed());
+    Ok(output.into_hasher())
+}
+
+/// Makes the file `path` of the existing repo `source` (`owner/name`)
+/// available as `remote` without uploading it again: the chunks recorded for
+/// it are byte ranges of the file in that repo. With `copy` the file is
+/// uploaded into the storage repos like a local file instead. The source
+/// repo is never modified, not even by a later removal of `remote`.
+pub fn adopt(source: &str, path: &str, remote: &str, copy: bool) -> Result<()> {
+    if source.split('/').count() != 2 || source.split('/').any(str::is_empty) {
+        bail!("source repo must look like owner/name, got {}", source);
+    }
+    ensure_temp_dirs()?;
+    let clone_dir = temp_dirs()
+        .work
+        .join(format!("adopt_{}", source.replace('/', "_")));
+    if clone_dir.exists() {
+        fs::remove_dir_all(&clone_dir)?;
+    }
+    clone_repo(&repo_url(source), &clone_dir)?;
+    let res = (|| {
+        let file_path = clone_dir.join(path);
+        if !file_path.is_file() {
+            bail!("{} has no file {}", source, path);
+        }
+        if copy {
+            upload(
+                remote,
+                &file_path.to_string_lossy(),
+                &UploadOptions::default(),
+            )?;
+            return Ok(());
+        }
+        let algo = ChecksumAlgo::default();
+        let mut file = File::open(&file_path)?;
+        let mut whole = Hasher::new(algo);
+        let mut chunks = Vec::new();
+        let mut buf = vec![0u8; settings().chunk_size() as usize];
+        let mut offset = 0u64;
+        loop {
+            let n = read_full(&mut file, &mut buf)?;
+            if n == 0 {
+                break;
+            }
+            whole.update(&buf[..n]);
+            chunks.push(ChunkInfo {
+                repo: source.to_string(),
+                path: path.to_string(),
+                size: n as u64,
+                index: chunks.len(),
+                offset,
+                checksum: Some(checksum_hex(algo, &buf[..n])),
+                owner: None,
+            });
+            offset += n as u64;
+        }
+        let file_meta = FileMetadata {
+            checksum: whole.finalize_hex(),
+            checksum_algo: algo,
+            size: offset,
+            chunks,
+            public: false,
+            mtime: Some(unix_now()),
+            archive: None,
+            symlink_target: None,
+            xattrs: None,
+            source_url: None,
+        };
+        let metadata_clone_dir = clone_metadata()?;
+        check_write_version(&metadata_clone_dir)?;
+        save_indexed(&metadata_clone_dir, remote, &file_meta, &[], &[])?;
+        update_namespace_stats(&metadata_clone_dir)?;
+        commit_metadata_for(
+            &metadata_clone_dir,
+            "Adopt",
+            1,
+            &format!("{}:{} as {}", source, path, remote),
+        )?;
+        fs::remove_dir_all(&metadata_clone_dir)?;
+        Ok(())
+    })();
+    fs::remove_dir_all(&clone_dir)?;
+    res
+}
+
+/// Deletes `remote`: the file metadata is dropped and its chunks wait in
+/// pending_delete.json until `gc` removes them from the storage repos after
+/// the grace period. Chunks that another file still references are left
+/// in place.
+pub fn remove(remote: &str) -> Result<()> {
+    remove_files(&[remote.to_string()], remote, false, false, None).map(|_| ())
+}
+
+/// Deletes the files `remotes` of the current namespace like `remove`.
+/// `permanent` deletes their chunks right away, with a single commit per
+/// repo, and releases their space in repos.json. `label` names the removal
+/// in commit messages. With `ask`, the user confirms by typing it once the
+/// space is known. Returns the bytes of chunks deleted or queued, or that
+/// would be with `dry_run`.
+fn remove_files(
+    remotes: &[String],
+    label: &str,
+    permanent: bool,
+    dry_run: bool,
+    ask: Option<&str>,
+) -> Result<u64> {
+    ensure_temp_dirs()?;
+    let metadata_clone_dir = clone_metadata()?;
+    if !dry_run {
+        check_write_version(&metadata_clone_dir)?;
+    }
+    // references from files of every namespace are counted in chunks.idx,
+    // adopted chunks in foreign repos are not and are never deleted
+    let mut index = load_chunk_index(&metadata_clone_dir)?;
+    let mut chunks = Vec::new();
+    for remote in remotes {
+        let meta = load_file_metadata(&metadata_clone_dir, remote)?;
+        chunks.extend(release_chunks(&mut index, &meta));
+        fs::remove_file(file_meta_path(&metadata_clone_dir, remote)?)?;
+    }
+    let mut repos_meta = load_repos_metadata(&metadata_clone_dir)?;
+    if !permanent {
+        let queued = chunks.iter().map(|c| c.size).sum();
+        if !dry_run {
+            let journal = Journal::begin(Intent::Remove {
+                remotes: remotes.to_vec(),
+                label: label.to_string(),
+                permanent,
+            })?;
+            defer_deletes(&metadata_clone_dir, &mut repos_meta, chunks)?;
+            save_repos_metadata(&metadata_clone_dir, &repos_meta)?;
+            save_chunk_index(&metadata_clone_dir, &index)?;
+            update_namespace_stats(&metadata_clone_dir)?;
+            commit_metadata_for(&metadata_clone_dir, "Remove", remotes.len(), label)?;
+            journal.finish();
+        }
+        fs::remove_dir_all(&metadata_clone_dir)?;
+        return Ok(queued);
+    }
+    let mut by_repo: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    let mut reclaimed = 0;
+    for chunk in chunks {
+        by_repo
+            .entry(chunk.location())
+            .or_default()
+            .push(chunk.path);
+        if let Some(info) = repos_meta.repos.get_mut(&chunk.repo) {
+            info.current_size = info.current_size.saturating_sub(chunk.size);
+        }
+        reclaimed += chunk.size;
+    }
+    if dry_run {
+        fs::remove_dir_all(&metadata_clone_dir)?;
+        return Ok(reclaimed);
+    }
+    if let Some(answer) = ask {
+        let what = format!(
+            "delete {} files of {}, reclaiming {}",
+            remotes.len(),
+            label,
+            human_size(reclaimed)
+        );
+        if let Err(e) = confirm(&what, answer) {
+            fs::remove_dir_all(&metadata_clone_dir)?;
+            return Err(e);
+        }
+    }
+    // the chunks are gone before the metadata commit drops the files
+    let journal = Journal::begin(Intent::Remove {
+        remotes: remotes.to_vec(),
+        label: label.to_string(),
+        permanent,
+    })?;
+    by_repo
+        .par_iter()
+        .filter(|(_, paths)| !paths.is_empty())
+        .map(|(repo, paths)| remove_chunks_from_repo(label, repo, paths))
+        .collect::<Result<Vec<_>>>()?;
+    save_repos_metadata(&metadata_clone_dir, &repos_meta)?;
+    save_chunk_index(&metadata_clone_dir, &index)?;
+    update_namespace_stats(&metadata_clone_dir)?;
+    commit_metadata_for(&metadata_clone_dir, "Remove", remotes.len(), label)?;
+    journal.finish();
+    fs::remove_dir_all(&metadata_clone_dir)?;
+    Ok(reclaimed)
+}
+
+/// The files of the current namespace under the remote directory `prefix`,
+/// keyed by their full path.
+fn files_under(metadata_clone_dir: &Path, prefix: &str) -> Result<BTreeMap<String, FileMetadata>> {
+    let prefix = prefix.trim_matches('/');
+    Ok(list_file_metadata(metadata_clone_dir, prefix)?
+        .into_iter()
+        .map(|(rel, meta)| {
+            if prefix.is_empty() {
+                (rel, meta)
+            } else {
+                (format!("{}/{}", prefix, rel), meta)
+            }
+        })
+        .collect())
+}
+
+/// `remote` as a directory in messages, the namespace for the root.
+fn dir_label(prefix: &str) -> String {
+    if prefix.is_empty() {
+        format!("namespace {}", namespace())
+    } else {
+        format!("{}/", prefix)
+    }
+}
+
+/// Deletes the file `remote`, or with `recursive` every file under the
+/// directory `remote`, in one metadata commit. Their chunks wait in
+/// pending_delete.json for `gc` like with `remove`. The whole namespace
+/// needs `force`.
+pub fn rm(remote: &str, recursive: bool, dry_run: bool, force: bool) -> Result<()> {
+    ensure_temp_dirs()?;
+    let metadata_clone_dir = clone_metadata()?;
+    let prefix = remote.trim_matches('/');
+    let is_file = !remote.ends_with('/')
+        && !prefix.is_empty()
+        && file_meta_path(&metadata_clone_dir, prefix)?.exists();
+    let files = if is_file {
+        BTreeMap::from([(
+            prefix.to_string(),
+            load_file_metadata(&metadata_clone_dir, prefix)?,
+        )])
+    } else {
+        files_under(&metadata_clone_dir, prefix)?
+    };
+    let missing = (files.is_empty() && !recursive && !prefix.is_empty())
+        .then(|| load_file_metadata(&metadata_clone_dir, prefix).err())
+        .flatten();
+    fs::remove_dir_all(&metadata_clone_dir)?;
+    let label = if is_file {
+        prefix.to_string()
+    } else {
+        dir_label(prefix)
+    };
+    if let Some(e) = missing {
+        return Err(e);
+    }
+    if !is_file && !recursive {
+        bail!(
+            "{} is a directory, pass -r to remove everything under it",
+            label
+        );
+    }
+    if prefix.is_empty() && !force {
+        bail!(
+            "refusing to remove every file of the {} without --force",
+            label
+        );
+    }
+    if files.is_empty() {
+        println!("no files under {}", label);
+        return Ok(());
+    }
+    let bytes: u64 = files.values().map(|meta| meta.size).sum();
+    if dry_run {
+        for (path, meta) in &files {
+            println!("would remove {} ({})", path, human_size(meta.size));
+        }
+        println!(
+            "would remove {} files of {}, {}",
+            files.len(),
+            label,
+            human_size(bytes)
+        );
+        return Ok(());
+    }
+    let remotes: Vec<String> = files.into_keys().collect();
+    let queued = remove_files(&remotes, &label, false, false, None)?;
+    println!(
+        "removed {} files of {}, {}; {} of chunks wait for gc",
+        remotes.len(),
+        label,
+        human_size(bytes),
+        human_size(queued)
+    );
+    Ok(())
+}
+
+/// Renames the file `src` to `dst`, into `dst` when it is a directory, or
+/// every file under the directory `src` to the same path under `dst`, in
+/// one metadata commit. The chunks stay where they are. Existing files are
+/// never overwritten, and the whole namespace needs `force`.
+pub fn mv(src: &str, dst: &str, dry_run: bool, force: bool) -> Result<()> {
+    ensure_temp_dirs()?;
+    let metadata_clone_dir = clone_metadata()?;
+    let res = plan_moves(&metadata_clone_dir, src, dst, force).and_then(|moves| {
+        if dry_run {
+            for (from, to) in &moves {
+                println!("would move {} -> {}", from, to);
+            }
+            println!("would move {} files", moves.len());
+            return Ok(());
+        }
+        check_write_version(&metadata_clone_dir)?;
+        // read every file before writing any, a target may be another's source
+        let mut moved = Vec::new();
+        for (from, to) in &moves {
+            let from_path = file_meta_path(&metadata_clone_dir, from)?;
+            moved.push((fs::read(&from_path)?, to));
+            fs::remove_file(&from_path)?;
+        }
+        for (data, to) in moved {
+            let to_path = file_meta_path(&metadata_clone_dir, to)?;
+            fs::create_dir_all(to_path.parent().context("metadata path has no parent")?)?;
+            fs::write(&to_path, data)?;
+        }
+        update_namespace_stats(&metadata_clone_dir)?;
+        commit_metadata_for(
+            &metadata_clone_dir,
+            "Move",
+            moves.len(),
+            &format!("{} to {}", src, dst),
+        )?;
+        println!("moved {} files", moves.len());
+        Ok(())
+    });
+    fs::remove_dir_all(&metadata_clone_dir)?;
+    res
+}
+
+/// The (from, to) paths of `mv src dst`, checked against the files of the
+/// metadata clone.
+fn plan_moves(
+    metadata_clone_dir: &Path,
+    src: &str,
+    dst: &str,
+    force: bool,
+) -> Result<Vec<(String, String)>> {
+    let (src_prefix, dst_prefix) = (src.trim_matches('/'), dst.trim_matches('/'));
+    let join = |dir: &str, rel: &str| {
+        if dir.is_empty() {
+            rel.to_string()
+        } else {
+            format!("{}/{}", dir, rel)
+        }
+    };
+    let is_file = !src.ends_with('/')
+        && !src_prefix.is_empty()
+        && file_meta_path(metadata_clone_dir, src_prefix)?.exists();
+    let moves = if is_file {
+        let into_dir = dst.ends_with('/')
+            || dst_prefix.is_empty()
+            || !files_under(metadata_clone_dir, dst_prefix)?.is_empty();
+        let to = if into_dir {
+            let name = src_prefix.rsplit('/').next().unwrap_or(src_prefix);
+            join(dst_prefix, name)
+        } else {
+            dst_prefix.to_string()
+        };
+        vec![(src_prefix.to_string(), to)]
+    } else {
+        if src_prefix.is_empty() && !force {
+            bail!(
+                "refusing to move every file of the {} without --force",
+                dir_label("")
+            );
+        }
+        let inside = src_prefix.is_empty()
+            || dst_prefix == src_prefix
+            || dst_prefix.starts_with(&format!("{}/", src_prefix));
+        if inside {
+            bail!(
+                "cannot move {} into itself, {}",
+                dir_label(src_prefix),
+                dir_label(dst_prefix)
+            );
+        }
+        let files = files_under(metadata_clone_dir, src_prefix)?;
+        if files.is_empty() {
+            load_file_metadata(metadata_clone_dir, src_prefix)?;
+        }
+        files
+            .into_keys()
+            .map(|from| {
+                let rel = from[src_prefix.len()..].trim_start_matches('/');
+                let to = join(dst_prefix, rel);
+                (from, to)
+            })
+            .collect()
+    };
+    let sources: HashSet<&str> = moves.iter().map(|(from, _)| from.as_str()).collect();
+    let mut taken = Vec::new();
+    for (_, to) in &moves {
+        check_remote_name(to)?;
+        if !sources.contains(to.as_str()) && file_meta_path(metadata_clone_dir, to)?.exists() {
+            taken.push(to.as_str());
+        }
+    }
+    if let Some(first) = taken.first() {
+        bail!(
+            "{} already exists{}, remove it first",
+            first,
+            match taken.len() {
+                1 => String::new(),
+                n => format!(" and {} more files", n - 1),
+            }
+        );
+    }
+    Ok(moves)
+}
+
+/// Compares `local` against `remote` and prints what differs, returning
+/// whether both sides are identical.
+///
+/// A local directory is compared against every remote file under the
+/// `remote` prefix, in the spirit of `rsync -n`. Files are compared by size:
+/// the recorded mtime is that of the upload, not of the source, so it says
+/// nothing about the content and a warning tells when size was all that
+/// was compared. `by_checksum` additionally hashes local files with the
+/// algorithm each remote file was uploaded with.
+pub fn diff(
+    remote: &str,
+    local: &str,
+    by_checksum: bool,
+    excludes: &ExcludeOptions,
+) -> Result<bool> {
+    ensure_temp_dirs()?;
+    let metadata_clone_dir = clone_metadata()?;
+    let local_path = Path::new(local);
+    // files on both sides, whose content only `by_checksum` compares
+    let mut compared = 0;
+    let identical = if local_path.is_dir() {
+        let excludes = Excludes::load(local_path, excludes)?;
+        // remote files under an excluded path are left out like local ones
+        let remote_files: BTreeMap<String, FileMetadata> =
+            list_file_metadata(&metadata_clone_dir, remote)?
+                .into_iter()
+                .filter(|(name, _)| excludes.excluded(&local_path.join(name), false).is_none())
+                .collect();
+        let mut local_files = BTreeMap::new();
+        let on_excluded = |path: &Path, reason: &str| {
+            if verbose() {
+                println!(
+                    "excluded     {} ({})",
+                    path.strip_prefix(local_path).unwrap_or(path).display(),
+                    reason
+                );
+            }
+        };
+        for entry in excludes.walk(on_excluded) {
+            let entry = entry?;
+            if entry.file_type().is_file() {
+                let rel = entry.path().strip_prefix(local_path)?;
+                local_files.insert(rel.to_string_lossy().into_owned(), entry.into_path());
+            }
+        }
+        let mut identical = true;
+        for name in local_files
+            .keys()
+            .filter(|n| !remote_files.contains_key(*n))
+        {
+            println!("only-local   {}", name);
+            identical = false;
+        }
+        let mut both = Vec::new();
+        for (name, meta) in &remote_files {
+            match local_files.get(name) {
+                Some(path) => both.push((name, path, meta)),
+                None => {
+                    println!("only-remote  {}", name);
+                    identical = false;
+                }
+            }
+        }
+        // hash in path order, a window at a time: parallel, but the reads stay
+        // close together on disk and results print as each window completes
+        compared = both.len();
+        let window = rayon::current_num_threads() * 2;
+        for files in both.chunks(window) {
+            let differences = files
+                .par_iter()
+                .map(|(_, path, meta)| file_differences(path, meta, by_checksum))
+                .collect::<Result<Vec<_>>>()?;
+            for ((name, _, _), differences) in files.iter().zip(differences) {
+                if !differences.is_empty() {
+                    println!("differs      {} ({})", name, differences.join(", "));
+                    identical = false;
+                }
+            }
+        }
+        identical
+    } else {
+        let remote_exists = file_meta_path(&metadata_clone_dir, remote)?.exists();
+        match (local_path.exists(), remote_exists) {
+            (false, false) => bail!("neither {} nor remote {} exist", local, remote),
+            (true, false) => {
+                println!("only-local");
+                false
+            }
+            (false, true) => {
+                println!("only-remote");
+                false
+            }
+            (true, true) => {
+                let meta = load_file_metadata(&metadata_clone_dir, remote)?;
+                compared = 1;
+                let differences = file_differences(local_path, &meta, by_checksum)?;
+                if differences.is_empty() {
+                    println!("identical");
+                }
+                for difference in &differences {
+                    println!("{}", difference);
+                }
+                differences.is_empty()
+            }
+        }
+    };
+    fs::remove_dir_all(&metadata_clone_dir)?;
+    if compared > 0 && !by_checksum {
+        eprintln!(
+            "--- {} files on both sides compared by size only, pass --checksum to compare their content",
+            compared
+        );
+    }
+    Ok(identical)
+}
+
+/// Lists how the local file at `path` differs from the remote `meta`.
+fn file_differences(path: &Path, meta: &FileMetadata, by_checksum: bool) -> Result<Vec<String>> {
+    let size = fs::metadata(path)?.len();
+    if size != meta.size {
+        return Ok(vec![format!("size: local {}, remote {}", size, meta.size)]);
+    }
+    if by_checksum {
+        let checksum = get_file_checksum(path, meta.checksum_algo)?;
+        if checksum != meta.checksum {
+            return Ok(vec![format!(
+                "checksum ({}): local {}, remote {}",
+                meta.checksum_algo, checksum, meta.checksum
+            )]);
+        }
+    }
+    Ok(Vec::new())
+}
 
-pub fn upload(remote: &str, local: &str) -> Result<()> {
-    ensure_tmpfs_dir()?;
-    let local_path = Path::new(local);
-    let checksum = get_file_sha256(local_path)?;
-    let file_size = fs::metadata(local_path)?.len();
-    // Clone metadata to get repos info
+/// How `init` treats the metadata repo.
+#[derive(Clone, Copy, Default)]
+pub struct InitOptions {
+    /// Initialize a metadata repo that has content but no repos.json
+    pub force: bool,
+    /// Only check that the existing metadata repo can be read and written
+    /// by this version and summarize it, without writing anything
+    pub from_existing: bool,
+}
+
+/// Prepares the process and the metadata repo for any command: repo
+/// transfers are bounded by `transfer_concurrency`, CPU-bound work runs on a
+/// rayon pool of `hash_threads`, and git authenticates over `transport`.
+pub fn init(config: &Config, opts: &InitOptions) -> Result<()> {
+    ensure_temp_dirs()?;
+    setup_auth(config)?;
+    set_transfer_concurrency(config.transfer_concurrency());
+    if opts.from_existing {
+        return check_existing();
+    }
+    // another account's metadata is only read, as it is, and the cached
+    // copy offline
+    let mut create = !remote_overridden() && !offline();
+    if create && !repo_exists("metadata") {
+        // an unreachable GitHub lists no repos either
+        match check_reachable("metadata") {
+            Ok(()) => {}
+            Err(e) if fall_back_offline(&e) => create = false,
+            Err(_) => {
+                require_scope("repo", "create the metadata repo")?;
+                create_repo("metadata", false)?;
+            }
+        }
+    }
+    let hash_threads = config
+        .hash_threads
+        .or_else(|| std::thread::available_parallelism().ok().map(|n| n.get()))
+        .unwrap_or(1);
+    ThreadPoolBuilder::new()
+        .num_threads(hash_threads)
+        .build_global()
+        .context("Failed to initialize rayon thread pool")?;
+    if !create {
+        return Ok(());
+    }
     let metadata_clone_dir = get_metadata_dir();
     if metadata_clone_dir.exists() {
         fs::remove_dir_all(&metadata_clone_dir)?;
     }
-    clone_repo(METADATA_REPO_URL, &metadata_clone_dir)?;
+    clone_repo(&metadata_repo_url(), &metadata_clone_dir)?;
+    if !metadata_clone_dir.join("repos.json").exists() {
+        if has_content(&metadata_clone_dir)? && !opts.force {
+            bail!(
+                "the metadata repo has content but no repos.json, refusing to initialize it; \
+                 run `gidrive init --force` to write a fresh repos.json into it"
+            );
+        }
+        bootstrap(&metadata_clone_dir)?;
+        commit_metadata(&metadata_clone_dir, "Initialize metadata")?;
+    } else if migrate_to_namespaces(&metadata_clone_dir)? {
+        check_write_version(&metadata_clone_dir)?;
+        commit_metadata(
+            &metadata_clone_dir,
+            &format!("Move files into the {} namespace", DEFAULT_NAMESPACE),
+        )?;
+    }
+    fs::remove_dir_all(&metadata_clone_dir)?;
+    Ok(())
+}
 
-    // check if current version and remote version are compatable
+/// `init --from-existing`: checks that the metadata repo exists, passes the
+/// signature check and has a version this one may write, then prints what
+/// it holds.
+fn check_existing() -> Result<()> {
+    if !repo_exists("metadata") {
+        bail!(
+            "{} does not exist, run `gidrive init` to create it",
+            metadata_repo_url()
+        );
+    }
+    let metadata_clone_dir =
+        clone_metadata().context("Failed to read the metadata repo, check the ssh key")?;
+    if !metadata_clone_dir.join("repos.json").exists() {
+        bail!("the metadata repo has no repos.json, it was never initialized");
+    }
     let version = load_version(&metadata_clone_dir)?;
-    if !versions_are_compatible(&version, VERSION) {
-        panic!(
-            "upload rejected: Incompatible version: current {}, found {}, you can only perform read operations",
-            VERSION, version
+    // nothing is written in read-only mode or offline, an older version is fine
+    if !read_only() && !offline() {
+        check_write_version(&metadata_clone_dir)?;
+    }
+    let repos_meta = load_repos_metadata(&metadata_clone_dir)?;
+    let namespaces = load_namespace_stats(&metadata_clone_dir)?.len().max(1);
+    let (files, corrupt) = list_all_file_metadata_tolerant(&metadata_clone_dir)?;
+    fs::remove_dir_all(&metadata_clone_dir)?;
+    println!("metadata:   version {} (this gidrive {})", version, VERSION);
+    println!(
+        "files:      {} in {} namespaces, {}",
+        files.len(),
+        namespaces,
+        human_size(files.values().map(|meta| meta.size).sum())
+    );
+    println!("repos:      {}", repos_meta.repos.len());
+    if !corrupt.is_empty() {
+        println!(
+            "unreadable: {} metadata files, run `gidrive fsck`",
+            corrupt.len()
         );
     }
-    let mut repos_meta = load_repos_metadata(&metadata_clone_dir)?;
+    Ok(())
+}
 
-    // Pre-assign repos for all chunks (sequential)
-    let mut assignments: Vec<(usize, String, u64)> = Vec::new();
-    let mut remaining = file_size;
-    let mut index = 0;
-    while remaining > 0 {
-        let chunk_size = remaining.min(CHUNK_SIZE as u64);
-        let repo_name = retry(
-            || find_or_create_repo_for_chunk(&mut repos_meta, chunk_size),
-            3,  // start delay 1 second
-            10, // add 1 second each retry; use 0 if you want fixed delay
-        );
-        sleep(1.3);
-        assignments.push((index, repo_name, chunk_size));
-        remaining -= chunk_size;
-        index += 1;
+/// How `ls` orders the files.
+#[derive(Clone, Copy, Default)]
+pub enum LsSort {
+    /// Lexicographically by remote path
+    #[default]
+    Name,
+    /// Smallest first
+    Size,
+    /// Oldest upload first, files from older versions without one before all
+    Mtime,
+}
+
+#[derive(Default)]
+pub struct LsOptions {
+    /// Fail on a corrupt metadata file instead of skipping it
+    pub strict: bool,
+    pub sort: LsSort,
+    pub reverse: bool,
+    /// List the subdirectories of a directory before its files
+    pub group_dirs: bool,
+    /// Print a JSON array of entries instead of one line per file
+    pub json: bool,
+    /// Print `sha256sum` lines of the checksum and path instead
+    pub checksums: bool,
+    /// Print the stable tab-separated lines of `listing::porcelain_line`
+    pub porcelain: bool,
+}
+
+/// Compares remote paths component by component, placing a directory before
+/// the files next to it when `group_dirs` is set.
+fn cmp_paths(a: &str, b: &str, group_dirs: bool) -> Ordering {
+    if !group_dirs {
+        return a.cmp(b);
     }
-    // Save and push updated repos.json
-    save_repos_metadata(&metadata_clone_dir, &repos_meta)?;
-    git_add_commit_push(&metadata_clone_dir, "Pre-assign repos for upload")?;
-    // Create temp chunk files sequentially
-    let mut chunk_paths: Vec<PathBuf> = Vec::new();
-    let mut file = BufReader::new(File::open(local_path)?);
-    for (_index, _repo, chunk_size) in &assignments {
-        let chunk_tmp_path =
-            PathBuf::from(TMPFS_DIR).join(format!("chunk_u_{}", chunk_paths.len()));
-        let mut chunk_file = BufWriter::new(File::create(&chunk_tmp_path)?);
-        let mut to_read = *chunk_size as usize;
-        while to_read > 0 {
-            let buf_size = to_read.min(8192);
-            let mut buf = vec![0u8; buf_size];
-            let read = file.read(&mut buf)?;
-            chunk_file.write_all(&buf[..read])?;
-            to_read -= read;
-            if read == 0 {
-                break;
+    let (mut a_parts, mut b_parts) = (a.split('/').peekable(), b.split('/').peekable());
+    loop {
+        match (a_parts.next(), b_parts.next()) {
+            (Some(x), Some(y)) if x == y => continue,
+            (Some(x), Some(y)) => {
+                let (x_dir, y_dir) = (a_parts.peek().is_some(), b_parts.peek().is_some());
+                return y_dir.cmp(&x_dir).then_with(|| x.cmp(y));
             }
+            (x, y) => return x.is_some().cmp(&y.is_some()),
         }
-        chunk_file.flush()?;
-        chunk_paths.push(chunk_tmp_path);
     }
-    // Group chunks by repo for batched parallel upload
-    let mut repo_map: HashMap<String, Vec<(usize, PathBuf, String)>> = HashMap::new();
-    for (idx, (i, repo, _)) in assignments.iter().enumerate() {
-        let dest_path = format!("{}_{:04}.chunk", checksum, *i);
-        repo_map.entry(repo.clone()).or_insert_with(Vec::new).push((
-            *i,
-            chunk_paths[idx].clone(),
-            dest_path,
-        ));
+}
+
+/// Orders `files` for `ls`: by the sort key, ties broken by path so the
+/// output is the same on every run.
+pub fn sort_entries(files: &mut [FileEntry], opts: &LsOptions) {
+    files.sort_by(|a, b| {
+        let key = match opts.sort {
+            LsSort::Name => Ordering::Equal,
+            LsSort::Size => a.size.cmp(&b.size),
+            LsSort::Mtime => a.mtime.cmp(&b.mtime),
+        };
+        key.then_with(|| cmp_paths(&a.path, &b.path, opts.group_dirs))
+    });
+    if opts.reverse {
+        files.reverse();
     }
-    // Parallel upload per repo (batched)
-    let _results: Vec<Result<(), anyhow::Error>> = repo_map
-        .par_iter()
-        .map(|(repo_name, chunk_list)| upload_chunks_to_repo(&checksum, repo_name, chunk_list))
+}
+
+/// Lists the files of the namespace. Metadata files that cannot be read are
+/// skipped with a warning, or fail the listing with `strict`.
+pub fn ls(opts: &LsOptions) -> Result<()> {
+    ensure_temp_dirs()?;
+    let metadata_clone_dir = clone_metadata()?;
+    let (files, corrupt) = list_file_metadata_tolerant(&metadata_clone_dir, "")?;
+    fs::remove_dir_all(&metadata_clone_dir)?;
+    let entries = files
+        .into_iter()
+        .map(|(path, meta)| FileEntry::new(path, meta))
         .collect();
-    // Cleanup temp chunks
-    for chunk_path in chunk_paths {
-        let _ = fs::remove_file(chunk_path);
+    print_listing(entries, &corrupt, opts)
+}
+
+/// The printing half of `ls`, also for the listing of the cache daemon.
+pub fn print_listing(
+    mut entries: Vec<FileEntry>,
+    corrupt: &[(PathBuf, String)],
+    opts: &LsOptions,
+) -> Result<()> {
+    if let (true, Some((path, reason))) = (opts.strict, corrupt.first()) {
+        bail!("corrupt metadata {}: {}", path.display(), reason);
     }
-    // Re-clone metadata for fresh state and write file metadata
-    if metadata_clone_dir.exists() {
-        fs::remove_dir_all(&metadata_clone_dir)?;
+    for (path, reason) in corrupt {
+        eprintln!(
+            "--- skipping corrupt metadata {}: {}, see `gidrive fsck`",
+            path.display(),
+            reason
+        );
     }
-    clone_repo(METADATA_REPO_URL, &metadata_clone_dir)?;
-    let fs_dir = metadata_clone_dir.join("fs");
-    let remote_path = Path::new(remote);
-    let file_name = remote_path
-        .file_name()
-        .context("Remote path must have a file name")?;
-    let meta_file_name = format!("{}.json", file_name.to_string_lossy());
-    let parent = remote_path.parent().unwrap_or(Path::new(""));
-    let file_meta_path = fs_dir.join(parent).join(meta_file_name);
-    fs::create_dir_all(
-        file_meta_path
-            .parent()
-            .context("Failed to get parent for file meta")?,
-    )?;
-    let chunks: Vec<ChunkInfo> = assignments
-        .iter()
-        .map(|(i, r, s)| ChunkInfo {
-            repo: r.clone(),
-            path: format!("{}_{:04}.chunk", checksum, *i),
-            size: *s,
-            index: *i,
-        })
-        .collect();
-    let file_meta = FileMetadata {
-        checksum: checksum.clone(),
-        size: file_size,
-        chunks,
+    sort_entries(&mut entries, opts);
+    if opts.json {
+        println!("{}", serde_json::to_string_pretty(&entries)?);
+        return Ok(());
+    }
+    if opts.checksums {
+        for entry in &entries {
+            if let Some(target) = &entry.symlink_target {
+                eprintln!("--- skipping {}, a symlink to {}", entry.path, target);
+            } else if entry.checksum_algo != ChecksumAlgo::Sha256 {
+                eprintln!(
+                    "--- skipping {}, its checksum is {}",
+                    entry.path, entry.checksum_algo
+                );
+            } else {
+                println!("{}", manifest::line(&entry.checksum, &entry.path));
+            }
+        }
+        return Ok(());
+    }
+    if opts.porcelain {
+        for entry in &entries {
+            println!("{}", listing::porcelain_line(entry));
+        }
+        return Ok(());
+    }
+    if entries.is_empty() {
+        if corrupt.is_empty() {
+            println!("No files");
+        }
+        return Ok(());
+    }
+    for line in listing::table(&entries) {
+        println!("{}", line);
+    }
+    Ok(())
+}
+
+/// Prints the metadata version, the totals of the namespace and the storage
+/// repos, and with `clients` the log of the clients that wrote the metadata.
+pub fn info(clients: bool, savings: bool) -> Result<()> {
+    ensure_temp_dirs()?;
+    let metadata_clone_dir = clone_metadata()?;
+    let version_path = metadata_clone_dir.join("version.txt");
+    let version = if version_path.exists() {
+        fs::read_to_string(&version_path)?.trim().to_string()
+    } else {
+        "unstamped".to_string()
+    };
+    let stats = load_namespace_stats(&metadata_clone_dir)?;
+    let repos_meta = load_repos_metadata(&metadata_clone_dir)?;
+    let log = load_clients_log(&metadata_clone_dir)?;
+    let all_files = if savings {
+        Some(list_all_file_metadata(&metadata_clone_dir))
+    } else {
+        None
     };
-    let data = serde_json::to_string_pretty(&file_meta).context("Failed to serialize file meta")?;
-    fs::write(&file_meta_path, data).context("Failed to write file meta")?;
-    git_add_commit_push(&metadata_clone_dir, &format!("Add metadata for {}", remote))?;
     fs::remove_dir_all(&metadata_clone_dir)?;
+    println!("metadata:   version {} (this gidrive {})", version, VERSION);
+    let (files, bytes) = stats
+        .get(namespace())
+        .map_or((0, 0), |ns| (ns.files, ns.bytes));
+    println!(
+        "namespace:  {}, {} files, {}",
+        namespace(),
+        files,
+        human_size(bytes)
+    );
+    println!(
+        "repos:      {}{}, {} stored, {} reclaimable by gc",
+        repos_meta.repos.len(),
+        repos_allowed().map_or(String::new(), |max| format!(" of {} allowed", max)),
+        human_size(repos_meta.repos.values().map(|r| r.current_size).sum()),
+        human_size(repos_meta.repos.values().map(|r| r.reclaimable).sum())
+    );
+    if let Some(all_files) = all_files {
+        print_savings(&all_files?, &repos_meta);
+    }
+    if clients {
+        println!();
+        if log.is_empty() {
+            println!("no clients logged yet");
+        }
+        for line in &log {
+            println!("{}", line.replace('\t', "  "));
+        }
+    }
     Ok(())
 }
 
-pub fn download(remote: &str, local: &str) -> Result<()> {
-    ensure_tmpfs_dir()?;
-    let metadata_clone_dir = get_metadata_dir();
-    if metadata_clone_dir.exists() {
-        fs::remove_dir_all(&metadata_clone_dir)?;
+/// Prints the bytes of the files of every namespace against the bytes their
+/// chunks take in the storage repos, each chunk counted once.
+fn print_savings(files: &BTreeMap<String, FileMetadata>, repos_meta: &ReposMetadata) {
+    let logical: u64 = files.values().map(|meta| meta.size).sum();
+    let mut stored: HashMap<(&str, &str), u64> = HashMap::new();
+    let mut adopted = 0;
+    for chunk in files.values().flat_map(|meta| &meta.chunks) {
+        if repos_meta.repos.contains_key(&chunk.repo) {
+            stored.insert((&chunk.repo, &chunk.path), chunk.size);
+        } else {
+            adopted += chunk.size;
+        }
     }
-    clone_repo(METADATA_REPO_URL, &metadata_clone_dir)?;
-    let fs_dir = metadata_clone_dir.join("fs");
-    let remote_path = Path::new(remote);
-    let file_name = remote_path
-        .file_name()
-        .context("Remote path must have a file name")?;
-    let meta_file_name = format!("{}.json", file_name.to_string_lossy());
-    let parent = remote_path.parent().unwrap_or(Path::new(""));
-    let file_meta_path = fs_dir.join(parent).join(meta_file_name);
-    if !file_meta_path.exists() {
-        return Err(anyhow::anyhow!("File metadata not found for {}", remote));
-    }
-    let data = fs::read_to_string(&file_meta_path)?;
-    let mut file_meta: FileMetadata = serde_json::from_str(&data)?;
-    // Sort chunks by index
-    file_meta.chunks.sort_by_key(|c| c.index);
-    // Group chunks by repo for batched parallel download
-    let mut repo_map: HashMap<String, Vec<(usize, String)>> = HashMap::new();
-    for (global_i, chunk) in file_meta.chunks.iter().enumerate() {
-        repo_map
-            .entry(chunk.repo.clone())
-            .or_insert_with(Vec::new)
-            .push((global_i, chunk.path.clone()));
-    }
-    let temp_dir = PathBuf::from(TMPFS_DIR).join(format!("dl_{}", file_meta.checksum));
-    fs::create_dir_all(&temp_dir).context("Failed to create dl temp dir")?;
-    // Parallel download per repo (batched)
-    let _results: Vec<Result<(), anyhow::Error>> = repo_map
+    let physical: u64 = stored.values().sum();
+    println!(
+        "logical:    {} in {} files of every namespace",
+        human_size(logical),
+        files.len()
+    );
+    println!(
+        "physical:   {} in {} chunks, {} more adopted from other repos",
+        human_size(physical),
+        stored.len(),
+        human_size(adopted)
+    );
+    println!(
+        "dedup:      {} saved ({:.2}x)",
+        human_size(logical.saturating_sub(physical + adopted)),
+        logical.saturating_sub(adopted) as f64 / physical.max(1) as f64
+    );
+}
+
+/// Signs the metadata as it is now with the configured key, after `keygen`
+/// or to accept changes made by hand. The current signature is not checked.
+pub fn sign() -> Result<()> {
+    if settings().signing_key().is_none() {
+        bail!("no signing.key configured, run `gidrive keygen` first");
+    }
+    ensure_temp_dirs()?;
+    let metadata_clone_dir = clone_metadata()?;
+    check_write_version(&metadata_clone_dir)?;
+    commit_metadata(&metadata_clone_dir, "Sign metadata")?;
+    fs::remove_dir_all(&metadata_clone_dir)?;
+    Ok(())
+}
+
+/// `fsck --reshard`: moves the metadata files of every namespace into
+/// `shards` shards, 0 for one tree, in one commit.
+pub fn reshard(shards: u32) -> Result<()> {
+    ensure_temp_dirs()?;
+    let metadata_clone_dir = clone_metadata()?;
+    let res = (|| {
+        check_write_version(&metadata_clone_dir)?;
+        let old = shard_count(&metadata_clone_dir)?;
+        if old == shards {
+            println!("the metadata is in {} shards already", shards);
+            return Ok(());
+        }
+        let files: usize = load_namespace_stats(&metadata_clone_dir)?
+            .values()
+            .map(|ns| ns.files)
+            .sum();
+        if shards == 0 && files >= METADATA_SHARD_THRESHOLD {
+            bail!(
+                "the metadata holds {} files, the next write would split it again at {}",
+                files,
+                METADATA_SHARD_THRESHOLD
+            );
+        }
+        let moved = move_to_shards(&metadata_clone_dir, shards)?;
+        commit_metadata(
+            &metadata_clone_dir,
+            &format!("Reshard the metadata from {} into {} shards", old, shards),
+        )?;
+        match shards {
+            0 => println!("moved {} metadata files into one tree", moved),
+            n => println!("moved {} metadata files into {} shards", moved, n),
+        }
+        Ok(())
+    })();
+    fs::remove_dir_all(&metadata_clone_dir)?;
+    res
+}
+
+/// Reports metadata files of any namespace that cannot be read or parsed,
+/// and chunks.idx entries that disagree with the references in fs/,
+/// returning whether there were none. With `rebuild_index` a stale or
+/// missing chunks.idx is rewritten from fs/ instead.
+pub fn fsck(rebuild_index: bool) -> Result<bool> {
+    ensure_temp_dirs()?;
+    let metadata_clone_dir = clone_metadata()?;
+    let res = check_metadata(&metadata_clone_dir, rebuild_index);
+    fs::remove_dir_all(&metadata_clone_dir)?;
+    if let Ok(ok) = res {
+        if let Err(e) = status::record_fsck(ok) {
+            eprintln!("--- failed to record the fsck result: {:#}", e);
+        }
+    }
+    res
+}
+
+fn check_metadata(metadata_clone_dir: &Path, rebuild_index: bool) -> Result<bool> {
+    let (files, corrupt) = list_all_file_metadata_tolerant(metadata_clone_dir)?;
+    for (path, reason) in &corrupt {
+        println!("corrupt {}: {}", path.display(), reason);
+    }
+    println!(
+        "{} files ok, {} corrupt metadata files",
+        files.len(),
+        corrupt.len()
+    );
+    if !corrupt.is_empty() {
+        println!("chunks.idx not checked, the corrupt metadata files must be fixed first");
+        return Ok(false);
+    }
+    let reachable = check_reachable_repos(metadata_clone_dir, files.values())?;
+    let built = build_chunk_index(metadata_clone_dir)?;
+    let stored = if metadata_clone_dir.join("chunks.idx").exists() {
+        Some(load_chunk_index(metadata_clone_dir)?)
+    } else {
+        None
+    };
+    let wrong = stored.as_ref().map(|stored| {
+        let keys: HashSet<_> = stored.keys().chain(built.keys()).collect();
+        keys.into_iter()
+            .filter(|key| stored.get(*key) != built.get(*key))
+            .count()
+    });
+    match wrong {
+        Some(0) => {
+            println!("chunks.idx ok, {} chunks", built.len());
+            return Ok(reachable);
+        }
+        _ if rebuild_index => {
+            check_write_version(metadata_clone_dir)?;
+            save_chunk_index(metadata_clone_dir, &built)?;
+            commit_metadata(metadata_clone_dir, "Rebuild chunks.idx")?;
+            println!("chunks.idx rebuilt, {} chunks", built.len());
+            return Ok(reachable);
+        }
+        Some(wrong) => println!(
+            "chunks.idx has {} wrong entries, fix it with fsck --rebuild-index",
+            wrong
+        ),
+        None => println!("no chunks.idx yet, the next write builds it"),
+    }
+    Ok(wrong.is_none() && reachable)
+}
+
+/// Whether every storage repo and every repo a chunk of `files` is in,
+/// under its owner, can be reached, printing those that cannot.
+fn check_reachable_repos<'a>(
+    metadata_clone_dir: &Path,
+    files: impl Iterator<Item = &'a FileMetadata>,
+) -> Result<bool> {
+    let repos_meta = load_repos_metadata(metadata_clone_dir)?;
+    let mut locations: BTreeSet<String> =
+        repos_meta.repos.values().map(RepoInfo::location).collect();
+    locations.extend(files.flat_map(|meta| meta.chunks.iter().map(ChunkInfo::location)));
+    let failed: Vec<(String, anyhow::Error)> = locations
         .par_iter()
-        .map(|(repo_name, chunk_list)| download_chunks_from_repo(repo_name, chunk_list, &temp_dir))
+        .filter_map(|repo| check_reachable(repo).err().map(|e| (repo_slug(repo), e)))
         .collect();
-    // Concatenate chunks in order to local file
-    let local_path = Path::new(local);
-    fs::create_dir_all(
-        local_path
-            .parent()
-            .context("Failed to create local parent dir")?,
-    )?;
-    let mut output = BufWriter::new(File::create(local_path)?);
-    let mut total_written = 0u64;
-    for i in 0..file_meta.chunks.len() {
-        let chunk_p = temp_dir.join(format!("chunk_{}", i));
-        let mut chunk_r =
-            BufReader::new(File::open(&chunk_p).context("Failed to open downloaded chunk")?);
-        total_written +=
-            io::copy(&mut chunk_r, &mut output).context("Failed to copy chunk to output")?;
-        fs::remove_file(&chunk_p).context("Failed to remove temp chunk")?;
-    }
-    output.flush().context("Failed to flush output")?;
-    fs::remove_dir(&temp_dir).context("Failed to remove dl temp dir")?;
-    if total_written != file_meta.size {
-        return Err(anyhow::anyhow!(
-            "Downloaded size mismatch: {} vs {}",
-            total_written,
-            file_meta.size
-        ));
-    }
-    let downloaded_checksum = get_file_sha256(local_path)?;
-    if downloaded_checksum != file_meta.checksum {
-        let _ = fs::remove_file(local_path);
-        return Err(anyhow::anyhow!(
-            "Checksum mismatch: {} vs {}",
-            downloaded_checksum,
-            file_meta.checksum
-        ));
+    for (slug, e) in &failed {
+        println!("unreachable {}: {:#}", slug, e);
     }
+    println!(
+        "{} repos reachable, {} unreachable",
+        locations.len() - failed.len(),
+        failed.len()
+    );
+    Ok(failed.is_empty())
+}
+
+/// Deletes every file of the current namespace. Chunks that files of other
+/// namespaces still reference stay in place.
+pub fn clean_namespace(dry_run: bool) -> Result<()> {
+    clean_prefix("", dry_run)
+}
+
+/// Deletes the single file `remote`, like `remove` but reporting the space.
+pub fn clean_file(remote: &str, dry_run: bool) -> Result<()> {
+    clean_remotes(&[remote.to_string()], remote, dry_run, "y")
+}
+
+/// Deletes every file under the remote directory `prefix`.
+pub fn clean_prefix(prefix: &str, dry_run: bool) -> Result<()> {
+    ensure_temp_dirs()?;
+    let metadata_clone_dir = clone_metadata()?;
+    let prefix = prefix.trim_matches('/');
+    let remotes: Vec<String> = files_under(&metadata_clone_dir, prefix)?
+        .into_keys()
+        .collect();
     fs::remove_dir_all(&metadata_clone_dir)?;
+    let label = dir_label(prefix);
+    if remotes.is_empty() {
+        println!("no files under {}", label);
+        return Ok(());
+    }
+    // a whole namespace is confirmed by typing its name
+    let answer = if prefix.is_empty() { namespace() } else { "y" };
+    clean_remotes(&remotes, &label, dry_run, answer)
+}
+
+fn clean_remotes(remotes: &[String], label: &str, dry_run: bool, answer: &str) -> Result<()> {
+    // clean is the way to get the space back now, it skips the grace period
+    let reclaimed = remove_files(remotes, label, true, dry_run, Some(answer))?;
+    if dry_run {
+        println!(
+            "would delete {} files of {}, reclaiming {}",
+            remotes.len(),
+            label,
+            human_size(reclaimed)
+        );
+    } else {
+        println!(
+            "deleted {} files of {}, reclaimed {}",
+            remotes.len(),
+            label,
+            human_size(reclaimed)
+        );
+    }
     Ok(())
 }
 
-pub fn init() -> Result<()> {
-    ssh_agent(SSH_KEY_PATH);
-    if !repo_exists("metadata") {
-        create_repo("metadata")?;
+/// Deletes the chunks waiting in pending_delete.json for longer than the
+/// grace period, all of them with `now`, and gives their space back in
+/// repos.json. Entries for chunks a file uses again, or of repos no longer
+/// in repos.json, are only dropped. Chunks of a repo that fails to push stay
+/// queued for the next run.
+pub fn gc(now: bool, dry_run: bool) -> Result<()> {
+    ensure_temp_dirs()?;
+    let metadata_clone_dir = clone_metadata()?;
+    if !dry_run {
+        check_write_version(&metadata_clone_dir)?;
     }
-    ensure_tmpfs_dir()?;
-    ThreadPoolBuilder::new()
-        .num_threads(NUM_PUSH_THREADS)
-        .build_global()
-        .context("Failed to initialize rayon thread pool")?;
-    let metadata_clone_dir = get_metadata_dir();
-    if metadata_clone_dir.exists() {
+    let index = load_chunk_index(&metadata_clone_dir)?;
+    let mut repos_meta = load_repos_metadata(&metadata_clone_dir)?;
+    let grace = settings().gc_grace_period();
+    let (mut due, mut waiting, dropped) = split_pending_deletes(
+        load_pending_deletes(&metadata_clone_dir)?,
+        &index,
+        &mut repos_meta,
+        grace,
+        now,
+    );
+    let due_chunks: usize = due.values().map(Vec::len).sum();
+    let due_bytes: u64 = due.values().flatten().map(|e| e.size).sum();
+    if dry_run {
         fs::remove_dir_all(&metadata_clone_dir)?;
+        println!(
+            "would delete {} chunks, reclaiming {}",
+            due_chunks,
+            human_size(due_bytes)
+        );
+        print_waiting(&waiting, grace);
+        return Ok(());
     }
-    clone_repo(METADATA_REPO_URL, &metadata_clone_dir)?;
-    let repos_path = metadata_clone_dir.join("repos.json");
-    if !repos_path.exists() {
-        let repos_meta = ReposMetadata {
-            next_id: 1,
-            repos: std::collections::BTreeMap::new(),
-        };
+    let journal = Journal::begin(Intent::Gc { now })?;
+    let results: Vec<(String, Result<()>)> = due
+        .par_iter()
+        .map(|(repo, entries)| {
+            let paths: Vec<String> = entries.iter().map(|e| e.path.clone()).collect();
+            let location = repos_meta.repos[repo].location();
+            (
+                repo.clone(),
+                remove_chunks_from_repo("gc", &location, &paths),
+            )
+        })
+        .collect();
+    let mut deleted = 0;
+    let mut reclaimed = 0;
+    let mut failed = Vec::new();
+    for (repo, res) in results {
+        let entries = due.remove(&repo).unwrap_or_default();
+        if let Err(e) = res {
+            eprintln!("--- failed to delete the chunks of {}: {}", repo, e);
+            failed.push(repo);
+            waiting.extend(entries);
+            continue;
+        }
+        if let Some(info) = repos_meta.repos.get_mut(&repo) {
+            for entry in &entries {
+                info.current_size = info.current_size.saturating_sub(entry.size);
+                info.reclaimable = info.reclaimable.saturating_sub(entry.size);
+            }
+        }
+        deleted += entries.len();
+        reclaimed += entries.iter().map(|e| e.size).sum::<u64>();
+    }
+    if deleted + dropped > 0 {
+        waiting.sort_by_key(|e| e.since);
+        save_pending_deletes(&metadata_clone_dir, &waiting)?;
         save_repos_metadata(&metadata_clone_dir, &repos_meta)?;
-        fs::create_dir_all(metadata_clone_dir.join("fs"))?;
-        git_add_commit_push(&metadata_clone_dir, "Initialize metadata")?;
+        commit_metadata(&metadata_clone_dir, &format!("Collect {} chunks", deleted))?;
     }
+    // chunks that failed to delete stay queued, the metadata is whole
+    journal.finish();
     fs::remove_dir_all(&metadata_clone_dir)?;
+    println!(
+        "deleted {} chunks, reclaimed {}",
+        deleted,
+        human_size(reclaimed)
+    );
+    print_waiting(&waiting, grace);
+    if !failed.is_empty() {
+        bail!(
+            "could not delete chunks from {}, they stay queued",
+            failed.join(", ")
+        );
+    }
     Ok(())
 }
 
-pub fn ls() -> Result<()> {
-    ensure_tmpfs_dir()?;
-    let metadata_clone_dir = get_metadata_dir();
-    if metadata_clone_dir.exists() {
-        fs::remove_dir_all(&metadata_clone_dir)?;
+/// Lists the operations the journal has as unfinished and drives those
+/// whose process is gone, crashed or killed, to their end unless
+/// `list_only`: an upload goes on by its plan from the local file, `rm` and
+/// `clean` finish with their files that still exist and `gc` runs again.
+/// With `abort`, the operation of that id is rolled back instead.
+pub fn resume(list_only: bool, abort: Option<&str>) -> Result<()> {
+    let entries = journal::incomplete()?;
+    if let Some(id) = abort {
+        let entry = entries
+            .into_iter()
+            .find(|e| e.id == id)
+            .with_context(|| format!("no interrupted operation {}", id))?;
+        check_resumable(&entry)?;
+        return roll_back(&entry);
+    }
+    if entries.is_empty() {
+        println!("no interrupted operations");
+        return Ok(());
+    }
+    for entry in &entries {
+        println!(
+            "{}  {}  {}{}",
+            entry.id,
+            format_utc(entry.started),
+            entry.intent,
+            entry_state(entry)
+        );
+    }
+    if list_only {
+        return Ok(());
+    }
+    let mut unfinished = Vec::new();
+    for entry in entries {
+        if entry.running() {
+            continue;
+        }
+        let id = entry.id.clone();
+        eprintln!("--- resuming {}: {}", id, entry.intent);
+        if let Err(e) = check_resumable(&entry).and_then(|_| redrive(entry)) {
+            eprintln!("--- could not resume {}: {:#}", id, e);
+            unfinished.push(id);
+        }
+    }
+    if !unfinished.is_empty() {
+        bail!(
+            "{} operations are still unfinished: {}",
+            unfinished.len(),
+            unfinished.join(", ")
+        );
+    }
+    Ok(())
+}
+
+/// How far the operation of `entry` got, for the listing of `resume`.
+fn entry_state(entry: &Entry) -> String {
+    if entry.running() {
+        return format!(", running as process {}", entry.pid);
     }
-    clone_repo(METADATA_REPO_URL, &metadata_clone_dir)?;
-    let fs_dir = metadata_clone_dir.join("fs");
-    if !fs_dir.exists() {
-        println!("No files");
+    let namespace = if entry.namespace != namespace() {
+        format!(" in namespace {}", entry.namespace)
     } else {
-        for entry in WalkDir::new(&fs_dir).into_iter().filter_map(|e| e.ok()) {
-            if entry.file_type().is_file()
-                && entry.path().extension().map_or(false, |e| e == "json")
-            {
-                let meta: FileMetadata = serde_json::from_reader(
-                    File::open(entry.path())
-                        .with_context(|| format!("reading metadata {:?}", entry.path()))?,
-                )?;
-                let human = human_size(meta.size);
-                if let Ok(rel_path) = entry.path().strip_prefix(&fs_dir) {
-                    let without_ext = rel_path.with_extension("");
-                    println!("{} {}", without_ext.display(), human);
-                }
-            }
+        String::new()
+    };
+    let progress = match (&entry.intent, &entry.plan) {
+        (Intent::Upload { .. }, Some(plan)) => {
+            let repos: HashSet<&str> = plan
+                .assignments
+                .iter()
+                .map(|(_, r, _)| r.as_str())
+                .collect();
+            format!(
+                ", {} of {} repo batches pushed",
+                entry.pushed.len(),
+                repos.len()
+            )
         }
+        (Intent::Upload { .. }, None) => ", nothing pushed".to_string(),
+        _ => String::new(),
+    };
+    format!("{}{}", namespace, progress)
+}
+
+/// Fails for an entry this process cannot act on.
+fn check_resumable(entry: &Entry) -> Result<()> {
+    if entry.running() {
+        bail!("{} is still running as process {}", entry.id, entry.pid);
     }
+    if entry.namespace != namespace() {
+        bail!(
+            "{} is in namespace {}, resume it with --namespace {}",
+            entry.id,
+            entry.namespace,
+            entry.namespace
+        );
+    }
+    Ok(())
+}
+
+/// Whether the upload of `remote` that started at `started` committed its
+/// metadata before it was interrupted.
+fn upload_finished(remote: &str, size: u64, started: u64) -> Result<bool> {
+    let metadata_clone_dir = clone_metadata()?;
+    let meta = load_file_metadata(&metadata_clone_dir, remote).ok();
+    fs::remove_dir_all(&metadata_clone_dir)?;
+    Ok(meta.is_some_and(|m| m.size == size && m.mtime.is_some_and(|t| t >= started)))
+}
+
+/// The remotes of `remotes` that still have metadata.
+fn existing_remotes(remotes: &[String]) -> Result<Vec<String>> {
+    let metadata_clone_dir = clone_metadata()?;
+    let left = remotes
+        .iter()
+        .filter(|r| load_file_metadata(&metadata_clone_dir, r).is_ok())
+        .cloned()
+        .collect();
     fs::remove_dir_all(&metadata_clone_dir)?;
+    Ok(left)
+}
+
+fn redrive(entry: Entry) -> Result<()> {
+    match entry.intent.clone() {
+        Intent::Upload {
+            remote,
+            local,
+            size,
+            checksum_algo,
+            public,
+            xattrs,
+        } => {
+            if upload_finished(&remote, size, entry.started)? {
+                println!("{} was uploaded already", remote);
+                journal::discard(&entry);
+                return Ok(());
+            }
+            let readable = local.filter(|l| fs::metadata(l).is_ok_and(|m| m.len() == size));
+            let Some(local) = readable else {
+                eprintln!(
+                    "--- the data of {} cannot be read again, rolling the upload back",
+                    remote
+                );
+                return roll_back(&entry);
+            };
+            if entry.plan.as_ref().is_some_and(|plan| !plan.intact()) {
+                journal::quarantine_entry(&entry.id, "its plan does not match its hash")?;
+                bail!("the journal entry of the upload of {} is corrupt", remote);
+            }
+            let opts = UploadOptions {
+                checksum_algo,
+                public,
+                xattrs,
+                ..Default::default()
+            };
+            let mut file = File::open(&local)?;
+            let journal = Journal::resume(entry)?;
+            upload_journaled(&remote, &mut file, size, &opts, Some(&local), Some(journal))
+                .map(|_| ())
+        }
+        Intent::Remove {
+            remotes,
+            label,
+            permanent,
+        } => {
+            let left = existing_remotes(&remotes)?;
+            // the rerun journals itself
+            journal::discard(&entry);
+            if left.is_empty() {
+                println!("{} was removed already", label);
+                return Ok(());
+            }
+            remove_files(&left, &label, permanent, false, None).map(|_| ())
+        }
+        Intent::Gc { now } => {
+            journal::discard(&entry);
+            gc(now, false)
+        }
+    }
+}
+
+/// Undoes what can be undone of the operation of `entry` and drops it.
+fn roll_back(entry: &Entry) -> Result<()> {
+    match &entry.intent {
+        Intent::Upload {
+            remote,
+            size,
+            public,
+            ..
+        } => {
+            if upload_finished(remote, *size, entry.started)? {
+                bail!("{} was uploaded already, rm it instead", remote);
+            }
+            if let Some(plan) = &entry.plan {
+                let metadata_clone_dir = clone_metadata()?;
+                let repos = load_repos_metadata(&metadata_clone_dir)?;
+                fs::remove_dir_all(&metadata_clone_dir)?;
+                let plan = UploadPlan {
+                    assignments: plan.assignments.clone(),
+                    new_repos: plan.new_repos.clone(),
+                    public: *public,
+                    repos,
+                };
+                release_reservations(&plan, remote)?;
+            }
+            println!(
+                "rolled back the upload of {}, chunks it pushed stay in their repos",
+                remote
+            );
+        }
+        Intent::Remove {
+            remotes,
+            label,
+            permanent: true,
+        } => {
+            if !existing_remotes(remotes)?.is_empty() {
+                bail!(
+                    "clean {} may have deleted chunks of its files already, finish it with resume",
+                    label
+                );
+            }
+        }
+        // its one metadata commit was made or not
+        Intent::Remove { .. } => {}
+        Intent::Gc { .. } => {
+            println!("chunks the gc deleted stay queued, the next gc drops them");
+        }
+    }
+    journal::discard(entry);
     Ok(())
 }
 
-pub fn clean() -> Result<()> {
-    let repos = list_repos()?;
-    for repo in repos {
-        println!("deleting repo:{}", &repo);
-        delete_repo(&repo)?;
+/// Prints how much the chunks still queued after a `gc` hold and when the
+/// first of them is due.
+fn print_waiting(waiting: &[PendingDelete], grace: u64) {
+    let Some(oldest) = waiting.iter().map(|e| e.since).min() else {
+        return;
+    };
+    println!(
+        "{} chunks ({}) wait for the grace period, the first until {}, gc --now to delete them",
+        waiting.len(),
+        human_size(waiting.iter().map(|e| e.size).sum()),
+        format_utc(oldest + grace)
+    );
+}
+
+/// Deletes the storage repo clones cached for downloads. Nothing remote is
+/// touched, the next download fetches what it needs again.
+pub fn clean_local(dry_run: bool) -> Result<()> {
+    let (clones, bytes) = cache::purge(dry_run)?;
+    let verb = if dry_run { "would delete" } else { "deleted" };
+    println!(
+        "{} {} cached repo clones in {}, {}",
+        verb,
+        clones,
+        cache::repo_cache_dir().display(),
+        human_size(bytes)
+    );
+    Ok(())
+}
+
+/// Deletes storage repos that neither hold chunks according to the metadata
+/// nor contain any file on GitHub, and drops them from repos.json.
+pub fn clean_empty_repos(dry_run: bool) -> Result<()> {
+    ensure_temp_dirs()?;
+    let metadata_clone_dir = clone_metadata()?;
+    if !dry_run {
+        check_write_version(&metadata_clone_dir)?;
+    }
+    let mut repos_meta = load_repos_metadata(&metadata_clone_dir)?;
+    let referenced: HashSet<String> = list_all_file_metadata(&metadata_clone_dir)?
+        .into_values()
+        .flat_map(|meta| meta.chunks)
+        .map(|c| c.repo)
+        .collect();
+    let mut empty = Vec::new();
+    for repo in repos_meta.repos.values() {
+        if repo.current_size != 0 || referenced.contains(&repo.name) {
+            continue;
+        }
+        // orphaned chunks from interrupted uploads are not in the metadata
+        let has_files = !cache::list_files(&repo.location())?.is_empty();
+        cache::forget(&repo.location());
+        if has_files {
+            println!(
+                "{} is recorded empty but holds files, keeping it",
+                repo.name
+            );
+        } else {
+            empty.push(repo.name.clone());
+        }
+    }
+    if empty.is_empty() {
+        println!("no empty repos");
+    } else if dry_run {
+        println!(
+            "would delete {} empty repos: {}",
+            empty.len(),
+            empty.join(", ")
+        );
+    } else {
+        let what = format!("delete {} empty repos: {}", empty.len(), empty.join(", "));
+        if let Err(e) =
+            require_scope("delete_repo", "delete repos").and_then(|_| confirm(&what, "y"))
+        {
+            fs::remove_dir_all(&metadata_clone_dir)?;
+            return Err(e);
+        }
+        let deletion = delete_repos(&empty);
+        for repo in &deletion.deleted {
+            repos_meta.repos.remove(repo);
+        }
+        if !deletion.deleted.is_empty() {
+            save_repos_metadata(&metadata_clone_dir, &repos_meta)?;
+            commit_metadata(
+                &metadata_clone_dir,
+                &format!("Delete {} empty repos", deletion.deleted.len()),
+            )?;
+        }
+        deletion.print();
+        fs::remove_dir_all(&metadata_clone_dir)?;
+        return deletion.result();
     }
+    fs::remove_dir_all(&metadata_clone_dir)?;
     Ok(())
 }
+
+/// Outcome of `delete_repos`.
+#[derive(Default)]
+struct RepoDeletion {
+    deleted: Vec<String>,
+    /// (repo, reason)
+    failed: Vec<(String, String)>,
+    /// Repos of the account left alone because repos.json does not know them
+    skipped: Vec<String>,
+}
+
+impl RepoDeletion {
+    fn print(&self) {
+        println!("deleted {} repos", self.deleted.len());
+        if !self.failed.is_empty() {
+            println!("failed to delete {} repos:", self.failed.len());
+            for (repo, reason) in &self.failed {
+                println!("  {}: {}", repo, reason);
+            }
+        }
+        if !self.skipped.is_empty() {
+            println!(
+                "skipped {} repos not in repos.json: {}",
+                self.skipped.len(),
+                self.skipped.join(", ")
+            );
+        }
+    }
+
+    fn result(&self) -> Result<()> {
+        if !self.failed.is_empty() {
+            bail!(
+                "{} repos could not be deleted, run clean again to retry them",
+                self.failed.len()
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Deletes `repos`, `transfer_concurrency()` at a time, carrying on past
+/// failures.
+fn delete_repos(repos: &[String]) -> RepoDeletion {
+    let done = std::sync::atomic::AtomicUsize::new(0);
+    let results: Vec<(&String, Result<()>)> = repos
+        .par_iter()
+        .map(|repo| {
+            let res = {
+                let _slot = transfer_slot();
+                delete_repo(repo)
+            };
+            let n = done.fetch_add(1, std::sync::atomic::Ordering::Relaxed) + 1;
+            match &res {
+                Ok(_) => println!("[{}/{}] deleted repo:{}", n, repos.len(), repo),
+                Err(e) => println!("[{}/{}] failed repo:{}: {:#}", n, repos.len(), repo, e),
+            }
+            (repo, res)
+        })
+        .collect();
+    let mut deletion = RepoDeletion::default();
+    for (repo, res) in results {
+        match res {
+            Ok(_) => deletion.deleted.push(repo.clone()),
+            Err(e) => deletion.failed.push((repo.clone(), format!("{:#}", e))),
+        }
+    }
+    deletion
+}
+
+/// Deletes the storage repos listed in repos.json, on every account they
+/// are on, then the metadata repo once all of them are gone. Other repos of
+/// the accounts are left alone.
+/// The user confirms by typing the account name.
+pub fn clean(dry_run: bool) -> Result<()> {
+    if !dry_run {
+        check_writable(|| "delete the drive".to_string())?;
+    }
+    ensure_temp_dirs()?;
+    let own_repos = list_repos()?;
+    let known = if own_repos.iter().any(|repo| repo == "metadata") {
+        let metadata_clone_dir = clone_metadata()?;
+        let repos_meta = load_repos_metadata(&metadata_clone_dir)?;
+        fs::remove_dir_all(&metadata_clone_dir)?;
+        repos_meta.repos
+    } else {
+        BTreeMap::new()
+    };
+    // the repos.json names of every storage account, its own listing first
+    let mut by_account: BTreeMap<String, HashSet<&str>> =
+        BTreeMap::from([(owner().to_string(), HashSet::new())]);
+    for info in known.values() {
+        let account = info.owner.clone().unwrap_or_else(|| owner().to_string());
+        by_account.entry(account).or_default().insert(&info.name);
+    }
+    let mut storage = Vec::new();
+    let mut skipped = Vec::new();
+    for (account, names) in &by_account {
+        let listed = if account == owner() {
+            own_repos.clone()
+        } else {
+            list_account_repos(account)?
+        };
+        for repo in listed {
+            if account == owner() && repo == "metadata" {
+                continue;
+            }
+            let qualified = qualified_repo(&repo, Some(account));
+            if names.contains(repo.as_str()) {
+                storage.push(qualified);
+            } else {
+                skipped.push(qualified);
+            }
+        }
+    }
+    if dry_run {
+        println!(
+            "would delete {} repos and then metadata: {}",
+            storage.len(),
+            storage.join(", ")
+        );
+        if !skipped.is_empty() {
+            println!(
+                "would skip {} repos not in repos.json: {}",
+                skipped.len(),
+                skipped.join(", ")
+            );
+        }
+        return Ok(());
+    }
+    require_scope("delete_repo", "delete repos")?;
+    let what = format!(
+        "delete {} repos of {} and then the metadata: {}",
+        storage.len(),
+        by_account.keys().cloned().collect::<Vec<_>>().join(", "),
+        storage.join(", ")
+    );
+    confirm(&what, owner())?;
+    let mut deletion = RepoDeletion {
+        skipped,
+        ..delete_repos(&storage)
+    };
+    if deletion.failed.is_empty() && repo_exists("metadata") {
+        match delete_repo("metadata") {
+            Ok(_) => deletion.deleted.push("metadata".to_string()),
+            Err(e) => deletion
+                .failed
+                .push(("metadata".to_string(), format!("{:#}", e))),
+        }
+    } else if !deletion.failed.is_empty() {
+        println!("kept metadata, it still records the repos that failed");
+    }
+    deletion.print();
+    deletion.result()
+}
@@ -0,0 +1,118 @@
+use anyhow::{Context, Result};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufWriter, Read, Write};
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+
+use crate::exclude::{Excludes, LinkPolicy};
+use crate::models::ArchiveInfo;
+use crate::utils::verbose;
+
+/// Format of the archives written by `upload --archive`.
+pub const FORMAT: &str = "tar+zstd";
+
+/// Writes a zstd-compressed tar of the contents of `dir` to `dest`, entries
+/// in path order. Paths `excludes` leaves out are skipped, and listed with
+/// `-v`. Symlinks are handled by its link policy, hard links are stored as
+/// copies.
+pub fn build(dir: &Path, dest: &Path, excludes: &Excludes) -> Result<ArchiveInfo> {
+    let file = BufWriter::new(File::create(dest).context("Failed to create archive")?);
+    let encoder = zstd::Encoder::new(file, 0)?;
+    let mut builder = tar::Builder::new(encoder);
+    builder.follow_symlinks(excludes.links == LinkPolicy::Follow);
+    let (mut entries, mut bytes) = (0, 0);
+    // (dev, inode) -> first path of the files with several hard links
+    let mut linked = HashMap::new();
+    let on_excluded = |path: &Path, reason: &str| {
+        if verbose() {
+            println!(
+                "excluded {} ({})",
+                path.strip_prefix(dir).unwrap_or(path).display(),
+                reason
+            );
+        }
+    };
+    for entry in excludes.walk(on_excluded) {
+        let entry = entry?;
+        let rel = entry.path().strip_prefix(dir)?;
+        if entry.file_type().is_file() {
+            let meta = entry.metadata()?;
+            bytes += meta.len();
+            if meta.nlink() > 1 {
+                if let Some(first) = linked.get(&(meta.dev(), meta.ino())) {
+                    if verbose() {
+                        println!(
+                            "{} is the same file as {}, stored as a copy",
+                            rel.display(),
+                            first
+                        );
+                    }
+                } else {
+                    linked.insert((meta.dev(), meta.ino()), rel.display().to_string());
+                }
+            }
+        }
+        builder
+            .append_path_with_name(entry.path(), rel)
+            .with_context(|| format!("Failed to archive {}", entry.path().display()))?;
+        entries += 1;
+    }
+    builder
+        .into_inner()?
+        .finish()?
+        .into_inner()
+        .map_err(|e| e.into_error())
+        .context("Failed to write archive")?
+        .sync_all()?;
+    Ok(ArchiveInfo {
+        format: FORMAT.to_string(),
+        entries,
+        bytes: Some(bytes),
+    })
+}
+
+/// Unpacks an archive read from `reader` into `dir`, returning the number of
+/// entries. Entries reaching outside `dir` are skipped by `tar`.
+pub fn extract(reader: impl Read, dir: &Path) -> Result<usize> {
+    std::fs::create_dir_all(dir).context("Failed to create extract dir")?;
+    let mut archive = tar::Archive::new(zstd::Decoder::new(reader)?);
+    let mut entries = 0;
+    for entry in archive.entries()? {
+        entry?
+            .unpack_in(dir)
+            .context("Failed to extract archive entry")?;
+        entries += 1;
+    }
+    drain(archive)?;
+    Ok(entries)
+}
+
+/// Writes the paths and sizes of the entries of an archive read from
+/// `reader` to `out`, one per line.
+pub fn list(reader: impl Read, out: &mut impl Write) -> Result<()> {
+    let mut archive = tar::Archive::new(zstd::Decoder::new(reader)?);
+    for entry in archive.entries()? {
+        let entry = entry?;
+        let kind = if entry.header().entry_type().is_dir() {
+            "/"
+        } else {
+            ""
+        };
+        writeln!(
+            out,
+            "{}{} {}",
+            entry.path()?.display(),
+            kind,
+            entry.header().size()?
+        )?;
+    }
+    drain(archive)
+}
+
+/// Reads the padding after the last entry, so a download writing the
+/// archive into a pipe is not cut off before it ends.
+fn drain(archive: tar::Archive<impl Read>) -> Result<()> {
+    std::io::copy(&mut archive.into_inner(), &mut std::io::sink())?;
+    Ok(())
+}
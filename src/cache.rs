@@ -0,0 +1,418 @@
+use anyhow::{bail, Context, Result};
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::time::UNIX_EPOCH;
+use walkdir::WalkDir;
+
+use crate::config::settings;
+use crate::git::{explain_auth, offline, owner, repo_slug, repo_url};
+#[cfg(not(feature = "libgit2"))]
+use crate::git::{git_with_progress, url_slug};
+use crate::metadata::{namespace, shards_of_version};
+use crate::models::ChecksumAlgo;
+use crate::retry::{with_retry, Operation};
+#[cfg(not(feature = "libgit2"))]
+use crate::utils::{check_output, very_verbose};
+use crate::utils::{command, for_each_block, run_args, Hasher};
+
+/// Ref the cached clones keep the default branch of their repo in.
+const CACHED_REF: &str = "refs/heads/cached";
+/// File touched on every use of a cached clone, for the LRU eviction.
+const USED_MARKER: &str = "gidrive-used";
+
+/// `$XDG_CACHE_HOME/gidrive`, falling back to `~/.cache`.
+pub fn cache_dir() -> PathBuf {
+    let base = std::env::var_os("XDG_CACHE_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".cache")))
+        .unwrap_or_default();
+    base.join("gidrive")
+}
+
+/// `cache_dir()/repos`
+pub fn repo_cache_dir() -> PathBuf {
+    cache_dir().join("repos")
+}
+
+fn cache_path(repo: &str) -> PathBuf {
+    // adopted source repos are named owner/name, and so are storage repos
+    // read from another account with --owner
+    let name = if owner() == settings().github_username() {
+        repo.to_string()
+    } else {
+        repo_slug(repo)
+    };
+    repo_cache_dir().join(format!("{}.git", name.replace('/', "__")))
+}
+
+/// Brings the bare clone of `repo` in the cache up to date with the tip of
+/// its default branch, creating it on first use, and returns its path. Only
+/// the tip is fetched and no worktree is written. The fetch is retried with
+/// the `clone` policy.
+pub fn refresh(repo: &str) -> Result<PathBuf> {
+    let path = cache_path(repo);
+    if offline() {
+        if !has_cached_ref(&path) {
+            bail!(
+                "offline: {} is not in the repo cache, it can only be read online",
+                repo_slug(repo)
+            );
+        }
+        File::create(path.join(USED_MARKER)).context("Failed to mark cached clone used")?;
+        return Ok(path);
+    }
+    if !path.join("HEAD").exists() {
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path).context("Failed to create repo cache dir")?;
+        run_args(
+            "git",
+            &[
+                "init".as_ref(),
+                "--quiet".as_ref(),
+                "--bare".as_ref(),
+                path.as_os_str(),
+            ],
+        )
+        .context("Failed to create cached clone")?;
+    }
+    with_retry(&settings().retry_policy(Operation::Clone), || {
+        fetch_tip(&path, &repo_url(repo)).context("Failed to fetch repo")
+    })
+    .map_err(explain_auth)?;
+    File::create(path.join(USED_MARKER)).context("Failed to mark cached clone used")?;
+    Ok(path)
+}
+
+/// Fetches the tip of the default branch of `url` into `CACHED_REF` of the
+/// bare clone at `path`.
+#[cfg(not(feature = "libgit2"))]
+fn fetch_tip(path: &Path, url: &str) -> Result<()> {
+    let refspec = format!("+HEAD:{}", CACHED_REF);
+    let fetch = [
+        "-C".as_ref(),
+        path.as_os_str(),
+        "fetch".as_ref(),
+        "--progress".as_ref(),
+        "--depth".as_ref(),
+        "1".as_ref(),
+        "--no-tags".as_ref(),
+        url.as_ref(),
+        refspec.as_ref(),
+    ];
+    let output = git_with_progress("fetch", &url_slug(url), &fetch)?;
+    let cmd = format!("git -C {} fetch {} {}", path.display(), url, refspec);
+    // quiet unless it fails, like the --quiet fetch it replaces
+    let echo = !output.status.success() && !very_verbose();
+    check_output(&cmd, output, echo)?;
+    Ok(())
+}
+
+#[cfg(feature = "libgit2")]
+fn fetch_tip(path: &Path, url: &str) -> Result<()> {
+    crate::libgit2::fetch_tip(path, url, &format!("+HEAD:{}", CACHED_REF))
+}
+
+fn has_cached_ref(clone: &Path) -> bool {
+    command("git")
+        .arg("-C")
+        .arg(clone)
+        .args(["rev-parse", "--quiet", "--verify", CACHED_REF])
+        .output()
+        .is_ok_and(|output| output.status.success())
+}
+
+/// Bare copy of the metadata repo of `owner()`, updated from every clone of
+/// it, which reads fall back on when GitHub cannot be reached.
+fn metadata_cache_path() -> PathBuf {
+    cache_dir().join(format!("metadata-{}.git", owner()))
+}
+
+/// Copies the tip of the metadata clone at `clone` into the metadata cache,
+/// creating it on first use.
+pub fn store_metadata(clone: &Path) -> Result<()> {
+    let path = metadata_cache_path();
+    if !path.join("HEAD").exists() {
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path).context("Failed to create metadata cache")?;
+        run_args(
+            "git",
+            &[
+                "init".as_ref(),
+                "--quiet".as_ref(),
+                "--bare".as_ref(),
+                path.as_os_str(),
+            ],
+        )
+        .context("Failed to create metadata cache")?;
+        // clones of the cache check out what was fetched
+        run_args(
+            "git",
+            &[
+                "-C".as_ref(),
+                path.as_os_str(),
+                "symbolic-ref".as_ref(),
+                "HEAD".as_ref(),
+                CACHED_REF.as_ref(),
+            ],
+        )?;
+    }
+    let refspec = format!("+HEAD:{}", CACHED_REF);
+    run_args(
+        "git",
+        &[
+            "-C".as_ref(),
+            path.as_os_str(),
+            "fetch".as_ref(),
+            "--quiet".as_ref(),
+            "--no-tags".as_ref(),
+            clone.as_os_str(),
+            refspec.as_ref(),
+        ],
+    )
+    .context("Failed to update metadata cache")?;
+    Ok(())
+}
+
+/// Paths of the files of the current namespace starting with `prefix`, as
+/// of the last metadata cached, none without a cache. Reads the cache only,
+/// for shell completion, which must answer at once and never prompt.
+pub fn cached_remote_paths(prefix: &str) -> Result<Vec<String>> {
+    let path = metadata_cache_path();
+    if !has_cached_ref(&path) {
+        return Ok(Vec::new());
+    }
+    let git = |args: &[&str]| -> Result<String> {
+        let output = command("git").arg("-C").arg(&path).args(args).output()?;
+        if !output.status.success() {
+            bail!("git {} failed in the metadata cache", args[0]);
+        }
+        Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+    };
+    let shards = git(&["show", &format!("{}:version.txt", CACHED_REF)])
+        .map_or(0, |version| shards_of_version(&version));
+    let root = format!("fs/{}/", namespace());
+    // -z leaves names with non-ASCII characters unquoted
+    let listed = git(&[
+        "ls-tree",
+        "-r",
+        "-z",
+        "--name-only",
+        CACHED_REF,
+        "--",
+        &root,
+    ])?;
+    let mut paths: Vec<String> = listed
+        .split('\0')
+        .filter_map(|line| {
+            let mut rel = line.strip_prefix(&root)?;
+            if shards > 0 {
+                rel = rel.split_once('/')?.1;
+            }
+            rel.strip_suffix(".json")
+        })
+        .filter(|remote| remote.starts_with(prefix))
+        .map(str::to_string)
+        .collect();
+    paths.sort();
+    Ok(paths)
+}
+
+/// Unix time the metadata cache was last updated, none without one.
+pub fn metadata_fetched() -> Option<u64> {
+    let path = metadata_cache_path();
+    if !has_cached_ref(&path) {
+        return None;
+    }
+    let modified = fs::metadata(path.join("FETCH_HEAD"))
+        .ok()?
+        .modified()
+        .ok()?;
+    Some(modified.duration_since(UNIX_EPOCH).ok()?.as_secs())
+}
+
+/// Clones the metadata cache into `dir`, its origin pointing at `url` as
+/// for a clone from GitHub.
+pub fn clone_cached_metadata(dir: &Path, url: &str) -> Result<()> {
+    let path = metadata_cache_path();
+    if metadata_fetched().is_none() {
+        bail!(
+            "offline: no metadata cached in {}, run once online first",
+            path.display()
+        );
+    }
+    if dir.exists() {
+        fs::remove_dir_all(dir)?;
+    }
+    run_args(
+        "git",
+        &[
+            "clone".as_ref(),
+            "--quiet".as_ref(),
+            path.as_os_str(),
+            dir.as_os_str(),
+        ],
+    )
+    .context("Failed to clone the metadata cache")?;
+    run_args(
+        "git",
+        &[
+            "-C".as_ref(),
+            dir.as_os_str(),
+            "remote".as_ref(),
+            "set-url".as_ref(),
+            "origin".as_ref(),
+            url.as_ref(),
+        ],
+    )?;
+    Ok(())
+}
+
+/// Drops the cached clone of `repo`, so the next `refresh` fetches it anew.
+pub fn forget(repo: &str) {
+    let _ = fs::remove_dir_all(cache_path(repo));
+}
+
+/// Size and `algo` checksum of `file` in the cached clone at `clone`,
+/// streamed out of git.
+pub fn blob_checksum(clone: &Path, file: &str, algo: ChecksumAlgo) -> Result<(u64, String)> {
+    let mut child = command("git")
+        .arg("-C")
+        .arg(clone)
+        .args(["cat-file", "blob", &format!("{}:{}", CACHED_REF, file)])
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .context("Failed to run git cat-file")?;
+    let mut blob = child.stdout.take().expect("stdout is piped");
+    let mut hasher = Hasher::new(algo);
+    let size = for_each_block(&mut blob, |block| {
+        hasher.update(block);
+        Ok(())
+    })?;
+    drop(blob);
+    let output = child.wait_with_output()?;
+    if !output.status.success() {
+        bail!(
+            "{} is missing from the repo: {}",
+            file,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok((size, hasher.finalize_hex()))
+}
+
+/// Writes `len` bytes at `offset` of `file` in the cached clone at `clone` to
+/// `dst`, streaming the blob out of git.
+pub fn copy_blob_range(clone: &Path, file: &str, offset: u64, len: u64, dst: &Path) -> Result<()> {
+    let mut child = command("git")
+        .arg("-C")
+        .arg(clone)
+        .args(["cat-file", "blob", &format!("{}:{}", CACHED_REF, file)])
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .context("Failed to run git cat-file")?;
+    let mut blob = child.stdout.take().expect("stdout is piped");
+    io::copy(&mut (&mut blob).take(offset), &mut io::sink())?;
+    let copied = io::copy(&mut (&mut blob).take(len), &mut File::create(dst)?)?;
+    // git may die of SIGPIPE once the range is read, its status tells nothing
+    drop(blob);
+    let output = child.wait_with_output()?;
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    if copied == 0 && (stderr.contains("does not exist") || stderr.contains("Not a valid")) {
+        bail!("{} is missing from the repo", file);
+    }
+    if copied != len {
+        bail!(
+            "{} has {} bytes at offset {} instead of {}: {}",
+            file,
+            copied,
+            offset,
+            len,
+            stderr.trim()
+        );
+    }
+    Ok(())
+}
+
+/// Paths of the files at the tip of `repo`, none for a repo without commits.
+pub fn list_files(repo: &str) -> Result<Vec<String>> {
+    let url = repo_url(repo);
+    let output = command("git").args(["ls-remote", &url, "HEAD"]).output()?;
+    if !output.status.success() {
+        bail!(
+            "Failed to reach {}: {}",
+            url,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    if output.stdout.is_empty() {
+        return Ok(Vec::new());
+    }
+    let clone = refresh(repo)?;
+    let output = command("git")
+        .arg("-C")
+        .arg(&clone)
+        .args(["ls-tree", "-r", "--name-only", CACHED_REF])
+        .output()?;
+    if !output.status.success() {
+        bail!("Failed to list the files of {}", repo);
+    }
+    Ok(String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .map(str::to_string)
+        .collect())
+}
+
+/// Cached clones as (last use, bytes, path), least recently used first.
+fn cached_clones() -> Result<Vec<(std::time::SystemTime, u64, PathBuf)>> {
+    let dir = repo_cache_dir();
+    if !dir.exists() {
+        return Ok(Vec::new());
+    }
+    let mut clones = Vec::new();
+    for entry in fs::read_dir(&dir).context("Failed to read repo cache dir")? {
+        let path = entry?.path();
+        let used = fs::metadata(path.join(USED_MARKER))
+            .and_then(|m| m.modified())
+            .unwrap_or(UNIX_EPOCH);
+        let bytes = WalkDir::new(&path)
+            .into_iter()
+            .filter_map(|e| e.ok()?.metadata().ok())
+            .filter(|m| m.is_file())
+            .map(|m| m.len())
+            .sum();
+        clones.push((used, bytes, path));
+    }
+    clones.sort();
+    Ok(clones)
+}
+
+/// Removes the least recently used clones until the cache takes at most
+/// `max_bytes`.
+pub fn evict(max_bytes: u64) -> Result<()> {
+    let clones = cached_clones()?;
+    let mut total: u64 = clones.iter().map(|(_, bytes, _)| bytes).sum();
+    for (_, bytes, path) in clones {
+        if total <= max_bytes {
+            break;
+        }
+        fs::remove_dir_all(&path)
+            .with_context(|| format!("Failed to remove {}", path.display()))?;
+        total -= bytes;
+    }
+    Ok(())
+}
+
+/// Removes every cached clone, or with `dry_run` only counts them. Returns
+/// the number of clones and their bytes.
+pub fn purge(dry_run: bool) -> Result<(usize, u64)> {
+    let clones = cached_clones()?;
+    let bytes = clones.iter().map(|(_, bytes, _)| bytes).sum();
+    if !dry_run && !clones.is_empty() {
+        fs::remove_dir_all(repo_cache_dir()).context("Failed to remove repo cache dir")?;
+    }
+    Ok((clones.len(), bytes))
+}
@@ -1,48 +1,653 @@
-use anyhow::{Context, Result};
+use anyhow::{anyhow, bail, Context, Result};
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::File;
+use std::io::{ErrorKind, Read, Write};
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Mutex};
+use std::thread;
+use std::time::Instant;
 
-use crate::constants::{GITHUB_USERNAME, TMPFS_DIR};
-use crate::git::{clone_repo, git_add_commit_push};
+use crate::cache;
+use crate::config::settings;
+use crate::constants::UPLOAD_QUEUE_DEPTH;
+use crate::daemon;
+use crate::git::{clone_repo, git_add_commit, git_push, repo_url};
+use crate::journal::Journal;
+use crate::metrics;
+use crate::models::{content_key, ChecksumAlgo, ChunkInfo};
+use crate::progress::{self, Event};
+use crate::report::RepoTimings;
+use crate::usage;
+use crate::utils::{
+    checksum_hex, for_each_block, get_file_checksum, read_full, temp_dirs, transfer_concurrency,
+    transfer_slot, verbose, Hasher,
+};
 
+/// A chunk written to the temp dir by `split_into_chunks`.
+pub struct ChunkFile {
+    pub index: usize,
+    pub path: PathBuf,
+    pub checksum: String,
+    /// (repo, path) of the same content already stored, set instead of
+    /// pushing the chunk
+    pub stored: Option<(String, String)>,
+}
+
+/// Path of a chunk inside its storage repo, the index padded to 8 digits so
+/// the names of a file sort in chunk order. Chunks are always read at the
+/// path recorded in their `ChunkInfo`, older ones padded to 4 digits.
+pub fn chunk_dest_path(chunk_checksum: &str, index: usize) -> String {
+    format!("{}_{:08}.chunk", chunk_checksum, index)
+}
+
+/// Splits `reader` into chunk files `chunk_u_<i>` in `dir` in a single pass,
+/// handing each one to `on_chunk` as soon as it is written. `ChunkFile.index`
+/// is the position in `chunk_sizes`.
+/// The stream is fed to `whole` and each chunk is hashed from the same buffer:
+/// while one chunk is hashed (whole-file and per-chunk) and written on the
+/// rayon pool, the next one is read into a second buffer. The first chunk
+/// of a `whole` that starts empty is hashed once, its checksum is the state
+/// of `whole` after it, which spares small files, one chunk, half their
+/// hashing.
+pub fn split_into_chunks(
+    reader: &mut (impl Read + Send),
+    chunk_sizes: &[u64],
+    dir: &Path,
+    algo: ChecksumAlgo,
+    whole: &mut Hasher,
+    mut on_chunk: impl FnMut(ChunkFile) -> Result<()>,
+) -> Result<()> {
+    let max_size = chunk_sizes.iter().copied().max().unwrap_or(0) as usize;
+    let mut current = vec![0u8; max_size];
+    let mut next = vec![0u8; max_size];
+    if let Some(size) = chunk_sizes.first() {
+        read_chunk(reader, &mut current[..*size as usize]).context("Failed to read chunk 0")?;
+    }
+    let fresh = whole.clone().finalize_hex() == Hasher::new(algo).finalize_hex();
+    for (index, size) in chunk_sizes.iter().enumerate() {
+        let data = &current[..*size as usize];
+        let next_data = chunk_sizes
+            .get(index + 1)
+            .map(|size| &mut next[..*size as usize]);
+        let path = dir.join(format!("chunk_u_{}", index));
+        let whole_is_chunk = index == 0 && fresh;
+        let ((_, read_res), chunk_res) = rayon::join(
+            || {
+                rayon::join(
+                    || whole.update(data),
+                    || next_data.map_or(Ok(()), |next_data| read_chunk(reader, next_data)),
+                )
+            },
+            || -> Result<Option<String>> {
+                std::fs::write(&path, data).context("Failed to write chunk file")?;
+                Ok((!whole_is_chunk).then(|| checksum_hex(algo, data)))
+            },
+        );
+        let checksum = match chunk_res? {
+            Some(checksum) => checksum,
+            None => whole.clone().finalize_hex(),
+        };
+        on_chunk(ChunkFile {
+            index,
+            path,
+            checksum,
+            stored: None,
+        })?;
+        read_res.with_context(|| format!("Failed to read chunk {}", index + 1))?;
+        std::mem::swap(&mut current, &mut next);
+    }
+    Ok(())
+}
+
+/// Chunks `reader` and pushes the chunks to their assigned repos as a
+/// producer/consumer pipeline: the calling thread writes chunk files and
+/// queues a repo's batch once its last chunk is written, while
+/// `transfer_concurrency()` workers clone/copy/push queued batches. At most
+/// `UPLOAD_QUEUE_DEPTH` batches wait in the queue, which bounds temp space.
+/// The first failure cancels the producer and the remaining queued batches.
+/// A chunk whose content is in `stored`, or earlier in the same upload, is
+/// not pushed: its `ChunkFile.stored` points at the existing copy.
+/// `whole` is finalized into the returned checksum, so a caller can seed it
+/// with earlier content. Each pushed batch is recorded in `journal`, and the
+/// batches it has as pushed already are not queued again. Returns that
+/// checksum, the written chunks in assignment order (with `index` taken from
+/// the assignment) and the per-repo timings.
+#[allow(clippy::too_many_arguments)]
+pub fn upload_pipelined(
+    reader: &mut (impl Read + Send),
+    assignments: &[(usize, String, u64)],
+    dir: &Path,
+    algo: ChecksumAlgo,
+    mut whole: Hasher,
+    label: &str,
+    stored: &HashMap<String, (String, String)>,
+    journal: Option<&Journal>,
+) -> Result<(String, Vec<ChunkFile>, Vec<RepoTimings>)> {
+    // a repo's batch is complete once its last assigned chunk is written
+    let mut last_chunk: HashMap<&str, usize> = HashMap::new();
+    for (pos, (_, repo, _)) in assignments.iter().enumerate() {
+        last_chunk.insert(repo.as_str(), pos);
+    }
+    let chunk_sizes: Vec<u64> = assignments.iter().map(|(_, _, size)| *size).collect();
+    let size_of: HashMap<usize, u64> = assignments
+        .iter()
+        .map(|(index, _, size)| (*index, *size))
+        .collect();
+    let (tx, rx) = mpsc::sync_channel::<(String, Vec<RepoChunk>)>(UPLOAD_QUEUE_DEPTH);
+    let rx = Mutex::new(rx);
+    let cancel = AtomicBool::new(false);
+    let first_error: Mutex<Option<anyhow::Error>> = Mutex::new(None);
+
+    // plain threads rather than the rayon pool: the producer hashes on the
+    // pool and would deadlock if all its threads were blocked on the queue
+    let (produced, timings) = thread::scope(|scope| {
+        let workers: Vec<_> = (0..transfer_concurrency())
+            .map(|_| {
+                scope.spawn(|| {
+                    let mut timings = Vec::new();
+                    loop {
+                        let batch = rx.lock().unwrap().recv();
+                        let Ok((repo_name, chunk_list)) = batch else {
+                            break;
+                        };
+                        metrics::upload_queue_changed(false);
+                        // keep draining after a failure so the producer never blocks
+                        if !cancel.load(Ordering::SeqCst) {
+                            progress::emit(Event::RepoStarted {
+                                repo: &repo_name,
+                                chunks: chunk_list.len(),
+                            });
+                            let started = Instant::now();
+                            match upload_chunks_to_repo(label, &repo_name, &chunk_list, algo) {
+                                Ok(t) => {
+                                    for (index, _, _) in &chunk_list {
+                                        progress::emit(Event::ChunkUploaded {
+                                            index: *index,
+                                            repo: &repo_name,
+                                            bytes: size_of[index],
+                                            total_chunks: assignments.len(),
+                                        });
+                                    }
+                                    progress::emit(Event::RepoFinished {
+                                        repo: &repo_name,
+                                        chunks: chunk_list.len(),
+                                        seconds: started.elapsed().as_secs_f64(),
+                                    });
+                                    if let Some(journal) = journal {
+                                        journal.pushed(&repo_name);
+                                    }
+                                    timings.push(t)
+                                }
+                                Err(e) => {
+                                    cancel.store(true, Ordering::SeqCst);
+                                    first_error.lock().unwrap().get_or_insert(
+                                        e.context(format!("Failed to upload to {}", repo_name)),
+                                    );
+                                }
+                            }
+                        }
+                        for (_, chunk_path, _) in &chunk_list {
+                            let _ = std::fs::remove_file(chunk_path);
+                        }
+                    }
+                    timings
+                })
+            })
+            .collect();
+
+        let mut chunks: Vec<ChunkFile> = Vec::with_capacity(assignments.len());
+        let mut pending: HashMap<&str, Vec<RepoChunk>> = HashMap::new();
+        let mut pushed: HashMap<String, (String, String)> = HashMap::new();
+        let produced = split_into_chunks(reader, &chunk_sizes, dir, algo, &mut whole, |chunk| {
+            if cancel.load(Ordering::SeqCst) {
+                let _ = std::fs::remove_file(&chunk.path);
+                bail!("upload cancelled");
+            }
+            let pos = chunk.index;
+            let (index, repo, _) = &assignments[pos];
+            let key = content_key(algo, &chunk.checksum);
+            let chunk = ChunkFile {
+                index: *index,
+                stored: stored.get(&key).or(pushed.get(&key)).cloned(),
+                ..chunk
+            };
+            let repo = repo.as_str();
+            if chunk.stored.is_some() {
+                let _ = std::fs::remove_file(&chunk.path);
+            } else {
+                let dest_path = chunk_dest_path(&chunk.checksum, chunk.index);
+                pushed.insert(key, (repo.to_string(), dest_path.clone()));
+                let batch = pending.entry(repo).or_default();
+                batch.push((chunk.index, chunk.path.clone(), dest_path));
+            }
+            if last_chunk[repo] == pos {
+                if let Some(batch) = pending.remove(repo) {
+                    if journal.is_some_and(|j| j.was_pushed(repo)) {
+                        // pushed before the interruption this upload resumes
+                        for (_, chunk_path, _) in &batch {
+                            let _ = std::fs::remove_file(chunk_path);
+                        }
+                    } else {
+                        metrics::upload_queue_changed(true);
+                        tx.send((repo.to_string(), batch)).map_err(|_| {
+                            metrics::upload_queue_changed(false);
+                            anyhow!("upload workers stopped")
+                        })?;
+                    }
+                }
+            }
+            chunks.push(chunk);
+            Ok(())
+        });
+        if produced.is_err() {
+            cancel.store(true, Ordering::SeqCst);
+        }
+        drop(tx);
+        // batches never queued because production stopped early
+        for (_, chunk_path, _) in pending.values().flatten() {
+            let _ = std::fs::remove_file(chunk_path);
+        }
+        let timings: Vec<RepoTimings> = workers
+            .into_iter()
+            .flat_map(|w| w.join().expect("upload worker panicked"))
+            .collect();
+        (produced.map(|_| chunks), timings)
+    });
+    if let Some(e) = first_error.into_inner().unwrap() {
+        return Err(e);
+    }
+    let chunks = produced?;
+    Ok((whole.finalize_hex(), chunks, timings))
+}
+
+/// Fills `buf` from `reader`, failing if the source ends early.
+fn read_chunk(reader: &mut impl Read, buf: &mut [u8]) -> Result<()> {
+    let filled = read_full(reader, buf).context("Failed to read source file")?;
+    if filled < buf.len() {
+        bail!(
+            "file shrank during upload: {} bytes missing from chunk",
+            buf.len() - filled
+        );
+    }
+    Ok(())
+}
+
+/// A chunk queued for a storage repo: (index, chunk file, path in the repo).
+pub type RepoChunk = (usize, PathBuf, String);
+
+/// Pushes `chunk_list` to one repo,
+/// in commits of at most `push_batch_chunks` chunks and `push_batch_size`
+/// bytes, each pushed on its own so a failure only loses the current one.
+/// Chunks the repo already has, from an earlier attempt or an earlier upload
+/// of the same content, are skipped and their indexes returned in
+/// `RepoTimings.skipped`; one at the same path with other bytes fails the
+/// push. With `verify_upload` the pushed chunks are read back from
+/// GitHub and those that differ pushed again.
 pub fn upload_chunks_to_repo(
-    checksum: &str,
+    label: &str,
     repo_name: &str,
-    chunk_list: &[(usize, PathBuf, String)],
-) -> Result<()> {
-    let repo_url = format!("git@github.com:{}/{}.git", GITHUB_USERNAME, repo_name);
-    let clone_dir = PathBuf::from(TMPFS_DIR).join(repo_name);
+    chunk_list: &[RepoChunk],
+    algo: ChecksumAlgo,
+) -> Result<RepoTimings> {
+    let mut timings = RepoTimings {
+        repo: repo_name.to_string(),
+        chunks: chunk_list.len(),
+        ..Default::default()
+    };
+    let repo_url = repo_url(repo_name);
+    let _slot = transfer_slot();
+    let clone_dir = temp_dirs().work.join(repo_name);
     if clone_dir.exists() {
         std::fs::remove_dir_all(&clone_dir).context("Failed to remove existing clone")?;
     }
+    let started = Instant::now();
     clone_repo(&repo_url, &clone_dir)?;
-    for (_index, chunk_path, dest_path) in chunk_list {
+    timings.clone = started.elapsed();
+    // chunk paths carry the checksum, an existing one has the same content
+    // unless the repo is corrupt or the checksums collide
+    let mut todo: Vec<&RepoChunk> = Vec::new();
+    for chunk in chunk_list {
+        let (index, chunk_path, dest_path) = chunk;
         let dest = clone_dir.join(dest_path);
-        std::fs::copy(chunk_path, &dest).context("Failed to copy chunk to repo")?;
+        if !dest.exists() {
+            todo.push(chunk);
+        } else if same_content(chunk_path, &dest)? {
+            timings.skipped.push(*index);
+        } else {
+            bail!(
+                "{} in {} has other content than the chunk being pushed, the repo is corrupt or the checksum collides",
+                dest_path,
+                repo_name
+            );
+        }
+    }
+    timings.chunks = todo.len();
+    let batches = push_batches(&todo)?;
+    for (i, batch) in batches.iter().enumerate() {
+        let started = Instant::now();
+        let mut batch_bytes = 0;
+        for (_index, chunk_path, dest_path) in batch {
+            let dest = clone_dir.join(dest_path);
+            batch_bytes +=
+                std::fs::copy(chunk_path, &dest).context("Failed to copy chunk to repo")?;
+        }
+        timings.bytes += batch_bytes;
+        timings.copy += started.elapsed();
+        let started = Instant::now();
+        // the label is a user string, it goes in the body
+        let msg = if batches.len() > 1 {
+            format!(
+                "Add {} chunks (batch {}/{})\n\n{}",
+                batch.len(),
+                i + 1,
+                batches.len(),
+                label
+            )
+        } else {
+            format!("Add {} chunks\n\n{}", batch.len(), label)
+        };
+        git_add_commit(&clone_dir, &msg)?;
+        timings.commit += started.elapsed();
+        let started = Instant::now();
+        git_push(&clone_dir)?;
+        usage::uploaded(batch_bytes);
+        timings.push += started.elapsed();
     }
-    git_add_commit_push(
+    if settings().verify_upload() && !todo.is_empty() {
+        let started = Instant::now();
+        let differing = verify_pushed(repo_name, &todo, algo, &mut timings)?;
+        if !differing.is_empty() {
+            eprintln!(
+                "--- {} chunks read back from {} differ from the pushed ones, pushing them again",
+                differing.len(),
+                repo_name
+            );
+            let mut again_bytes = 0;
+            for (_, chunk_path, dest_path) in &differing {
+                let dest = clone_dir.join(dest_path);
+                std::fs::remove_file(&dest).context("Failed to remove chunk from repo")?;
+                again_bytes +=
+                    std::fs::copy(chunk_path, &dest).context("Failed to copy chunk to repo")?;
+            }
+            git_add_commit(
+                &clone_dir,
+                &format!("Push {} chunks again\n\n{}", differing.len(), label),
+            )?;
+            git_push(&clone_dir)?;
+            usage::uploaded(again_bytes);
+            let still = verify_pushed(repo_name, &differing, algo, &mut timings)?;
+            if let Some((_, _, dest_path)) = still.first() {
+                bail!(
+                    "{} in {} still differs from the pushed chunk after pushing it again",
+                    dest_path,
+                    repo_name
+                );
+            }
+        }
+        timings.verify += started.elapsed();
+    }
+    std::fs::remove_dir_all(&clone_dir).context("Failed to clean up data repo clone")?;
+    Ok(timings)
+}
+
+/// Fetches `repo` anew into the repo cache and compares the size and
+/// checksum of each of `chunks` there with the staged chunk and the
+/// checksum in its name. Returns the chunks that differ.
+fn verify_pushed<'a>(
+    repo: &str,
+    chunks: &[&'a RepoChunk],
+    algo: ChecksumAlgo,
+    timings: &mut RepoTimings,
+) -> Result<Vec<&'a RepoChunk>> {
+    let clone = cache::refresh(repo)?;
+    let mut differing = Vec::new();
+    for chunk in chunks {
+        let (_, chunk_path, dest_path) = *chunk;
+        let size = std::fs::metadata(chunk_path)?.len();
+        let checksum = dest_path.split('_').next().unwrap_or_default();
+        match cache::blob_checksum(&clone, dest_path, algo) {
+            Ok((read, actual)) => {
+                timings.bytes_verified += read;
+                usage::downloaded(read);
+                if read != size || actual != checksum {
+                    if verbose() {
+                        eprintln!(
+                            "{} in {}: {} bytes with checksum {}, expected {} bytes with {}",
+                            dest_path, repo, read, actual, size, checksum
+                        );
+                    }
+                    differing.push(*chunk);
+                }
+            }
+            Err(e) => {
+                if verbose() {
+                    eprintln!("{} in {}: {:#}", dest_path, repo, e);
+                }
+                differing.push(*chunk);
+            }
+        }
+    }
+    Ok(differing)
+}
+
+/// Whether the files `a` and `b` hold the same bytes.
+fn same_content(a: &Path, b: &Path) -> Result<bool> {
+    let mut remaining = std::fs::metadata(a)?.len();
+    if remaining != std::fs::metadata(b)?.len() {
+        return Ok(false);
+    }
+    let (mut a, mut b) = (File::open(a)?, File::open(b)?);
+    let (mut buf_a, mut buf_b) = (vec![0; 64 * 1024], vec![0; 64 * 1024]);
+    while remaining > 0 {
+        let n = remaining.min(buf_a.len() as u64) as usize;
+        a.read_exact(&mut buf_a[..n])?;
+        b.read_exact(&mut buf_b[..n])?;
+        if buf_a[..n] != buf_b[..n] {
+            return Ok(false);
+        }
+        remaining -= n as u64;
+    }
+    Ok(true)
+}
+
+/// Splits `chunks` into push batches within the configured chunk count and
+/// size, a single chunk over the size making a batch of its own.
+fn push_batches<'a>(chunks: &[&'a RepoChunk]) -> Result<Vec<Vec<&'a RepoChunk>>> {
+    let (max_chunks, max_bytes) = (settings().push_batch_chunks(), settings().push_batch_size());
+    let mut batches: Vec<Vec<&RepoChunk>> = Vec::new();
+    let mut batch_bytes = 0;
+    for &chunk in chunks {
+        let size = std::fs::metadata(&chunk.1)
+            .context("Failed to read chunk file")?
+            .len();
+        match batches.last_mut() {
+            Some(batch) if batch.len() < max_chunks && batch_bytes + size <= max_bytes => {
+                batch.push(chunk);
+                batch_bytes += size;
+            }
+            _ => {
+                batches.push(vec![chunk]);
+                batch_bytes = size;
+            }
+        }
+    }
+    Ok(batches)
+}
+
+/// Deletes the chunk files `paths` from one repo in a single commit.
+pub fn remove_chunks_from_repo(label: &str, repo_name: &str, paths: &[String]) -> Result<()> {
+    let repo_url = repo_url(repo_name);
+    let _slot = transfer_slot();
+    let clone_dir = temp_dirs().work.join(repo_name);
+    if clone_dir.exists() {
+        std::fs::remove_dir_all(&clone_dir).context("Failed to remove existing clone")?;
+    }
+    clone_repo(&repo_url, &clone_dir)?;
+    for path in paths {
+        match std::fs::remove_file(clone_dir.join(path)) {
+            Err(e) if e.kind() != ErrorKind::NotFound => {
+                return Err(e).context("Failed to remove chunk from repo")
+            }
+            _ => {}
+        }
+    }
+    git_add_commit(
         &clone_dir,
-        &format!("Add {} chunks for {}", chunk_list.len(), checksum),
+        &format!("Remove {} chunks\n\n{}", paths.len(), label),
     )?;
+    git_push(&clone_dir)?;
     std::fs::remove_dir_all(&clone_dir).context("Failed to clean up data repo clone")?;
     Ok(())
 }
 
+/// Fetches `chunk_list` from the cached clone of one repo into
+/// `temp_dir/chunk_<i>`, in index order, calling `on_staged` with the index
+/// of each chunk once it is staged.
+/// Chunks already staged there and matching their size and checksum are
+/// kept. A chunk that fails to copy or verify is retried from the same clone,
+/// then once more from a fresh clone. Returns the timings and the chunks that
+/// could not be recovered as (index, reason); staged chunks are left in
+/// `temp_dir` either way so a later attempt can resume from them.
 pub fn download_chunks_from_repo(
     repo_name: &str,
-    chunk_list: &[(usize, String)],
+    chunk_list: &[(usize, ChunkInfo)],
     temp_dir: &Path,
-) -> Result<()> {
-    let repo_url = format!("git@github.com:{}/{}.git", GITHUB_USERNAME, repo_name);
-    let clone_dir = PathBuf::from(TMPFS_DIR).join(format!("dl_{}", repo_name));
-    if clone_dir.exists() {
-        std::fs::remove_dir_all(&clone_dir).context("Failed to remove existing dl clone")?;
+    algo: ChecksumAlgo,
+    on_staged: impl Fn(usize),
+) -> (RepoTimings, Vec<(usize, String)>) {
+    let mut timings = RepoTimings {
+        repo: repo_name.to_string(),
+        chunks: chunk_list.len(),
+        ..Default::default()
+    };
+    let staged = |global_i: usize| temp_dir.join(format!("chunk_{}", global_i));
+    let mut todo: Vec<(usize, &ChunkInfo, String)> = Vec::new();
+    for (i, chunk) in chunk_list {
+        if verify_chunk(&staged(*i), chunk, algo).is_ok() {
+            on_staged(*i);
+        } else {
+            todo.push((*i, chunk, String::new()));
+        }
     }
-    clone_repo(&repo_url, &clone_dir)?;
-    for (global_i, chunk_path_str) in chunk_list {
-        let src = clone_dir.join(chunk_path_str);
-        let dst = temp_dir.join(format!("chunk_{}", global_i));
-        std::fs::copy(&src, &dst).context("Failed to copy chunk from repo")?;
+    todo.sort_by_key(|(i, _, _)| *i);
+    todo.retain(|(i, chunk, _)| {
+        let served = daemon::fetch_chunk(repo_name, chunk, algo, &staged(*i));
+        if served {
+            on_staged(*i);
+        }
+        !served
+    });
+    let _slot = transfer_slot();
+    for attempt in 0..2 {
+        if todo.is_empty() {
+            break;
+        }
+        if attempt > 0 {
+            metrics::retried("download");
+            cache::forget(repo_name);
+        }
+        let started = Instant::now();
+        let cached = cache::refresh(repo_name);
+        timings.clone += started.elapsed();
+        let clone = match cached {
+            Ok(clone) => clone,
+            Err(e) => {
+                for (_, _, reason) in todo.iter_mut() {
+                    *reason = format!("{:#}", e);
+                }
+                continue;
+            }
+        };
+        let started = Instant::now();
+        todo.retain_mut(|(global_i, chunk, reason)| {
+            let dst = staged(*global_i);
+            let fetch = || -> Result<()> {
+                cache::copy_blob_range(&clone, &chunk.path, chunk.offset, chunk.size, &dst)
+                    .context("Failed to copy chunk from repo")?;
+                verify_chunk(&dst, chunk, algo)
+            };
+            match fetch().or_else(|_| fetch()) {
+                Ok(()) => {
+                    usage::downloaded(chunk.size);
+                    on_staged(*global_i);
+                    false
+                }
+                Err(e) => {
+                    *reason = format!("{:#}", e);
+                    true
+                }
+            }
+        });
+        timings.copy += started.elapsed();
+    }
+    let failed = todo
+        .into_iter()
+        .map(|(global_i, _, reason)| (global_i, reason))
+        .collect();
+    (timings, failed)
+}
+
+/// Checks a staged chunk against the size and checksum recorded for it.
+pub fn verify_chunk(path: &Path, chunk: &ChunkInfo, algo: ChecksumAlgo) -> Result<()> {
+    let size = std::fs::metadata(path).context("chunk not staged")?.len();
+    if size != chunk.size {
+        bail!("size mismatch: {} vs {}", size, chunk.size);
+    }
+    if let Some(expected) = &chunk.checksum {
+        let actual = get_file_checksum(path, algo)?;
+        if &actual != expected {
+            bail!("checksum mismatch: {} vs {}", actual, expected);
+        }
     }
-    std::fs::remove_dir_all(&clone_dir).context("Failed to clean up dl repo clone")?;
     Ok(())
 }
+
+/// A staged chunk whose length is not the size recorded for it, found
+/// before any of it is written out.
+#[derive(Debug)]
+pub struct ShortChunk {
+    pub index: usize,
+    pub repo: String,
+    pub staged: u64,
+    pub expected: u64,
+}
+
+impl fmt::Display for ShortChunk {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "chunk {} ({}) is {} bytes staged, {} recorded",
+            self.index, self.repo, self.staged, self.expected
+        )
+    }
+}
+
+impl std::error::Error for ShortChunk {}
+
+/// Copies the chunk staged at `path` to `output`, failing with `ShortChunk`
+/// before writing anything when its length is not `chunk.size`.
+pub fn copy_staged(path: &Path, chunk: &ChunkInfo, output: &mut impl Write) -> Result<u64> {
+    let mut staged = File::open(path).context("Failed to open downloaded chunk")?;
+    let len = staged.metadata()?.len();
+    if len != chunk.size {
+        return Err(ShortChunk {
+            index: chunk.index,
+            repo: chunk.repo.clone(),
+            staged: len,
+            expected: chunk.size,
+        }
+        .into());
+    }
+    let copied = for_each_block(&mut staged, |data| output.write_all(data))
+        .context("Failed to copy chunk to output")?;
+    if copied != chunk.size {
+        bail!(
+            "chunk {} ({}) changed while it was copied",
+            chunk.index,
+            chunk.repo
+        );
+    }
+    Ok(copied)
+}
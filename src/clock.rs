@@ -0,0 +1,128 @@
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, SystemTime};
+
+/// Source of the wall-clock time and of sleeps: the gc grace period, lock
+/// ages and retry delays all go through it.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> SystemTime;
+    fn sleep(&self, duration: Duration);
+}
+
+/// The real clock, used unless `set_clock` installed another one.
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+
+    fn sleep(&self, duration: Duration) {
+        std::thread::sleep(duration);
+    }
+}
+
+/// A clock that only moves when slept on or advanced, recording every sleep,
+/// so tests of time-dependent code run instantly.
+pub struct MockClock {
+    now: Mutex<SystemTime>,
+    slept: Mutex<Vec<Duration>>,
+}
+
+impl MockClock {
+    pub fn new(now: SystemTime) -> Self {
+        MockClock {
+            now: Mutex::new(now),
+            slept: Mutex::new(Vec::new()),
+        }
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        *self.now.lock().unwrap() += duration;
+    }
+
+    /// Every sleep so far, in order.
+    pub fn slept(&self) -> Vec<Duration> {
+        self.slept.lock().unwrap().clone()
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> SystemTime {
+        *self.now.lock().unwrap()
+    }
+
+    fn sleep(&self, duration: Duration) {
+        self.slept.lock().unwrap().push(duration);
+        self.advance(duration);
+    }
+}
+
+static CLOCK: RwLock<Option<Arc<dyn Clock>>> = RwLock::new(None);
+
+/// Makes `clock` the one every later call reads, for the whole process.
+pub fn set_clock(clock: Arc<dyn Clock>) {
+    *CLOCK.write().unwrap() = Some(clock);
+}
+
+/// Goes back to the real clock.
+pub fn reset_clock() {
+    *CLOCK.write().unwrap() = None;
+}
+
+pub fn clock() -> Arc<dyn Clock> {
+    CLOCK
+        .read()
+        .unwrap()
+        .clone()
+        .unwrap_or_else(|| Arc::new(SystemClock))
+}
+
+/// Runs `f` with a `MockClock` set, one caller at a time as the clock is
+/// the process's, and returns its result with the sleeps it made.
+#[cfg(test)]
+pub(crate) fn with_mock_clock<T>(f: impl FnOnce(&MockClock) -> T) -> (T, Vec<Duration>) {
+    static SERIAL: Mutex<()> = Mutex::new(());
+    let _serial = SERIAL.lock().unwrap_or_else(|e| e.into_inner());
+    let mock = Arc::new(MockClock::new(SystemTime::UNIX_EPOCH));
+    set_clock(mock.clone());
+    let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| f(&mock)));
+    reset_clock();
+    match result {
+        Ok(value) => (value, mock.slept()),
+        Err(panic) => std::panic::resume_unwind(panic),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::{sleep, unix_now};
+
+    #[test]
+    fn a_mock_clock_moves_only_when_slept_on_or_advanced() {
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1000);
+        let mock = MockClock::new(start);
+        assert_eq!(mock.now(), start);
+        mock.advance(Duration::from_secs(5));
+        mock.sleep(Duration::from_secs(2));
+        mock.sleep(Duration::from_millis(1));
+        assert_eq!(mock.now(), start + Duration::from_millis(7001));
+        assert_eq!(
+            mock.slept(),
+            [Duration::from_secs(2), Duration::from_millis(1)]
+        );
+    }
+
+    #[test]
+    fn the_set_clock_is_the_one_time_and_sleeps_go_through() {
+        let ((), slept) = with_mock_clock(|mock| {
+            assert_eq!(unix_now(), 0);
+            mock.advance(Duration::from_secs(86_400));
+            sleep(30.0);
+            // no sleep for nothing
+            sleep(0.0);
+            assert_eq!(unix_now(), 86_430);
+        });
+        assert_eq!(slept, [Duration::from_secs(30)]);
+    }
+}
@@ -0,0 +1,1343 @@
+use anyhow::{bail, Context, Result};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+use std::time::Duration;
+
+use crate::constants::{
+    DEFAULT_CHUNK_CACHE_SIZE, DEFAULT_CHUNK_SIZE, DEFAULT_GC_GRACE_PERIOD, DEFAULT_GITHUB_USERNAME,
+    DEFAULT_IO_BUFFER_SIZE, DEFAULT_MAX_SIZE_PER_REPO, DEFAULT_MAX_UPLOAD_SIZE,
+    DEFAULT_PUSH_BATCH_CHUNKS, DEFAULT_PUSH_BATCH_SIZE, DEFAULT_REPO_CACHE_SIZE,
+    DEFAULT_REPO_PREFIX, DEFAULT_SSH_KEY_PATH, DEFAULT_TRANSFER_CONCURRENCY,
+};
+use crate::retry::{Operation, RetryOn, RetryPolicy};
+use crate::utils::{parse_duration, parse_size};
+
+/// User settings read from `config.toml`, every key is optional.
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Repos cloned, pushed or downloaded at the same time
+    pub transfer_concurrency: Option<usize>,
+    /// Threads for CPU-bound work like hashing, one per core by default
+    pub hash_threads: Option<usize>,
+    /// Size of the chunks new uploads are split into, in bytes
+    pub chunk_size: Option<u64>,
+    /// Bytes of chunks a storage repo takes before a new one is created
+    pub max_repo_size: Option<u64>,
+    /// Repos each storage account may hold, no limit by default
+    pub max_repos: Option<usize>,
+    /// Bytes of a file, or of the files of a batch, uploaded without
+    /// `--allow-large`, 0 for no limit
+    pub max_upload_size: Option<u64>,
+    /// Chunks committed and pushed to a storage repo at once
+    pub push_batch_chunks: Option<usize>,
+    /// Bytes of chunks committed and pushed to a storage repo at once
+    pub push_batch_size: Option<u64>,
+    /// Bytes the cached clones of storage repos may take, 0 to keep none
+    pub repo_cache_size: Option<u64>,
+    /// Bytes of chunks `cache-daemon` keeps on disk, 0 to keep none
+    pub chunk_cache_size: Option<u64>,
+    /// Bytes read at once when hashing, chunking or copying files
+    pub io_buffer_size: Option<u64>,
+    /// Seconds chunks no file uses anymore are kept before `gc` deletes them
+    pub gc_grace_period: Option<u64>,
+    /// Name of new storage repos, followed by 8 random hex digits
+    pub repo_prefix: Option<String>,
+    /// How uploads spread their chunks over the storage repos
+    pub placement: Option<PlacementStrategy>,
+    /// Directory for clones of the metadata and storage repos
+    pub work_dir: Option<String>,
+    /// Directory for staged chunks and downloads being assembled
+    pub staging_dir: Option<String>,
+    /// How git reaches GitHub: ssh with `github.ssh_key`, or https with a token
+    pub transport: Option<Transport>,
+    /// Refuse every write to GitHub, like `--read-only`
+    pub read_only: Option<bool>,
+    /// Read pushed chunks back from GitHub before committing the metadata
+    pub verify_upload: Option<bool>,
+    /// Count the bytes and requests sent to GitHub for `gidrive stats`
+    pub accounting: Option<bool>,
+    /// Clone only the metadata file a single-file read needs
+    pub sparse_checkout: Option<bool>,
+    /// How new storage repos are spread over the accounts
+    pub account_policy: Option<AccountPolicy>,
+    pub github: GithubConfig,
+    /// Further accounts holding storage repos, by username
+    pub accounts: BTreeMap<String, AccountConfig>,
+    pub serve: ServeConfig,
+    pub signing: SigningConfig,
+    pub hooks: HooksConfig,
+    pub retry: RetryConfig,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct GithubConfig {
+    /// Account owning the metadata and storage repos
+    pub username: Option<String>,
+    /// Private key git authenticates with over SSH
+    pub ssh_key: Option<String>,
+    /// Token git over HTTPS and the REST API authenticate with, GITHUB_TOKEN
+    /// or `gh auth token` by default
+    pub token: Option<String>,
+    /// Share one SSH connection between concurrent git processes
+    pub ssh_multiplex: Option<bool>,
+    /// How repos are created, deleted and listed: the REST API or gh
+    pub api: Option<GithubApi>,
+}
+
+/// Credentials of a storage account other than `github.username`, those of
+/// `[github]` for the keys left unset.
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct AccountConfig {
+    pub ssh_key: Option<String>,
+    /// For git over HTTPS and for creating and deleting its repos
+    pub token: Option<String>,
+}
+
+/// Which account gets a new storage repo.
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum AccountPolicy {
+    /// The accounts in turn
+    #[default]
+    RoundRobin,
+    /// The one whose repos hold the fewest bytes
+    LeastUsed,
+}
+
+impl std::fmt::Display for AccountPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            AccountPolicy::RoundRobin => write!(f, "round-robin"),
+            AccountPolicy::LeastUsed => write!(f, "least-used"),
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Transport {
+    #[default]
+    Ssh,
+    Https,
+}
+
+impl std::fmt::Display for Transport {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Transport::Ssh => write!(f, "ssh"),
+            Transport::Https => write!(f, "https"),
+        }
+    }
+}
+
+/// What creates, deletes and lists repos on GitHub.
+#[derive(Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum GithubApi {
+    /// Its REST API, called with the token
+    #[default]
+    Rest,
+    /// The gh CLI
+    Gh,
+}
+
+impl std::fmt::Display for GithubApi {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            GithubApi::Rest => write!(f, "rest"),
+            GithubApi::Gh => write!(f, "gh"),
+        }
+    }
+}
+
+/// How uploads spread their chunks over the storage repos.
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum PlacementStrategy {
+    /// Biggest chunks first, each into the repo it leaves the least room
+    /// in, filling repos up: the fewest repos
+    #[default]
+    BestFit,
+    /// Chunks in order, each into the repo with room holding the fewest
+    /// chunks of the file so far: downloads fetch from more repos at once
+    Striped,
+}
+
+impl std::fmt::Display for PlacementStrategy {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            PlacementStrategy::BestFit => write!(f, "best-fit"),
+            PlacementStrategy::Striped => write!(f, "striped"),
+        }
+    }
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServeConfig {
+    /// Bearer token `serve` requires on every request
+    pub token: Option<String>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct SigningConfig {
+    /// Private key signing the metadata on every write, from `gidrive keygen`
+    pub key: Option<String>,
+    /// Public keys metadata reads must be signed with, comma separated
+    pub public_key: Option<String>,
+}
+
+/// Commands run around transfers, see `hooks::run`.
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct HooksConfig {
+    /// Run before an upload starts
+    pub pre_upload: Option<String>,
+    /// Run after an upload succeeded
+    pub post_upload: Option<String>,
+    /// Run after a download succeeded
+    pub post_download: Option<String>,
+    /// Run after an upload or download failed
+    pub on_error: Option<String>,
+    /// Fail the upload when `pre_upload` fails, instead of warning
+    pub pre_upload_required: Option<bool>,
+}
+
+/// Retry policies by operation, see `retry::Operation`.
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct RetryConfig {
+    pub push: RetryPolicyConfig,
+    pub clone: RetryPolicyConfig,
+    pub api: RetryPolicyConfig,
+    pub create_repo: RetryPolicyConfig,
+}
+
+/// The keys of one `[retry.<operation>]` table, unset ones take the
+/// operation's default.
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct RetryPolicyConfig {
+    /// Attempts in all, 1 to never retry
+    pub max_attempts: Option<u32>,
+    /// Seconds waited after the first failure, doubled after each further one
+    pub base_delay: Option<u64>,
+    /// Seconds waited at most between attempts
+    pub max_delay: Option<u64>,
+    /// Percent of the delay it is randomly moved by, either way
+    pub jitter: Option<u8>,
+    /// Whether failures known to be permanent are retried too
+    pub retry_on: Option<RetryOn>,
+}
+
+/// What a config key holds, to parse `config set` values and check files.
+#[derive(Clone, Copy)]
+enum Kind {
+    /// Integer of at least 1
+    Count,
+    /// Byte size within (min, max)
+    Bytes(u64, u64),
+    /// Seconds, also written like "7d" or "12h"
+    Duration,
+    Text,
+    RepoPrefix,
+    Username,
+    /// true or false
+    Bool,
+    /// Integer from 0 to 100
+    Percent,
+    /// One of the listed words
+    Choice(&'static [&'static str]),
+    /// File that must exist, `~` is the home directory
+    File,
+}
+
+struct Key {
+    /// Dotted for keys in a table
+    name: &'static str,
+    /// Environment variable overriding the file
+    env: &'static str,
+    kind: Kind,
+}
+
+/// Every key of the config file.
+const KEYS: &[Key] = &[
+    Key {
+        name: "transfer_concurrency",
+        env: "GIDRIVE_TRANSFER_CONCURRENCY",
+        kind: Kind::Count,
+    },
+    Key {
+        name: "hash_threads",
+        env: "GIDRIVE_HASH_THREADS",
+        kind: Kind::Count,
+    },
+    Key {
+        name: "chunk_size",
+        env: "GIDRIVE_CHUNK_SIZE",
+        // GitHub rejects files over 100 MB
+        kind: Kind::Bytes(1024, 100 * 1024 * 1024),
+    },
+    Key {
+        name: "max_repo_size",
+        env: "GIDRIVE_MAX_REPO_SIZE",
+        kind: Kind::Bytes(1024, u64::MAX),
+    },
+    Key {
+        name: "max_repos",
+        env: "GIDRIVE_MAX_REPOS",
+        kind: Kind::Count,
+    },
+    Key {
+        name: "max_upload_size",
+        env: "GIDRIVE_MAX_UPLOAD_SIZE",
+        kind: Kind::Bytes(0, u64::MAX),
+    },
+    Key {
+        name: "push_batch_chunks",
+        env: "GIDRIVE_PUSH_BATCH_CHUNKS",
+        kind: Kind::Count,
+    },
+    Key {
+        name: "push_batch_size",
+        env: "GIDRIVE_PUSH_BATCH_SIZE",
+        kind: Kind::Bytes(1024, u64::MAX),
+    },
+    Key {
+        name: "repo_cache_size",
+        env: "GIDRIVE_REPO_CACHE_SIZE",
+        kind: Kind::Bytes(0, u64::MAX),
+    },
+    Key {
+        name: "chunk_cache_size",
+        env: "GIDRIVE_CHUNK_CACHE_SIZE",
+        kind: Kind::Bytes(0, u64::MAX),
+    },
+    Key {
+        name: "io_buffer_size",
+        env: "GIDRIVE_IO_BUFFER_SIZE",
+        kind: Kind::Bytes(4 * 1024, 64 * 1024 * 1024),
+    },
+    Key {
+        name: "gc_grace_period",
+        env: "GIDRIVE_GC_GRACE_PERIOD",
+        kind: Kind::Duration,
+    },
+    Key {
+        name: "repo_prefix",
+        env: "GIDRIVE_REPO_PREFIX",
+        kind: Kind::RepoPrefix,
+    },
+    Key {
+        name: "placement",
+        env: "GIDRIVE_PLACEMENT",
+        kind: Kind::Choice(&["best-fit", "striped"]),
+    },
+    Key {
+        name: "work_dir",
+        env: "GIDRIVE_WORK_DIR",
+        kind: Kind::Text,
+    },
+    Key {
+        name: "staging_dir",
+        env: "GIDRIVE_STAGING_DIR",
+        kind: Kind::Text,
+    },
+    Key {
+        name: "transport",
+        env: "GIDRIVE_TRANSPORT",
+        kind: Kind::Choice(&["ssh", "https"]),
+    },
+    Key {
+        name: "read_only",
+        env: "GIDRIVE_READ_ONLY",
+        kind: Kind::Bool,
+    },
+    Key {
+        name: "verify_upload",
+        env: "GIDRIVE_VERIFY_UPLOAD",
+        kind: Kind::Bool,
+    },
+    Key {
+        name: "accounting",
+        env: "GIDRIVE_ACCOUNTING",
+        kind: Kind::Bool,
+    },
+    Key {
+        name: "sparse_checkout",
+        env: "GIDRIVE_SPARSE_CHECKOUT",
+        kind: Kind::Bool,
+    },
+    Key {
+        name: "account_policy",
+        env: "GIDRIVE_ACCOUNT_POLICY",
+        kind: Kind::Choice(&["round-robin", "least-used"]),
+    },
+    Key {
+        name: "github.username",
+        env: "GIDRIVE_GITHUB_USERNAME",
+        kind: Kind::Username,
+    },
+    Key {
+        name: "github.ssh_key",
+        env: "GIDRIVE_SSH_KEY",
+        kind: Kind::File,
+    },
+    Key {
+        name: "github.ssh_multiplex",
+        env: "GIDRIVE_SSH_MULTIPLEX",
+        kind: Kind::Bool,
+    },
+    Key {
+        name: "github.api",
+        env: "GIDRIVE_GITHUB_API",
+        kind: Kind::Choice(&["rest", "gh"]),
+    },
+    Key {
+        name: "github.token",
+        env: "GIDRIVE_GITHUB_TOKEN",
+        kind: Kind::Text,
+    },
+    Key {
+        name: "serve.token",
+        env: "GIDRIVE_SERVE_TOKEN",
+        kind: Kind::Text,
+    },
+    Key {
+        name: "signing.key",
+        env: "GIDRIVE_SIGNING_KEY",
+        kind: Kind::File,
+    },
+    Key {
+        name: "signing.public_key",
+        env: "GIDRIVE_SIGNING_PUBLIC_KEY",
+        kind: Kind::Text,
+    },
+    Key {
+        name: "hooks.pre_upload",
+        env: "GIDRIVE_PRE_UPLOAD_HOOK",
+        kind: Kind::Text,
+    },
+    Key {
+        name: "hooks.post_upload",
+        env: "GIDRIVE_POST_UPLOAD_HOOK",
+        kind: Kind::Text,
+    },
+    Key {
+        name: "hooks.post_download",
+        env: "GIDRIVE_POST_DOWNLOAD_HOOK",
+        kind: Kind::Text,
+    },
+    Key {
+        name: "hooks.on_error",
+        env: "GIDRIVE_ON_ERROR_HOOK",
+        kind: Kind::Text,
+    },
+    Key {
+        name: "hooks.pre_upload_required",
+        env: "GIDRIVE_PRE_UPLOAD_HOOK_REQUIRED",
+        kind: Kind::Bool,
+    },
+    Key {
+        name: "retry.push.max_attempts",
+        env: "GIDRIVE_RETRY_PUSH_MAX_ATTEMPTS",
+        kind: Kind::Count,
+    },
+    Key {
+        name: "retry.push.base_delay",
+        env: "GIDRIVE_RETRY_PUSH_BASE_DELAY",
+        kind: Kind::Duration,
+    },
+    Key {
+        name: "retry.push.max_delay",
+        env: "GIDRIVE_RETRY_PUSH_MAX_DELAY",
+        kind: Kind::Duration,
+    },
+    Key {
+        name: "retry.push.jitter",
+        env: "GIDRIVE_RETRY_PUSH_JITTER",
+        kind: Kind::Percent,
+    },
+    Key {
+        name: "retry.push.retry_on",
+        env: "GIDRIVE_RETRY_PUSH_RETRY_ON",
+        kind: Kind::Choice(&["transient", "any"]),
+    },
+    Key {
+        name: "retry.clone.max_attempts",
+        env: "GIDRIVE_RETRY_CLONE_MAX_ATTEMPTS",
+        kind: Kind::Count,
+    },
+    Key {
+        name: "retry.clone.base_delay",
+        env: "GIDRIVE_RETRY_CLONE_BASE_DELAY",
+        kind: Kind::Duration,
+    },
+    Key {
+        name: "retry.clone.max_delay",
+        env: "GIDRIVE_RETRY_CLONE_MAX_DELAY",
+        kind: Kind::Duration,
+    },
+    Key {
+        name: "retry.clone.jitter",
+        env: "GIDRIVE_RETRY_CLONE_JITTER",
+        kind: Kind::Percent,
+    },
+    Key {
+        name: "retry.clone.retry_on",
+        env: "GIDRIVE_RETRY_CLONE_RETRY_ON",
+        kind: Kind::Choice(&["transient", "any"]),
+    },
+    Key {
+        name: "retry.api.max_attempts",
+        env: "GIDRIVE_RETRY_API_MAX_ATTEMPTS",
+        kind: Kind::Count,
+    },
+    Key {
+        name: "retry.api.base_delay",
+        env: "GIDRIVE_RETRY_API_BASE_DELAY",
+        kind: Kind::Duration,
+    },
+    Key {
+        name: "retry.api.max_delay",
+        env: "GIDRIVE_RETRY_API_MAX_DELAY",
+        kind: Kind::Duration,
+    },
+    Key {
+        name: "retry.api.jitter",
+        env: "GIDRIVE_RETRY_API_JITTER",
+        kind: Kind::Percent,
+    },
+    Key {
+        name: "retry.api.retry_on",
+        env: "GIDRIVE_RETRY_API_RETRY_ON",
+        kind: Kind::Choice(&["transient", "any"]),
+    },
+    Key {
+        name: "retry.create_repo.max_attempts",
+        env: "GIDRIVE_RETRY_CREATE_REPO_MAX_ATTEMPTS",
+        kind: Kind::Count,
+    },
+    Key {
+        name: "retry.create_repo.base_delay",
+        env: "GIDRIVE_RETRY_CREATE_REPO_BASE_DELAY",
+        kind: Kind::Duration,
+    },
+    Key {
+        name: "retry.create_repo.max_delay",
+        env: "GIDRIVE_RETRY_CREATE_REPO_MAX_DELAY",
+        kind: Kind::Duration,
+    },
+    Key {
+        name: "retry.create_repo.jitter",
+        env: "GIDRIVE_RETRY_CREATE_REPO_JITTER",
+        kind: Kind::Percent,
+    },
+    Key {
+        name: "retry.create_repo.retry_on",
+        env: "GIDRIVE_RETRY_CREATE_REPO_RETRY_ON",
+        kind: Kind::Choice(&["transient", "any"]),
+    },
+];
+
+/// Keys of an `[accounts.<username>]` table, without environment variables.
+const ACCOUNT_KEYS: &[Key] = &[
+    Key {
+        name: "ssh_key",
+        env: "",
+        kind: Kind::File,
+    },
+    Key {
+        name: "token",
+        env: "",
+        kind: Kind::Text,
+    },
+];
+
+/// Where the effective value of a key comes from.
+#[derive(Clone, Copy)]
+pub enum Origin {
+    Default,
+    File,
+    Env(&'static str),
+    Flag(&'static str),
+}
+
+impl std::fmt::Display for Origin {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Origin::Default => write!(f, "default"),
+            Origin::File => write!(f, "file {}", config_path().display()),
+            Origin::Env(var) => write!(f, "env {}", var),
+            Origin::Flag(flag) => write!(f, "flag {}", flag),
+        }
+    }
+}
+
+/// A key set by a command line flag: (key, flag, value).
+pub type Override = (&'static str, &'static str, String);
+
+/// `$XDG_CONFIG_HOME/gidrive/config.toml`, falling back to `~/.config`.
+pub fn config_path() -> PathBuf {
+    let base = std::env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))
+        .unwrap_or_default();
+    base.join("gidrive").join("config.toml")
+}
+
+static ACTIVE: OnceLock<Config> = OnceLock::new();
+
+/// Makes `config` the settings of this process, before anything reads them.
+/// Later calls are ignored.
+pub fn activate(config: Config) {
+    let _ = ACTIVE.set(config);
+}
+
+/// The settings passed to `activate`, or the defaults.
+pub fn settings() -> &'static Config {
+    ACTIVE.get_or_init(Config::default)
+}
+
+impl Config {
+    /// Loads the config file, a missing file gives the defaults, with the
+    /// `GIDRIVE_*` environment variables applied over it.
+    pub fn load() -> Result<Config> {
+        Config::load_with(&[])
+    }
+
+    /// Like `load`, with `overrides` from the command line applied last:
+    /// flag > env > file > default.
+    pub fn load_with(overrides: &[Override]) -> Result<Config> {
+        let (table, _) = layers(overrides)?;
+        to_config(table)
+    }
+
+    pub fn transfer_concurrency(&self) -> usize {
+        self.transfer_concurrency
+            .unwrap_or(DEFAULT_TRANSFER_CONCURRENCY)
+    }
+
+    pub fn chunk_size(&self) -> u64 {
+        self.chunk_size.unwrap_or(DEFAULT_CHUNK_SIZE)
+    }
+
+    pub fn max_repo_size(&self) -> u64 {
+        self.max_repo_size.unwrap_or(DEFAULT_MAX_SIZE_PER_REPO)
+    }
+
+    pub fn max_upload_size(&self) -> u64 {
+        self.max_upload_size.unwrap_or(DEFAULT_MAX_UPLOAD_SIZE)
+    }
+
+    pub fn push_batch_chunks(&self) -> usize {
+        self.push_batch_chunks.unwrap_or(DEFAULT_PUSH_BATCH_CHUNKS)
+    }
+
+    pub fn push_batch_size(&self) -> u64 {
+        self.push_batch_size.unwrap_or(DEFAULT_PUSH_BATCH_SIZE)
+    }
+
+    pub fn repo_cache_size(&self) -> u64 {
+        self.repo_cache_size.unwrap_or(DEFAULT_REPO_CACHE_SIZE)
+    }
+
+    pub fn chunk_cache_size(&self) -> u64 {
+        self.chunk_cache_size.unwrap_or(DEFAULT_CHUNK_CACHE_SIZE)
+    }
+
+    pub fn io_buffer_size(&self) -> usize {
+        self.io_buffer_size.unwrap_or(DEFAULT_IO_BUFFER_SIZE) as usize
+    }
+
+    pub fn gc_grace_period(&self) -> u64 {
+        self.gc_grace_period.unwrap_or(DEFAULT_GC_GRACE_PERIOD)
+    }
+
+    pub fn verify_upload(&self) -> bool {
+        self.verify_upload.unwrap_or(false)
+    }
+
+    pub fn accounting(&self) -> bool {
+        self.accounting.unwrap_or(true)
+    }
+
+    pub fn sparse_checkout(&self) -> bool {
+        self.sparse_checkout.unwrap_or(true)
+    }
+
+    pub fn repo_prefix(&self) -> &str {
+        self.repo_prefix.as_deref().unwrap_or(DEFAULT_REPO_PREFIX)
+    }
+
+    pub fn placement(&self) -> PlacementStrategy {
+        self.placement.unwrap_or_default()
+    }
+
+    /// `work_dir`, none for a directory per invocation.
+    pub fn work_dir(&self) -> Option<PathBuf> {
+        self.work_dir.as_deref().map(expand_home)
+    }
+
+    /// `staging_dir`, none for a directory per invocation.
+    pub fn staging_dir(&self) -> Option<PathBuf> {
+        self.staging_dir.as_deref().map(expand_home)
+    }
+
+    pub fn github_username(&self) -> &str {
+        self.github
+            .username
+            .as_deref()
+            .unwrap_or(DEFAULT_GITHUB_USERNAME)
+    }
+
+    pub fn ssh_multiplex(&self) -> bool {
+        self.github.ssh_multiplex.unwrap_or(true)
+    }
+
+    pub fn ssh_key(&self) -> &str {
+        self.github
+            .ssh_key
+            .as_deref()
+            .unwrap_or(DEFAULT_SSH_KEY_PATH)
+    }
+
+    pub fn transport(&self) -> Transport {
+        self.transport.unwrap_or_default()
+    }
+
+    pub fn github_api(&self) -> GithubApi {
+        self.github.api.unwrap_or_default()
+    }
+
+    pub fn account_policy(&self) -> AccountPolicy {
+        self.account_policy.unwrap_or_default()
+    }
+
+    /// `github.username` followed by the accounts of `[accounts.*]`.
+    pub fn storage_accounts(&self) -> Vec<&str> {
+        let primary = self.github_username();
+        std::iter::once(primary)
+            .chain(
+                self.accounts
+                    .keys()
+                    .map(String::as_str)
+                    .filter(|a| *a != primary),
+            )
+            .collect()
+    }
+
+    /// Key git authenticates as `account` with over SSH.
+    pub fn account_ssh_key(&self, account: &str) -> &str {
+        self.accounts
+            .get(account)
+            .and_then(|a| a.ssh_key.as_deref())
+            .unwrap_or_else(|| self.ssh_key())
+    }
+
+    /// Token of an account of `[accounts.*]`, none for `github.username`
+    /// and accounts using its credentials.
+    pub fn account_token(&self, account: &str) -> Option<&str> {
+        self.accounts.get(account).and_then(|a| a.token.as_deref())
+    }
+
+    /// Clone URL of the repo `owner/name` over the configured transport.
+    pub fn repo_url(&self, slug: &str) -> String {
+        match self.transport() {
+            Transport::Ssh => format!("git@github.com:{}.git", slug),
+            Transport::Https => format!("https://github.com/{}.git", slug),
+        }
+    }
+
+    pub fn metadata_repo_url(&self) -> String {
+        self.repo_url(&format!("{}/metadata", self.github_username()))
+    }
+
+    /// The retry policy of `operation`, the config over its defaults.
+    pub fn retry_policy(&self, operation: Operation) -> RetryPolicy {
+        let config = match operation {
+            Operation::Push => &self.retry.push,
+            Operation::Clone => &self.retry.clone,
+            Operation::Api => &self.retry.api,
+            Operation::CreateRepo => &self.retry.create_repo,
+        };
+        let mut policy = operation.default_policy();
+        if let Some(n) = config.max_attempts {
+            policy.max_attempts = n;
+        }
+        if let Some(secs) = config.base_delay {
+            policy.base_delay = Duration::from_secs(secs);
+        }
+        if let Some(secs) = config.max_delay {
+            policy.max_delay = Duration::from_secs(secs);
+        }
+        if let Some(percent) = config.jitter {
+            policy.jitter = f64::from(percent) / 100.0;
+        }
+        if let Some(retry_on) = config.retry_on {
+            policy.retry_on = retry_on;
+        }
+        policy
+    }
+
+    /// The value `key` takes, configured or default, as `config get` shows it.
+    pub fn value(&self, key: &str) -> Result<Option<String>> {
+        let threads = || {
+            std::thread::available_parallelism()
+                .map_or(1, |n| n.get())
+                .to_string()
+        };
+        let retry = key.strip_prefix("retry.").and_then(|k| k.split_once('.'));
+        let account = key
+            .strip_prefix("accounts.")
+            .and_then(|k| k.split_once('.'));
+        if let Some((account, field)) = account {
+            let config = self.accounts.get(account);
+            return Ok(match field {
+                "ssh_key" => config.map(|_| self.account_ssh_key(account).to_string()),
+                "token" => self.account_token(account).map(str::to_string),
+                _ => bail!("unknown config key `{}`, see `gidrive config list`", key),
+            });
+        }
+        if let Some((operation, field)) = retry {
+            let policy = Operation::from_name(operation).map(|op| self.retry_policy(op));
+            let value = match (policy, field) {
+                (Some(p), "max_attempts") => p.max_attempts.to_string(),
+                (Some(p), "base_delay") => p.base_delay.as_secs().to_string(),
+                (Some(p), "max_delay") => p.max_delay.as_secs().to_string(),
+                (Some(p), "jitter") => ((p.jitter * 100.0).round() as u8).to_string(),
+                (Some(p), "retry_on") => p.retry_on.to_string(),
+                _ => bail!("unknown config key `{}`, see `gidrive config list`", key),
+            };
+            return Ok(Some(value));
+        }
+        Ok(match key {
+            "transfer_concurrency" => Some(self.transfer_concurrency().to_string()),
+            "hash_threads" => Some(self.hash_threads.map_or_else(threads, |n| n.to_string())),
+            "chunk_size" => Some(self.chunk_size().to_string()),
+            "max_repo_size" => Some(self.max_repo_size().to_string()),
+            "max_repos" => self.max_repos.map(|n| n.to_string()),
+            "max_upload_size" => Some(self.max_upload_size().to_string()),
+            "push_batch_chunks" => Some(self.push_batch_chunks().to_string()),
+            "push_batch_size" => Some(self.push_batch_size().to_string()),
+            "repo_cache_size" => Some(self.repo_cache_size().to_string()),
+            "chunk_cache_size" => Some(self.chunk_cache_size().to_string()),
+            "io_buffer_size" => Some(self.io_buffer_size().to_string()),
+            "gc_grace_period" => Some(self.gc_grace_period().to_string()),
+            "repo_prefix" => Some(self.repo_prefix().to_string()),
+            "placement" => Some(self.placement().to_string()),
+            "work_dir" => self.work_dir().map(|dir| dir.display().to_string()),
+            "staging_dir" => self.staging_dir().map(|dir| dir.display().to_string()),
+            "transport" => Some(self.transport().to_string()),
+            "read_only" => Some(self.read_only.unwrap_or(false).to_string()),
+            "verify_upload" => Some(self.verify_upload().to_string()),
+            "accounting" => Some(self.accounting().to_string()),
+            "sparse_checkout" => Some(self.sparse_checkout().to_string()),
+            "account_policy" => Some(self.account_policy().to_string()),
+            "github.username" => Some(self.github_username().to_string()),
+            "github.ssh_key" => Some(self.ssh_key().to_string()),
+            "github.ssh_multiplex" => Some(self.ssh_multiplex().to_string()),
+            "github.api" => Some(self.github_api().to_string()),
+            "github.token" => self.github.token.clone(),
+            "serve.token" => self.serve.token.clone(),
+            "signing.key" => self.signing.key.clone(),
+            "signing.public_key" => self.signing.public_key.clone(),
+            "hooks.pre_upload" => self.hooks.pre_upload.clone(),
+            "hooks.post_upload" => self.hooks.post_upload.clone(),
+            "hooks.post_download" => self.hooks.post_download.clone(),
+            "hooks.on_error" => self.hooks.on_error.clone(),
+            "hooks.pre_upload_required" => {
+                Some(self.hooks.pre_upload_required.unwrap_or(false).to_string())
+            }
+            _ => bail!("unknown config key `{}`, see `gidrive config list`", key),
+        })
+    }
+
+    /// Path of the metadata signing key, if one is configured.
+    pub fn signing_key(&self) -> Option<PathBuf> {
+        self.signing.key.as_deref().map(expand_home)
+    }
+
+    /// The public keys metadata must be signed with, none to not verify.
+    pub fn signing_public_keys(&self) -> Vec<&str> {
+        self.signing
+            .public_key
+            .as_deref()
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|k| !k.is_empty())
+            .collect()
+    }
+
+    /// Checks what `load` cannot: that referenced files exist.
+    pub fn check_files(&self) -> Result<()> {
+        if let Some(key) = self.signing_key() {
+            if !key.is_file() {
+                bail!(
+                    "`signing.key`: {} does not exist or is not a file",
+                    key.display()
+                );
+            }
+        }
+        if self.transport() == Transport::Https {
+            return Ok(());
+        }
+        let ssh_key = expand_home(self.ssh_key());
+        if !ssh_key.is_file() {
+            bail!(
+                "`github.ssh_key`: {} does not exist or is not a file",
+                ssh_key.display()
+            );
+        }
+        for (account, config) in &self.accounts {
+            let Some(key) = config.ssh_key.as_deref().map(expand_home) else {
+                continue;
+            };
+            if !key.is_file() {
+                bail!(
+                    "`accounts.{}.ssh_key`: {} does not exist or is not a file",
+                    account,
+                    key.display()
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Prints every key as `key = value`, marking the ones left at their
+/// default, or with `show_origin` where every value comes from.
+pub fn list(show_origin: bool, overrides: &[Override]) -> Result<()> {
+    let (table, origins) = layers(overrides)?;
+    let config = to_config(table)?;
+    for key in KEYS {
+        let origin = origins.get(key.name).copied().unwrap_or(Origin::Default);
+        match (config.value(key.name)?, origin) {
+            (Some(value), _) if show_origin => {
+                println!("{} = {}  # {}", key.name, shown(key.name, &value), origin)
+            }
+            (Some(value), Origin::Default) => println!("{} = {}  # default", key.name, value),
+            (Some(value), _) => println!("{} = {}", key.name, shown(key.name, &value)),
+            (None, _) => println!("{} unset", key.name),
+        }
+    }
+    for account in config.accounts.keys() {
+        for key in ACCOUNT_KEYS {
+            let name = format!("accounts.{}.{}", account, key.name);
+            match config.value(&name)? {
+                Some(value) if show_origin => {
+                    println!("{} = {}  # {}", name, shown(&name, &value), Origin::File)
+                }
+                Some(value) => println!("{} = {}", name, shown(&name, &value)),
+                None => println!("{} unset", name),
+            }
+        }
+    }
+    Ok(())
+}
+
+/// `value` of `key` as `list` and `set` print it: tokens are masked, `get`
+/// is the one way to read them back.
+fn shown(key: &str, value: &str) -> String {
+    if key.ends_with(".token") {
+        "********".to_string()
+    } else {
+        value.to_string()
+    }
+}
+
+/// Writes the config file readable by its owner only, as it may hold
+/// tokens.
+fn write_config(path: &Path, data: &str) -> Result<()> {
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(path)
+        .with_context(|| format!("Failed to write config {}", path.display()))?;
+    // files of older versions were created world-readable
+    file.set_permissions(fs::Permissions::from_mode(0o600))
+        .with_context(|| format!("Failed to write config {}", path.display()))?;
+    file.write_all(data.as_bytes())
+        .with_context(|| format!("Failed to write config {}", path.display()))
+}
+
+pub fn get(key: &str, overrides: &[Override]) -> Result<()> {
+    match Config::load_with(overrides)?.value(key)? {
+        Some(value) => println!("{}", value),
+        None => bail!("`{}` is not set", key),
+    }
+    Ok(())
+}
+
+/// Sets `key` in the config file, creating it on first use. Comments and
+/// the other keys of the file are kept as they are.
+pub fn set(key: &str, value: &str) -> Result<()> {
+    let item = match parse_value(find_key(key)?, value, &format!("`{}`", key))? {
+        toml::Value::Integer(n) => toml_edit::value(n),
+        toml::Value::Boolean(b) => toml_edit::value(b),
+        _ => toml_edit::value(value),
+    };
+    let path = config_path();
+    let data = if path.exists() {
+        std::fs::read_to_string(&path)
+            .with_context(|| format!("Failed to read config {}", path.display()))?
+    } else {
+        default_file()
+    };
+    let mut doc: toml_edit::DocumentMut = data
+        .parse()
+        .with_context(|| format!("Invalid config {}", path.display()))?;
+    match key.rsplit_once('.') {
+        Some((tables, leaf)) => {
+            let mut parent = doc.as_item_mut();
+            for (depth, table) in tables.split('.').enumerate() {
+                if !parent.get(table).is_some_and(|t| t.is_table()) {
+                    // `[retry.push]` needs no `[retry]` header of its own
+                    let mut new = toml_edit::Table::new();
+                    new.set_implicit(depth + 1 < tables.split('.').count());
+                    parent[table] = toml_edit::Item::Table(new);
+                }
+                parent = &mut parent[table];
+            }
+            parent[leaf] = item;
+        }
+        None => doc[key] = item,
+    }
+    let data = doc.to_string();
+    to_config(parse_file(&data)?)?;
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir).context("Failed to create config dir")?;
+    }
+    write_config(&path, &data)?;
+    println!("{} = {}", key, shown(key, value));
+    Ok(())
+}
+
+/// Loads the config with every check, referenced files included.
+pub fn validate(overrides: &[Override]) -> Result<()> {
+    let path = config_path();
+    let config = Config::load_with(overrides)?;
+    config.check_files()?;
+    if path.exists() {
+        println!("{} is valid", path.display());
+    } else {
+        println!(
+            "no config file at {}, the defaults are valid",
+            path.display()
+        );
+    }
+    Ok(())
+}
+
+/// Opens the config file in `$EDITOR`, creating it on first use, and
+/// validates it afterwards.
+pub fn edit() -> Result<()> {
+    let path = config_path();
+    if !path.exists() {
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir).context("Failed to create config dir")?;
+        }
+        write_config(&path, &default_file())?;
+    }
+    let editor = std::env::var("EDITOR").unwrap_or_else(|_| "vi".into());
+    let status = std::process::Command::new("sh")
+        .arg("-c")
+        .arg(format!("{} \"$1\"", editor))
+        .arg("sh")
+        .arg(&path)
+        .status()
+        .with_context(|| format!("Failed to start {}", editor))?;
+    if !status.success() {
+        bail!("{} exited with {}", editor, status);
+    }
+    validate(&[])
+}
+
+/// A new config file with every key at its default. Keys without a fixed
+/// default, like hash_threads or serve.token, are left out.
+fn default_file() -> String {
+    let defaults = Config::default();
+    let mut s = String::from("# gidrive settings, see `gidrive config list`\n");
+    let mut table = "";
+    for key in KEYS {
+        let value = match (key.name, defaults.value(key.name)) {
+            ("hash_threads", _) | (_, Ok(None) | Err(_)) => continue,
+            (_, Ok(Some(value)))
+                if matches!(
+                    key.kind,
+                    Kind::Count | Kind::Bytes(..) | Kind::Duration | Kind::Percent | Kind::Bool
+                ) =>
+            {
+                value
+            }
+            (_, Ok(Some(value))) => format!("{:?}", value),
+        };
+        let (section, leaf) = key.name.rsplit_once('.').unwrap_or(("", key.name));
+        if section != table {
+            s.push_str(&format!("\n[{}]\n", section));
+            table = section;
+        }
+        s.push_str(&format!("{} = {}\n", leaf, value));
+    }
+    s
+}
+
+/// Reads the config file and applies the environment and `overrides` over
+/// it, returning the merged table and where each set key comes from.
+fn layers(overrides: &[Override]) -> Result<(toml::Table, BTreeMap<&'static str, Origin>)> {
+    let path = config_path();
+    let mut table = if path.exists() {
+        let data = std::fs::read_to_string(&path)
+            .with_context(|| format!("Failed to read config {}", path.display()))?;
+        parse_file(&data).with_context(|| format!("Invalid config {}", path.display()))?
+    } else {
+        toml::Table::new()
+    };
+    let mut origins = BTreeMap::new();
+    for key in KEYS {
+        if lookup(&table, key.name).is_some() {
+            origins.insert(key.name, Origin::File);
+        }
+    }
+    for key in KEYS {
+        // an empty variable counts as unset, like an empty CI secret
+        let Some(raw) = std::env::var(key.env).ok().filter(|v| !v.is_empty()) else {
+            continue;
+        };
+        insert(&mut table, key.name, parse_value(key, &raw, key.env)?);
+        origins.insert(key.name, Origin::Env(key.env));
+    }
+    for (name, flag, raw) in overrides {
+        let key = find_key(name)?;
+        insert(&mut table, key.name, parse_value(key, raw, flag)?);
+        origins.insert(key.name, Origin::Flag(flag));
+    }
+    Ok((table, origins))
+}
+
+/// Parses the text of a config file, naming the offending key on invalid
+/// values rather than passing on a serde message.
+fn parse_file(data: &str) -> Result<toml::Table> {
+    let mut table: toml::Table = toml::from_str(data)?;
+    check_table(&table, "")?;
+    // sizes may be written as "2MiB" and durations as "7d", the config
+    // structs take bytes and seconds
+    for key in KEYS {
+        let parse = match key.kind {
+            Kind::Bytes(..) => parse_size,
+            Kind::Duration => parse_duration,
+            _ => continue,
+        };
+        if let Some(toml::Value::String(raw)) = lookup(&table, key.name) {
+            let n = i64::try_from(parse(raw)?)
+                .with_context(|| format!("`{}` is too large", key.name))?;
+            insert(&mut table, key.name, toml::Value::Integer(n));
+        }
+    }
+    Ok(table)
+}
+
+fn to_config(table: toml::Table) -> Result<Config> {
+    let config: Config = table.try_into()?;
+    if config.chunk_size() > config.max_repo_size() {
+        bail!(
+            "`chunk_size` ({}) is bigger than `max_repo_size` ({})",
+            config.chunk_size(),
+            config.max_repo_size()
+        );
+    }
+    for operation in Operation::ALL {
+        let policy = config.retry_policy(operation);
+        if policy.base_delay > policy.max_delay {
+            bail!(
+                "`retry.{}.base_delay` ({}) is bigger than `retry.{}.max_delay` ({})",
+                operation,
+                policy.base_delay.as_secs(),
+                operation,
+                policy.max_delay.as_secs()
+            );
+        }
+    }
+    Ok(config)
+}
+
+fn check_table(table: &toml::Table, prefix: &str) -> Result<()> {
+    for (name, value) in table {
+        let name = format!("{}{}", prefix, name);
+        if let toml::Value::Table(inner) = value {
+            let account = name
+                .strip_prefix("accounts.")
+                .is_some_and(|account| !account.contains('.'));
+            if name == "accounts" && prefix.is_empty() {
+                check_table(inner, "accounts.")?;
+                continue;
+            }
+            if account {
+                if !valid_username(&name["accounts.".len()..]) {
+                    bail!("`{}`: the table name must be a GitHub username", name);
+                }
+                check_table(inner, &format!("{}.", name))?;
+                continue;
+            }
+            if KEYS
+                .iter()
+                .any(|k| k.name.starts_with(&format!("{}.", name)))
+            {
+                check_table(inner, &format!("{}.", name))?;
+                continue;
+            }
+        }
+        check_value(&format!("`{}`", name), find_key(&name)?.kind, value)?;
+    }
+    Ok(())
+}
+
+/// Parses `raw` as given in the environment or on the command line, `label`
+/// naming where it comes from in errors.
+fn parse_value(key: &Key, raw: &str, label: &str) -> Result<toml::Value> {
+    let value = match key.kind {
+        Kind::Count => match raw.parse::<i64>() {
+            Ok(n) => toml::Value::Integer(n),
+            Err(_) => bail!("{} takes a whole number, got {:?}", label, raw),
+        },
+        Kind::Bool => match raw {
+            "true" | "1" | "yes" => toml::Value::Boolean(true),
+            "false" | "0" | "no" => toml::Value::Boolean(false),
+            _ => bail!("{} takes true or false, got {:?}", label, raw),
+        },
+        Kind::Bytes(..) => match parse_size(raw).map(i64::try_from) {
+            Ok(Ok(n)) => toml::Value::Integer(n),
+            Ok(Err(_)) => bail!("{} is too large, got {:?}", label, raw),
+            Err(e) => bail!("{} takes a size like 2MiB or 2097152: {}", label, e),
+        },
+        Kind::Percent => match raw.trim_end_matches('%').parse::<i64>() {
+            Ok(n) => toml::Value::Integer(n),
+            Err(_) => bail!("{} takes a percentage like 20, got {:?}", label, raw),
+        },
+        Kind::Duration => match parse_duration(raw).map(i64::try_from) {
+            Ok(Ok(n)) => toml::Value::Integer(n),
+            Ok(Err(_)) => bail!("{} is too long, got {:?}", label, raw),
+            Err(e) => bail!("{} takes a duration like 7d or 3600: {}", label, e),
+        },
+        _ => toml::Value::String(raw.to_string()),
+    };
+    check_value(label, key.kind, &value)?;
+    Ok(value)
+}
+
+fn check_value(label: &str, kind: Kind, value: &toml::Value) -> Result<()> {
+    let problem = match (kind, value) {
+        (Kind::Count, toml::Value::Integer(n)) if *n >= 1 => return Ok(()),
+        (Kind::Count, _) => "must be a whole number of at least 1".to_string(),
+        (Kind::Bytes(min, max), toml::Value::Integer(n))
+            if *n >= 0 && (min..=max).contains(&(*n as u64)) =>
+        {
+            return Ok(())
+        }
+        (Kind::Bytes(min, max), toml::Value::String(s))
+            if parse_size(s).is_ok_and(|n| (min..=max).contains(&n)) =>
+        {
+            return Ok(())
+        }
+        (Kind::Bytes(min, u64::MAX), _) => format!(
+            "must be a size like \"2MiB\" or a number of bytes, of at least {}",
+            min
+        ),
+        (Kind::Bytes(min, max), _) => format!(
+            "must be a size like \"2MiB\" or a number of bytes, from {} to {}",
+            min, max
+        ),
+        (Kind::Duration, toml::Value::Integer(n)) if *n >= 0 => return Ok(()),
+        (Kind::Duration, toml::Value::String(s)) if parse_duration(s).is_ok() => return Ok(()),
+        (Kind::Duration, _) => {
+            "must be a duration like \"7d\", \"12h\" or a number of seconds".to_string()
+        }
+        (Kind::Percent, toml::Value::Integer(n)) if (0..=100).contains(n) => return Ok(()),
+        (Kind::Percent, _) => "must be a whole number from 0 to 100".to_string(),
+        (Kind::Bool, toml::Value::Boolean(_)) => return Ok(()),
+        (Kind::Bool, _) => "must be true or false".to_string(),
+        (Kind::RepoPrefix, toml::Value::String(s)) if valid_repo_prefix(s) => return Ok(()),
+        (Kind::RepoPrefix, _) => {
+            "must be 1 to 90 letters, digits, '-', '_' or '.', like \"storage-\"".to_string()
+        }
+        (Kind::Username, toml::Value::String(s)) if valid_username(s) => return Ok(()),
+        (Kind::Username, _) => {
+            "must be a GitHub username: letters, digits and single inner hyphens".to_string()
+        }
+        (Kind::Choice(words), toml::Value::String(s)) if words.contains(&s.as_str()) => {
+            return Ok(())
+        }
+        (Kind::Choice(words), _) => format!("must be one of {}", words.join(", ")),
+        (Kind::Text | Kind::File, toml::Value::String(_)) => return Ok(()),
+        (Kind::Text | Kind::File, _) => "must be a string".to_string(),
+    };
+    bail!("{} {}, got {}", label, problem, value)
+}
+
+fn find_key(name: &str) -> Result<&'static Key> {
+    let account_key = name
+        .strip_prefix("accounts.")
+        .and_then(|k| k.split_once('.'))
+        .filter(|(account, _)| valid_username(account))
+        .and_then(|(_, leaf)| ACCOUNT_KEYS.iter().find(|k| k.name == leaf));
+    KEYS.iter()
+        .find(|k| k.name == name)
+        .or(account_key)
+        .with_context(|| format!("unknown config key `{}`, see `gidrive config list`", name))
+}
+
+fn valid_repo_prefix(s: &str) -> bool {
+    (1..=90).contains(&s.len())
+        && s.chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_.".contains(c))
+}
+
+pub fn valid_username(s: &str) -> bool {
+    (1..=39).contains(&s.len())
+        && !s.starts_with('-')
+        && !s.ends_with('-')
+        && !s.contains("--")
+        && s.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+}
+
+fn insert(table: &mut toml::Table, key: &str, value: toml::Value) {
+    match key.split_once('.') {
+        Some((section, rest)) => {
+            let inner = table
+                .entry(section)
+                .or_insert_with(|| toml::Value::Table(toml::Table::new()));
+            if let toml::Value::Table(inner) = inner {
+                insert(inner, rest, value);
+            }
+        }
+        None => {
+            table.insert(key.to_string(), value);
+        }
+    }
+}
+
+fn lookup<'a>(table: &'a toml::Table, key: &str) -> Option<&'a toml::Value> {
+    match key.split_once('.') {
+        Some((section, rest)) => lookup(table.get(section)?.as_table()?, rest),
+        None => table.get(key),
+    }
+}
+
+pub fn expand_home(path: &str) -> PathBuf {
+    match (path.strip_prefix("~/"), std::env::var_os("HOME")) {
+        (Some(rest), Some(home)) => Path::new(&home).join(rest),
+        _ => PathBuf::from(path),
+    }
+}
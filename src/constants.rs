@@ -1,8 +1,26 @@
-pub const NUM_PUSH_THREADS: usize = 8;
-pub const GITHUB_USERNAME: &str = "test-storage-00";
-pub const SSH_KEY_PATH: &str = "~/.ssh/storage01";
-pub const METADATA_REPO_URL: &str = "git@github.com:test-storage-00/metadata.git";
-pub const TMPFS_DIR: &str = "/tmp/gidrive-fds234sf";
-pub const CHUNK_SIZE: usize = 2 * 1024 * 1024; // 2 MB
-pub const MAX_SIZE_PER_REPO: u64 = 20 * 1024 * 1024; // 20 MB
+pub const DEFAULT_TRANSFER_CONCURRENCY: usize = 4; // repos cloned/pushed at once
+pub const FETCH_ATTEMPTS: u32 = 5; // requests of a `fetch` URL in all, the first and its resumes
+pub const DROPPED_CONNECTION_DELAY: u64 = 15; // seconds before retrying a push whose connection dropped
+pub const UPLOAD_QUEUE_DEPTH: usize = 2; // repo batches waiting for a push thread
+pub const DEFAULT_GITHUB_USERNAME: &str = "test-storage-00";
+pub const DEFAULT_SSH_KEY_PATH: &str = "~/.ssh/storage01";
+pub const DEFAULT_CHUNK_SIZE: u64 = 2 * 1024 * 1024; // 2 MB
+pub const DEFAULT_MAX_SIZE_PER_REPO: u64 = 20 * 1024 * 1024; // 20 MB
+pub const DEFAULT_PUSH_BATCH_CHUNKS: usize = 25; // chunks per commit and push to a storage repo
+pub const DEFAULT_PUSH_BATCH_SIZE: u64 = 50 * 1024 * 1024; // 50 MB per commit and push
+pub const DEFAULT_MAX_UPLOAD_SIZE: u64 = 5 * 1024 * 1024 * 1024; // 5 GB, bigger uploads need --allow-large
+pub const DEFAULT_REPO_CACHE_SIZE: u64 = 2 * 1024 * 1024 * 1024; // 2 GB of cached storage repo clones
+pub const DEFAULT_CHUNK_CACHE_SIZE: u64 = 1024 * 1024 * 1024; // 1 GB of chunks kept by the cache daemon
+pub const DEFAULT_REPO_PREFIX: &str = "storage-"; // followed by 8 random hex digits
+pub const DEFAULT_IO_BUFFER_SIZE: u64 = 256 * 1024; // reads when hashing or copying files, see benches/io_buffer.rs
+pub const DEFAULT_NAMESPACE: &str = "default";
 pub const VERSION: &str = "0.1.1";
+pub const CLIENTS_LOG_ENTRIES: usize = 500; // lines kept in clients.log of the metadata repo
+pub const METADATA_REPLAYS: usize = 5; // times a metadata commit is made again on top of a concurrent one
+pub const STALE_LOCK_AGE: u64 = 10 * 60; // seconds before a git lock file in the repo cache counts as stale
+pub const COMMIT_MESSAGE_CHARS: usize = 200; // longer commit messages and clients.log entries are cut
+pub const METADATA_SHARD_THRESHOLD: usize = 20_000; // files of all namespaces before fs/ is split into shards
+pub const METADATA_SHARDS: u32 = 256; // subtrees of a split fs/, a reshard changes it
+pub const REMOTE_NAME_BYTES: usize = 250; // per path component, the metadata file adds .json within NAME_MAX
+pub const MAX_XATTR_SIZE: usize = 64 * 1024; // per extended attribute kept with --xattrs, bigger ones are skipped
+pub const DEFAULT_GC_GRACE_PERIOD: u64 = 7 * 24 * 60 * 60; // seconds released chunks are kept for --at and recovery
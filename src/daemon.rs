@@ -0,0 +1,489 @@
+//! `cache-daemon`: a process keeping the metadata and the chunks it fetched
+//! warm across the CLI invocations of a user, on a unix socket in the cache
+//! dir. Every connection sends one JSON request line and reads one JSON
+//! response line. Without a daemon listening, or one serving another drive,
+//! commands read GitHub themselves as before.
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::cache::{self, cache_dir};
+use crate::chunks::verify_chunk;
+use crate::config::settings;
+use crate::git::{clone_repo, git_refresh, head_commit, metadata_repo_url, offline};
+use crate::metadata::{list_all_file_metadata_tolerant, namespace, revision};
+use crate::models::{content_key, ChecksumAlgo, ChunkInfo, FileEntry};
+use crate::signing;
+use crate::utils::{checksum_hex, temp_dirs};
+
+/// How long the daemon trusts its metadata clone before fetching again.
+const METADATA_TTL: Duration = Duration::from_secs(30);
+/// How long a command waits on the daemon before giving up on it.
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// `cache_dir()/daemon.sock`
+pub fn socket_path() -> PathBuf {
+    cache_dir().join("daemon.sock")
+}
+
+/// `cache_dir()/chunks`, one file per chunk named by the hash of its key.
+fn chunk_dir() -> PathBuf {
+    cache_dir().join("chunks")
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+enum Request {
+    /// The files of `namespace`, as `ls` prints them
+    List {
+        metadata: String,
+        namespace: String,
+    },
+    /// Path of `chunk` of `repo` in the chunk cache, fetched on a miss
+    Chunk {
+        metadata: String,
+        repo: String,
+        chunk: ChunkInfo,
+        algo: ChecksumAlgo,
+    },
+    /// The metadata changed, fetch it on the next request
+    Invalidate {
+        metadata: String,
+    },
+    Stats,
+    Clear,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum Response {
+    Files {
+        files: Vec<FileEntry>,
+        corrupt: Vec<(PathBuf, String)>,
+    },
+    Chunk {
+        path: PathBuf,
+    },
+    Stats(Stats),
+    Cleared {
+        chunks: usize,
+        bytes: u64,
+    },
+    Done,
+    Error {
+        message: String,
+    },
+}
+
+/// What `cache stats` prints.
+#[derive(Serialize, Deserialize)]
+pub struct Stats {
+    /// Metadata repo the daemon serves
+    pub metadata: String,
+    pub uptime: u64,
+    pub listings: u64,
+    pub metadata_fetches: u64,
+    pub chunk_hits: u64,
+    pub chunk_misses: u64,
+    /// Chunks in the cache and their bytes
+    pub chunks: usize,
+    pub bytes: u64,
+    pub max_bytes: u64,
+}
+
+/// Files of a namespace and the metadata files of it that cannot be read.
+pub type Listing = (Vec<FileEntry>, Vec<(PathBuf, String)>);
+
+/// The metadata clone of the daemon and the listings of every namespace,
+/// read again only when a fetch brought another commit.
+struct WarmMetadata {
+    dir: PathBuf,
+    fetched: Option<Instant>,
+    head: String,
+    listings: HashMap<String, Arc<Listing>>,
+}
+
+struct Daemon {
+    metadata_url: String,
+    started: Instant,
+    metadata: Mutex<WarmMetadata>,
+    /// fetches into the repo cache are made one at a time
+    fetches: Mutex<()>,
+    listings: AtomicU64,
+    metadata_fetches: AtomicU64,
+    chunk_hits: AtomicU64,
+    chunk_misses: AtomicU64,
+}
+
+/// Serves the metadata and chunk caches on `socket_path()` until killed.
+pub fn run() -> Result<()> {
+    let socket = socket_path();
+    fs::create_dir_all(chunk_dir()).context("Failed to create chunk cache dir")?;
+    if UnixStream::connect(&socket).is_ok() {
+        bail!("a cache daemon already listens on {}", socket.display());
+    }
+    let _ = fs::remove_file(&socket);
+    let listener = UnixListener::bind(&socket)
+        .with_context(|| format!("cannot listen on {}", socket.display()))?;
+    // the socket serves private repos, only to this user
+    fs::set_permissions(&socket, fs::Permissions::from_mode(0o600))?;
+    let daemon = Daemon {
+        metadata_url: metadata_repo_url(),
+        started: Instant::now(),
+        metadata: Mutex::new(WarmMetadata {
+            dir: temp_dirs().work.join("daemon_metadata"),
+            fetched: None,
+            head: String::new(),
+            listings: HashMap::new(),
+        }),
+        fetches: Mutex::new(()),
+        listings: AtomicU64::new(0),
+        metadata_fetches: AtomicU64::new(0),
+        chunk_hits: AtomicU64::new(0),
+        chunk_misses: AtomicU64::new(0),
+    };
+    eprintln!(
+        "--- cache daemon for {} on {}",
+        daemon.metadata_url,
+        socket.display()
+    );
+    thread::scope(|scope| {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    let daemon = &daemon;
+                    scope.spawn(move || daemon.serve(stream));
+                }
+                Err(e) => eprintln!("--- cache daemon: accept failed: {e}"),
+            }
+        }
+    });
+    Ok(())
+}
+
+impl Daemon {
+    fn serve(&self, stream: UnixStream) {
+        let mut line = String::new();
+        if BufReader::new(&stream).read_line(&mut line).is_err() {
+            return;
+        }
+        let response = serde_json::from_str(&line)
+            .context("malformed request")
+            .and_then(|request| self.answer(request))
+            .unwrap_or_else(|e| Response::Error {
+                message: format!("{:#}", e),
+            });
+        let mut stream = stream;
+        if let Ok(mut data) = serde_json::to_vec(&response) {
+            data.push(b'\n');
+            let _ = stream.write_all(&data);
+        }
+    }
+
+    fn answer(&self, request: Request) -> Result<Response> {
+        match request {
+            Request::List { metadata, .. }
+            | Request::Chunk { metadata, .. }
+            | Request::Invalidate { metadata }
+                if metadata != self.metadata_url =>
+            {
+                bail!("the daemon serves {}, not {}", self.metadata_url, metadata)
+            }
+            Request::List { namespace, .. } => {
+                self.listings.fetch_add(1, Ordering::Relaxed);
+                let listing = self.listing(&namespace)?;
+                Ok(Response::Files {
+                    files: listing.0.clone(),
+                    corrupt: listing.1.clone(),
+                })
+            }
+            Request::Chunk {
+                repo, chunk, algo, ..
+            } => Ok(Response::Chunk {
+                path: self.chunk(&repo, &chunk, algo)?,
+            }),
+            Request::Invalidate { .. } => {
+                self.metadata.lock().unwrap().fetched = None;
+                Ok(Response::Done)
+            }
+            Request::Stats => {
+                let (chunks, bytes) = cached_chunks()?
+                    .iter()
+                    .fold((0, 0), |(n, total), (_, bytes, _)| (n + 1, total + bytes));
+                Ok(Response::Stats(Stats {
+                    metadata: self.metadata_url.clone(),
+                    uptime: self.started.elapsed().as_secs(),
+                    listings: self.listings.load(Ordering::Relaxed),
+                    metadata_fetches: self.metadata_fetches.load(Ordering::Relaxed),
+                    chunk_hits: self.chunk_hits.load(Ordering::Relaxed),
+                    chunk_misses: self.chunk_misses.load(Ordering::Relaxed),
+                    chunks,
+                    bytes,
+                    max_bytes: settings().chunk_cache_size(),
+                }))
+            }
+            Request::Clear => {
+                let _fetches = self.fetches.lock().unwrap();
+                let mut metadata = self.metadata.lock().unwrap();
+                metadata.fetched = None;
+                metadata.listings.clear();
+                let (chunks, bytes) = clear_chunks()?;
+                Ok(Response::Cleared { chunks, bytes })
+            }
+        }
+    }
+
+    /// The files of `namespace`, from the listings of the commit the clone
+    /// is at once it is fetched when older than `METADATA_TTL`.
+    fn listing(&self, namespace: &str) -> Result<Arc<Listing>> {
+        let mut metadata = self.metadata.lock().unwrap();
+        if metadata
+            .fetched
+            .is_none_or(|at| at.elapsed() >= METADATA_TTL)
+        {
+            self.metadata_fetches.fetch_add(1, Ordering::Relaxed);
+            if metadata.dir.join(".git").exists() {
+                git_refresh(&metadata.dir)?;
+            } else {
+                if metadata.dir.exists() {
+                    fs::remove_dir_all(&metadata.dir)?;
+                }
+                clone_repo(&self.metadata_url, &metadata.dir)?;
+            }
+            signing::verify(&metadata.dir)?;
+            let head = head_commit(&metadata.dir)?;
+            if head != metadata.head {
+                metadata.listings.clear();
+                metadata.head = head;
+            }
+            metadata.fetched = Some(Instant::now());
+        }
+        if metadata.listings.is_empty() {
+            let (files, corrupt) = list_all_file_metadata_tolerant(&metadata.dir)?;
+            let mut listings: HashMap<String, Listing> = HashMap::new();
+            for (path, meta) in files {
+                let Some((ns, path)) = path.split_once('/') else {
+                    continue;
+                };
+                let listing = listings.entry(ns.to_string()).or_default();
+                listing.0.push(FileEntry::new(path.to_string(), meta));
+            }
+            for (path, reason) in corrupt {
+                let ns = path
+                    .iter()
+                    .nth(1)
+                    .map(|ns| ns.to_string_lossy().into_owned());
+                let listing = listings.entry(ns.unwrap_or_default()).or_default();
+                listing.1.push((path, reason));
+            }
+            metadata.listings = listings
+                .into_iter()
+                .map(|(ns, listing)| (ns, Arc::new(listing)))
+                .collect();
+        }
+        Ok(metadata
+            .listings
+            .get(namespace)
+            .cloned()
+            .unwrap_or_default())
+    }
+
+    /// Path of `chunk` in the chunk cache, copied there from the repo cache
+    /// and checked on a miss. The least recently used chunks are removed
+    /// past `chunk_cache_size`.
+    fn chunk(&self, repo: &str, chunk: &ChunkInfo, algo: ChecksumAlgo) -> Result<PathBuf> {
+        let key = match &chunk.checksum {
+            Some(checksum) => content_key(algo, checksum),
+            None => format!("{}:{}:{}:{}", repo, chunk.path, chunk.offset, chunk.size),
+        };
+        let path = chunk_dir().join(checksum_hex(ChecksumAlgo::Sha256, key.as_bytes()));
+        if touch(&path).is_ok() {
+            self.chunk_hits.fetch_add(1, Ordering::Relaxed);
+            return Ok(path);
+        }
+        let _fetches = self.fetches.lock().unwrap();
+        // another connection may have fetched it meanwhile
+        if touch(&path).is_ok() {
+            self.chunk_hits.fetch_add(1, Ordering::Relaxed);
+            return Ok(path);
+        }
+        self.chunk_misses.fetch_add(1, Ordering::Relaxed);
+        let clone = cache::refresh(repo)?;
+        let part = path.with_extension("part");
+        cache::copy_blob_range(&clone, &chunk.path, chunk.offset, chunk.size, &part)
+            .context("Failed to copy chunk from repo")?;
+        if let Err(e) = verify_chunk(&part, chunk, algo) {
+            let _ = fs::remove_file(&part);
+            return Err(e);
+        }
+        fs::rename(&part, &path).context("Failed to store chunk")?;
+        evict_chunks(settings().chunk_cache_size(), &path)?;
+        if let Err(e) = cache::evict(settings().repo_cache_size()) {
+            eprintln!("--- could not trim the repo cache: {:#}", e);
+        }
+        Ok(path)
+    }
+}
+
+/// Marks the cached chunk at `path` used, failing if it is not cached.
+fn touch(path: &Path) -> Result<()> {
+    File::options()
+        .write(true)
+        .open(path)?
+        .set_modified(SystemTime::now())?;
+    Ok(())
+}
+
+/// (last use, bytes, path) of every cached chunk, least recently used first.
+fn cached_chunks() -> Result<Vec<(SystemTime, u64, PathBuf)>> {
+    let mut chunks = Vec::new();
+    let Ok(entries) = fs::read_dir(chunk_dir()) else {
+        return Ok(chunks);
+    };
+    for entry in entries {
+        let entry = entry?;
+        let meta = entry.metadata()?;
+        if meta.is_file() && entry.path().extension().is_none() {
+            chunks.push((
+                meta.modified().unwrap_or(UNIX_EPOCH),
+                meta.len(),
+                entry.path(),
+            ));
+        }
+    }
+    chunks.sort();
+    Ok(chunks)
+}
+
+/// Removes the least recently used chunks but `keep` until the cache takes
+/// at most `max_bytes`.
+fn evict_chunks(max_bytes: u64, keep: &Path) -> Result<()> {
+    let chunks = cached_chunks()?;
+    let mut total: u64 = chunks.iter().map(|(_, bytes, _)| bytes).sum();
+    for (_, bytes, path) in chunks {
+        if total <= max_bytes {
+            break;
+        }
+        if path != keep {
+            fs::remove_file(&path)
+                .with_context(|| format!("Failed to remove {}", path.display()))?;
+            total -= bytes;
+        }
+    }
+    Ok(())
+}
+
+/// Removes every cached chunk. Returns their number and bytes.
+fn clear_chunks() -> Result<(usize, u64)> {
+    let chunks = cached_chunks()?;
+    let bytes = chunks.iter().map(|(_, bytes, _)| bytes).sum();
+    if chunk_dir().exists() {
+        fs::remove_dir_all(chunk_dir()).context("Failed to remove chunk cache dir")?;
+        fs::create_dir_all(chunk_dir())?;
+    }
+    Ok((chunks.len(), bytes))
+}
+
+/// Whether the daemon may answer for this process: it reads the latest
+/// metadata, online, and a daemon socket exists.
+fn usable() -> bool {
+    static USABLE: OnceLock<bool> = OnceLock::new();
+    *USABLE.get_or_init(|| !offline() && revision().is_none() && socket_path().exists())
+}
+
+/// Sends `request` to the daemon, `None` when none is listening.
+fn ask(request: &Request) -> Option<Result<Response>> {
+    let stream = UnixStream::connect(socket_path()).ok()?;
+    let exchange = || -> Result<Response> {
+        stream.set_read_timeout(Some(CLIENT_TIMEOUT))?;
+        let mut data = serde_json::to_vec(request)?;
+        data.push(b'\n');
+        (&stream).write_all(&data)?;
+        let mut line = String::new();
+        BufReader::new(&stream).read_line(&mut line)?;
+        match serde_json::from_str(&line).context("malformed daemon response")? {
+            Response::Error { message } => bail!(message),
+            response => Ok(response),
+        }
+    };
+    Some(exchange())
+}
+
+/// The files of the namespace from the daemon, `None` when it cannot give
+/// them and the metadata must be read from GitHub.
+pub fn list() -> Option<Listing> {
+    if !usable() {
+        return None;
+    }
+    let request = Request::List {
+        metadata: metadata_repo_url(),
+        namespace: namespace().to_string(),
+    };
+    match ask(&request)? {
+        Ok(Response::Files { files, corrupt }) => Some((files, corrupt)),
+        Ok(_) => None,
+        Err(e) => {
+            eprintln!("--- cache daemon: {:#}, reading the metadata repo", e);
+            None
+        }
+    }
+}
+
+/// Stages `chunk` of `repo` at `dst` from the daemon's chunk cache. Returns
+/// false when the daemon could not, the chunk must be fetched as usual then.
+pub fn fetch_chunk(repo: &str, chunk: &ChunkInfo, algo: ChecksumAlgo, dst: &Path) -> bool {
+    if !usable() {
+        return false;
+    }
+    let request = Request::Chunk {
+        metadata: metadata_repo_url(),
+        repo: repo.to_string(),
+        chunk: chunk.clone(),
+        algo,
+    };
+    let Some(Ok(Response::Chunk { path })) = ask(&request) else {
+        return false;
+    };
+    fs::copy(&path, dst).is_ok() && verify_chunk(dst, chunk, algo).is_ok()
+}
+
+/// Tells the daemon this process changed the metadata, so that its next
+/// listing does not wait out `METADATA_TTL`.
+pub fn metadata_changed() {
+    if usable() {
+        let _ = ask(&Request::Invalidate {
+            metadata: metadata_repo_url(),
+        });
+    }
+}
+
+/// The counters of the running daemon, `None` when none is listening.
+pub fn stats() -> Result<Option<Stats>> {
+    match ask(&Request::Stats) {
+        None => Ok(None),
+        Some(Ok(Response::Stats(stats))) => Ok(Some(stats)),
+        Some(Ok(_)) => bail!("unexpected daemon response"),
+        Some(Err(e)) => Err(e),
+    }
+}
+
+/// Empties the chunk cache, through the daemon when one is listening so
+/// that it also forgets its listings. Returns the chunks and bytes removed.
+pub fn clear() -> Result<(usize, u64)> {
+    match ask(&Request::Clear) {
+        None => clear_chunks(),
+        Some(Ok(Response::Cleared { chunks, bytes })) => Ok((chunks, bytes)),
+        Some(Ok(_)) => bail!("unexpected daemon response"),
+        Some(Err(e)) => Err(e),
+    }
+}
@@ -0,0 +1,218 @@
+use anyhow::Result;
+use std::collections::BTreeSet;
+use std::path::Path;
+
+use crate::config::{config_path, expand_home, settings, GithubApi, Transport};
+use crate::git::{gh_auth_token, setup_auth, token_scopes};
+use crate::github;
+use crate::utils::{command, ensure_temp_dirs, free_space, human_size, temp_dirs};
+
+/// Scopes a classic token needs, with the operations needing them.
+const SCOPES: &[(&str, &str)] = &[
+    ("repo", "private storage repos, reading and creating them"),
+    ("delete_repo", "clean --empty-repos and clean --all"),
+];
+
+/// Checks the configuration, credentials and token scopes every command
+/// relies on, printing one line per check. Returns whether all passed.
+pub fn doctor() -> Result<bool> {
+    let config = settings();
+    let mut ok = true;
+    let mut check = |name: &str, res: Result<String, String>| match res {
+        Ok(detail) => println!("ok    {:<12} {}", name, detail),
+        Err(detail) => {
+            ok = false;
+            println!("FAIL  {:<12} {}", name, detail)
+        }
+    };
+    let path = config_path();
+    check(
+        "config",
+        Ok(if path.exists() {
+            path.display().to_string()
+        } else {
+            "no file, defaults".into()
+        }),
+    );
+    check("account", Ok(config.github_username().to_string()));
+    for account in config.storage_accounts().into_iter().skip(1) {
+        let key = expand_home(config.account_ssh_key(account));
+        let token = match config.account_token(account) {
+            Some(_) => "its own token",
+            None => "the default token",
+        };
+        check(
+            "storage",
+            match config.transport() {
+                Transport::Ssh if !key.is_file() => {
+                    Err(format!("{}: {} does not exist", account, key.display()))
+                }
+                Transport::Ssh => Ok(format!("{}: {}, {}", account, key.display(), token)),
+                Transport::Https => Ok(format!("{}: {}", account, token)),
+            },
+        );
+    }
+    match config.transport() {
+        Transport::Ssh => check(
+            "ssh key",
+            config
+                .check_files()
+                .map(|_| config.ssh_key().to_string())
+                .map_err(|e| e.to_string()),
+        ),
+        Transport::Https => check(
+            "token",
+            match (
+                &config.github.token,
+                github::token(config.github_username()),
+            ) {
+                (Some(_), _) => Ok("github.token".into()),
+                (None, Ok(_)) => Ok("from GITHUB_TOKEN, GH_TOKEN or gh auth token".into()),
+                (None, Err(e)) => Err(format!("{:#}", e)),
+            },
+        ),
+    }
+    if config.transport() == Transport::Ssh {
+        let keys: BTreeSet<_> = config
+            .storage_accounts()
+            .into_iter()
+            .map(|account| expand_home(config.account_ssh_key(account)))
+            .filter(|key| key.is_file())
+            .collect();
+        for key in keys {
+            check("passphrase", key_unlocked(&key));
+        }
+    }
+    let trusted = config.signing_public_keys().len();
+    check(
+        "signing",
+        match config.signing_key() {
+            Some(key) if !key.is_file() => Err(format!("{} does not exist", key.display())),
+            Some(key) => Ok(format!(
+                "writes signed with {}, reads checked against {} keys",
+                key.display(),
+                trusted
+            )),
+            None if trusted > 0 => Ok(format!("reads checked against {} keys", trusted)),
+            None => Ok("off".into()),
+        },
+    );
+    match config.github_api() {
+        GithubApi::Rest => check(
+            "api",
+            github::authenticated_as(config.github_username())
+                .map(|login| format!("REST, authenticated as {}", login))
+                .map_err(|e| format!("{:#}", e)),
+        ),
+        GithubApi::Gh => check(
+            "gh",
+            gh_auth_token()
+                .map(|_| "logged in".into())
+                .map_err(|e| e.to_string()),
+        ),
+    }
+    match token_scopes() {
+        Ok(Some(scopes)) => {
+            check("scopes", Ok(scopes.join(", ")));
+            for (scope, needed_for) in SCOPES {
+                let res = if scopes.iter().any(|s| s == scope) {
+                    Ok(needed_for.to_string())
+                } else {
+                    Err(format!(
+                        "missing, needed for {}: gh auth refresh -h github.com -s {}",
+                        needed_for, scope
+                    ))
+                };
+                check(scope, res);
+            }
+        }
+        Ok(None) => check(
+            "scopes",
+            Ok("not reported, fine-grained or app token: permissions unchecked".into()),
+        ),
+        Err(e) => check("scopes", Err(e.to_string())),
+    }
+    let dirs = temp_dirs();
+    for (name, dir) in [("work dir", &dirs.work), ("staging dir", &dirs.staging)] {
+        check(
+            name,
+            ensure_temp_dirs()
+                .and_then(|_| free_space(dir))
+                .map(|free| format!("{} free in {}", human_size(free), dir.display()))
+                .map_err(|e| e.to_string()),
+        );
+    }
+    let metadata = ensure_temp_dirs()
+        .and_then(|_| setup_auth(config))
+        .map_err(|e| e.to_string())
+        .and_then(|_| {
+            let url = config.metadata_repo_url();
+            let output = command("git")
+                .args(["ls-remote", &url, "HEAD"])
+                .output()
+                .map_err(|e| e.to_string())?;
+            if output.status.success() {
+                Ok(url)
+            } else {
+                Err(format!(
+                    "{} unreachable: {}",
+                    url,
+                    String::from_utf8_lossy(&output.stderr).trim()
+                ))
+            }
+        });
+    check("metadata", metadata);
+    Ok(ok)
+}
+
+/// Whether git can use `key` without a prompt, which it never gets: the key
+/// has no passphrase, or an ssh-agent holds it.
+fn key_unlocked(key: &Path) -> Result<String, String> {
+    let output = |program: &str, args: &[&str], path: Option<&Path>| {
+        let mut cmd = command(program);
+        cmd.args(args);
+        if let Some(path) = path {
+            cmd.arg(path);
+        }
+        cmd.output()
+            .map_err(|e| format!("cannot run {}: {}", program, e))
+    };
+    let no_passphrase = output("ssh-keygen", &["-y", "-P", "", "-f"], Some(key))?;
+    if no_passphrase.status.success() {
+        return Ok(format!("{} has none", key.display()));
+    }
+    let agent = output("ssh-add", &["-l"], None)?;
+    if agent.status.code() == Some(2) {
+        return Err(format!(
+            "{} needs one and no ssh-agent is running: start one and `ssh-add {}`",
+            key.display(),
+            key.display()
+        ));
+    }
+    // the fingerprint of an encrypted key is read from its .pub file
+    let fingerprint = output("ssh-keygen", &["-l", "-f"], Some(key))
+        .ok()
+        .filter(|o| o.status.success())
+        .and_then(|o| {
+            String::from_utf8_lossy(&o.stdout)
+                .split_whitespace()
+                .nth(1)
+                .map(str::to_string)
+        });
+    let held = String::from_utf8_lossy(&agent.stdout);
+    match fingerprint {
+        Some(fingerprint) if held.contains(&fingerprint) => {
+            Ok(format!("{} unlocked by the ssh-agent", key.display()))
+        }
+        None if agent.status.success() => Ok(format!(
+            "{} needs one, the ssh-agent holds {} keys, maybe this one",
+            key.display(),
+            held.lines().count()
+        )),
+        _ => Err(format!(
+            "{} needs one the ssh-agent does not hold: `ssh-add {}`",
+            key.display(),
+            key.display()
+        )),
+    }
+}
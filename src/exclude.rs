@@ -0,0 +1,354 @@
+use anyhow::{Context, Result};
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use ignore::Match;
+use std::collections::{BTreeMap, HashSet};
+use std::fs::FileType;
+use std::io;
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+use std::path::{Path, PathBuf};
+use walkdir::{DirEntry, WalkDir};
+
+/// Left out of every recursive operation unless `--no-default-excludes`.
+pub const DEFAULT_EXCLUDES: &[&str] = &[".git/", "*.tmp", ".DS_Store"];
+/// Gitignore-syntax patterns for the dir holding it and everything below.
+pub const IGNORE_FILE: &str = ".gidriveignore";
+
+/// One `--exclude` or `--include` flag, in command line order.
+#[derive(Clone, Debug)]
+pub enum ExcludeRule {
+    Exclude(String),
+    Include(String),
+}
+
+/// What recursive operations do with symlinks met in a tree.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum LinkPolicy {
+    /// Leave them out with a warning, like fifos, sockets and devices
+    #[default]
+    Skip,
+    /// Take the file or dir they point to, each dir once
+    Follow,
+    /// Store the link itself, recreated on download
+    Preserve,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct ExcludeOptions {
+    /// Later rules win over earlier ones, all of them over the ignore files
+    pub rules: Vec<ExcludeRule>,
+    pub no_default_excludes: bool,
+    pub links: LinkPolicy,
+}
+
+/// Name of a file type recursive operations never store, `None` for
+/// regular files and dirs.
+pub fn special_kind(file_type: FileType) -> Option<&'static str> {
+    if file_type.is_symlink() {
+        Some("symlink")
+    } else if file_type.is_fifo() {
+        Some("fifo")
+    } else if file_type.is_socket() {
+        Some("socket")
+    } else if file_type.is_block_device() || file_type.is_char_device() {
+        Some("device")
+    } else {
+        None
+    }
+}
+
+/// Decides which paths of a local tree recursive operations leave out: the
+/// command line rules first, then the `.gidriveignore` files from the
+/// deepest up, then `DEFAULT_EXCLUDES`. The ignore files are read once, when
+/// the tree is loaded.
+pub struct Excludes {
+    root: PathBuf,
+    cli: Gitignore,
+    /// Matchers of the ignore files by the dir holding them, deepest last
+    files: BTreeMap<PathBuf, Gitignore>,
+    defaults: Gitignore,
+    pub links: LinkPolicy,
+}
+
+impl Excludes {
+    pub fn load(root: &Path, opts: &ExcludeOptions) -> Result<Self> {
+        let mut cli = GitignoreBuilder::new(root);
+        for rule in &opts.rules {
+            let line = match rule {
+                ExcludeRule::Exclude(pattern) => pattern.clone(),
+                ExcludeRule::Include(pattern) => format!("!{}", pattern),
+            };
+            cli.add_line(None, &line)
+                .with_context(|| format!("invalid pattern {:?}", line))?;
+        }
+        let mut defaults = GitignoreBuilder::new(root);
+        if !opts.no_default_excludes {
+            for pattern in DEFAULT_EXCLUDES {
+                defaults.add_line(None, pattern)?;
+            }
+        }
+        let mut excludes = Excludes {
+            root: root.to_path_buf(),
+            cli: cli.build()?,
+            files: BTreeMap::new(),
+            defaults: defaults.build()?,
+            links: opts.links,
+        };
+        // a dir's ignore file applies to its siblings, read it before them
+        let mut dirs = vec![root.to_path_buf()];
+        while let Some(dir) = dirs.pop() {
+            let file = dir.join(IGNORE_FILE);
+            if file.is_file() {
+                let mut builder = GitignoreBuilder::new(&dir);
+                if let Some(e) = builder.add(&file) {
+                    return Err(e).with_context(|| format!("invalid {}", file.display()));
+                }
+                excludes.files.insert(dir.clone(), builder.build()?);
+            }
+            let Ok(entries) = std::fs::read_dir(&dir) else {
+                continue;
+            };
+            for entry in entries.flatten() {
+                let path = entry.path();
+                if entry.file_type().is_ok_and(|t| t.is_dir())
+                    && excludes.excluded(&path, true).is_none()
+                {
+                    dirs.push(path);
+                }
+            }
+        }
+        Ok(excludes)
+    }
+
+    /// Why `path`, inside the root, is left out, `None` if it is not.
+    pub fn excluded(&self, path: &Path, is_dir: bool) -> Option<String> {
+        let Ok(rel) = path.strip_prefix(&self.root) else {
+            return None;
+        };
+        if rel.as_os_str().is_empty() {
+            return None;
+        }
+        // patterns of the ignore files know their file, the others get a label
+        let mut matchers = vec![(&self.cli, "--exclude")];
+        for (dir, matcher) in self.files.iter().rev() {
+            if path.starts_with(dir) {
+                matchers.push((matcher, IGNORE_FILE));
+            }
+        }
+        matchers.push((&self.defaults, "default excludes"));
+        for (matcher, label) in matchers {
+            match matcher.matched_path_or_any_parents(path, is_dir) {
+                Match::None => continue,
+                Match::Whitelist(_) => return None,
+                Match::Ignore(glob) => {
+                    let origin = glob
+                        .from()
+                        .map_or(label.to_string(), |file| file.display().to_string());
+                    return Some(format!("{} from {}", glob.original(), origin));
+                }
+            }
+        }
+        None
+    }
+
+    /// Walks the tree in path order without the excluded entries and the
+    /// contents of excluded dirs, passing each of those to `on_excluded`
+    /// with the reason. Special files and the symlinks `links` does not keep
+    /// are skipped with a warning, so are symlink loops and dirs already
+    /// reached through another link when following them.
+    pub fn walk<'a>(
+        &'a self,
+        mut on_excluded: impl FnMut(&Path, &str) + 'a,
+    ) -> impl Iterator<Item = walkdir::Result<DirEntry>> + 'a {
+        let follow = self.links == LinkPolicy::Follow;
+        // (dev, inode) of the dirs entered, a followed link can lead back
+        let mut visited = HashSet::new();
+        WalkDir::new(&self.root)
+            .min_depth(1)
+            .follow_links(follow)
+            .sort_by_file_name()
+            .into_iter()
+            .filter_entry(move |entry| {
+                if let Some(reason) = self.excluded(entry.path(), entry.file_type().is_dir()) {
+                    on_excluded(entry.path(), &reason);
+                    return false;
+                }
+                if follow && entry.file_type().is_dir() {
+                    if let Ok(meta) = entry.metadata() {
+                        if !visited.insert((meta.dev(), meta.ino())) {
+                            warn_skipped(entry.path(), "dir already walked through another link");
+                            return false;
+                        }
+                    }
+                }
+                true
+            })
+            .filter(move |entry| match entry {
+                Ok(entry) => match special_kind(entry.file_type()) {
+                    Some("symlink") if self.links == LinkPolicy::Preserve => true,
+                    Some(kind) => {
+                        warn_skipped(entry.path(), kind);
+                        false
+                    }
+                    None => true,
+                },
+                Err(e) if follow => {
+                    let Some(path) = e.path() else {
+                        return true;
+                    };
+                    if e.loop_ancestor().is_some() {
+                        warn_skipped(path, "symlink loop");
+                    } else if e
+                        .io_error()
+                        .is_some_and(|e| e.kind() == io::ErrorKind::NotFound)
+                    {
+                        warn_skipped(path, "dangling symlink");
+                    } else {
+                        return true;
+                    }
+                    false
+                }
+                Err(_) => true,
+            })
+    }
+}
+
+fn warn_skipped(path: &Path, why: &str) {
+    eprintln!("--- skipping {}: {}", path.display(), why);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::os::unix::fs::symlink;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// A fresh dir holding `files`, with `links` as (link, target).
+    fn tree(files: &[(&str, &str)], links: &[(&str, &str)]) -> PathBuf {
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        let root = std::env::temp_dir().join(format!(
+            "gidrive-exclude-{}-{}",
+            std::process::id(),
+            NEXT.fetch_add(1, Ordering::Relaxed)
+        ));
+        let _ = fs::remove_dir_all(&root);
+        for (path, content) in files {
+            let path = root.join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, content).unwrap();
+        }
+        for (link, target) in links {
+            symlink(target, root.join(link)).unwrap();
+        }
+        root
+    }
+
+    /// The paths `opts` walks and those it leaves out with the reasons,
+    /// relative to `root`.
+    fn walk(root: &Path, opts: &ExcludeOptions) -> (Vec<String>, Vec<(String, String)>) {
+        let excludes = Excludes::load(root, opts).unwrap();
+        let rel = |path: &Path| path.strip_prefix(root).unwrap().display().to_string();
+        let mut excluded = Vec::new();
+        let walked = excludes
+            .walk(|path, reason| excluded.push((rel(path), reason.to_string())))
+            .map(|entry| rel(entry.unwrap().path()))
+            .collect();
+        (walked, excluded)
+    }
+
+    fn nested() -> PathBuf {
+        tree(
+            &[
+                (".gidriveignore", "*.log\n!keep.log\nbuild/\n"),
+                ("a.txt", ""),
+                ("b.log", ""),
+                ("keep.log", ""),
+                ("x.tmp", ""),
+                ("build/out.bin", ""),
+                ("sub/.gidriveignore", "!b.log\n*.txt\nsecret/\n"),
+                ("sub/b.log", ""),
+                ("sub/notes.txt", ""),
+                ("sub/secret/key", ""),
+                ("sub/deep/c.log", ""),
+                ("sub/deep/d.bin", ""),
+            ],
+            &[("loop", "."), ("sub/deep/up", "../..")],
+        )
+    }
+
+    #[test]
+    fn deeper_ignore_files_win_and_the_command_line_wins_over_all() {
+        let root = nested();
+        let (walked, excluded) = walk(&root, &ExcludeOptions::default());
+        assert_eq!(
+            walked,
+            [
+                ".gidriveignore",
+                "a.txt",
+                "keep.log",
+                "sub",
+                "sub/.gidriveignore",
+                "sub/b.log",
+                "sub/deep",
+                "sub/deep/d.bin",
+            ]
+        );
+        let ignore = |dir: &Path| dir.join(IGNORE_FILE).display().to_string();
+        let reasons = [
+            ("b.log", format!("*.log from {}", ignore(&root))),
+            ("build", format!("build/ from {}", ignore(&root))),
+            ("sub/deep/c.log", format!("*.log from {}", ignore(&root))),
+            (
+                "sub/notes.txt",
+                format!("*.txt from {}", ignore(&root.join("sub"))),
+            ),
+            (
+                "sub/secret",
+                format!("secret/ from {}", ignore(&root.join("sub"))),
+            ),
+            ("x.tmp", "*.tmp from default excludes".to_string()),
+        ];
+        let reasons: Vec<(String, String)> = reasons
+            .into_iter()
+            .map(|(path, reason)| (path.to_string(), reason))
+            .collect();
+        assert_eq!(excluded, reasons);
+
+        let opts = ExcludeOptions {
+            rules: vec![
+                ExcludeRule::Exclude("keep.log".to_string()),
+                ExcludeRule::Include("*.tmp".to_string()),
+            ],
+            ..ExcludeOptions::default()
+        };
+        let (walked, excluded) = walk(&root, &opts);
+        assert!(walked.contains(&"x.tmp".to_string()));
+        assert!(excluded.contains(&(
+            "keep.log".to_string(),
+            "keep.log from --exclude".to_string()
+        )));
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn a_symlink_cycle_is_walked_once_when_followed() {
+        let root = nested();
+        let follow = ExcludeOptions {
+            links: LinkPolicy::Follow,
+            ..ExcludeOptions::default()
+        };
+        let (followed, _) = walk(&root, &follow);
+        let (skipped, _) = walk(&root, &ExcludeOptions::default());
+        assert_eq!(followed, skipped);
+
+        let preserve = ExcludeOptions {
+            links: LinkPolicy::Preserve,
+            ..ExcludeOptions::default()
+        };
+        let (preserved, _) = walk(&root, &preserve);
+        assert!(preserved.contains(&"loop".to_string()));
+        assert!(preserved.contains(&"sub/deep/up".to_string()));
+        assert_eq!(preserved.len(), skipped.len() + 2);
+        fs::remove_dir_all(root).unwrap();
+    }
+}
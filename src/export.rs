@@ -0,0 +1,259 @@
+use serde::Serialize;
+use std::collections::BTreeSet;
+use std::fmt::Write;
+use std::io;
+use std::path::Path;
+
+use crate::constants::VERSION;
+use crate::git::{repo_slug, repo_url};
+use crate::models::{ChecksumAlgo, FileMetadata};
+
+/// How a restore script fetches the chunks.
+#[derive(Clone, Copy)]
+pub enum ScriptTransport {
+    /// `git clone` of every storage repo over SSH
+    Git,
+    /// `curl` of raw.githubusercontent.com URLs with a token
+    Curl,
+}
+
+/// Quotes `s` for a POSIX shell.
+fn sh_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', r"'\''"))
+}
+
+/// Builds a POSIX shell script that reassembles `remote` from `file_meta`
+/// with only git (or curl), coreutils and `sha256sum` (`b3sum` for blake3),
+/// checking every chunk and the whole file against the stored checksums.
+pub fn restore_script(
+    remote: &str,
+    file_meta: &FileMetadata,
+    transport: ScriptTransport,
+) -> String {
+    let name = Path::new(remote)
+        .file_name()
+        .map_or("restored".into(), |n| n.to_string_lossy().into_owned());
+    let hash_cmd = match file_meta.checksum_algo {
+        ChecksumAlgo::Sha256 => "sha256sum \"$1\" | cut -d' ' -f1",
+        ChecksumAlgo::Blake3 => "b3sum --no-names \"$1\"",
+    };
+    let mut s = String::new();
+    let _ = writeln!(s, "#!/bin/sh");
+    let _ = writeln!(
+        s,
+        "# Restores {} ({} bytes, {} {}), generated by gidrive {}.",
+        remote, file_meta.size, file_meta.checksum_algo, file_meta.checksum, VERSION
+    );
+    let _ = writeln!(s, "# usage: sh restore.sh [OUTPUT]");
+    let _ = writeln!(s, "set -eu");
+    let _ = writeln!(s, "out=${{1:-{}}}", sh_quote(&name));
+    let _ = writeln!(s, "work=\"$(mktemp -d)\"");
+    let _ = writeln!(s, "trap 'rm -rf \"$work\"' EXIT");
+    let _ = writeln!(s, "hash() {{ {}; }}", hash_cmd);
+    let _ = writeln!(s, "fail() {{ echo \"restore failed: $*\" >&2; exit 1; }}");
+    match transport {
+        ScriptTransport::Git => {
+            let _ = writeln!(s, "# fetch REPO PATH: copies a chunk to $work/chunk");
+            let _ = writeln!(s, "fetch() {{ cp \"$work/repos/$1/$2\" \"$work/chunk\"; }}");
+            let repos: BTreeSet<String> = file_meta.chunks.iter().map(|c| c.location()).collect();
+            for repo in repos {
+                let _ = writeln!(
+                    s,
+                    "git clone -q --depth 1 {} \"$work/repos/\"{}",
+                    sh_quote(&repo_url(&repo)),
+                    sh_quote(&repo_slug(&repo))
+                );
+            }
+        }
+        ScriptTransport::Curl => {
+            if file_meta.public {
+                let _ = writeln!(s, "# public repos, no token needed");
+                let _ = writeln!(s, "GITHUB_TOKEN=\"${{GITHUB_TOKEN:-}}\"");
+            } else {
+                let _ = writeln!(s, "# a token with read access to the storage repos");
+                let _ = writeln!(s, "GITHUB_TOKEN=\"${{GITHUB_TOKEN:-PASTE_TOKEN_HERE}}\"");
+            }
+            let _ = writeln!(s, "# fetch REPO PATH: downloads a chunk to $work/chunk");
+            let _ = writeln!(s, "fetch() {{");
+            let _ = writeln!(s, "  url=\"https://raw.githubusercontent.com/$1/HEAD/$2\"");
+            let _ = writeln!(s, "  if [ -n \"$GITHUB_TOKEN\" ]; then");
+            let _ = writeln!(
+                s,
+                "    curl -fsSL -H \"Authorization: token $GITHUB_TOKEN\" \"$url\" -o \"$work/chunk\""
+            );
+            let _ = writeln!(s, "  else");
+            let _ = writeln!(s, "    curl -fsSL \"$url\" -o \"$work/chunk\"");
+            let _ = writeln!(s, "  fi");
+            let _ = writeln!(s, "}}");
+        }
+    }
+    let _ = writeln!(
+        s,
+        "# chunk INDEX REPO PATH OFFSET SIZE CHECKSUM: verifies and appends one chunk"
+    );
+    let _ = writeln!(s, "chunk() {{");
+    let _ = writeln!(s, "  fetch \"$2\" \"$3\" || fail \"cannot fetch chunk $1\"");
+    let _ = writeln!(s, "  # the chunk may be a range of a bigger file");
+    let _ = writeln!(
+        s,
+        "  tail -c +$(($4 + 1)) \"$work/chunk\" | head -c \"$5\" > \"$work/range\""
+    );
+    let _ = writeln!(
+        s,
+        "  [ \"$(wc -c < \"$work/range\" | tr -d ' ')\" = \"$5\" ] || fail \"chunk $1 has the wrong size\""
+    );
+    let _ = writeln!(
+        s,
+        "  [ -z \"$6\" ] || [ \"$(hash \"$work/range\")\" = \"$6\" ] || fail \"chunk $1 has the wrong checksum\""
+    );
+    let _ = writeln!(s, "  cat \"$work/range\" >> \"$work/file\"");
+    let _ = writeln!(s, "}}");
+    let _ = writeln!(s, ": > \"$work/file\"");
+    for chunk in &file_meta.chunks {
+        let _ = writeln!(
+            s,
+            "chunk {} {} {} {} {} {}",
+            chunk.index,
+            sh_quote(&repo_slug(&chunk.location())),
+            sh_quote(&chunk.path),
+            chunk.offset,
+            chunk.size,
+            sh_quote(chunk.checksum.as_deref().unwrap_or(""))
+        );
+    }
+    let _ = writeln!(
+        s,
+        "[ \"$(hash \"$work/file\")\" = {} ] || fail \"file checksum mismatch\"",
+        sh_quote(&file_meta.checksum)
+    );
+    let _ = writeln!(s, "mv \"$work/file\" \"$out\"");
+    let _ = writeln!(s, "echo \"restored $out\" >&2");
+    s
+}
+
+/// Output of `export-map`.
+#[derive(Clone, Copy, PartialEq)]
+pub enum MapFormat {
+    /// A header line, then a line per chunk
+    Csv,
+    /// One JSON array of the chunks
+    Json,
+    /// A JSON object per line
+    Ndjson,
+}
+
+/// Where a chunk of a file is stored, a row of `export-map`.
+#[derive(Serialize)]
+struct MapRow<'a> {
+    path: &'a str,
+    chunk_index: usize,
+    owner: &'a str,
+    repo: &'a str,
+    chunk_path: &'a str,
+    /// Start of the chunk in the blob at `chunk_path`, non-zero for adopted files
+    offset: u64,
+    size: u64,
+    checksum: &'a str,
+    /// The repo is public, readable by anyone
+    public: bool,
+    /// gidrive stores chunks as they are, never encrypted
+    encrypted: bool,
+    /// Part of a zstd-compressed `upload --archive`
+    compressed: bool,
+}
+
+const MAP_COLUMNS: &str =
+    "path,chunk_index,owner,repo,chunk_path,offset,size,checksum,public,encrypted,compressed";
+
+/// Quotes a CSV field holding a comma, quote or line break.
+fn csv_field(s: &str) -> String {
+    if s.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_string()
+    }
+}
+
+/// Writes the storage map one file at a time, so that a drive of any size
+/// streams through.
+pub struct MapWriter<W: io::Write> {
+    out: W,
+    format: MapFormat,
+    rows: usize,
+}
+
+impl<W: io::Write> MapWriter<W> {
+    pub fn new(mut out: W, format: MapFormat) -> io::Result<Self> {
+        match format {
+            MapFormat::Csv => writeln!(out, "{}", MAP_COLUMNS)?,
+            MapFormat::Json => write!(out, "[")?,
+            MapFormat::Ndjson => {}
+        }
+        Ok(MapWriter {
+            out,
+            format,
+            rows: 0,
+        })
+    }
+
+    /// Writes a row per chunk of `remote`, whose chunks without an owner
+    /// are under `default_owner`.
+    pub fn file(
+        &mut self,
+        remote: &str,
+        meta: &FileMetadata,
+        default_owner: &str,
+    ) -> io::Result<()> {
+        for chunk in &meta.chunks {
+            let row = MapRow {
+                path: remote,
+                chunk_index: chunk.index,
+                owner: chunk.owner.as_deref().unwrap_or(default_owner),
+                repo: &chunk.repo,
+                chunk_path: &chunk.path,
+                offset: chunk.offset,
+                size: chunk.size,
+                checksum: chunk.checksum.as_deref().unwrap_or(""),
+                public: meta.public,
+                encrypted: false,
+                compressed: meta.archive.is_some(),
+            };
+            match self.format {
+                MapFormat::Csv => writeln!(
+                    self.out,
+                    "{},{},{},{},{},{},{},{},{},{},{}",
+                    csv_field(row.path),
+                    row.chunk_index,
+                    csv_field(row.owner),
+                    csv_field(row.repo),
+                    csv_field(row.chunk_path),
+                    row.offset,
+                    row.size,
+                    row.checksum,
+                    row.public,
+                    row.encrypted,
+                    row.compressed
+                )?,
+                MapFormat::Json => {
+                    let separator = if self.rows == 0 { "" } else { "," };
+                    write!(self.out, "{}\n  ", separator)?;
+                    serde_json::to_writer(&mut self.out, &row)?;
+                }
+                MapFormat::Ndjson => {
+                    serde_json::to_writer(&mut self.out, &row)?;
+                    writeln!(self.out)?;
+                }
+            }
+            self.rows += 1;
+        }
+        Ok(())
+    }
+
+    /// Closes the JSON array.
+    pub fn finish(mut self) -> io::Result<()> {
+        if self.format == MapFormat::Json {
+            writeln!(self.out, "\n]")?;
+        }
+        self.out.flush()
+    }
+}
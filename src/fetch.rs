@@ -0,0 +1,308 @@
+//! The body of an HTTP(S) URL read as one stream for `gidrive fetch`. A
+//! transfer the network cuts short is resumed where it stopped with a Range
+//! request; cookies, forms and scripts are out of scope.
+
+use anyhow::{bail, Result};
+use base64::Engine;
+use std::io::{self, ErrorKind, Read};
+use std::sync::OnceLock;
+use std::time::Duration;
+
+use crate::clock::clock;
+use crate::constants::FETCH_ATTEMPTS;
+use crate::models::ChecksumAlgo;
+use crate::utils::{human_size, Hasher};
+
+fn agent() -> &'static ureq::Agent {
+    static AGENT: OnceLock<ureq::Agent> = OnceLock::new();
+    AGENT.get_or_init(|| {
+        // no overall timeout, a big body takes as long as it takes
+        ureq::AgentBuilder::new()
+            .timeout_connect(Duration::from_secs(30))
+            .timeout_read(Duration::from_secs(60))
+            .user_agent(concat!("gidrive/", env!("CARGO_PKG_VERSION")))
+            .redirect_auth_headers(ureq::RedirectAuthHeaders::SameHost)
+            .build()
+    })
+}
+
+/// `url` split into the URL without its `user:password@` and the
+/// Authorization header they make, if any.
+fn split_credentials(url: &str) -> (String, Option<String>) {
+    let Some((scheme, rest)) = url.split_once("://") else {
+        return (url.to_string(), None);
+    };
+    let authority_end = rest.find(['/', '?', '#']).unwrap_or(rest.len());
+    let Some(at) = rest[..authority_end].rfind('@') else {
+        return (url.to_string(), None);
+    };
+    let (user, password) = match rest[..at].split_once(':') {
+        Some((user, password)) => (user, password),
+        None => (&rest[..at], ""),
+    };
+    let credentials = format!("{}:{}", percent_decode(user), percent_decode(password));
+    let header = format!(
+        "Basic {}",
+        base64::engine::general_purpose::STANDARD.encode(credentials)
+    );
+    (format!("{}://{}", scheme, &rest[at + 1..]), Some(header))
+}
+
+fn percent_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes
+            .get(i + 1..i + 3)
+            .and_then(|h| std::str::from_utf8(h).ok());
+        match hex.and_then(|h| u8::from_str_radix(h, 16).ok()) {
+            Some(byte) if bytes[i] == b'%' => {
+                out.push(byte);
+                i += 3;
+            }
+            _ => {
+                out.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+/// `url` without the credentials in it, as printed and recorded.
+pub fn redact(url: &str) -> String {
+    split_credentials(url).0
+}
+
+/// A request that failed, `retry` when another attempt may succeed.
+struct Failed {
+    message: String,
+    retry: bool,
+}
+
+/// GETs `url`, from byte `from` on if not 0, as long as it still matches
+/// `validator`.
+fn get(
+    url: &str,
+    auth: Option<&str>,
+    from: u64,
+    validator: Option<&str>,
+) -> Result<ureq::Response, Failed> {
+    // a compressed body would not match its Content-Length or ranges
+    let mut request = agent().get(url).set("Accept-Encoding", "identity");
+    if let Some(auth) = auth {
+        request = request.set("Authorization", auth);
+    }
+    if from > 0 {
+        request = request.set("Range", &format!("bytes={}-", from));
+        if let Some(validator) = validator {
+            request = request.set("If-Range", validator);
+        }
+    }
+    match request.call() {
+        Ok(response) => Ok(response),
+        Err(ureq::Error::Status(status, response)) => Err(Failed {
+            message: format!("HTTP {} {}", status, response.status_text()),
+            retry: status == 408 || status == 429 || status >= 500,
+        }),
+        Err(ureq::Error::Transport(transport)) => Err(Failed {
+            message: match transport.message() {
+                Some(message) => format!("{}: {}", transport.kind(), message),
+                None => transport.kind().to_string(),
+            },
+            retry: true,
+        }),
+    }
+}
+
+/// The body of a URL, fetched again from where it stopped when the
+/// connection fails, and checked against the expected sha256 as the last
+/// byte is read.
+pub struct HttpSource {
+    url: String,
+    auth: Option<String>,
+    body: Box<dyn Read + Send + Sync>,
+    /// Content-Length of the body, None when the server does not say
+    pub size: Option<u64>,
+    /// Bytes read so far
+    pos: u64,
+    /// Strong ETag or Last-Modified, so that a resumed transfer never
+    /// mixes two versions of the file
+    validator: Option<String>,
+    resumes: u32,
+    expected_sha256: Option<String>,
+    sha256: Option<Hasher>,
+}
+
+impl HttpSource {
+    /// Starts the transfer of `url`, retrying transient failures.
+    pub fn open(url: &str, expected_sha256: Option<&str>) -> Result<HttpSource> {
+        if !url.starts_with("http://") && !url.starts_with("https://") {
+            bail!("{} is not an http:// or https:// URL", redact(url));
+        }
+        let expected_sha256 = match expected_sha256 {
+            Some(hex) if hex.len() == 64 && hex.bytes().all(|b| b.is_ascii_hexdigit()) => {
+                Some(hex.to_ascii_lowercase())
+            }
+            Some(hex) => bail!("{} is not a sha256 checksum", hex),
+            None => None,
+        };
+        let (url, auth) = split_credentials(url);
+        let mut attempts = 1;
+        let response = loop {
+            match get(&url, auth.as_deref(), 0, None) {
+                Ok(response) => break response,
+                Err(failed) if failed.retry && attempts < FETCH_ATTEMPTS => {
+                    eprintln!("--- GET {} failed ({}), retrying", url, failed.message);
+                    clock().sleep(Duration::from_secs(2u64.pow(attempts)));
+                    attempts += 1;
+                }
+                Err(failed) => bail!("GET {} failed: {}", url, failed.message),
+            }
+        };
+        let size = response
+            .header("Content-Length")
+            .and_then(|len| len.parse().ok());
+        let validator = response
+            .header("ETag")
+            .filter(|tag| !tag.starts_with("W/"))
+            .or(response.header("Last-Modified"))
+            .map(str::to_string);
+        Ok(HttpSource {
+            url,
+            auth,
+            body: response.into_reader(),
+            size,
+            pos: 0,
+            validator,
+            resumes: 0,
+            sha256: expected_sha256
+                .as_ref()
+                .map(|_| Hasher::new(ChecksumAlgo::Sha256)),
+            expected_sha256,
+        })
+    }
+
+    /// The URL without its credentials.
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    /// Fetches the rest of the body from `pos` on after `err` cut the
+    /// transfer, as often as `FETCH_ATTEMPTS` allows in all.
+    fn resume(&mut self, err: io::Error) -> io::Result<()> {
+        let mut message = err.to_string();
+        loop {
+            if self.resumes + 1 >= FETCH_ATTEMPTS {
+                return Err(io::Error::new(
+                    err.kind(),
+                    format!(
+                        "the transfer of {} failed at {}: {}",
+                        self.url,
+                        human_size(self.pos),
+                        message
+                    ),
+                ));
+            }
+            self.resumes += 1;
+            eprintln!(
+                "--- the transfer of {} stopped at {} ({}), resuming",
+                self.url,
+                human_size(self.pos),
+                message
+            );
+            clock().sleep(Duration::from_secs(2u64.pow(self.resumes)));
+            let refused = |message: String| io::Error::new(ErrorKind::Unsupported, message);
+            match get(
+                &self.url,
+                self.auth.as_deref(),
+                self.pos,
+                self.validator.as_deref(),
+            ) {
+                Ok(response) if response.status() == 206 => {
+                    let range = format!("bytes {}-", self.pos);
+                    if !response
+                        .header("Content-Range")
+                        .is_some_and(|r| r.starts_with(&range))
+                    {
+                        return Err(refused(format!(
+                            "{} answered a resume at byte {} with another range",
+                            self.url, self.pos
+                        )));
+                    }
+                    self.body = response.into_reader();
+                    return Ok(());
+                }
+                Ok(_) => {
+                    return Err(refused(format!(
+                        "{} changed or cannot resume at byte {}, fetch it again",
+                        self.url, self.pos
+                    )))
+                }
+                Err(failed) if failed.retry => message = failed.message,
+                Err(failed) => {
+                    return Err(refused(format!(
+                        "resuming {} failed: {}",
+                        self.url, failed.message
+                    )))
+                }
+            }
+        }
+    }
+
+    /// Compares the body read in full against the expected sha256.
+    fn check_sha256(&mut self) -> io::Result<()> {
+        let (Some(hasher), Some(expected)) = (self.sha256.take(), &self.expected_sha256) else {
+            return Ok(());
+        };
+        let actual = hasher.finalize_hex();
+        if &actual != expected {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "{} has sha256 {}, not the expected {}",
+                    self.url, actual, expected
+                ),
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl Read for HttpSource {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let err = match self.body.read(buf) {
+                Ok(0) if self.size.is_some_and(|size| self.pos < size) => {
+                    io::Error::new(ErrorKind::UnexpectedEof, "the connection closed early")
+                }
+                Ok(0) => {
+                    self.check_sha256()?;
+                    return Ok(0);
+                }
+                Ok(n) => {
+                    self.pos += n as u64;
+                    if let Some(hasher) = &mut self.sha256 {
+                        hasher.update(&buf[..n]);
+                    }
+                    match self.size {
+                        Some(size) if self.pos > size => {
+                            return Err(io::Error::new(
+                                ErrorKind::InvalidData,
+                                format!("{} sent more than its {} bytes", self.url, size),
+                            ))
+                        }
+                        // the upload stops reading at the size, fail before it commits
+                        Some(size) if self.pos == size => self.check_sha256()?,
+                        _ => {}
+                    }
+                    return Ok(n);
+                }
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => e,
+            };
+            self.resume(err)?;
+        }
+    }
+}
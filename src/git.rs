@@ -1,82 +1,1325 @@
-use anyhow::{Context, Result};
+use anyhow::{bail, Context, Result};
 use serde_json::Value;
-use std::path::Path;
+#[cfg(not(feature = "libgit2"))]
+use std::collections::HashMap;
+use std::collections::{BTreeMap, BTreeSet};
+#[cfg(not(feature = "libgit2"))]
+use std::ffi::OsStr;
+use std::fmt;
+#[cfg(not(feature = "libgit2"))]
+use std::io::{self, Read, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+#[cfg(not(feature = "libgit2"))]
+use std::process::Output;
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, OnceLock};
+#[cfg(not(feature = "libgit2"))]
 use std::thread;
-use std::time::Duration;
 
-use crate::constants::GITHUB_USERNAME;
-use crate::utils::run;
+use crate::config::{expand_home, settings, valid_username, Config, GithubApi, Transport};
+use crate::constants::COMMIT_MESSAGE_CHARS;
+use crate::github;
+use crate::models::ReposMetadata;
+use crate::progress;
+#[cfg(not(feature = "libgit2"))]
+use crate::progress::Event;
+use crate::retry::{classify, is_auth_failure, with_retry, CommandFailed, ErrorClass, Operation};
+#[cfg(not(feature = "libgit2"))]
+use crate::utils::{check_output, run_args, verbose, very_verbose};
+use crate::utils::{
+    child_env, command, explain_no_space, one_line, run_output, set_child_env, temp_dirs,
+};
 
-pub fn create_repo(repo_name: &str) -> Result<()> {
-    let cmd = format!(
-        "gh repo create {}/{} --private --confirm",
-        GITHUB_USERNAME, repo_name
-    );
-    run(&cmd)?;
+/// The metadata repo and storage repo owner of this process when they are
+/// not those of the configured account, from `--metadata-repo` and `--owner`.
+struct RemoteOverride {
+    metadata_url: Option<String>,
+    owner: Option<String>,
+    allow_write: bool,
+}
+
+static REMOTE_OVERRIDE: OnceLock<RemoteOverride> = OnceLock::new();
+
+/// Reads the metadata from `metadata_url` and the storage repos from the
+/// account of `owner`, by default the one in `metadata_url`, for the whole
+/// process. Every remote write is refused unless `allow_write`.
+pub fn set_remote_override(
+    metadata_url: Option<String>,
+    owner: Option<String>,
+    allow_write: bool,
+) -> Result<()> {
+    let owner = owner.or_else(|| metadata_url.as_deref().and_then(url_owner));
+    if let Some(owner) = &owner {
+        if !valid_username(owner) {
+            bail!("{:?} is not a GitHub username", owner);
+        }
+    }
+    REMOTE_OVERRIDE
+        .set(RemoteOverride {
+            metadata_url,
+            owner,
+            allow_write,
+        })
+        .map_err(|_| anyhow::anyhow!("the remote override is already set"))
+}
+
+/// The owner in a GitHub clone URL, `git@github.com:alice/metadata.git`
+/// or `https://github.com/alice/metadata.git`.
+fn url_owner(url: &str) -> Option<String> {
+    let path = url
+        .strip_prefix("git@github.com:")
+        .or_else(|| url.strip_prefix("ssh://git@github.com/"))
+        .or_else(|| url.strip_prefix("https://github.com/"))?;
+    let (owner, _) = path.split_once('/')?;
+    Some(owner.to_string())
+}
+
+/// `owner/name` of a GitHub clone URL, for progress events.
+pub fn url_slug(url: &str) -> String {
+    let path = url.trim_end_matches(".git");
+    match path.rsplit(['/', ':']).take(2).collect::<Vec<_>>()[..] {
+        [name, owner] => format!("{}/{}", owner, name),
+        _ => path.to_string(),
+    }
+}
+
+/// Whether `--metadata-repo` or `--owner` points this process at another
+/// metadata repo than the configured account's.
+pub fn remote_overridden() -> bool {
+    REMOTE_OVERRIDE.get().is_some()
+}
+
+/// Account the storage repos and the metadata repo are under.
+pub fn owner() -> &'static str {
+    REMOTE_OVERRIDE
+        .get()
+        .and_then(|o| o.owner.as_deref())
+        .unwrap_or_else(|| settings().github_username())
+}
+
+pub fn metadata_repo_url() -> String {
+    match REMOTE_OVERRIDE.get().and_then(|o| o.metadata_url.clone()) {
+        Some(url) => url,
+        None => settings().repo_url(&format!("{}/metadata", owner())),
+    }
+}
+
+/// Accounts of the storage repos repos.json records one for, by name.
+static REPO_OWNERS: Mutex<BTreeMap<String, String>> = Mutex::new(BTreeMap::new());
+
+/// Remembers the account of every repo of `repos` that names one, so
+/// `repo_slug` finds repos under the other storage accounts.
+pub fn record_repo_owners(repos: &ReposMetadata) {
+    let mut owners = REPO_OWNERS.lock().unwrap();
+    for repo in repos.repos.values() {
+        if let Some(repo_owner) = &repo.owner {
+            owners.insert(repo.name.clone(), repo_owner.clone());
+        }
+    }
+}
+
+/// `owner/name` of a repo in metadata: storage repos are stored by bare name
+/// under the account repos.json records, the owner's by default, adopted
+/// foreign repos with their owner.
+pub fn repo_slug(repo_name: &str) -> String {
+    if repo_name.contains('/') {
+        return repo_name.to_string();
+    }
+    let owners = REPO_OWNERS.lock().unwrap();
+    let repo_owner = owners.get(repo_name).map_or(owner(), String::as_str);
+    format!("{}/{}", repo_owner, repo_name)
+}
+
+/// Clone URL of `repo_name`. Over HTTPS, repos of an account with a token
+/// of its own name the account as user, for the askpass helper to pick it.
+pub fn repo_url(repo_name: &str) -> String {
+    let slug = repo_slug(repo_name);
+    let account = slug_owner(&slug);
+    match settings().transport() {
+        Transport::Https if settings().account_token(account).is_some() => {
+            format!("https://{}@github.com/{}.git", account, slug)
+        }
+        _ => settings().repo_url(&slug),
+    }
+}
+
+fn slug_owner(slug: &str) -> &str {
+    slug.split('/').next().unwrap_or_default()
+}
+
+/// Whether `repo_name` is under the account of this process rather than
+/// another storage account.
+fn own_repo(repo_name: &str) -> bool {
+    slug_owner(&repo_slug(repo_name)) == owner()
+}
+
+/// The variable `prefix_ACCOUNT` holding a credential of `account`.
+pub fn account_var(prefix: &str, account: &str) -> String {
+    format!(
+        "{}_{}",
+        prefix,
+        account.to_ascii_uppercase().replace('-', "_")
+    )
+}
+
+/// The gh command of `args` run with the token of `account`, when it has
+/// one of its own, passed in its environment so it never shows in messages.
+fn gh_command(account: &str, args: &[&str]) -> Command {
+    let mut gh = command("gh");
+    gh.args(args);
+    if settings().account_token(account).is_some() {
+        if let Some(token) = child_env(&account_var("GIDRIVE_ASKPASS_TOKEN", account)) {
+            gh.env("GH_TOKEN", token);
+        }
+    }
+    gh
+}
+
+/// The name of `repo` under `repo_owner` that `repo_slug` and `repo_url`
+/// take: bare under the account of this process, `owner/name` under another
+/// one. Without an owner, as in older metadata, it is the drive's account.
+pub fn qualified_repo(repo: &str, repo_owner: Option<&str>) -> String {
+    match repo_owner {
+        Some(repo_owner) if repo_owner != owner() && !repo.contains('/') => {
+            format!("{}/{}", repo_owner, repo)
+        }
+        _ => repo.to_string(),
+    }
+}
+
+/// Fails unless `repo_name` answers `git ls-remote` with the credentials
+/// of this process.
+pub fn check_reachable(repo_name: &str) -> Result<()> {
+    let url = repo_url(repo_name);
+    let output = command("git").args(["ls-remote", &url, "HEAD"]).output()?;
+    if !output.status.success() {
+        return Err(explain_auth(anyhow::anyhow!(
+            "Failed to reach {}: {}",
+            url,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    Ok(())
+}
+
+/// Runs gh with `args` as `account`, retried with the `api` policy.
+fn gh(account: &str, args: &[&str]) -> Result<String> {
+    with_retry(&settings().retry_policy(Operation::Api), || {
+        gh_once(account, args)
+    })
+}
+
+/// Runs gh with `args` as `account` like `run`, turning GitHub's permission
+/// failures into errors that name the missing scope and how to grant it.
+fn gh_once(account: &str, args: &[&str]) -> Result<String> {
+    let output = gh_command(account, args).output()?;
+    let cmd = format!("gh {}", args.join(" "));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    if !progress::json_enabled() {
+        eprint!("{}", stderr);
+    }
+    if output.status.success() {
+        return Ok(String::from_utf8_lossy(&output.stdout).into());
+    }
+    if let Some(scope) = missing_scope(&cmd, &stderr) {
+        bail!(
+            "GitHub refused `{}`: the token lacks the `{}` scope, grant it with \
+             `gh auth refresh -h github.com -s {}`",
+            cmd,
+            scope,
+            scope
+        );
+    }
+    if stderr.contains("HTTP 403") {
+        bail!(
+            "GitHub refused `{}` (403): the token is not allowed to do this, \
+             see its scopes with `gidrive doctor`",
+            cmd
+        );
+    }
+    if stderr.contains("HTTP 404") || stderr.contains("Could not resolve to a Repository") {
+        bail!(
+            "`{}`: repo not found (404), it does not exist or it is private and \
+             the token lacks the `repo` scope",
+            cmd
+        );
+    }
+    Err(CommandFailed {
+        message: format!("Command failed: {}", cmd),
+        stderr: stderr.into_owned(),
+    }
+    .into())
+}
+
+/// The scope a failed gh command asks for: gh suggests
+/// `gh auth refresh ... -s <scope>`, the API names it in its message.
+fn missing_scope(cmd: &str, stderr: &str) -> Option<String> {
+    if let Some(hint) = stderr.split("gh auth refresh").nth(1) {
+        let mut words = hint.split_whitespace();
+        while let Some(word) = words.next() {
+            if word == "-s" || word == "--scopes" {
+                return words.next().map(|s| s.trim_matches('"').to_string());
+            }
+        }
+    }
+    if let Some(rest) = stderr.split("following scopes:").nth(1) {
+        return rest
+            .split(|c: char| c.is_whitespace() || c == ',')
+            .find(|w| !w.is_empty())
+            .map(|s| s.trim_matches(['"', '\'', '.']).to_string());
+    }
+    if stderr.contains("HTTP 403") && cmd.starts_with("gh repo delete") {
+        return Some("delete_repo".into());
+    }
+    None
+}
+
+static SCOPES: OnceLock<Option<Vec<String>>> = OnceLock::new();
+
+/// OAuth scopes of the token repos are managed with, `None` when GitHub
+/// reports none, as for fine-grained and app tokens whose permissions cannot
+/// be listed.
+pub fn token_scopes() -> Result<Option<Vec<String>>> {
+    if let Some(scopes) = SCOPES.get() {
+        return Ok(scopes.clone());
+    }
+    if settings().github_api() == GithubApi::Rest {
+        let scopes = github::token_scopes(owner())?;
+        return Ok(SCOPES.get_or_init(|| scopes).clone());
+    }
+    let output = run_output("gh api -i user")?;
+    if !output.status.success() {
+        bail!(
+            "gh api user failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    let scopes = String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .take_while(|line| !line.trim().is_empty())
+        .find_map(|line| {
+            let (name, value) = line.split_once(':')?;
+            name.eq_ignore_ascii_case("x-oauth-scopes").then(|| {
+                value
+                    .split(',')
+                    .map(|s| s.trim().to_string())
+                    .filter(|s| !s.is_empty())
+                    .collect()
+            })
+        });
+    Ok(SCOPES.get_or_init(|| scopes).clone())
+}
+
+/// Fails before `needed_for` starts when the token reports its scopes and
+/// `scope` is not among them (`repo` includes `public_repo`). Tokens that
+/// report no scopes, or a failing check, are given the benefit of the doubt.
+pub fn require_scope(scope: &str, needed_for: &str) -> Result<()> {
+    let Ok(Some(scopes)) = token_scopes() else {
+        return Ok(());
+    };
+    let granted = scopes
+        .iter()
+        .any(|s| s == scope || (scope == "public_repo" && s == "repo"));
+    if !granted {
+        bail!(
+            "the GitHub token lacks the `{}` scope needed to {}, grant it with \
+             `gh auth refresh -h github.com -s {}` (scopes: {})",
+            scope,
+            needed_for,
+            scope,
+            scopes.join(", ")
+        );
+    }
+    Ok(())
+}
+
+/// Repos of the account as of the last `list_repos`, kept up to date
+/// with the creations and deletions of this process.
+static REPO_CACHE: Mutex<Option<BTreeSet<String>>> = Mutex::new(None);
+
+/// Why remote writes are refused.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Refusal {
+    /// `--read-only` or `read_only`
+    ReadOnly,
+    /// `--metadata-repo` or `--owner` points at other repos
+    Overridden,
+    /// The metadata comes from the cache, with `--offline` or because the
+    /// metadata repo was unreachable
+    Offline,
+}
+
+/// A remote write was refused for `reason`.
+#[derive(Debug)]
+pub struct ReadOnlyMode {
+    /// What was about to be done, like "push to metadata"
+    pub operation: String,
+    pub reason: Refusal,
+}
+
+impl fmt::Display for ReadOnlyMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.reason {
+            Refusal::Overridden => write!(
+                f,
+                "refusing to {} in the repos of {}, pass --allow-write to write",
+                self.operation,
+                owner()
+            ),
+            Refusal::ReadOnly => write!(
+                f,
+                "read-only mode: refusing to {}, run without --read-only to write",
+                self.operation
+            ),
+            Refusal::Offline => write!(
+                f,
+                "offline: refusing to {} with the cached metadata, retry once GitHub is reachable",
+                self.operation
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ReadOnlyMode {}
+
+static READ_ONLY: AtomicBool = AtomicBool::new(false);
+
+/// Makes every repo creation, deletion and push of this process fail with
+/// `ReadOnlyMode`.
+pub fn set_read_only() {
+    READ_ONLY.store(true, Ordering::Relaxed);
+}
+
+pub fn read_only() -> bool {
+    READ_ONLY.load(Ordering::Relaxed)
+}
+
+static OFFLINE: AtomicBool = AtomicBool::new(false);
+
+/// Makes this process read the metadata and storage repos from the cache
+/// only, refusing every remote write with `ReadOnlyMode`.
+pub fn set_offline() {
+    OFFLINE.store(true, Ordering::Relaxed);
+}
+
+pub fn offline() -> bool {
+    OFFLINE.load(Ordering::Relaxed)
+}
+
+/// Fails with `ReadOnlyMode` in read-only mode, offline and for overridden
+/// repos without `--allow-write`. Every remote write of this module checks
+/// it, callers only to fail before any local work.
+pub fn check_writable(operation: impl FnOnce() -> String) -> Result<()> {
+    let reason = if read_only() {
+        Refusal::ReadOnly
+    } else if offline() {
+        Refusal::Offline
+    } else if REMOTE_OVERRIDE.get().is_some_and(|o| !o.allow_write) {
+        Refusal::Overridden
+    } else {
+        return Ok(());
+    };
+    Err(ReadOnlyMode {
+        operation: operation(),
+        reason,
+    }
+    .into())
+}
+
+pub fn create_repo(repo_name: &str, public: bool) -> Result<()> {
+    check_writable(|| format!("create repo {}", repo_name))?;
+    let visibility = if public { "--public" } else { "--private" };
+    let slug = repo_slug(repo_name);
+    let args = ["repo", "create", &slug, visibility, "--confirm"];
+    with_retry(
+        &settings().retry_policy(Operation::CreateRepo),
+        || match settings().github_api() {
+            GithubApi::Rest => github::create_repo(&slug, public),
+            GithubApi::Gh => gh_once(slug_owner(&slug), &args).map(|_| ()),
+        },
+    )?;
+    if let Some(cache) = REPO_CACHE
+        .lock()
+        .unwrap()
+        .as_mut()
+        .filter(|_| own_repo(repo_name))
+    {
+        cache.insert(repo_name.to_string());
+    }
     Ok(())
 }
 
 pub fn delete_repo(repo_name: &str) -> Result<()> {
-    let cmd = format!("gh repo delete {}/{} --yes", GITHUB_USERNAME, repo_name);
-    run(&cmd)?;
+    check_writable(|| format!("delete repo {}", repo_name))?;
+    let slug = repo_slug(repo_name);
+    match settings().github_api() {
+        GithubApi::Rest => with_retry(&settings().retry_policy(Operation::Api), || {
+            github::delete_repo(&slug)
+        })?,
+        GithubApi::Gh => {
+            gh(slug_owner(&slug), &["repo", "delete", &slug, "--yes"])?;
+        }
+    }
+    if let Some(cache) = REPO_CACHE
+        .lock()
+        .unwrap()
+        .as_mut()
+        .filter(|_| own_repo(repo_name))
+    {
+        cache.remove(repo_name);
+    }
     Ok(())
 }
 
+/// Lists all repos of the account, every page of them, and refreshes the
+/// repo cache.
 pub fn list_repos() -> Result<Vec<String>> {
-    let output = run(&format!(
-        "gh repo list {} --json name --limit 1000000",
-        GITHUB_USERNAME
-    ))?;
+    let names = list_account_repos(owner())?;
+    *REPO_CACHE.lock().unwrap() = Some(names.iter().cloned().collect());
+    Ok(names)
+}
 
-    let names: Vec<String> = serde_json::from_str::<Value>(&output)?
+/// Lists all repos of `account`, with its own token if it has one.
+pub fn list_account_repos(account: &str) -> Result<Vec<String>> {
+    if settings().github_api() == GithubApi::Rest {
+        return with_retry(&settings().retry_policy(Operation::Api), || {
+            github::list_repos(account)
+        });
+    }
+    let output = gh(
+        account,
+        &[
+            "repo", "list", account, "--json", "name", "--limit", "1000000",
+        ],
+    )?;
+    Ok(serde_json::from_str::<Value>(&output)?
         .as_array()
-        .unwrap()
+        .context("unexpected gh repo list output")?
         .iter()
-        .map(|repo| repo["name"].as_str().unwrap().to_string())
-        .collect();
+        .filter_map(|repo| repo["name"].as_str().map(str::to_string))
+        .collect())
+}
 
-    println!("{names:?}");
-    Ok(names)
+/// Repos starting with `prefix`, from the cache when this process already
+/// listed the repos.
+pub fn list_repos_with_prefix(prefix: &str) -> Result<Vec<String>> {
+    if REPO_CACHE.lock().unwrap().is_none() {
+        list_repos()?;
+    }
+    let cache = REPO_CACHE.lock().unwrap();
+    Ok(cache
+        .iter()
+        .flatten()
+        .filter(|name| name.starts_with(prefix))
+        .cloned()
+        .collect())
 }
 
+/// Whether `repo_name` exists, answered from the repo cache. Falls back to
+/// asking GitHub about the one repo if listing fails.
 pub fn repo_exists(repo_name: &str) -> bool {
-    let cmd = format!(
-        "gh repo view {}/{} >/dev/null 2>&1",
-        GITHUB_USERNAME, repo_name
-    );
-    run(&cmd).is_ok()
+    if own_repo(repo_name) {
+        if let Ok(names) = list_repos_with_prefix(repo_name) {
+            return names.iter().any(|name| name == repo_name);
+        }
+    }
+    let slug = repo_slug(repo_name);
+    match settings().github_api() {
+        GithubApi::Rest => github::repo_exists(&slug).unwrap_or(false),
+        GithubApi::Gh => gh_command(slug_owner(&slug), &["repo", "view", &slug])
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status()
+            .is_ok_and(|status| status.success()),
+    }
 }
 
-pub fn ssh_agent(key_path: &str) {
-    std::env::set_var(
-        "GIT_SSH_COMMAND",
-        format!("ssh -i {} -o IdentitiesOnly=yes", key_path),
-    );
+/// Points git at the credentials of the configured transport, and those of
+/// every storage account at their repos.
+pub fn setup_auth(config: &Config) -> Result<()> {
+    match config.transport() {
+        Transport::Ssh if config.accounts.is_empty() => {
+            ssh_agent(config.ssh_key(), config.ssh_multiplex())
+        }
+        Transport::Ssh => ssh_accounts(config)?,
+        Transport::Https => {
+            let token = match &config.github.token {
+                Some(token) => token.clone(),
+                None => github::token(config.github_username())?,
+            };
+            https_askpass(&token)?;
+        }
+    }
+    for (account, account_config) in &config.accounts {
+        if let Some(token) = &account_config.token {
+            set_child_env(&account_var("GIDRIVE_ASKPASS_TOKEN", account), token);
+        }
+    }
+    Ok(())
+}
+
+/// Like `ssh_agent` for several accounts: a wrapper picks the key of the
+/// account in the repo path git asks ssh for. A missing key only warns, the
+/// repos of the other accounts stay reachable.
+fn ssh_accounts(config: &Config) -> Result<()> {
+    set_child_env("GIDRIVE_ACCOUNT_SSH_KEY", expand_home(config.ssh_key()));
+    for account in config.storage_accounts().into_iter().skip(1) {
+        let key = expand_home(config.account_ssh_key(account));
+        let own_key = config.accounts[account].ssh_key.is_some();
+        if own_key && !key.is_file() {
+            eprintln!(
+                "--- ssh key {} of account {} does not exist, its repos are unreachable",
+                key.display(),
+                account
+            );
+        }
+        set_child_env(&account_var("GIDRIVE_ACCOUNT_SSH_KEY", account), key);
+    }
+    // connections are shared per account, another key is another login
+    let multiplex = if config.ssh_multiplex() {
+        format!(
+            " -o ControlMaster=auto -o ControlPath={}/ssh-$account-%C -o ControlPersist=60",
+            temp_dirs().work.display()
+        )
+    } else {
+        String::new()
+    };
+    let wrapper = temp_dirs().work.join("ssh.sh");
+    std::fs::write(
+        &wrapper,
+        format!(
+            "#!/bin/sh\n\
+             # the last argument is the command, like git-upload-pack 'owner/repo.git'\n\
+             for arg; do last=$arg; done\n\
+             account=$(printf '%s' \"$last\" | sed -n \"s/^[^ ]* '\\{{0,1\\}}\\/\\{{0,1\\}}\\([A-Za-z0-9-]*\\)\\/.*/\\1/p\" | tr a-z- A-Z_)\n\
+             eval \"key=\\${{GIDRIVE_ACCOUNT_SSH_KEY_$account:-\\$GIDRIVE_ACCOUNT_SSH_KEY}}\"\n\
+             exec ssh -i \"$key\" -o IdentitiesOnly=yes -o BatchMode=yes{} \"$@\"\n",
+            multiplex
+        ),
+    )
+    .context("Failed to write ssh wrapper")?;
+    std::fs::set_permissions(&wrapper, std::fs::Permissions::from_mode(0o700))?;
+    set_child_env("GIT_SSH_COMMAND", wrapper);
+    Ok(())
+}
+
+/// Makes git use `key_path` over SSH, never asking for a passphrase: a key
+/// needing one works through an agent only. With `multiplex`, concurrent git
+/// processes share one connection per host through a control socket in
+/// the temp dir, kept open a minute after the last one exits, instead of each
+/// opening its own and getting some dropped by GitHub.
+pub fn ssh_agent(key_path: &str, multiplex: bool) {
+    let mut cmd = format!("ssh -i {} -o IdentitiesOnly=yes -o BatchMode=yes", key_path);
+    if multiplex {
+        cmd.push_str(&format!(
+            " -o ControlMaster=auto -o ControlPath={}/ssh-%C -o ControlPersist=60",
+            temp_dirs().work.display()
+        ));
+    }
+    set_child_env("GIT_SSH_COMMAND", cmd);
+}
+
+/// Makes git authenticate over HTTPS with `token`: an askpass helper hands
+/// it to git from the environment, so it never shows up in URLs, command
+/// lines or error messages. gh uses it too unless GH_TOKEN is already set.
+pub fn https_askpass(token: &str) -> Result<()> {
+    let helper = temp_dirs().work.join("askpass.sh");
+    std::fs::write(
+        &helper,
+        "#!/bin/sh\n\
+         # the token of the account a URL names as user, the default one otherwise\n\
+         case \"$1\" in\n  \
+           Username*) echo x-access-token ;;\n  \
+           *) account=$(printf '%s' \"$1\" | sed -n 's/.*\\/\\/\\([A-Za-z0-9-]*\\)@.*/\\1/p' | tr a-z- A-Z_)\n     \
+              eval \"token=\\${GIDRIVE_ASKPASS_TOKEN_$account:-\\$GIDRIVE_ASKPASS_TOKEN}\"\n     \
+              echo \"$token\" ;;\n\
+         esac\n",
+    )
+    .context("Failed to write askpass helper")?;
+    std::fs::set_permissions(&helper, std::fs::Permissions::from_mode(0o700))?;
+    set_child_env("GIDRIVE_ASKPASS_TOKEN", token);
+    set_child_env("GIT_ASKPASS", &helper);
+    if std::env::var_os("GH_TOKEN").is_none() {
+        set_child_env("GH_TOKEN", token);
+    }
+    Ok(())
+}
+
+/// The token gh is logged in with. Not run through `run`, which would echo
+/// it to stderr.
+pub fn gh_auth_token() -> Result<String> {
+    let output = command("gh")
+        .args(["auth", "token"])
+        .output()
+        .context("Failed to run gh auth token")?;
+    let token = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    if !output.status.success() || token.is_empty() {
+        bail!("gh has no token, run `gh auth login` or set github.token");
+    }
+    Ok(token)
+}
+
+/// Adds what to check to `e` when git could not authenticate, which it
+/// reports at once instead of prompting.
+pub fn explain_auth(e: anyhow::Error) -> anyhow::Error {
+    if !is_auth_failure(&e) {
+        return e;
+    }
+    let hint = match settings().transport() {
+        Transport::Ssh => format!(
+            "authentication required: is your ssh-agent running, or is {} passphrase-less? \
+             `gidrive doctor` checks the keys",
+            settings().ssh_key()
+        ),
+        Transport::Https => "authentication required: the token was refused, check \
+                             github.token or `gh auth status`"
+            .to_string(),
+    };
+    e.context(hint)
+}
+
+/// The commit checked out in the clone `dir`.
+pub fn head_commit(dir: &Path) -> Result<String> {
+    let output = command("git")
+        .arg("-C")
+        .arg(dir)
+        .args(["rev-parse", "HEAD"])
+        .output()?;
+    if !output.status.success() {
+        bail!("no commit in {}", dir.display());
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+/// Stdout of git run with `args` in the clone `dir`, failing with its
+/// stderr.
+fn git_stdout(dir: &Path, args: &[&str]) -> Result<Vec<u8>> {
+    let output = command("git")
+        .arg("-C")
+        .arg(dir)
+        .args(args)
+        .output()
+        .context("Failed to run git")?;
+    if !output.status.success() {
+        bail!(
+            "git {} failed: {}",
+            args[0],
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(output.stdout)
+}
+
+/// Paths of the files that differ between the commits `from` and `to` of
+/// the clone `dir`, renames as a deletion and an addition.
+pub fn changed_files(dir: &Path, from: &str, to: &str) -> Result<Vec<String>> {
+    let out = git_stdout(
+        dir,
+        &["diff", "--name-only", "--no-renames", "-z", from, to],
+    )?;
+    Ok(String::from_utf8_lossy(&out)
+        .split('\0')
+        .filter(|path| !path.is_empty())
+        .map(str::to_string)
+        .collect())
+}
+
+/// The content of `path` in the commit `rev` of the clone `dir`, none if
+/// the commit has no such file.
+pub fn file_at(dir: &Path, rev: &str, path: &str) -> Result<Option<Vec<u8>>> {
+    if git_stdout(dir, &["ls-tree", "--name-only", rev, "--", path])?.is_empty() {
+        return Ok(None);
+    }
+    git_stdout(dir, &["cat-file", "blob", &format!("{}:{}", rev, path)]).map(Some)
+}
+
+/// A progress line git writes to stderr with `--progress`.
+#[derive(Debug, PartialEq)]
+pub struct GitProgress {
+    /// "Receiving objects", "Writing objects", ...
+    pub phase: String,
+    /// Objects done so far
+    pub done: Option<u64>,
+    /// Objects in all, when git gives a percentage
+    pub total: Option<u64>,
+    /// Bytes transferred so far, when git gives them
+    pub bytes: Option<u64>,
+}
+
+/// Phases moving objects over the wire, reported as `GitTransfer` events
+/// like the libgit2 transfers.
+#[cfg(not(feature = "libgit2"))]
+const TRANSFER_PHASES: [&str; 2] = ["Receiving objects", "Writing objects"];
+
+/// The progress in `line` of git stderr, with the `\r` or `\n` ending it:
+/// "Phase:  45% (9/20), 1.20 MiB | 2.00 MiB/s" or "Phase: 5, done.", with
+/// or without "remote: " before. Any other line git redraws, ending in
+/// `\r`, is progress in a wording this does not know, without numbers.
+/// None for the other lines.
+pub fn parse_progress(line: &str) -> Option<GitProgress> {
+    let text = line.trim();
+    let text = text.strip_prefix("remote:").map_or(text, str::trim_start);
+    let known = text.split_once(": ").and_then(|(phase, rest)| {
+        let words = phase.chars().all(|c| c.is_ascii_alphabetic() || c == ' ');
+        if !words || !phase.starts_with(|c: char| c.is_ascii_uppercase()) {
+            return None;
+        }
+        let rest = rest.trim_start();
+        let (count, rest) = rest.split_at(
+            rest.find(|c: char| !c.is_ascii_digit())
+                .unwrap_or(rest.len()),
+        );
+        let count: u64 = count.parse().ok()?;
+        let phase = phase.to_string();
+        if let Some(rest) = rest.strip_prefix("% (") {
+            let (done, rest) = rest.split_once('/')?;
+            let (total, rest) = rest.split_once(')')?;
+            Some(GitProgress {
+                phase,
+                done: Some(done.parse().ok()?),
+                total: Some(total.parse().ok()?),
+                bytes: rest.strip_prefix(", ").and_then(parse_git_size),
+            })
+        } else if rest.is_empty() || rest.starts_with(',') {
+            Some(GitProgress {
+                phase,
+                done: Some(count),
+                total: None,
+                bytes: None,
+            })
+        } else {
+            None
+        }
+    });
+    match known {
+        Some(progress) => Some(progress),
+        None if line.ends_with('\r') && !text.is_empty() => Some(GitProgress {
+            phase: text
+                .split_once(':')
+                .map_or(text, |(phase, _)| phase)
+                .to_string(),
+            done: None,
+            total: None,
+            bytes: None,
+        }),
+        None => None,
+    }
+}
+
+/// Bytes of a size as git prints it, "1.20 MiB" or "512 bytes".
+fn parse_git_size(s: &str) -> Option<u64> {
+    let mut words = s.split_whitespace();
+    let value: f64 = words.next()?.parse().ok()?;
+    let unit: u64 = match words.next()?.trim_end_matches(',') {
+        "bytes" => 1,
+        "KiB" => 1 << 10,
+        "MiB" => 1 << 20,
+        "GiB" => 1 << 30,
+        "TiB" => 1 << 40,
+        _ => return None,
+    };
+    Some((value * unit as f64) as u64)
+}
+
+/// The phases of a git command of `repo` reported so far, each with the
+/// tenth it was at.
+#[cfg(not(feature = "libgit2"))]
+struct ProgressReport<'a> {
+    direction: &'a str,
+    repo: &'a str,
+    steps: HashMap<String, Option<u64>>,
+}
+
+#[cfg(not(feature = "libgit2"))]
+impl ProgressReport<'_> {
+    /// Reports `progress` when it starts a phase or reaches another tenth
+    /// of it: a transfer phase as a `GitTransfer` event and, with `-v`, as
+    /// a line naming the repo, like a phase of a wording this does not know.
+    /// Counting and compressing are left out.
+    fn update(&mut self, progress: GitProgress) {
+        let step = progress
+            .done
+            .zip(progress.total)
+            .and_then(|(done, total)| (done * 10).checked_div(total));
+        if self.steps.get(&progress.phase) == Some(&step) {
+            return;
+        }
+        self.steps.insert(progress.phase.clone(), step);
+        let shown = !verbose() || very_verbose() || progress::json_enabled();
+        match (progress.done, progress.total) {
+            (Some(done), Some(total)) if TRANSFER_PHASES.contains(&progress.phase.as_str()) => {
+                progress::emit(Event::GitTransfer {
+                    direction: self.direction,
+                    repo: self.repo,
+                    objects: done as usize,
+                    total_objects: total as usize,
+                    bytes: progress.bytes.unwrap_or(0),
+                });
+                if !shown && total > 0 {
+                    eprintln!(
+                        "--- {} {}: {} {}%",
+                        self.direction,
+                        self.repo,
+                        progress.phase,
+                        done * 100 / total
+                    );
+                }
+            }
+            // a wording git changed, once per phase
+            (None, None) if !shown => {
+                eprintln!("--- {} {}: {}", self.direction, self.repo, progress.phase)
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Runs git with `args`, which pass `--progress`, reading its stderr as git
+/// writes it: the progress lines of `repo` are reported by `ProgressReport`,
+/// and with `-vv` all of stderr passes through as it comes. Returns the
+/// output with the progress lines left out of stderr.
+#[cfg(not(feature = "libgit2"))]
+pub fn git_with_progress(direction: &str, repo: &str, args: &[&OsStr]) -> io::Result<Output> {
+    let mut child = command("git")
+        .args(args)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    let (Some(mut stdout), Some(mut stderr)) = (child.stdout.take(), child.stderr.take()) else {
+        unreachable!("both are piped");
+    };
+    let passthrough = very_verbose() && !progress::json_enabled();
+    let mut report = ProgressReport {
+        direction,
+        repo,
+        steps: HashMap::new(),
+    };
+    let read = thread::scope(|s| -> io::Result<(Vec<u8>, Vec<u8>)> {
+        let out = s.spawn(move || {
+            let mut out = Vec::new();
+            stdout.read_to_end(&mut out).map(|_| out)
+        });
+        let (mut kept, mut pending) = (Vec::new(), Vec::new());
+        let mut buf = [0; 4096];
+        loop {
+            let n = match stderr.read(&mut buf) {
+                Ok(0) => break,
+                Ok(n) => n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+            if passthrough {
+                let _ = io::stderr().write_all(&buf[..n]);
+            }
+            pending.extend_from_slice(&buf[..n]);
+            // progress is redrawn with \r, other lines end in \n
+            while let Some(end) = pending.iter().position(|b| matches!(b, b'\r' | b'\n')) {
+                let line: Vec<u8> = pending.drain(..=end).collect();
+                match parse_progress(&String::from_utf8_lossy(&line)) {
+                    Some(progress) => report.update(progress),
+                    None if line.trim_ascii().is_empty() => {}
+                    None => kept.extend_from_slice(&line),
+                }
+            }
+        }
+        match parse_progress(&String::from_utf8_lossy(&pending)) {
+            Some(progress) => report.update(progress),
+            None => kept.extend_from_slice(&pending),
+        }
+        let out = out.join().expect("the stdout reader does not panic")?;
+        Ok((out, kept))
+    });
+    match read {
+        Ok((stdout, stderr)) => Ok(Output {
+            status: child.wait()?,
+            stdout,
+            stderr,
+        }),
+        Err(e) => {
+            let _ = child.kill();
+            let _ = child.wait();
+            Err(e)
+        }
+    }
+}
+
+/// `owner/name` of the origin of the clone `dir` for progress, looked up
+/// only when progress is shown.
+#[cfg(not(feature = "libgit2"))]
+fn origin_slug(dir: &Path) -> String {
+    if !verbose() && !progress::json_enabled() {
+        return String::new();
+    }
+    let origin = command("git")
+        .arg("-C")
+        .arg(dir)
+        .args(["remote", "get-url", "origin"])
+        .output();
+    match origin {
+        Ok(output) if output.status.success() => {
+            url_slug(String::from_utf8_lossy(&output.stdout).trim())
+        }
+        _ => dir.display().to_string(),
+    }
 }
 
+/// Clones `url` into `dir`, retried with the `clone` policy.
 pub fn clone_repo(url: &str, dir: &Path) -> Result<()> {
-    std::fs::create_dir_all(dir).context("Failed to create clone dir")?;
+    clone_with(url, dir, None)
+}
+
+/// Like `clone_repo`, with only the last commit.
+pub fn shallow_clone_repo(url: &str, dir: &Path) -> Result<()> {
+    clone_with(url, dir, Some(1))
+}
+
+/// Clones `url` into `dir` checking out only the files `paths` names,
+/// relative to the repo root, with no blob fetched but theirs where the
+/// server takes filters; one that does not sends them all, the checkout is
+/// sparse still. `sparse_checkout_add` brings in more. Retried with the
+/// `clone` policy.
+#[cfg(not(feature = "libgit2"))]
+pub fn sparse_clone_repo(url: &str, dir: &Path, paths: &[&Path]) -> Result<()> {
+    with_retry(&settings().retry_policy(Operation::Clone), || {
+        if dir.exists() {
+            std::fs::remove_dir_all(dir).context("Failed to remove failed clone")?;
+        }
+        let quiet_clone = [
+            "clone",
+            "--quiet",
+            "--filter=blob:none",
+            "--no-checkout",
+            url,
+        ];
+        let mut args: Vec<&OsStr> = quiet_clone.iter().map(OsStr::new).collect();
+        args.push(dir.as_os_str());
+        run_args("git", &args).context("Failed to clone repo")?;
+        sparse_checkout(dir, "set", paths)?;
+        let checkout = [
+            "-C".as_ref(),
+            dir.as_os_str(),
+            "checkout".as_ref(),
+            "--quiet".as_ref(),
+        ];
+        run_args("git", &checkout).context("Failed to check out the sparse clone")?;
+        Ok(())
+    })
+    .map_err(explain_auth)
+}
+
+/// Checks out the files `paths` names too in the sparse clone `dir`,
+/// fetching their blobs. Paths that do not exist are no error.
+#[cfg(not(feature = "libgit2"))]
+pub fn sparse_checkout_add(dir: &Path, paths: &[&Path]) -> Result<()> {
+    with_retry(&settings().retry_policy(Operation::Clone), || {
+        sparse_checkout(dir, "add", paths)
+    })
+    .map_err(explain_auth)
+}
+
+/// `git sparse-checkout set|add` with one pattern per path, each matching
+/// that file alone: anchored, and the characters patterns give a meaning to
+/// escaped.
+#[cfg(not(feature = "libgit2"))]
+fn sparse_checkout(dir: &Path, subcommand: &str, paths: &[&Path]) -> Result<()> {
+    let mut patterns = Vec::new();
+    for path in paths {
+        let mut pattern = String::from("/");
+        for c in path.to_string_lossy().chars() {
+            if matches!(c, '\\' | '*' | '?' | '[' | ']' | '!' | '#' | ' ') {
+                pattern.push('\\');
+            }
+            pattern.push(c);
+        }
+        patterns.push(pattern);
+    }
+    let mut args: Vec<&OsStr> = vec![
+        "-C".as_ref(),
+        dir.as_os_str(),
+        "sparse-checkout".as_ref(),
+        subcommand.as_ref(),
+    ];
+    if subcommand == "set" {
+        args.push("--no-cone".as_ref());
+    }
+    args.extend(patterns.iter().map(OsStr::new));
+    run_args("git", &args).context("Failed to set up the sparse checkout")?;
+    Ok(())
+}
+
+fn clone_with(url: &str, dir: &Path, depth: Option<i32>) -> Result<()> {
+    with_retry(&settings().retry_policy(Operation::Clone), || {
+        // git clones into an empty dir only, a failed attempt leaves some
+        if dir.exists() {
+            std::fs::remove_dir_all(dir).context("Failed to remove failed clone")?;
+        }
+        std::fs::create_dir_all(dir).context("Failed to create clone dir")?;
+        clone_once(url, dir, depth)
+            .context("Failed to clone repo")
+            .map_err(explain_no_space)?;
+        Ok(())
+    })
+    .map_err(explain_auth)
+}
+
+#[cfg(not(feature = "libgit2"))]
+fn clone_once(url: &str, dir: &Path, depth: Option<i32>) -> Result<()> {
+    let depth = depth.map(|d| d.to_string());
+    let mut args: Vec<&OsStr> = vec!["clone".as_ref(), "--progress".as_ref()];
+    if let Some(depth) = &depth {
+        args.extend([OsStr::new("--depth"), OsStr::new(depth)]);
+    }
+    args.extend([OsStr::new(url), dir.as_os_str()]);
+    let output = git_with_progress("fetch", &url_slug(url), &args)?;
     let cmd = format!("git clone {} {}", url, dir.display());
-    run(&cmd).context("Failed to clone repo")?;
+    check_output(&cmd, output, !very_verbose())?;
+    Ok(())
+}
+
+#[cfg(feature = "libgit2")]
+fn clone_once(url: &str, dir: &Path, depth: Option<i32>) -> Result<()> {
+    crate::libgit2::clone(url, dir, depth)
+}
+
+/// Checks out the commit `at` names in the clone `dir`: a commit id or ref,
+/// else the last commit made at or before the date `at`, in any format
+/// `git log --before` takes. Returns the commit id and its date.
+pub fn git_checkout_at(dir: &Path, at: &str) -> Result<(String, String)> {
+    let git = |args: &[&str]| -> Result<String> {
+        let output = command("git")
+            .arg("-C")
+            .arg(dir)
+            .args(args)
+            .output()
+            .context("Failed to run git")?;
+        if !output.status.success() {
+            bail!(
+                "git {} failed: {}",
+                args[0],
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+        }
+        Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+    };
+    let commit = match git(&[
+        "rev-parse",
+        "--verify",
+        "--quiet",
+        &format!("{}^{{commit}}", at),
+    ]) {
+        Ok(commit) => commit,
+        Err(_) => git(&["rev-list", "-1", &format!("--before={}", at), "HEAD"])
+            .ok()
+            .filter(|commit| !commit.is_empty())
+            .with_context(|| {
+                format!(
+                    "{:?} is neither a metadata commit nor a date after the first one",
+                    at
+                )
+            })?,
+    };
+    git(&["checkout", "--quiet", "--detach", &commit]).context("Failed to check out metadata")?;
+    let date = git(&["log", "-1", "--format=%cd", "--date=iso-strict", &commit])?;
+    Ok((commit, date))
+}
+
+/// Brings an existing clone up to date with origin/main, dropping local changes.
+pub fn git_refresh(dir: &Path) -> Result<()> {
+    #[cfg(not(feature = "libgit2"))]
+    let res = (|| -> Result<()> {
+        let fetch = [
+            "-C".as_ref(),
+            dir.as_os_str(),
+            "fetch".as_ref(),
+            "--progress".as_ref(),
+            "origin".as_ref(),
+        ];
+        let output = git_with_progress("fetch", &origin_slug(dir), &fetch)?;
+        let cmd = format!("git -C {} fetch origin", dir.display());
+        check_output(&cmd, output, !very_verbose())?;
+        run_args(
+            "git",
+            &[
+                "-C".as_ref(),
+                dir.as_os_str(),
+                "reset".as_ref(),
+                "--hard".as_ref(),
+                "origin/main".as_ref(),
+            ],
+        )?;
+        Ok(())
+    })();
+    #[cfg(feature = "libgit2")]
+    let res = crate::libgit2::fetch_reset(dir);
+    res.context("Failed to refresh clone")
+        .map_err(explain_auth)?;
     Ok(())
 }
 
 pub fn git_add_commit_push(dir: &Path, msg: &str) -> Result<()> {
-    let cmd_add = format!("cd {} && git add .", dir.display());
-    run(&cmd_add).context("Failed to git add")?;
-    let cmd_commit = format!("cd {} && git commit -m \"{}\"", dir.display(), msg);
-    run(&cmd_commit).context("Failed to git commit");
-    let cmd_push = format!("cd {} && git push origin main", dir.display());
-    let mut backoff = 1u64;
-    loop {
-        match run(&cmd_push) {
-            Ok(_) => break,
-            Err(e) => {
-                eprintln!("Push failed: {:?}. Retrying in {}s...", e, backoff);
-                thread::sleep(Duration::from_secs(backoff));
-                backoff = (backoff.saturating_mul(2)).min(60);
+    git_add_commit(dir, msg)?;
+    git_push(dir)
+}
+
+/// Commits everything in `dir`. `msg` can hold remote names, so it goes to
+/// git as an argument rather than through a shell, each of its lines cut to
+/// `COMMIT_MESSAGE_CHARS`.
+#[cfg(not(feature = "libgit2"))]
+pub fn git_add_commit(dir: &Path, msg: &str) -> Result<()> {
+    let dir = dir.as_os_str();
+    run_args("git", &["-C".as_ref(), dir, "add".as_ref(), ".".as_ref()])
+        .context("Failed to git add")?;
+    // nothing staged is nothing to commit, which the push then shows
+    let staged = command("git")
+        .arg("-C")
+        .arg(dir)
+        .args(["diff", "--cached", "--quiet"])
+        .status()
+        .context("Failed to run git diff")?;
+    match staged.code() {
+        Some(0) => return Ok(()),
+        Some(1) => {}
+        _ => bail!(
+            "git diff --cached in {} failed with {}",
+            dir.to_string_lossy(),
+            staged
+        ),
+    }
+    let msg = commit_message(msg);
+    run_args(
+        "git",
+        &[
+            "-C".as_ref(),
+            dir,
+            "commit".as_ref(),
+            "-m".as_ref(),
+            msg.as_ref(),
+        ],
+    )
+    .context("Failed to git commit")?;
+    Ok(())
+}
+
+#[cfg(feature = "libgit2")]
+pub fn git_add_commit(dir: &Path, msg: &str) -> Result<()> {
+    let msg = commit_message(msg);
+    crate::libgit2::commit_all(dir, &msg).context("Failed to commit")
+}
+
+/// `msg` with control characters and bidi overrides out of each line and
+/// every line cut to `COMMIT_MESSAGE_CHARS`.
+fn commit_message(msg: &str) -> String {
+    msg.lines()
+        .map(|line| one_line(line, COMMIT_MESSAGE_CHARS))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Pushes `dir` to origin, retried with the `push` policy. A push rejected
+/// because another client pushed first is rebased onto theirs and pushed
+/// again, unless both changed the same files.
+pub fn git_push(dir: &Path) -> Result<()> {
+    check_writable(|| format!("push {}", dir.display()))?;
+    with_retry(
+        &settings().retry_policy(Operation::Push),
+        || match push_once(dir) {
+            Err(e) if classify(&e) == ErrorClass::NeedsRebase => {
+                eprintln!(
+                    "Push rejected in {}, rebasing onto the remote",
+                    dir.display()
+                );
+                git_rebase(dir)?;
+                push_once(dir)
             }
+            res => res,
+        },
+    )
+    .map_err(explain_auth)
+}
+
+#[cfg(feature = "libgit2")]
+fn push_once(dir: &Path) -> Result<()> {
+    crate::libgit2::push(dir)
+}
+
+#[cfg(not(feature = "libgit2"))]
+fn push_once(dir: &Path) -> Result<()> {
+    let push = [
+        "-C".as_ref(),
+        dir.as_os_str(),
+        "push".as_ref(),
+        "--progress".as_ref(),
+        "origin".as_ref(),
+        "main".as_ref(),
+    ];
+    let output =
+        git_with_progress("push", &origin_slug(dir), &push).context("Failed to run git push")?;
+    if !progress::json_enabled() && !very_verbose() {
+        eprint!("{}", String::from_utf8_lossy(&output.stderr));
+    }
+    if output.status.success() {
+        return Ok(());
+    }
+    Err(CommandFailed {
+        message: format!("Push failed in {}", dir.display()),
+        stderr: String::from_utf8_lossy(&output.stderr).into_owned(),
+    }
+    .into())
+}
+
+/// A push that could not be rebased onto the remote because another client
+/// changed the same files. The clone is left as it was, with its commit.
+#[derive(Debug)]
+pub struct RebaseConflict {
+    pub dir: PathBuf,
+}
+
+impl fmt::Display for RebaseConflict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "the push of {} conflicts with the remote: another client changed the same files",
+            self.dir.display()
+        )
+    }
+}
+
+impl std::error::Error for RebaseConflict {}
+
+/// Replays the local commits of `dir` onto origin/main. On a conflict the
+/// clone is left as it was and `RebaseConflict` returned.
+#[cfg(feature = "libgit2")]
+fn git_rebase(dir: &Path) -> Result<()> {
+    if !crate::libgit2::rebase(dir).context("Failed to rebase")? {
+        return Err(RebaseConflict {
+            dir: dir.to_path_buf(),
         }
+        .into());
     }
     Ok(())
 }
+
+#[cfg(not(feature = "libgit2"))]
+fn git_rebase(dir: &Path) -> Result<()> {
+    let git = |args: &[&str]| {
+        let mut full: Vec<&OsStr> = vec!["-C".as_ref(), dir.as_os_str()];
+        full.extend(args.iter().map(|a| OsStr::new(*a)));
+        run_args("git", &full)
+    };
+    let Err(e) = git(&["pull", "--rebase", "--quiet", "origin", "main"]) else {
+        return Ok(());
+    };
+    let git_dir = dir.join(".git");
+    if !git_dir.join("rebase-merge").exists() && !git_dir.join("rebase-apply").exists() {
+        // the fetch failed, classified like any other
+        return Err(anyhow::Error::from(e).context("Failed to fetch for a rebase"));
+    }
+    let _ = git(&["rebase", "--abort"]);
+    Err(RebaseConflict {
+        dir: dir.to_path_buf(),
+    }
+    .into())
+}
@@ -0,0 +1,276 @@
+//! The few GitHub REST endpoints gidrive manages repos with, called with a
+//! token so that the gh CLI is not needed: creating, deleting, listing and
+//! looking up repos, and the scopes of the token.
+
+use anyhow::{Context, Result};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Mutex, OnceLock};
+use std::time::Duration;
+
+use crate::config::settings;
+use crate::git::gh_auth_token;
+use crate::retry::ErrorClass;
+
+const API: &str = "https://api.github.com";
+
+/// A request GitHub answered with an error status, or that did not reach it.
+#[derive(Debug)]
+pub struct ApiError {
+    /// Like "DELETE /repos/alice/storage-1a2b3c4d"
+    pub request: String,
+    /// None when the request failed in transport
+    pub status: Option<u16>,
+    /// The API's own message, or the transport error
+    pub message: String,
+    /// A 403 or 429 of the primary or secondary rate limit
+    pub rate_limited: bool,
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let Some(status) = self.status else {
+            return write!(f, "{} failed: {}", self.request, self.message);
+        };
+        write!(
+            f,
+            "GitHub refused {} ({}): {}",
+            self.request, status, self.message
+        )?;
+        match status {
+            _ if self.rate_limited => write!(f, ", the rate limit was hit"),
+            401 => write!(f, ", the token was refused, check github.token"),
+            403 if self.request.starts_with("DELETE") => write!(
+                f,
+                ", the token lacks the `delete_repo` scope, grant it with \
+                 `gh auth refresh -h github.com -s delete_repo` or a new token"
+            ),
+            403 => write!(
+                f,
+                ", the token is not allowed to do this, see its scopes with `gidrive doctor`"
+            ),
+            404 => write!(
+                f,
+                ", it does not exist or it is private and the token lacks the `repo` scope"
+            ),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl std::error::Error for ApiError {}
+
+fn agent() -> &'static ureq::Agent {
+    static AGENT: OnceLock<ureq::Agent> = OnceLock::new();
+    AGENT.get_or_init(|| {
+        ureq::AgentBuilder::new()
+            .timeout(Duration::from_secs(30))
+            .user_agent(concat!("gidrive/", env!("CARGO_PKG_VERSION")))
+            .build()
+    })
+}
+
+/// The token the API is called with for `account`: its own of
+/// `[accounts.*]`, else `github.token`, GITHUB_TOKEN or GH_TOKEN, else the
+/// one gh is logged in with when it is installed.
+pub fn token(account: &str) -> Result<String> {
+    let config = settings();
+    if let Some(token) = config
+        .account_token(account)
+        .or(config.github.token.as_deref())
+    {
+        return Ok(token.to_string());
+    }
+    for var in ["GITHUB_TOKEN", "GH_TOKEN"] {
+        if let Some(token) = std::env::var(var).ok().filter(|t| !t.is_empty()) {
+            return Ok(token);
+        }
+    }
+    gh_auth_token().context("no GitHub token: set github.token or GITHUB_TOKEN")
+}
+
+/// Sends `method path` with the token of `account` and `body`, returning
+/// the response.
+fn call(account: &str, method: &str, path: &str, body: Option<Value>) -> Result<ureq::Response> {
+    let url = if path.starts_with("https://") {
+        path.to_string()
+    } else {
+        format!("{}{}", API, path)
+    };
+    let request = agent()
+        .request(method, &url)
+        .set("Authorization", &format!("Bearer {}", token(account)?))
+        .set("Accept", "application/vnd.github+json")
+        .set("X-GitHub-Api-Version", "2022-11-28");
+    let res = match body {
+        Some(body) => request.send_json(body),
+        None => request.call(),
+    };
+    let described = || format!("{} {}", method, url.trim_start_matches(API));
+    match res {
+        Ok(response) => Ok(response),
+        Err(ureq::Error::Status(status, response)) => {
+            let rate_limited = matches!(status, 403 | 429)
+                && (response.header("x-ratelimit-remaining") == Some("0")
+                    || response.header("retry-after").is_some());
+            let body: Value = response.into_json().unwrap_or_default();
+            // validation failures, like a taken name, are detailed in `errors`
+            let message = std::iter::once(&body["message"])
+                .chain(
+                    body["errors"]
+                        .as_array()
+                        .into_iter()
+                        .flatten()
+                        .map(|e| &e["message"]),
+                )
+                .filter_map(Value::as_str)
+                .collect::<Vec<_>>()
+                .join(" ");
+            let rate_limited = rate_limited || message.contains("rate limit");
+            Err(ApiError {
+                request: described(),
+                status: Some(status),
+                message,
+                rate_limited,
+            }
+            .into())
+        }
+        Err(ureq::Error::Transport(e)) => Err(ApiError {
+            request: described(),
+            status: None,
+            message: e.to_string(),
+            rate_limited: false,
+        }
+        .into()),
+    }
+}
+
+/// Login of the account the token of `account` belongs to.
+fn login(account: &str) -> Result<String> {
+    static LOGINS: Mutex<Option<HashMap<String, String>>> = Mutex::new(None);
+    if let Some(login) = LOGINS
+        .lock()
+        .unwrap()
+        .as_ref()
+        .and_then(|logins| logins.get(account))
+    {
+        return Ok(login.clone());
+    }
+    let user: Value = call(account, "GET", "/user", None)?.into_json()?;
+    let login = user["login"]
+        .as_str()
+        .context("unexpected GET /user response")?
+        .to_string();
+    LOGINS
+        .lock()
+        .unwrap()
+        .get_or_insert_with(HashMap::new)
+        .insert(account.to_string(), login.clone());
+    Ok(login)
+}
+
+/// Creates the repo `owner/name` of `slug`, under the token's user or the
+/// organization `owner`. A repo of that name existing already is fine.
+pub fn create_repo(slug: &str, public: bool) -> Result<()> {
+    let (owner, name) = slug.split_once('/').context("repo slug without owner")?;
+    let path = if login(owner)?.eq_ignore_ascii_case(owner) {
+        "/user/repos".to_string()
+    } else {
+        format!("/orgs/{}/repos", owner)
+    };
+    let body = json!({ "name": name, "private": !public, "auto_init": false });
+    match call(owner, "POST", &path, Some(body)) {
+        Err(e)
+            if e.downcast_ref::<ApiError>()
+                .is_some_and(|e| e.status == Some(422) && e.message.contains("already exists")) =>
+        {
+            Ok(())
+        }
+        res => res.map(|_| ()),
+    }
+}
+
+pub fn delete_repo(slug: &str) -> Result<()> {
+    let owner = slug.split('/').next().unwrap_or_default();
+    call(owner, "DELETE", &format!("/repos/{}", slug), None)?;
+    Ok(())
+}
+
+/// Whether the repo `owner/name` exists and the token can see it.
+pub fn repo_exists(slug: &str) -> Result<bool> {
+    let owner = slug.split('/').next().unwrap_or_default();
+    match call(owner, "GET", &format!("/repos/{}", slug), None) {
+        Ok(_) => Ok(true),
+        Err(e)
+            if e.downcast_ref::<ApiError>()
+                .is_some_and(|e| e.status == Some(404)) =>
+        {
+            Ok(false)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// Names of the repos of `account`, every page of them: private ones too
+/// when the token is its own.
+pub fn list_repos(account: &str) -> Result<Vec<String>> {
+    let mut next = Some(if login(account)?.eq_ignore_ascii_case(account) {
+        "/user/repos?affiliation=owner&per_page=100".to_string()
+    } else {
+        format!("/users/{}/repos?per_page=100", account)
+    });
+    let mut names = Vec::new();
+    while let Some(path) = next {
+        let response = call(account, "GET", &path, None)?;
+        next = response.header("link").and_then(next_page);
+        let page: Value = response.into_json()?;
+        names.extend(
+            page.as_array()
+                .context("unexpected repo list response")?
+                .iter()
+                .filter_map(|repo| repo["name"].as_str().map(str::to_string)),
+        );
+    }
+    Ok(names)
+}
+
+/// The URL of the `rel="next"` page in a Link header.
+fn next_page(link: &str) -> Option<String> {
+    link.split(',').find_map(|part| {
+        let (url, rel) = part.split_once(';')?;
+        rel.contains("rel=\"next\"")
+            .then(|| url.trim().trim_matches(['<', '>']).to_string())
+    })
+}
+
+/// OAuth scopes of the token of `account`, `None` when GitHub reports none,
+/// as for fine-grained and app tokens whose permissions cannot be listed.
+pub fn token_scopes(account: &str) -> Result<Option<Vec<String>>> {
+    let response = call(account, "GET", "/user", None)?;
+    Ok(response.header("x-oauth-scopes").map(|value| {
+        value
+            .split(',')
+            .map(|s| s.trim().to_string())
+            .filter(|s| !s.is_empty())
+            .collect()
+    }))
+}
+
+/// Login the token of `account` authenticates as, for `doctor`.
+pub fn authenticated_as(account: &str) -> Result<String> {
+    login(account)
+}
+
+/// The class of a failed API request among the causes of `e`, none if
+/// there is none. Hitting the rate limit, a server error and not reaching
+/// GitHub are retried, GitHub refusing the request is not.
+pub fn classify(e: &anyhow::Error) -> Option<ErrorClass> {
+    let e = e.chain().find_map(|c| c.downcast_ref::<ApiError>())?;
+    Some(match e.status {
+        _ if e.rate_limited => ErrorClass::Transient,
+        None => ErrorClass::Transient,
+        Some(status) if status >= 500 => ErrorClass::Transient,
+        Some(_) => ErrorClass::Permanent,
+    })
+}
@@ -0,0 +1,104 @@
+use anyhow::{bail, Context, Result};
+use std::os::fd::AsFd;
+use std::process::{Command, Stdio};
+use std::time::Duration;
+
+use crate::config::settings;
+
+/// Points of a transfer where the command configured in `[hooks]` runs.
+#[derive(Clone, Copy)]
+pub enum Hook {
+    PreUpload,
+    PostUpload,
+    PostDownload,
+    OnError,
+}
+
+impl Hook {
+    pub fn name(self) -> &'static str {
+        match self {
+            Hook::PreUpload => "pre_upload",
+            Hook::PostUpload => "post_upload",
+            Hook::PostDownload => "post_download",
+            Hook::OnError => "on_error",
+        }
+    }
+
+    fn command(self) -> Option<&'static str> {
+        let hooks = &settings().hooks;
+        match self {
+            Hook::PreUpload => hooks.pre_upload.as_deref(),
+            Hook::PostUpload => hooks.post_upload.as_deref(),
+            Hook::PostDownload => hooks.post_download.as_deref(),
+            Hook::OnError => hooks.on_error.as_deref(),
+        }
+    }
+}
+
+/// The transfer a hook runs for, handed to it as `GIDRIVE_HOOK_*`
+/// environment variables. Unknown values are left unset.
+#[derive(Clone, Copy, Default)]
+pub struct HookEnv<'a> {
+    /// "upload" or "download"
+    pub operation: &'a str,
+    pub remote: &'a str,
+    pub local: &'a str,
+    pub size: Option<u64>,
+    pub checksum: Option<&'a str>,
+    pub duration: Option<Duration>,
+    pub error: Option<&'a str>,
+}
+
+/// Runs the command configured for `hook`, if any. Its arguments are split
+/// like a shell would but no shell runs it, the transfer only reaches it
+/// through the environment. A failing hook only warns, except `pre_upload`
+/// with `hooks.pre_upload_required`, which fails the upload.
+pub fn run(hook: Hook, env: &HookEnv) -> Result<()> {
+    let Some(command) = hook.command() else {
+        return Ok(());
+    };
+    let Err(e) = spawn(hook, command, env) else {
+        return Ok(());
+    };
+    if matches!(hook, Hook::PreUpload) && settings().hooks.pre_upload_required.unwrap_or(false) {
+        return Err(e.context("pre_upload hook failed"));
+    }
+    eprintln!("--- {} hook failed: {:#}", hook.name(), e);
+    Ok(())
+}
+
+fn spawn(hook: Hook, command: &str, env: &HookEnv) -> Result<()> {
+    let argv = shlex::split(command)
+        .filter(|argv| !argv.is_empty())
+        .with_context(|| format!("hooks.{} is not a valid command", hook.name()))?;
+    let mut cmd = Command::new(&argv[0]);
+    cmd.args(&argv[1..])
+        .env("GIDRIVE_HOOK", hook.name())
+        .env("GIDRIVE_HOOK_OPERATION", env.operation)
+        .env("GIDRIVE_HOOK_REMOTE", env.remote)
+        .env("GIDRIVE_HOOK_LOCAL", env.local)
+        // stdout is reserved for data (cat), hook output is diagnostics
+        .stdout(Stdio::from(std::io::stderr().as_fd().try_clone_to_owned()?));
+    if let Some(size) = env.size {
+        cmd.env("GIDRIVE_HOOK_SIZE", size.to_string());
+    }
+    if let Some(checksum) = env.checksum {
+        cmd.env("GIDRIVE_HOOK_CHECKSUM", checksum);
+    }
+    if let Some(duration) = env.duration {
+        cmd.env(
+            "GIDRIVE_HOOK_DURATION",
+            format!("{:.3}", duration.as_secs_f64()),
+        );
+    }
+    if let Some(error) = env.error {
+        cmd.env("GIDRIVE_HOOK_ERROR", error);
+    }
+    let status = cmd
+        .status()
+        .with_context(|| format!("Failed to run {}", argv[0]))?;
+    if !status.success() {
+        bail!("{} exited with {}", argv[0], status);
+    }
+    Ok(())
+}
@@ -0,0 +1,276 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use crate::metadata::{namespace, UploadPlan};
+use crate::models::ChecksumAlgo;
+use crate::utils::{checksum_hex, unix_now};
+
+/// `$XDG_STATE_HOME/gidrive`, falling back to `~/.local/state`.
+pub fn state_dir() -> PathBuf {
+    let base = std::env::var_os("XDG_STATE_HOME")
+        .map(PathBuf::from)
+        .or_else(|| {
+            std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".local").join("state"))
+        })
+        .unwrap_or_default();
+    base.join("gidrive")
+}
+
+/// `state_dir()/journal`
+pub fn journal_dir() -> PathBuf {
+    state_dir().join("journal")
+}
+
+/// Entries that did not parse, kept for a look rather than deleted.
+fn quarantine_dir() -> PathBuf {
+    journal_dir().join("quarantine")
+}
+
+/// What a journaled operation set out to do, with what `resume` needs to
+/// drive it again.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(tag = "operation", rename_all = "snake_case")]
+pub enum Intent {
+    Upload {
+        remote: String,
+        /// The local file, none for stdin, archives and `serve` uploads
+        local: Option<PathBuf>,
+        size: u64,
+        checksum_algo: ChecksumAlgo,
+        public: bool,
+        /// Store the extended attributes of `local`, absent in entries of
+        /// older versions
+        #[serde(default)]
+        xattrs: bool,
+    },
+    Remove {
+        remotes: Vec<String>,
+        label: String,
+        permanent: bool,
+    },
+    Gc {
+        now: bool,
+    },
+}
+
+impl fmt::Display for Intent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Intent::Upload { remote, local, .. } => match local {
+                Some(local) => write!(f, "upload {} from {}", remote, local.display()),
+                None => write!(f, "upload {}", remote),
+            },
+            Intent::Remove {
+                label, permanent, ..
+            } if *permanent => write!(f, "clean {}", label),
+            Intent::Remove { label, .. } => write!(f, "rm {}", label),
+            Intent::Gc { now: true } => write!(f, "gc --now"),
+            Intent::Gc { now: false } => write!(f, "gc"),
+        }
+    }
+}
+
+/// Chunk assignment of an upload, recorded once repos.json reserved its
+/// space, so a resume pushes to the same repos without planning again.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct JournaledPlan {
+    /// (chunk index, repo name, chunk size)
+    pub assignments: Vec<(usize, String, u64)>,
+    pub new_repos: Vec<String>,
+    /// sha256 of the assignments and new repos, checked before a resume
+    pub hash: String,
+}
+
+impl JournaledPlan {
+    fn new(plan: &UploadPlan) -> Self {
+        JournaledPlan {
+            assignments: plan.assignments.clone(),
+            new_repos: plan.new_repos.clone(),
+            hash: plan_hash(&plan.assignments, &plan.new_repos),
+        }
+    }
+
+    /// Whether the plan is still the one that was recorded.
+    pub fn intact(&self) -> bool {
+        self.hash == plan_hash(&self.assignments, &self.new_repos)
+    }
+}
+
+fn plan_hash(assignments: &[(usize, String, u64)], new_repos: &[String]) -> String {
+    let data = serde_json::to_vec(&(assignments, new_repos)).unwrap_or_default();
+    checksum_hex(ChecksumAlgo::Sha256, &data)
+}
+
+/// An operation as recorded in the journal.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Entry {
+    pub id: String,
+    pub pid: u32,
+    pub started: u64,
+    /// The namespace the remote names are in
+    pub namespace: String,
+    pub intent: Intent,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub plan: Option<JournaledPlan>,
+    /// Repos an upload pushed its batch to
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub pushed: Vec<String>,
+}
+
+impl Entry {
+    /// Whether the process that wrote the entry still runs, rather than
+    /// having crashed or been killed.
+    pub fn running(&self) -> bool {
+        let comm = |pid: &str| fs::read_to_string(Path::new("/proc").join(pid).join("comm")).ok();
+        self.pid != std::process::id()
+            && comm(&self.pid.to_string()).is_some_and(|c| Some(c) == comm("self"))
+    }
+}
+
+/// A mutating operation in progress. Its intent is written to the journal
+/// before any side effect and the entry is removed by `finish` once the
+/// operation succeeded, so failures, crashes and kills leave it for `resume`.
+pub struct Journal {
+    path: PathBuf,
+    entry: Mutex<Entry>,
+}
+
+impl Journal {
+    pub fn begin(intent: Intent) -> Result<Journal> {
+        let mut id = [0u8; 4];
+        let _ = getrandom::getrandom(&mut id);
+        let started = unix_now();
+        let id = format!("{}-{}", started, hex::encode(id));
+        let journal = Journal {
+            path: journal_dir().join(format!("{}.json", id)),
+            entry: Mutex::new(Entry {
+                id,
+                pid: std::process::id(),
+                started,
+                namespace: namespace().to_string(),
+                intent,
+                plan: None,
+                pushed: Vec::new(),
+            }),
+        };
+        fs::create_dir_all(journal_dir()).context("Failed to create the journal dir")?;
+        journal.save(&journal.entry.lock().unwrap())?;
+        Ok(journal)
+    }
+
+    /// Takes over the entry of an interrupted operation that is resumed.
+    pub fn resume(mut entry: Entry) -> Result<Journal> {
+        entry.pid = std::process::id();
+        let journal = Journal {
+            path: journal_dir().join(format!("{}.json", entry.id)),
+            entry: Mutex::new(entry),
+        };
+        journal.save(&journal.entry.lock().unwrap())?;
+        Ok(journal)
+    }
+
+    /// The plan of an upload, once repos.json reserved it.
+    pub fn plan(&self) -> Option<JournaledPlan> {
+        self.entry.lock().unwrap().plan.clone()
+    }
+
+    pub fn planned(&self, plan: &UploadPlan) -> Result<()> {
+        let mut entry = self.entry.lock().unwrap();
+        entry.plan = Some(JournaledPlan::new(plan));
+        self.save(&entry)
+    }
+
+    /// Drops the plan of an upload whose reservations were given back, so a
+    /// resume plans again.
+    pub fn unplanned(&self) {
+        let mut entry = self.entry.lock().unwrap();
+        entry.plan = None;
+        entry.pushed.clear();
+        if let Err(e) = self.save(&entry) {
+            eprintln!("--- could not update the journal: {:#}", e);
+        }
+    }
+
+    pub fn was_pushed(&self, repo: &str) -> bool {
+        self.entry.lock().unwrap().pushed.iter().any(|r| r == repo)
+    }
+
+    /// Records the batch of `repo` as pushed. A failure to write it only
+    /// costs a resume a clone of the repo.
+    pub fn pushed(&self, repo: &str) {
+        let mut entry = self.entry.lock().unwrap();
+        entry.pushed.push(repo.to_string());
+        if let Err(e) = self.save(&entry) {
+            eprintln!("--- could not update the journal: {:#}", e);
+        }
+    }
+
+    /// Writes `entry` through a rename, so a crash leaves the old or the
+    /// new entry and never half of one.
+    fn save(&self, entry: &Entry) -> Result<()> {
+        let data =
+            serde_json::to_string_pretty(entry).context("Failed to serialize journal entry")?;
+        let tmp = self.path.with_extension("tmp");
+        fs::write(&tmp, data).context("Failed to write journal entry")?;
+        fs::rename(&tmp, &self.path).context("Failed to write journal entry")
+    }
+
+    /// Removes the entry of the operation, which succeeded.
+    pub fn finish(self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// Removes the entry of an operation that was finished or rolled back.
+pub fn discard(entry: &Entry) {
+    let _ = fs::remove_file(journal_dir().join(format!("{}.json", entry.id)));
+}
+
+/// Entries of the operations that did not finish, oldest first. An entry
+/// that does not parse is moved to the quarantine dir with a warning.
+pub fn incomplete() -> Result<Vec<Entry>> {
+    let dir = journal_dir();
+    let Ok(read_dir) = fs::read_dir(&dir) else {
+        return Ok(Vec::new());
+    };
+    let mut entries = Vec::new();
+    for file in read_dir {
+        let path = file?.path();
+        if path.extension().is_none_or(|ext| ext != "json") {
+            continue;
+        }
+        let parsed = fs::read_to_string(&path)
+            .map_err(anyhow::Error::from)
+            .and_then(|data| Ok(serde_json::from_str::<Entry>(&data)?));
+        match parsed {
+            Ok(entry) => entries.push(entry),
+            Err(e) => quarantine(&path, &format!("{:#}", e))?,
+        }
+    }
+    entries.sort_by(|a, b| a.started.cmp(&b.started).then_with(|| a.id.cmp(&b.id)));
+    Ok(entries)
+}
+
+/// Moves the journal file `path` out of the way of `resume`.
+pub fn quarantine(path: &Path, reason: &str) -> Result<()> {
+    fs::create_dir_all(quarantine_dir()).context("Failed to create the quarantine dir")?;
+    let name = path.file_name().context("journal entry without a name")?;
+    let dest = quarantine_dir().join(name);
+    fs::rename(path, &dest).context("Failed to quarantine journal entry")?;
+    eprintln!(
+        "--- journal entry {} is corrupt ({}), moved to {}",
+        name.to_string_lossy(),
+        reason,
+        dest.display()
+    );
+    Ok(())
+}
+
+/// Moves the entry `id` to the quarantine dir.
+pub fn quarantine_entry(id: &str, reason: &str) -> Result<()> {
+    quarantine(&journal_dir().join(format!("{}.json", id)), reason)
+}
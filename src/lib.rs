@@ -1,7 +1,36 @@
 pub mod api;
+pub mod archive;
+pub mod cache;
 pub mod chunks;
+pub mod clock;
+pub mod config;
 pub mod constants;
+pub mod daemon;
+pub mod doctor;
+pub mod exclude;
+pub mod export;
+pub mod fetch;
 pub mod git;
+pub mod github;
+pub mod hooks;
+pub mod journal;
+#[cfg(feature = "libgit2")]
+pub mod libgit2;
+pub mod listing;
+pub mod manifest;
 pub mod metadata;
+pub mod metrics;
 pub mod models;
+pub mod progress;
+pub mod report;
+pub mod repos;
+pub mod retry;
+pub mod serve;
+pub mod signing;
+pub mod status;
+pub mod usage;
 pub mod utils;
+pub mod watch;
+pub mod xattrs;
+
+pub use models::{ChunkInfo, FileMetadata};
@@ -0,0 +1,340 @@
+//! Clones, fetches, commits and pushes through libgit2 instead of the git
+//! binary, built with the `libgit2` feature. Failures are `git2::Error`s,
+//! classified by their code and class rather than by what git printed.
+
+use anyhow::{Context, Result};
+use git2::build::{CheckoutBuilder, RepoBuilder};
+use git2::{
+    Cred, CredentialType, ErrorClass as GitClass, ErrorCode, FetchOptions, IndexAddOption,
+    PushOptions, RemoteCallbacks, Repository, ResetType, Signature,
+};
+use std::cell::Cell;
+use std::path::Path;
+
+use crate::config::{expand_home, settings};
+use crate::git::{account_var, url_slug};
+use crate::progress::{self, Event};
+use crate::retry::ErrorClass;
+use crate::utils::{child_env, verbose};
+
+/// Branch the metadata and storage repos are pushed to.
+const BRANCH: &str = "main";
+
+/// The account whose credentials reach `url`: the user an HTTPS URL names,
+/// else the owner in its path.
+fn url_account(url: &str, username: Option<&str>) -> String {
+    match username {
+        Some(user) if user != "git" => user.to_string(),
+        _ => url_slug(url)
+            .split('/')
+            .next()
+            .unwrap_or_default()
+            .to_string(),
+    }
+}
+
+/// Callbacks answering credential requests from the config like the ssh
+/// wrapper and askpass helper of the git backend do, and emitting
+/// `git_transfer` events for `direction` with `--progress json`, and the
+/// per-repo progress lines of `-v`. libgit2 has no raw output for `-vv`.
+fn callbacks<'a>(url: &str, direction: &'static str) -> RemoteCallbacks<'a> {
+    let mut callbacks = RemoteCallbacks::new();
+    // libgit2 asks again after a refused credential, each is offered once
+    let tried_agent = Cell::new(false);
+    let tried_key = Cell::new(false);
+    let tried_token = Cell::new(false);
+    callbacks.credentials(move |url, username, allowed| {
+        let account = url_account(url, username);
+        if allowed.contains(CredentialType::SSH_KEY) {
+            let user = username.unwrap_or("git");
+            if !tried_agent.replace(true) {
+                return Cred::ssh_key_from_agent(user);
+            }
+            if !tried_key.replace(true) {
+                let key = expand_home(settings().account_ssh_key(&account));
+                return Cred::ssh_key(user, None, &key, None);
+            }
+        }
+        if allowed.contains(CredentialType::USER_PASS_PLAINTEXT) && !tried_token.replace(true) {
+            let token = child_env(&account_var("GIDRIVE_ASKPASS_TOKEN", &account))
+                .or_else(|| child_env("GIDRIVE_ASKPASS_TOKEN"));
+            if let Some(token) = token.and_then(|t| t.into_string().ok()) {
+                return Cred::userpass_plaintext("x-access-token", &token);
+            }
+        }
+        Err(git2::Error::new(
+            ErrorCode::Auth,
+            GitClass::Net,
+            format!("no credentials of {} reach {}", account, url),
+        ))
+    });
+    let repo = url_slug(url);
+    // with -v, the line the git backend prints for the same phase
+    let phase = if direction == "push" {
+        "Writing objects"
+    } else {
+        "Receiving objects"
+    };
+    let shown = verbose() && !progress::json_enabled();
+    // an event per tenth of the objects, not per object
+    let emitted = Cell::new(None);
+    let emit = move |objects: usize, total_objects: usize, bytes: usize| {
+        let step = (objects * 10).checked_div(total_objects);
+        if emitted.replace(step) != step {
+            progress::emit(Event::GitTransfer {
+                direction,
+                repo: &repo,
+                objects,
+                total_objects,
+                bytes: bytes as u64,
+            });
+            if shown && total_objects > 0 {
+                eprintln!(
+                    "--- {} {}: {} {}%",
+                    direction,
+                    repo,
+                    phase,
+                    objects * 100 / total_objects
+                );
+            }
+        }
+    };
+    if direction == "push" {
+        callbacks.push_transfer_progress(emit);
+    } else {
+        callbacks.transfer_progress(move |stats| {
+            emit(
+                stats.received_objects(),
+                stats.total_objects(),
+                stats.received_bytes(),
+            );
+            true
+        });
+    }
+    callbacks
+}
+
+/// `url` rewritten by the longest `url.<base>.insteadOf` prefix of the git
+/// config matching it, as libgit2 will when it connects.
+fn rewritten_url(url: &str) -> String {
+    let Ok(config) = git2::Config::open_default() else {
+        return url.to_string();
+    };
+    let mut best: Option<(String, String)> = None;
+    if let Ok(mut entries) = config.entries(Some(r"url\..*\.insteadof")) {
+        while let Some(Ok(entry)) = entries.next() {
+            let (Some(name), Some(prefix)) = (entry.name(), entry.value()) else {
+                continue;
+            };
+            let base = &name["url.".len()..name.len() - ".insteadof".len()];
+            let longer = best.as_ref().is_none_or(|(p, _)| prefix.len() > p.len());
+            if url.starts_with(prefix) && longer {
+                best = Some((prefix.to_string(), base.to_string()));
+            }
+        }
+    }
+    match best {
+        Some((prefix, base)) => format!("{}{}", base, &url[prefix.len()..]),
+        None => url.to_string(),
+    }
+}
+
+/// Options fetching from `url` the last `depth` commits when given. Over
+/// the local transport, which cannot fetch shallow, the whole history,
+/// also when `insteadOf` turns `url` into a path.
+fn fetch_options<'a>(url: &str, depth: Option<i32>) -> FetchOptions<'a> {
+    let mut options = FetchOptions::new();
+    options.remote_callbacks(callbacks(url, "fetch"));
+    let url = rewritten_url(url);
+    let local = url.starts_with('/') || url.starts_with("file://");
+    if let Some(depth) = depth.filter(|_| !local) {
+        options.depth(depth);
+    }
+    options
+}
+
+/// Clones `url` into the empty dir `dir`, with only the last `depth`
+/// commits when given.
+pub fn clone(url: &str, dir: &Path, depth: Option<i32>) -> Result<()> {
+    RepoBuilder::new()
+        .fetch_options(fetch_options(url, depth))
+        .clone(url, dir)?;
+    Ok(())
+}
+
+/// Fetches the default branch of `url` into `refspec` of the bare repo at
+/// `path`, the tip only.
+pub fn fetch_tip(path: &Path, url: &str, refspec: &str) -> Result<()> {
+    let repo = Repository::open_bare(path)?;
+    let mut remote = repo.remote_anonymous(url)?;
+    remote.fetch(&[refspec], Some(&mut fetch_options(url, Some(1))), None)?;
+    Ok(())
+}
+
+/// Fetches `main` of origin into the clone `dir` and opens it.
+fn fetch_origin(dir: &Path) -> Result<Repository> {
+    let repo = Repository::open(dir)?;
+    {
+        let mut remote = repo.find_remote("origin")?;
+        let url = remote.url().unwrap_or_default().to_string();
+        remote.fetch(
+            &[format!("+refs/heads/{0}:refs/remotes/origin/{0}", BRANCH)],
+            Some(&mut fetch_options(&url, None)),
+            None,
+        )?;
+    }
+    Ok(repo)
+}
+
+/// Makes the clone `dir` what origin has now, dropping local changes.
+pub fn fetch_reset(dir: &Path) -> Result<()> {
+    let repo = fetch_origin(dir)?;
+    let tip = repo
+        .find_reference(&format!("refs/remotes/origin/{}", BRANCH))?
+        .peel_to_commit()?;
+    repo.reset(tip.as_object(), ResetType::Hard, None)?;
+    Ok(())
+}
+
+fn signature(repo: &Repository) -> Result<Signature<'static>> {
+    Ok(repo
+        .signature()
+        .or_else(|_| Signature::now("gidrive", "gidrive@localhost"))?)
+}
+
+/// Commits everything in the clone `dir` with `msg`, nothing when it has
+/// no changes. An empty clone gets its first commit on `main`.
+pub fn commit_all(dir: &Path, msg: &str) -> Result<()> {
+    let repo = Repository::open(dir)?;
+    let mut index = repo.index()?;
+    index.add_all(["*"], IndexAddOption::DEFAULT, None)?;
+    index.update_all(["*"], None)?;
+    index.write()?;
+    let tree = repo.find_tree(index.write_tree()?)?;
+    let parent = match repo.head() {
+        Ok(head) => Some(head.peel_to_commit()?),
+        Err(e) if e.code() == ErrorCode::UnbornBranch => {
+            repo.set_head(&format!("refs/heads/{}", BRANCH))?;
+            None
+        }
+        Err(e) => return Err(e.into()),
+    };
+    if parent.as_ref().is_some_and(|p| p.tree_id() == tree.id()) {
+        return Ok(());
+    }
+    let sig = signature(&repo)?;
+    repo.commit(
+        Some("HEAD"),
+        &sig,
+        &sig,
+        msg,
+        &tree,
+        &parent.iter().collect::<Vec<_>>(),
+    )?;
+    Ok(())
+}
+
+/// Pushes `main` of the clone `dir` to origin. A push behind the remote
+/// fails with `NotFastForward`, whether libgit2 or GitHub found it. A push
+/// the remote rejected otherwise fails with what the remote printed, the
+/// `remote:` lines of git.
+pub fn push(dir: &Path) -> Result<()> {
+    let repo = Repository::open(dir)?;
+    let mut remote = repo.find_remote("origin")?;
+    let url = remote.url().unwrap_or_default().to_string();
+    let rejected = Cell::new(None);
+    let printed = Cell::new(String::new());
+    let mut callbacks = callbacks(&url, "push");
+    callbacks.push_update_reference(|_, status| {
+        if let Some(status) = status {
+            rejected.set(Some(status.to_string()));
+        }
+        Ok(())
+    });
+    callbacks.sideband_progress(|data| {
+        let mut text = printed.take();
+        text.push_str(&String::from_utf8_lossy(data));
+        printed.set(text);
+        true
+    });
+    let mut options = PushOptions::new();
+    options.remote_callbacks(callbacks);
+    remote.push(
+        &[format!("refs/heads/{0}:refs/heads/{0}", BRANCH)],
+        Some(&mut options),
+    )?;
+    drop(options);
+    if let Some(status) = rejected.take() {
+        let code = if status.contains("fast-forward") || status.contains("fetch first") {
+            ErrorCode::NotFastForward
+        } else {
+            ErrorCode::GenericError
+        };
+        let mut message = format!("push of {} rejected: {}", dir.display(), status);
+        let printed = printed.take();
+        let lines: Vec<&str> = printed
+            .lines()
+            .map(str::trim)
+            .filter(|l| !l.is_empty())
+            .collect();
+        if !lines.is_empty() {
+            message.push_str(&format!(" (remote: {})", lines.join("; ")));
+        }
+        return Err(git2::Error::new(code, GitClass::Reference, message)).context("Failed to push");
+    }
+    Ok(())
+}
+
+/// Replays the local commits of the clone `dir` onto origin/main. Returns
+/// false on a conflict, leaving the clone as it was.
+pub fn rebase(dir: &Path) -> Result<bool> {
+    let repo = fetch_origin(dir)?;
+    let upstream_ref = repo.find_reference(&format!("refs/remotes/origin/{}", BRANCH))?;
+    let upstream = repo.reference_to_annotated_commit(&upstream_ref)?;
+    let mut checkout = CheckoutBuilder::new();
+    checkout.force();
+    let mut options = git2::RebaseOptions::new();
+    options.checkout_options(checkout);
+    let mut rebase = repo.rebase(None, Some(&upstream), None, Some(&mut options))?;
+    let sig = signature(&repo)?;
+    while let Some(op) = rebase.next() {
+        op?;
+        if repo.index()?.has_conflicts() {
+            rebase.abort()?;
+            return Ok(false);
+        }
+        match rebase.commit(None, &sig, None) {
+            // the commit is on the remote already
+            Err(e) if e.code() == ErrorCode::Applied => {}
+            res => {
+                res?;
+            }
+        }
+    }
+    rebase.finish(Some(&sig))?;
+    Ok(true)
+}
+
+/// The class of a libgit2 failure among the causes of `e`, none if there
+/// is none or its code and class do not tell, for its message to be
+/// classified like the stderr of git.
+pub fn classify(e: &anyhow::Error) -> Option<ErrorClass> {
+    let e = e.chain().find_map(|c| c.downcast_ref::<git2::Error>())?;
+    Some(match (e.code(), e.class()) {
+        (ErrorCode::NotFastForward, _) => ErrorClass::NeedsRebase,
+        (ErrorCode::Auth | ErrorCode::Certificate | ErrorCode::NotFound, _) => {
+            ErrorClass::Permanent
+        }
+        (_, GitClass::Net | GitClass::Ssh | GitClass::Http | GitClass::Ssl) => {
+            ErrorClass::Transient
+        }
+        _ => return None,
+    })
+}
+
+/// Whether `e` comes from libgit2 finding no credentials that work.
+pub fn is_auth_failure(e: &anyhow::Error) -> bool {
+    e.chain()
+        .filter_map(|c| c.downcast_ref::<git2::Error>())
+        .any(|e| e.code() == ErrorCode::Auth)
+}
@@ -0,0 +1,108 @@
+//! The lines `ls` prints: an aligned table for people, and `--porcelain`
+//! lines for scripts whose format never changes.
+
+use crate::models::FileEntry;
+use crate::utils::{format_utc, human_size};
+
+/// The kind of an entry as the first porcelain field names it.
+fn kind(entry: &FileEntry) -> &'static str {
+    if entry.symlink_target.is_some() {
+        "symlink"
+    } else if entry.archive.is_some() {
+        "archive"
+    } else {
+        "file"
+    }
+}
+
+/// `s` with backslashes, tabs and line breaks escaped, so that a porcelain
+/// field never spans two fields or two lines.
+fn escape_field(s: &str) -> String {
+    s.replace('\\', "\\\\")
+        .replace('\t', "\\t")
+        .replace('\n', "\\n")
+        .replace('\r', "\\r")
+}
+
+/// The porcelain line of `entry`, eight tab-separated fields: kind (file,
+/// archive or symlink), size in bytes, chunk count, mtime in seconds since
+/// the epoch or `-`, checksum algorithm, checksum, path and symlink target,
+/// empty for other kinds. Backslash, tab, CR and LF in the path and target
+/// are escaped as `\\`, `\t`, `\r` and `\n`. Fields are only ever added at
+/// the end.
+pub fn porcelain_line(entry: &FileEntry) -> String {
+    [
+        kind(entry).to_string(),
+        entry.size.to_string(),
+        entry.chunks.to_string(),
+        entry.mtime.map_or("-".to_string(), |t| t.to_string()),
+        entry.checksum_algo.to_string(),
+        entry.checksum.clone(),
+        escape_field(&entry.path),
+        entry
+            .symlink_target
+            .as_deref()
+            .map_or(String::new(), escape_field),
+    ]
+    .join("\t")
+}
+
+/// The table of `entries`: path, size and chunk count, and the upload time
+/// when any entry has one, under a header and aligned once all rows are
+/// known. Symlinks show their target, archives their format and entries.
+pub fn table(entries: &[FileEntry]) -> Vec<String> {
+    let with_mtime = entries.iter().any(|entry| entry.mtime.is_some());
+    let mut rows = vec![[
+        "PATH".to_string(),
+        "SIZE".to_string(),
+        "CHUNKS".to_string(),
+        "MODIFIED".to_string(),
+        String::new(),
+    ]];
+    for entry in entries {
+        let (path, size, chunks) = match &entry.symlink_target {
+            Some(target) => (
+                format!("{} -> {}", entry.path, target),
+                "-".to_string(),
+                "-".to_string(),
+            ),
+            None => (
+                entry.path.clone(),
+                human_size(entry.size),
+                entry.chunks.to_string(),
+            ),
+        };
+        let note = entry.archive.as_ref().map_or(String::new(), |info| {
+            format!("{} archive, {} entries", info.format, info.entries)
+        });
+        let mtime = entry.mtime.map_or("-".to_string(), format_utc);
+        rows.push([path, size, chunks, mtime, note]);
+    }
+    let mut widths = [0; 4];
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+    rows.into_iter()
+        .map(|[path, size, chunks, mtime, note]| {
+            let mut line = format!(
+                "{:<path_w$}  {:>size_w$}  {:>chunks_w$}",
+                path,
+                size,
+                chunks,
+                path_w = widths[0],
+                size_w = widths[1],
+                chunks_w = widths[2],
+            );
+            if with_mtime {
+                line.push_str(&format!("  {:<w$}", mtime, w = widths[3]));
+            }
+            if !note.is_empty() {
+                line.push_str("  ");
+                line.push_str(&note);
+            }
+            line.trim_end().to_string()
+        })
+        .collect()
+}
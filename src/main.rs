@@ -1,5 +1,19 @@
-use clap::{Parser, Subcommand};
-use gidrive::api;
+use clap::{
+    ArgAction, ArgGroup, ArgMatches, Args, CommandFactory, FromArgMatches, Parser, Subcommand,
+    ValueEnum,
+};
+use clap_complete::Shell;
+use gidrive::config::{self, config_path, settings, Config};
+use gidrive::exclude::{ExcludeOptions, ExcludeRule, LinkPolicy};
+use gidrive::export::{self, ScriptTransport};
+use gidrive::models::ChecksumAlgo;
+use gidrive::{
+    api, cache, daemon, doctor, git, metadata, progress, repos, serve, signing, status, usage,
+    utils, watch,
+};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
 // ──────────────────────────────────────────────────────────────
 // CLI definition
@@ -9,47 +23,1318 @@ use gidrive::api;
 struct Cli {
     #[command(subcommand)]
     command: Commands,
+    /// Print where the time of an upload/download went
+    #[arg(long, global = true)]
+    timings: bool,
+    /// Independent file tree to work in [default: default]
+    #[arg(long, global = true)]
+    namespace: Option<String>,
+    /// Repos cloned, pushed or downloaded at the same time [default: 4]
+    #[arg(long, global = true)]
+    transfer_concurrency: Option<usize>,
+    /// Disk the cached storage repo clones may take, like 2GiB [default: 2GiB]
+    #[arg(long, global = true, value_name = "SIZE")]
+    repo_cache_size: Option<String>,
+    /// Threads hashing files at the same time [default: one per core]
+    #[arg(long, global = true)]
+    hash_threads: Option<usize>,
+    /// Print sizes in powers of 1000 (kB, MB) instead of 1024 (KiB, MiB)
+    #[arg(long, global = true)]
+    si: bool,
+    /// Write to a metadata repo stamped by an incompatible version, at your own risk
+    #[arg(long, global = true)]
+    force_write: bool,
+    /// Read metadata whose signature does not check out, at your own risk
+    #[arg(long, global = true)]
+    no_verify: bool,
+    /// Refuse anything that would create, delete or push to a repo, also read_only = true
+    #[arg(long, global = true)]
+    read_only: bool,
+    /// Read the metadata and storage repos from the local cache without reaching GitHub, refusing every write
+    #[arg(long, global = true)]
+    offline: bool,
+    /// Read the metadata from this repo instead of the configured account's, like git@github.com:alice/metadata.git
+    #[arg(long, global = true, value_name = "URL")]
+    metadata_repo: Option<String>,
+    /// Read the storage repos from this account, by default the one in --metadata-repo
+    #[arg(long, global = true, value_name = "USER")]
+    owner: Option<String>,
+    /// Allow writes to the repos --metadata-repo and --owner point at
+    #[arg(long, global = true)]
+    allow_write: bool,
+    /// Upload files, or batches of files, bigger than max_upload_size [default: 5GiB]
+    #[arg(long, global = true)]
+    allow_large: bool,
+    /// Read pushed chunks back from GitHub and push those that differ again, also verify_upload = true
+    #[arg(long, global = true)]
+    verify_upload: bool,
+    /// Answer yes to every confirmation, also GIDRIVE_ASSUME_YES=1
+    #[arg(short, long, global = true)]
+    yes: bool,
+    /// Print details, like the paths --exclude and .gidriveignore files leave out and git transfers per repo;
+    /// -vv also the download schedule and git output as it comes
+    #[arg(short, long, global = true, action = ArgAction::Count)]
+    verbose: u8,
+    /// Progress output on stderr: human, or json events for other programs
+    ///
+    /// With json, git output and status lines are left out and progress is
+    /// written to stderr as newline-delimited JSON objects whose "event"
+    /// field is one of:
+    ///   transfer_started  direction ("upload"/"download"), remote, bytes, total_chunks
+    ///   repo_started      repo, chunks
+    ///   chunk_uploaded    index, repo, bytes, total_chunks
+    ///   chunk_downloaded  index, repo, bytes, total_chunks
+    ///   repo_finished     repo, chunks, seconds
+    ///   transfer_finished direction, remote, bytes, chunks, seconds
+    ///   git_transfer      direction ("fetch"/"push"), repo, objects, total_objects, bytes
+    /// Errors still end the process with a plain message and exit code 101.
+    #[arg(
+        long,
+        global = true,
+        value_enum,
+        default_value = "human",
+        verbatim_doc_comment
+    )]
+    progress: Progress,
+}
+
+/// Which paths of a local directory recursive operations leave out.
+#[derive(Args)]
+struct ExcludeArgs {
+    /// Leave out paths matching a gitignore pattern, like node_modules/ (repeatable);
+    /// .gidriveignore files in the directory add more
+    #[arg(long, value_name = "PATTERN")]
+    exclude: Vec<String>,
+    /// Keep paths matching a pattern that an earlier --exclude or an ignore file leaves out
+    #[arg(long, value_name = "PATTERN")]
+    include: Vec<String>,
+    /// Do not leave out .git/, *.tmp and .DS_Store
+    #[arg(long)]
+    no_default_excludes: bool,
+    /// Store what symlinks point to instead of skipping them, each dir once
+    #[arg(long, conflicts_with = "preserve_symlinks")]
+    follow_symlinks: bool,
+    /// Store symlinks as links, recreated on download
+    #[arg(long)]
+    preserve_symlinks: bool,
+}
+
+impl ExcludeArgs {
+    fn links(&self) -> LinkPolicy {
+        if self.follow_symlinks {
+            LinkPolicy::Follow
+        } else if self.preserve_symlinks {
+            LinkPolicy::Preserve
+        } else {
+            LinkPolicy::Skip
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, ValueEnum)]
+enum Progress {
+    Human,
+    Json,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum MapFormat {
+    Csv,
+    Json,
+    Ndjson,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Sort {
+    Name,
+    Size,
+    Mtime,
 }
 
 #[derive(Subcommand)]
 enum Commands {
-    /// Upload a file: you must pass <REMOTE> and <LOCAL>
-    Upload { remote: String, local: String },
-    /// Download a file: you must pass <REMOTE> and <LOCAL>
-    Download { remote: String, local: String },
+    /// Upload a file: pass <REMOTE> <LOCAL>, or only <LOCAL> to upload it to the root
+    #[command(visible_alias = "put")]
+    Upload {
+        #[arg(value_name = "REMOTE")]
+        first: String,
+        #[arg(value_name = "LOCAL")]
+        second: Option<String>,
+        /// Print the planned chunk and repo assignment without uploading
+        #[arg(long)]
+        dry_run: bool,
+        /// Checksum algorithm: sha256 or blake3 (faster on multi-core machines)
+        #[arg(long, default_value = "sha256")]
+        checksum: ChecksumAlgo,
+        /// Store the file in public repos, downloadable by anyone (see `share`)
+        #[arg(long)]
+        public: bool,
+        /// Skip the upload when <REMOTE> already has the same content
+        #[arg(long)]
+        if_changed: bool,
+        /// Upload the directory <LOCAL> as one tar+zstd file (see `download --extract`)
+        #[arg(long)]
+        archive: bool,
+        /// Store the extended attributes of <LOCAL> too, macOS resource forks and Finder
+        /// tags among them; values over 64KiB are skipped
+        #[arg(long, conflicts_with = "archive")]
+        xattrs: bool,
+        #[command(flatten)]
+        excludes: ExcludeArgs,
+    },
+    /// Download a file to <LOCAL> (default ./<name>, a directory, or - for stdout)
+    #[command(visible_alias = "get")]
+    Download {
+        remote: String,
+        local: Option<String>,
+        /// Overwrite <LOCAL> if it already exists
+        #[arg(short, long)]
+        force: bool,
+        /// Unpack the archive <REMOTE> into the directory <LOCAL> [default: .]
+        #[arg(long)]
+        extract: bool,
+        /// Only check that every chunk and the whole file match their checksums:
+        /// exits 0 if intact, 1 if not, writing nothing
+        #[arg(long, conflicts_with_all = ["local", "force", "extract"])]
+        verify_only: bool,
+        /// Read the file as it was at a metadata commit or a date, like "2 days ago"
+        #[arg(long, value_name = "COMMIT_OR_DATE")]
+        at: Option<String>,
+        /// Set the extended attributes stored with `upload --xattrs` on <LOCAL>
+        #[arg(long, conflicts_with_all = ["extract", "verify_only"])]
+        xattrs: bool,
+    },
+    /// Append <LOCAL> (or - for stdin) to the end of an existing remote file
+    Append { remote: String, local: String },
+    /// Upload the file at an http(s) <URL> to <REMOTE> as it downloads, resuming dropped transfers
+    Fetch {
+        url: String,
+        remote: String,
+        /// Fail unless the downloaded file has this sha256, before anything is recorded
+        #[arg(long, value_name = "HEX")]
+        expected_sha256: Option<String>,
+        /// Print the planned chunk and repo assignment without uploading
+        #[arg(long)]
+        dry_run: bool,
+        /// Checksum algorithm: sha256 or blake3 (faster on multi-core machines)
+        #[arg(long, default_value = "sha256")]
+        checksum: ChecksumAlgo,
+        /// Store the file in public repos, downloadable by anyone (see `share`)
+        #[arg(long)]
+        public: bool,
+    },
+    /// Compare a local file or directory against remote: exits 0 if identical, 1 if not
+    Diff {
+        remote: String,
+        local: String,
+        /// Compare checksums of same-sized files too
+        #[arg(long)]
+        checksum: bool,
+        #[command(flatten)]
+        excludes: ExcludeArgs,
+    },
+    /// Upload files created or modified in <LOCAL_DIR> to <REMOTE_PREFIX> until Ctrl-C
+    Watch {
+        remote_prefix: String,
+        local_dir: PathBuf,
+        /// Seconds a file must stay unchanged before it is uploaded
+        #[arg(long, default_value_t = 2.0)]
+        debounce: f64,
+        /// Also remove remote files whose local copy was deleted
+        #[arg(long)]
+        delete: bool,
+        /// Upload the files that settled together smallest first, false for the order they changed in
+        #[arg(long, default_value_t = true, action = ArgAction::Set, value_name = "BOOL")]
+        small_first: bool,
+        #[command(flatten)]
+        excludes: ExcludeArgs,
+        /// Keep Prometheus metrics in this file for a textfile collector, like /var/lib/node_exporter/gidrive.prom
+        #[arg(long, value_name = "PATH")]
+        metrics_file: Option<PathBuf>,
+        /// Seconds between rewrites of --metrics-file
+        #[arg(long, default_value_t = 15.0, requires = "metrics_file")]
+        metrics_interval: f64,
+    },
+    /// Serve the remote files over a local HTTP API and read-only WebDAV at /dav/, authenticated with serve.token from the config
+    Serve {
+        #[arg(long, default_value = "127.0.0.1:7070")]
+        listen: String,
+    },
+    /// Keep the metadata and fetched chunks warm for later commands, on a unix socket in the cache dir
+    CacheDaemon,
+    /// Inspect or empty the chunk cache of the cache daemon
+    Cache {
+        #[command(subcommand)]
+        command: CacheCommand,
+    },
+    /// Print a shell script that restores <REMOTE> with plain git, without gidrive
+    ExportScript {
+        remote: String,
+        /// Fetch chunks with curl from raw.githubusercontent.com using a token
+        #[arg(long)]
+        curl: bool,
+    },
+    /// Print where every chunk is stored: file, chunk index, owner, repo, chunk path,
+    /// size, checksum, public, encrypted, compressed; read from the metadata only
+    ExportMap {
+        /// Only the files under this remote directory
+        #[arg(long, value_name = "DIR")]
+        prefix: Option<String>,
+        /// csv with a header line, a json array, or ndjson, an object per line
+        #[arg(long, value_enum, default_value = "csv")]
+        format: MapFormat,
+    },
+    /// Print what a third party needs to download a file uploaded with --public
+    Share { remote: String },
+    /// Make <PATH> of an existing repo <SOURCE> (owner/name) available as <REMOTE>
+    Adopt {
+        source: String,
+        path: String,
+        remote: String,
+        /// Upload a copy into the storage repos instead of pointing at the source repo
+        #[arg(long)]
+        copy: bool,
+    },
+    /// Inspect and manage the storage repos
+    Repos {
+        #[command(subcommand)]
+        command: ReposCommand,
+    },
+    /// Create and initialize the metadata repo, which every other command does when needed
+    Init {
+        /// Initialize a metadata repo that has content but no repos.json
+        #[arg(long)]
+        force: bool,
+        /// Only check that the metadata repo of another machine is usable here and summarize it
+        #[arg(long, conflicts_with = "force")]
+        from_existing: bool,
+    },
+    /// Check the config, credentials, token scopes and metadata repo access
+    Doctor,
+    /// Summarize the drive: metadata reachability, last write, totals, repo fill, stale locks, last fsck
+    Status {
+        /// Print a JSON object instead, for monitoring
+        #[arg(long)]
+        json: bool,
+    },
+    /// Bytes pushed and fetched, git operations and API calls of this profile, by day
+    Stats {
+        /// Days within this long ago, in s, m, h, d or w
+        #[arg(long, default_value = "30d")]
+        since: String,
+        /// Print a JSON object instead
+        #[arg(long)]
+        json: bool,
+    },
+    /// Create a key signing the metadata on every write, and trust it for reads
+    Keygen {
+        /// Replace the existing key, then run `sign` and update other machines
+        #[arg(long)]
+        rotate: bool,
+    },
+    /// Sign the metadata as it is now with the configured key
+    Sign,
+    /// Read, change and check the settings in the config file
+    Config {
+        #[command(subcommand)]
+        command: ConfigCommand,
+    },
+    /// Print a remote file to stdout
+    Cat { remote: String },
     /// List files
-    Ls,
-    /// Clean temporary or cached files
-    Clean,
+    Ls {
+        /// Fail on a corrupt metadata file instead of skipping it
+        #[arg(long)]
+        strict: bool,
+        /// Order by path, size or upload time, ties broken by path
+        #[arg(long, value_enum, default_value = "name")]
+        sort: Sort,
+        /// Reverse the order, largest or newest first
+        #[arg(long)]
+        reverse: bool,
+        /// List the subdirectories of a directory before its files
+        #[arg(long)]
+        group_dirs: bool,
+        /// Print a JSON array of {path, size, checksum, mtime, chunks} in list order
+        #[arg(long)]
+        json: bool,
+        /// Print "<sha256>  <path>" lines that `verify --manifest` and `sha256sum -c` check
+        #[arg(long, conflicts_with = "json")]
+        checksums: bool,
+        /// Print one tab-separated line per file, a format kept stable for scripts:
+        /// kind (file, archive, symlink), size in bytes, chunks, mtime in epoch
+        /// seconds or "-", checksum algorithm, checksum, path, symlink target.
+        /// Backslash, tab, CR and LF in paths are escaped as \\, \t, \r and \n
+        #[arg(long, conflicts_with_all = ["json", "checksums"])]
+        porcelain: bool,
+        /// List the files as they were at a metadata commit or a date, like "2024-05-01 12:00"
+        #[arg(long, value_name = "COMMIT_OR_DATE")]
+        at: Option<String>,
+    },
+    /// Check remote files against a sha256sum manifest like `ls --checksums` writes:
+    /// exits 0 if every listed file matches, 1 if not
+    Verify {
+        /// The manifest, - for stdin
+        #[arg(long, value_name = "FILE")]
+        manifest: String,
+        /// Also fetch each file and check its chunks and content
+        #[arg(long)]
+        deep: bool,
+    },
+    /// Report metadata files that cannot be read, in every namespace, and a stale chunk index
+    Fsck {
+        /// Rewrite chunks.idx from the files of every namespace
+        #[arg(long)]
+        rebuild_index: bool,
+        /// Move the metadata files into this many shards, 0 for one tree; versions
+        /// without sharding cannot write to a sharded tree
+        #[arg(long, value_name = "SHARDS", conflicts_with = "rebuild_index",
+              value_parser = clap::value_parser!(u32).range(0..=4096))]
+        reshard: Option<u32>,
+    },
+    /// Show the metadata version, the namespace totals and the storage repos
+    Info {
+        /// Also list the last clients that wrote the metadata: time, version, host, operation
+        #[arg(long)]
+        clients: bool,
+        /// Also compare the size of all files with the space their chunks take once
+        #[arg(long)]
+        savings: bool,
+        /// List the entries of an archive uploaded with --archive instead
+        #[arg(long, value_name = "REMOTE")]
+        archive_list: Option<String>,
+    },
+    /// Remove a file, or with -r every file under a directory, in one metadata commit;
+    /// the chunks wait for gc
+    Rm {
+        remote: String,
+        /// Remove the directory <REMOTE> and everything under it
+        #[arg(short, long)]
+        recursive: bool,
+        /// List the files that would be removed without removing anything
+        #[arg(long)]
+        dry_run: bool,
+        /// Allow removing the root, every file of the namespace
+        #[arg(long)]
+        force: bool,
+    },
+    /// Rename a file, or every file under a directory, in one metadata commit; no chunk moves
+    Mv {
+        src: String,
+        /// New path, or a directory (ending in /) to move the file into
+        dst: String,
+        /// List the renames without making them
+        #[arg(long)]
+        dry_run: bool,
+        /// Allow moving from the root
+        #[arg(long)]
+        force: bool,
+    },
+    /// Reclaim space: delete one file, a directory, empty repos, or everything
+    #[command(group(ArgGroup::new("mode").args(["file", "prefix", "empty_repos", "all", "local"])))]
+    Clean {
+        /// Delete one remote file with its chunks
+        #[arg(long, value_name = "REMOTE")]
+        file: Option<String>,
+        /// Delete every remote file under a directory
+        #[arg(long, value_name = "DIR")]
+        prefix: Option<String>,
+        /// Delete storage repos that hold no chunks
+        #[arg(long)]
+        empty_repos: bool,
+        /// Delete the storage repos in repos.json, then the metadata repo
+        #[arg(long)]
+        all: bool,
+        /// Delete the local clones of storage repos cached for downloads
+        #[arg(long)]
+        local: bool,
+        /// Print what would be deleted without deleting anything
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Delete the chunks no file has used for longer than gc_grace_period (7 days by default)
+    Gc {
+        /// Delete every chunk no file uses, however recently it was released
+        #[arg(long)]
+        now: bool,
+        /// Print what would be deleted without deleting anything
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Finish the uploads, rm, clean and gc runs a crash or kill interrupted, as listed by --list
+    Resume {
+        /// Only list the interrupted operations
+        #[arg(long)]
+        list: bool,
+        /// Roll back the operation <ID> instead of finishing it
+        #[arg(long, value_name = "ID", conflicts_with = "list")]
+        abort: Option<String>,
+    },
+    /// Print a shell completion script for <SHELL> to stdout
+    #[command(hide = true)]
+    Completions { shell: Shell },
+    /// List the remote paths starting with <PREFIX> from the metadata cache,
+    /// one per line, for the completion scripts
+    #[command(name = "__complete-remote", hide = true)]
+    CompleteRemote {
+        #[arg(default_value = "")]
+        prefix: String,
+    },
+}
+
+#[derive(Subcommand)]
+enum ReposCommand {
+    /// List the storage repos with their size, fill level and chunk count
+    List,
+    /// List the chunks of a repo and the files referencing them
+    Show { repo: String },
+    /// Stop assigning new chunks to a repo, its chunks stay readable
+    Retire { repo: String },
+}
+
+#[derive(Subcommand)]
+enum CacheCommand {
+    /// Print the hit counters and the chunk cache fill of the running daemon
+    Stats,
+    /// Remove every cached chunk and make the daemon fetch the metadata again
+    Clear,
+}
+
+#[derive(Subcommand)]
+enum ConfigCommand {
+    /// Print every setting with its value, configured or default
+    List {
+        /// Tell for every value whether it comes from a flag, the environment, the file or the default
+        #[arg(long)]
+        show_origin: bool,
+    },
+    /// Print the value of one setting, like github.username
+    Get { key: String },
+    /// Change a setting in the config file, creating the file if needed
+    Set { key: String, value: String },
+    /// Check the config file, including that the SSH key exists
+    Validate,
+    /// Open the config file in $EDITOR and check it afterwards
+    Edit,
+}
+
+/// The --exclude and --include flags of the subcommand in `matches`, in
+/// the order they were given, which decides between overlapping patterns.
+fn exclude_options(matches: &ArgMatches, args: &ExcludeArgs) -> ExcludeOptions {
+    let mut rules = Vec::new();
+    if let Some((_, sub)) = matches.subcommand() {
+        for (id, rule) in [
+            ("exclude", ExcludeRule::Exclude as fn(String) -> ExcludeRule),
+            ("include", ExcludeRule::Include),
+        ] {
+            let (Some(indices), Some(values)) = (sub.indices_of(id), sub.get_many::<String>(id))
+            else {
+                continue;
+            };
+            rules.extend(indices.zip(values.map(|v| rule(v.clone()))));
+        }
+    }
+    rules.sort_by_key(|(index, _)| *index);
+    ExcludeOptions {
+        rules: rules.into_iter().map(|(_, rule)| rule).collect(),
+        no_default_excludes: args.no_default_excludes,
+        links: args.links(),
+    }
+}
+
+/// The `ls` flags as the `api::LsOptions` they select.
+fn ls_options(
+    strict: bool,
+    sort: Sort,
+    reverse: bool,
+    group_dirs: bool,
+    json: bool,
+    checksums: bool,
+    porcelain: bool,
+) -> api::LsOptions {
+    api::LsOptions {
+        strict,
+        sort: match sort {
+            Sort::Name => api::LsSort::Name,
+            Sort::Size => api::LsSort::Size,
+            Sort::Mtime => api::LsSort::Mtime,
+        },
+        reverse,
+        group_dirs,
+        json,
+        checksums,
+        porcelain,
+    }
+}
+
+/// `cache stats`: the counters of the running daemon.
+fn cache_stats() -> anyhow::Result<()> {
+    let Some(stats) = daemon::stats()? else {
+        println!(
+            "no cache daemon listens on {}",
+            daemon::socket_path().display()
+        );
+        return Ok(());
+    };
+    let lookups = stats.chunk_hits + stats.chunk_misses;
+    println!("daemon:     {} for {}s", stats.metadata, stats.uptime);
+    println!(
+        "metadata:   {} listings, {} fetches",
+        stats.listings, stats.metadata_fetches
+    );
+    println!(
+        "chunks:     {} hits, {} misses{}",
+        stats.chunk_hits,
+        stats.chunk_misses,
+        stats
+            .chunk_hits
+            .saturating_mul(100)
+            .checked_div(lookups)
+            .map(|rate| format!(", {}% hit rate", rate))
+            .unwrap_or_default()
+    );
+    println!(
+        "cache:      {} chunks, {} of {}",
+        stats.chunks,
+        utils::human_size(stats.bytes),
+        utils::human_size(stats.max_bytes)
+    );
+    Ok(())
+}
+
+/// Prints a human status line, left out when stderr carries JSON events.
+fn status(line: &str) {
+    if !progress::json_enabled() {
+        eprintln!("{line}");
+    }
 }
 
+/// Resolves `upload <REMOTE> <LOCAL>` and the short `upload <LOCAL>` form
+/// into (remote, local); the short form uploads to the root under the file name.
+fn upload_paths(first: &str, second: Option<&str>) -> (String, String) {
+    match second {
+        Some(local) => (first.to_string(), local.to_string()),
+        None => {
+            let Some(name) = Path::new(first).file_name() else {
+                eprintln!("--- cannot derive a remote name from {first:?}, pass <REMOTE> <LOCAL>");
+                std::process::exit(1);
+            };
+            (name.to_string_lossy().into_owned(), first.to_string())
+        }
+    }
+}
+
+/// Resolves where `download` writes to, `None` meaning stdout.
+/// Without <LOCAL> the file lands in the current directory under its remote
+/// name, and an existing directory as <LOCAL> receives the file inside it.
+fn download_path(remote: &str, local: Option<&str>) -> Option<PathBuf> {
+    let remote_name = || {
+        let Some(name) = Path::new(remote).file_name() else {
+            eprintln!("--- cannot derive a local name from {remote:?}, pass <LOCAL>");
+            std::process::exit(1);
+        };
+        PathBuf::from(name)
+    };
+    match local {
+        Some("-") => None,
+        None => Some(remote_name()),
+        Some(local) if Path::new(local).is_dir() => Some(Path::new(local).join(remote_name())),
+        Some(local) => Some(PathBuf::from(local)),
+    }
+}
+
+/// `script`, the completion script clap generated for `shell`, made to
+/// complete the remote path of `download`, `rm` and `info` with
+/// `gidrive __complete-remote`. Only where it directly follows the
+/// subcommand: after global flags it falls back to clap's completion.
+fn with_remote_completion(shell: Shell, script: String) -> String {
+    match shell {
+        Shell::Bash => script + BASH_REMOTE_COMPLETION,
+        // the trailer registers `_gidrive`, or runs it when autoloaded
+        Shell::Zsh => match script.rfind("if [ \"$funcstack[1]\" = \"_gidrive\" ]") {
+            Some(trailer) => script[..trailer].to_string() + ZSH_REMOTE_COMPLETION,
+            None => script,
+        },
+        Shell::Fish => script + FISH_REMOTE_COMPLETION,
+        _ => script,
+    }
+}
+
+const BASH_REMOTE_COMPLETION: &str = r#"
+_gidrive_remote() {
+    if [[ "$COMP_CWORD" -eq 2 && "${COMP_WORDS[1]}" =~ ^(download|rm|info)$ ]]; then
+        local IFS=$'\n'
+        COMPREPLY=( $(gidrive __complete-remote "${COMP_WORDS[2]}" 2>/dev/null) )
+        return 0
+    fi
+    _gidrive "$@"
+}
+complete -F _gidrive_remote -o bashdefault -o default gidrive
+"#;
+
+const ZSH_REMOTE_COMPLETION: &str = r#"_gidrive_remote() {
+    if (( CURRENT == 3 )) && [[ ${words[2]} == (download|rm|info) ]]; then
+        local -a remotes
+        remotes=( ${(f)"$(gidrive __complete-remote "$PREFIX" 2>/dev/null)"} )
+        compadd -a remotes
+    else
+        _gidrive "$@"
+    fi
+}
+
+compdef _gidrive_remote gidrive
+if [ "$funcstack[1]" = "_gidrive" ]; then
+    _gidrive_remote "$@"
+fi
+"#;
+
+const FISH_REMOTE_COMPLETION: &str = r#"complete -c gidrive -n "__fish_seen_subcommand_from download rm info; and test (count (commandline -opc)) -eq 2" -f -a "(gidrive __complete-remote (commandline -ct) 2>/dev/null)"
+"#;
+
 // ──────────────────────────────────────────────────────────────
 // Entry point
 // ──────────────────────────────────────────────────────────────
 fn main() {
-    let cli = Cli::parse();
+    let matches = Cli::command().get_matches();
+    let cli = Cli::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+
+    // completions must not touch the network, handle them before init
+    if let Commands::Completions { shell } = cli.command {
+        let mut cmd = Cli::command();
+        let name = cmd.get_name().to_string();
+        let mut script = Vec::new();
+        clap_complete::generate(shell, &mut cmd, name, &mut script);
+        let script = with_remote_completion(shell, String::from_utf8_lossy(&script).into_owned());
+        // a closed pipe ends the script, as with any output
+        let _ = io::stdout().write_all(script.as_bytes());
+        return;
+    }
+
+    // settings given as flags, applied over GIDRIVE_* variables and the file
+    let mut overrides = Vec::new();
+    if let Some(n) = cli.transfer_concurrency {
+        overrides.push((
+            "transfer_concurrency",
+            "--transfer-concurrency",
+            n.to_string(),
+        ));
+    }
+    if let Some(n) = cli.hash_threads {
+        overrides.push(("hash_threads", "--hash-threads", n.to_string()));
+    }
+    if let Some(size) = &cli.repo_cache_size {
+        overrides.push(("repo_cache_size", "--repo-cache-size", size.clone()));
+    }
+    if cli.read_only {
+        overrides.push(("read_only", "--read-only", "true".to_string()));
+    }
+    if cli.verify_upload {
+        overrides.push(("verify_upload", "--verify-upload", "true".to_string()));
+    }
+
+    // the config file is local, these never need init either
+    if let Commands::Config { command } = &cli.command {
+        let res = match command {
+            ConfigCommand::List { show_origin } => config::list(*show_origin, &overrides),
+            ConfigCommand::Get { key } => config::get(key, &overrides),
+            ConfigCommand::Set { key, value } => config::set(key, value),
+            ConfigCommand::Validate => config::validate(&overrides),
+            ConfigCommand::Edit => config::edit(),
+        };
+        if let Err(e) = res {
+            eprintln!("--- config: {e:#}");
+            std::process::exit(1);
+        }
+        return;
+    }
+    if let Commands::Keygen { rotate } = cli.command {
+        if let Err(e) = signing::keygen(rotate) {
+            eprintln!("--- keygen: {e:#}");
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    // `put local remote` habits from other tools would silently upload the
+    // wrong way round, catch the obvious case before touching anything
+    if let Commands::Upload { first, second, .. } = &cli.command {
+        let (remote, local) = upload_paths(first, second.as_deref());
+        if !cli.yes && !Path::new(&local).exists() && Path::new(&remote).exists() {
+            eprintln!(
+                "--- local file {local:?} does not exist but {remote:?} does, \
+                 did you swap <REMOTE> and <LOCAL>? pass --yes to upload anyway"
+            );
+            std::process::exit(1);
+        }
+    }
+
+    if let Commands::Download {
+        remote,
+        local,
+        force,
+        extract: false,
+        verify_only: false,
+        ..
+    } = &cli.command
+    {
+        if let Some(path) = download_path(remote, local.as_deref()) {
+            if !force && path.exists() {
+                eprintln!("--- {path:?} already exists, pass --force to overwrite it");
+                std::process::exit(1);
+            }
+        }
+    }
+
+    if let Commands::Clean {
+        file,
+        prefix,
+        empty_repos,
+        all,
+        local,
+        ..
+    } = &cli.command
+    {
+        let no_mode = file.is_none() && prefix.is_none() && !empty_repos && !all && !local;
+        if no_mode && cli.namespace.is_none() {
+            eprintln!(
+                "--- pass --file, --prefix, --empty-repos, --namespace, --all or --local to clean"
+            );
+            std::process::exit(1);
+        }
+    }
+
+    if cli.si {
+        utils::use_si_sizes();
+    }
+    if cli.yes {
+        utils::assume_yes();
+    }
+    if cli.verbose > 0 {
+        utils::set_verbosity(cli.verbose);
+    }
+    if cli.force_write {
+        metadata::force_write();
+    }
+    if cli.allow_large {
+        api::allow_large();
+    }
+    // signing accepts the metadata as it is, whatever signed it before
+    if cli.no_verify || matches!(cli.command, Commands::Sign) {
+        signing::skip_verify();
+    }
+    if cli.progress == Progress::Json {
+        progress::enable_json();
+    }
+
+    if let Some(namespace) = &cli.namespace {
+        if let Err(e) = metadata::set_namespace(namespace) {
+            eprintln!("--- {e}");
+            std::process::exit(1);
+        }
+    }
+    if let Commands::Ls { at: Some(at), .. } | Commands::Download { at: Some(at), .. } =
+        &cli.command
+    {
+        metadata::set_revision(at);
+    }
+
+    match Config::load_with(&overrides) {
+        Ok(config) => config::activate(config),
+        Err(e) => {
+            eprintln!("--- {e:#}");
+            std::process::exit(1);
+        }
+    }
+    // written when main returns or unwinds, process::exit needs a flush
+    let _usage = usage::FlushOnDrop;
+    if settings().read_only.unwrap_or(false) {
+        git::set_read_only();
+    }
+    if cli.offline {
+        git::set_offline();
+    }
+    if cli.metadata_repo.is_some() || cli.owner.is_some() {
+        if let Err(e) = git::set_remote_override(
+            cli.metadata_repo.clone(),
+            cli.owner.clone(),
+            cli.allow_write,
+        ) {
+            eprintln!("--- {e:#}");
+            std::process::exit(1);
+        }
+    }
+
+    // diagnoses what init needs, so it runs without it
+    if let Commands::Doctor = cli.command {
+        let res = doctor::doctor();
+        utils::remove_invocation_dir();
+        match res {
+            Ok(true) => return,
+            Ok(false) => std::process::exit(1),
+            Err(e) => panic!("--- doctor returned err: {e}"),
+        }
+    }
+
+    // reports an unreachable metadata repo instead of failing in init
+    if let Commands::Status { json } = cli.command {
+        let res = status::status(json);
+        utils::remove_invocation_dir();
+        match res {
+            Ok(true) => return,
+            Ok(false) => std::process::exit(1),
+            Err(e) => panic!("--- status returned err: {e}"),
+        }
+    }
+
+    // asked on every tab, answered from the metadata cache only: no prompt,
+    // no network, nothing but paths on stdout
+    if let Commands::CompleteRemote { prefix } = &cli.command {
+        for path in cache::cached_remote_paths(prefix).unwrap_or_default() {
+            println!("{path}");
+        }
+        return;
+    }
+
+    // the usage counters are local
+    if let Commands::Stats { since, json } = &cli.command {
+        if let Err(e) = usage::stats(since, *json) {
+            eprintln!("--- stats: {e:#}");
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    // the repo cache is local, purging it needs no access to GitHub
+    if let Commands::Clean {
+        local: true,
+        dry_run,
+        ..
+    } = cli.command
+    {
+        match api::clean_local(dry_run) {
+            Ok(_) => status("--- clean done"),
+            Err(e) => panic!("--- clean returned err: {e}"),
+        }
+        return;
+    }
+
+    if let Commands::Cache { command } = &cli.command {
+        let res = match command {
+            CacheCommand::Stats => cache_stats(),
+            CacheCommand::Clear => daemon::clear().map(|(chunks, bytes)| {
+                println!(
+                    "removed {} cached chunks, {}",
+                    chunks,
+                    utils::human_size(bytes)
+                )
+            }),
+        };
+        if let Err(e) = res {
+            eprintln!("--- cache: {e:#}");
+            std::process::exit(1);
+        }
+        return;
+    }
 
-    match api::init() {
-        Ok(_) => println!("--- init done"),
+    // a cache daemon lists from its warm metadata clone, without init
+    if let Commands::Ls {
+        strict,
+        sort,
+        reverse,
+        group_dirs,
+        json,
+        checksums,
+        porcelain,
+        at: None,
+    } = cli.command
+    {
+        if let Some((files, corrupt)) = daemon::list() {
+            let opts = ls_options(
+                strict, sort, reverse, group_dirs, json, checksums, porcelain,
+            );
+            match api::print_listing(files, &corrupt, &opts) {
+                Ok(_) => status("--- list done"),
+                Err(e) => panic!("--- ls returned err: {e}"),
+            }
+            utils::remove_invocation_dir();
+            return;
+        }
+    }
+
+    let init_opts = match cli.command {
+        Commands::Init {
+            force,
+            from_existing,
+        } => api::InitOptions {
+            force,
+            from_existing,
+        },
+        _ => api::InitOptions::default(),
+    };
+    match api::init(settings(), &init_opts) {
+        Ok(_) => status("--- init done"),
         Err(e) => panic!("--- init returned err:{e}"),
     }
 
     match cli.command {
-        Commands::Upload { remote, local } => match api::upload(&remote, &local) {
-            Ok(_) => println!("--- upload done"),
-            Err(e) => panic!("--- upload returned err: {e}"),
-        },
-        Commands::Download { remote, local } => match api::download(&remote, &local) {
-            Ok(_) => println!("--- download done"),
+        Commands::Upload {
+            first,
+            second,
+            dry_run,
+            checksum,
+            public,
+            if_changed,
+            archive,
+            xattrs,
+            excludes,
+        } => {
+            let (mut remote, local) = upload_paths(&first, second.as_deref());
+            if archive && second.is_none() {
+                remote.push_str(".tar.zst");
+            }
+            let opts = api::UploadOptions {
+                dry_run,
+                checksum_algo: checksum,
+                public,
+                if_changed,
+                links: excludes.links(),
+                xattrs,
+                ..Default::default()
+            };
+            let res = if archive {
+                let excludes = exclude_options(&matches, &excludes);
+                api::upload_archive(&remote, &local, &opts, &excludes)
+            } else {
+                api::upload(&remote, &local, &opts)
+            };
+            match res {
+                Ok(report) => {
+                    if cli.timings && !dry_run {
+                        report.print();
+                    }
+                    status("--- upload done")
+                }
+                Err(e) => panic!("--- upload returned err: {e}"),
+            }
+        }
+        Commands::Download {
+            remote,
+            verify_only: true,
+            ..
+        } => match api::verify_remote(&remote) {
+            Ok(true) => status("--- download done"),
+            Ok(false) => {
+                usage::flush();
+                progress::finish();
+                std::process::exit(1)
+            }
             Err(e) => panic!("--- download returned err: {e}"),
         },
-        Commands::Ls => match api::ls() {
-            Ok(_) => println!("--- list done"),
+        Commands::Download {
+            remote,
+            local,
+            extract,
+            xattrs,
+            ..
+        } => {
+            if xattrs {
+                api::restore_xattrs();
+            }
+            let res = match download_path(&remote, local.as_deref()) {
+                _ if extract => api::download_extract(&remote, local.as_deref().unwrap_or(".")),
+                Some(path) => api::download(&remote, &path.to_string_lossy()),
+                None => api::cat(&remote),
+            };
+            match res {
+                Ok(report) => {
+                    if cli.timings {
+                        report.print();
+                    }
+                    status("--- download done")
+                }
+                Err(e) => panic!("--- download returned err: {e}"),
+            }
+        }
+        Commands::Fetch {
+            url,
+            remote,
+            expected_sha256,
+            dry_run,
+            checksum,
+            public,
+        } => {
+            let opts = api::UploadOptions {
+                dry_run,
+                checksum_algo: checksum,
+                public,
+                ..Default::default()
+            };
+            match api::fetch(&url, &remote, expected_sha256.as_deref(), &opts) {
+                Ok(report) => {
+                    if cli.timings && !dry_run {
+                        report.print();
+                    }
+                    status("--- fetch done")
+                }
+                Err(e) => panic!("--- fetch returned err: {e}"),
+            }
+        }
+        Commands::Append { remote, local } => match api::append(&remote, &local) {
+            Ok(report) => {
+                if cli.timings {
+                    report.print();
+                }
+                status("--- append done")
+            }
+            Err(e) => panic!("--- append returned err: {e}"),
+        },
+        Commands::Diff {
+            remote,
+            local,
+            checksum,
+            excludes,
+        } => match api::diff(
+            &remote,
+            &local,
+            checksum,
+            &exclude_options(&matches, &excludes),
+        ) {
+            Ok(identical) => {
+                usage::flush();
+                progress::finish();
+                std::process::exit(if identical { 0 } else { 1 })
+            }
+            Err(e) => panic!("--- diff returned err: {e}"),
+        },
+        Commands::Watch {
+            remote_prefix,
+            local_dir,
+            debounce,
+            delete,
+            small_first,
+            excludes,
+            metrics_file,
+            metrics_interval,
+        } => match watch::watch(
+            &remote_prefix,
+            &local_dir,
+            Duration::from_secs_f64(debounce),
+            delete,
+            small_first,
+            &exclude_options(&matches, &excludes),
+            metrics_file
+                .map(|path| watch::MetricsFile {
+                    path,
+                    interval: Duration::from_secs_f64(metrics_interval),
+                })
+                .as_ref(),
+        ) {
+            Ok(_) => status("--- watch done"),
+            Err(e) => panic!("--- watch returned err: {e}"),
+        },
+        Commands::Serve { listen } => {
+            let Some(token) = settings()
+                .serve
+                .token
+                .as_deref()
+                .filter(|t| !t.trim().is_empty())
+            else {
+                eprintln!(
+                    "--- serve needs a bearer token, set serve.token in {}",
+                    config_path().display()
+                );
+                std::process::exit(1);
+            };
+            match serve::serve(&listen, token) {
+                Ok(_) => status("--- serve done"),
+                Err(e) => panic!("--- serve returned err: {e}"),
+            }
+        }
+        Commands::ExportMap { prefix, format } => {
+            let format = match format {
+                MapFormat::Csv => export::MapFormat::Csv,
+                MapFormat::Json => export::MapFormat::Json,
+                MapFormat::Ndjson => export::MapFormat::Ndjson,
+            };
+            match api::export_map(prefix.as_deref().unwrap_or(""), format) {
+                Ok(_) => status("--- export-map done"),
+                Err(e) => panic!("--- export-map returned err: {e}"),
+            }
+        }
+        Commands::ExportScript { remote, curl } => {
+            let transport = if curl {
+                ScriptTransport::Curl
+            } else {
+                ScriptTransport::Git
+            };
+            match api::export_script(&remote, transport) {
+                Ok(script) => {
+                    print!("{script}");
+                    status("--- export-script done")
+                }
+                Err(e) => panic!("--- export-script returned err: {e}"),
+            }
+        }
+        Commands::Share { remote } => match api::share(&remote) {
+            Ok(script) => {
+                print!("{script}");
+                status("--- share done")
+            }
+            Err(e) => panic!("--- share returned err: {e}"),
+        },
+        Commands::Adopt {
+            source,
+            path,
+            remote,
+            copy,
+        } => match api::adopt(&source, &path, &remote, copy) {
+            Ok(_) => status("--- adopt done"),
+            Err(e) => panic!("--- adopt returned err: {e}"),
+        },
+        Commands::Repos { command } => {
+            let res = match &command {
+                ReposCommand::List => repos::list(),
+                ReposCommand::Show { repo } => repos::show(repo),
+                ReposCommand::Retire { repo } => repos::retire(repo),
+            };
+            match res {
+                Ok(_) => status("--- repos done"),
+                Err(e) => panic!("--- repos returned err: {e}"),
+            }
+        }
+        Commands::Cat { remote } => match api::cat(&remote) {
+            Ok(report) => {
+                if cli.timings {
+                    report.print();
+                }
+                status("--- cat done")
+            }
+            Err(e) => panic!("--- cat returned err: {e}"),
+        },
+        Commands::Ls {
+            strict,
+            sort,
+            reverse,
+            group_dirs,
+            json,
+            checksums,
+            porcelain,
+            ..
+        } => match api::ls(&ls_options(
+            strict, sort, reverse, group_dirs, json, checksums, porcelain,
+        )) {
+            Ok(_) => status("--- list done"),
             Err(e) => panic!("--- ls returned err: {e}"),
         },
-        Commands::Clean => match api::clean() {
-            Ok(_) => println!("--- clean done"),
-            Err(e) => panic!("--- clean returned err: {e}"),
+        Commands::Verify { manifest, deep } => match api::verify_manifest(&manifest, deep) {
+            Ok(true) => status("--- verify done"),
+            Ok(false) => {
+                usage::flush();
+                progress::finish();
+                std::process::exit(1)
+            }
+            Err(e) => panic!("--- verify returned err: {e}"),
+        },
+        Commands::Info {
+            archive_list: Some(remote),
+            ..
+        } => match api::archive_list(&remote) {
+            Ok(_) => status("--- info done"),
+            Err(e) => panic!("--- info returned err: {e}"),
+        },
+        Commands::Info {
+            clients, savings, ..
+        } => match api::info(clients, savings) {
+            Ok(_) => status("--- info done"),
+            Err(e) => panic!("--- info returned err: {e}"),
+        },
+        Commands::Sign => match api::sign() {
+            Ok(_) => status("--- sign done"),
+            Err(e) => panic!("--- sign returned err: {e}"),
+        },
+        Commands::Fsck {
+            reshard: Some(shards),
+            ..
+        } => match api::reshard(shards) {
+            Ok(_) => status("--- fsck done"),
+            Err(e) => panic!("--- fsck returned err: {e}"),
+        },
+        Commands::Fsck { rebuild_index, .. } => match api::fsck(rebuild_index) {
+            Ok(true) => status("--- fsck done"),
+            Ok(false) => {
+                usage::flush();
+                progress::finish();
+                std::process::exit(1)
+            }
+            Err(e) => panic!("--- fsck returned err: {e}"),
+        },
+        Commands::Clean {
+            file,
+            prefix,
+            empty_repos,
+            all,
+            dry_run,
+            ..
+        } => {
+            let res = if let Some(remote) = file {
+                api::clean_file(&remote, dry_run)
+            } else if let Some(prefix) = prefix {
+                api::clean_prefix(&prefix, dry_run)
+            } else if empty_repos {
+                api::clean_empty_repos(dry_run)
+            } else if all {
+                api::clean(dry_run)
+            } else {
+                api::clean_namespace(dry_run)
+            };
+            match res {
+                Ok(_) => status("--- clean done"),
+                Err(e) => panic!("--- clean returned err: {e}"),
+            }
+        }
+        Commands::Rm {
+            remote,
+            recursive,
+            dry_run,
+            force,
+        } => match api::rm(&remote, recursive, dry_run, force) {
+            Ok(_) => status("--- rm done"),
+            Err(e) => panic!("--- rm returned err: {e}"),
+        },
+        Commands::Mv {
+            src,
+            dst,
+            dry_run,
+            force,
+        } => match api::mv(&src, &dst, dry_run, force) {
+            Ok(_) => status("--- mv done"),
+            Err(e) => panic!("--- mv returned err: {e}"),
+        },
+        Commands::Gc { now, dry_run } => match api::gc(now, dry_run) {
+            Ok(_) => status("--- gc done"),
+            Err(e) => panic!("--- gc returned err: {e}"),
+        },
+        Commands::Resume { list, abort } => match api::resume(list, abort.as_deref()) {
+            Ok(_) => status("--- resume done"),
+            Err(e) => panic!("--- resume returned err: {e}"),
+        },
+        Commands::CacheDaemon => match daemon::run() {
+            Ok(_) => status("--- cache daemon done"),
+            Err(e) => panic!("--- cache daemon returned err: {e}"),
         },
+        // init ran above with its options
+        Commands::Init { .. } => {}
+        Commands::Completions { .. }
+        | Commands::CompleteRemote { .. }
+        | Commands::Config { .. }
+        | Commands::Cache { .. }
+        | Commands::Doctor
+        | Commands::Status { .. }
+        | Commands::Stats { .. }
+        | Commands::Keygen { .. } => {
+            unreachable!()
+        }
     }
+    progress::finish();
+    utils::remove_invocation_dir();
 }
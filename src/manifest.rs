@@ -0,0 +1,61 @@
+//! Checksum manifests in the format of `sha256sum`: `ls --checksums` writes
+//! one, `verify --manifest` checks the drive against it, and `sha256sum -c`
+//! checks a download of the files with the same layout.
+
+/// A `sha256sum` line of `checksum` and `path`. Like sha256sum, a path with
+/// a backslash or a line break is escaped and the line starts with `\`.
+pub fn line(checksum: &str, path: &str) -> String {
+    if path.contains(['\\', '\n', '\r']) {
+        format!("\\{}  {}", checksum, escape(path))
+    } else {
+        format!("{}  {}", checksum, path)
+    }
+}
+
+/// `path` as sha256sum prints it in the lines of the manifest and of `-c`.
+pub fn escape(path: &str) -> String {
+    path.replace('\\', "\\\\")
+        .replace('\n', "\\n")
+        .replace('\r', "\\r")
+}
+
+fn unescape(path: &str) -> Option<String> {
+    let mut out = String::with_capacity(path.len());
+    let mut chars = path.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        match chars.next()? {
+            '\\' => out.push('\\'),
+            'n' => out.push('\n'),
+            'r' => out.push('\r'),
+            _ => return None,
+        }
+    }
+    Some(out)
+}
+
+/// The (checksum, path) of a manifest `line`, in text or `*` binary mode.
+/// None for a line of another format.
+pub fn parse_line(line: &str) -> Option<(String, String)> {
+    let (escaped, line) = match line.strip_prefix('\\') {
+        Some(rest) => (true, rest),
+        None => (false, line),
+    };
+    let (checksum, rest) = line.split_at_checked(64)?;
+    if !checksum.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+    let path = rest
+        .strip_prefix("  ")
+        .or_else(|| rest.strip_prefix(" *"))
+        .filter(|path| !path.is_empty())?;
+    let path = if escaped {
+        unescape(path)?
+    } else {
+        path.to_string()
+    };
+    Some((checksum.to_ascii_lowercase(), path))
+}
@@ -1,14 +1,707 @@
-use anyhow::{Context, Result};
+use anyhow::{anyhow, bail, Context, Result};
 use serde_json;
-use std::collections::BTreeMap;
+use sha2::{Digest, Sha256};
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt;
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::OnceLock;
+use std::time::{SystemTime, UNIX_EPOCH};
+use walkdir::WalkDir;
 
-use crate::constants::{MAX_SIZE_PER_REPO, TMPFS_DIR, VERSION};
-use crate::git::{create_repo, repo_exists};
-use crate::models::{RepoInfo, ReposMetadata};
+use crate::cache;
+use crate::config::{settings, AccountPolicy, PlacementStrategy};
+use crate::constants::{
+    CLIENTS_LOG_ENTRIES, COMMIT_MESSAGE_CHARS, DEFAULT_NAMESPACE, METADATA_REPLAYS,
+    METADATA_SHARDS, METADATA_SHARD_THRESHOLD, REMOTE_NAME_BYTES, UPLOAD_QUEUE_DEPTH, VERSION,
+};
+use crate::daemon;
+use crate::git::{
+    changed_files, check_writable, clone_repo, create_repo, file_at, git_add_commit,
+    git_checkout_at, git_push, git_refresh, head_commit, metadata_repo_url, offline, owner,
+    read_only, record_repo_owners, remote_overridden, repo_exists, set_offline, RebaseConflict,
+};
+#[cfg(not(feature = "libgit2"))]
+use crate::git::{sparse_checkout_add, sparse_clone_repo};
+use crate::models::{
+    content_key, ChunkIndex, ChunkInfo, FileMetadata, IndexedChunk, NamespaceStats, PendingDelete,
+    RepoInfo, ReposMetadata,
+};
+use crate::retry::{classify, ErrorClass};
+use crate::signing;
+use crate::utils::{
+    command, format_utc, human_size, one_line, sleep, temp_dirs, unix_now, versions_are_compatible,
+};
+
+static NAMESPACE: OnceLock<String> = OnceLock::new();
+
+/// Scopes every later file metadata access of this process to `namespace`.
+pub fn set_namespace(namespace: &str) -> Result<()> {
+    if namespace.is_empty() || namespace.contains('/') || namespace == "." || namespace == ".." {
+        bail!("invalid namespace {:?}", namespace);
+    }
+    NAMESPACE
+        .set(namespace.to_string())
+        .map_err(|_| anyhow!("namespace is already set"))
+}
+
+/// The namespace selected with `set_namespace`, `DEFAULT_NAMESPACE` otherwise.
+pub fn namespace() -> &'static str {
+    NAMESPACE.get().map_or(DEFAULT_NAMESPACE, |ns| ns.as_str())
+}
+
+/// Directory holding the file metadata of the current namespace: `fs/<namespace>`
+pub fn fs_root(metadata_clone_dir: &Path) -> PathBuf {
+    metadata_clone_dir.join("fs").join(namespace())
+}
+
+/// `--at`: the metadata revision read instead of the latest one.
+static REVISION: OnceLock<String> = OnceLock::new();
+/// The commit `REVISION` resolved to on the first clone, and its date
+static RESOLVED: OnceLock<(String, String)> = OnceLock::new();
+
+/// Makes every later metadata clone of this process check out the commit
+/// `at` names, or the last one made by the date `at`. Nothing can be
+/// written then.
+pub fn set_revision(at: &str) {
+    let _ = REVISION.set(at.to_string());
+}
+
+pub fn revision() -> Option<&'static str> {
+    REVISION.get().map(String::as_str)
+}
 
 pub fn get_metadata_dir() -> PathBuf {
-    PathBuf::from(TMPFS_DIR).join("metadata")
+    temp_dirs().work.join("metadata")
+}
+
+/// Replaces any previous metadata clone with a fresh one and returns its dir,
+/// once its signature is verified. Offline, or when the metadata repo cannot
+/// be reached but was cached before, the clone is made from the cache and
+/// every write refused from then on.
+pub fn clone_metadata() -> Result<PathBuf> {
+    let metadata_clone_dir = get_metadata_dir();
+    if metadata_clone_dir.exists() {
+        std::fs::remove_dir_all(&metadata_clone_dir)?;
+    }
+    if offline() {
+        clone_from_cache(&metadata_clone_dir)?;
+    } else {
+        match clone_repo(&metadata_repo_url(), &metadata_clone_dir) {
+            Ok(()) => {
+                if let Err(e) = cache::store_metadata(&metadata_clone_dir) {
+                    eprintln!("--- could not cache the metadata: {:#}", e);
+                }
+            }
+            Err(e) if fall_back_offline(&e) => clone_from_cache(&metadata_clone_dir)?,
+            Err(e) => return Err(e),
+        }
+    }
+    if let Some(at) = revision() {
+        // a date resolves once, later clones read the same commit
+        let target = RESOLVED.get().map_or(at, |(commit, _)| commit.as_str());
+        let (commit, date) = git_checkout_at(&metadata_clone_dir, target)?;
+        if RESOLVED.set((commit.clone(), date.clone())).is_ok() {
+            eprintln!(
+                "--- reading the metadata as of {} ({})",
+                &commit[..12.min(commit.len())],
+                date
+            );
+        }
+    }
+    signing::verify(&metadata_clone_dir)?;
+    Ok(metadata_clone_dir)
+}
+
+/// Like `clone_metadata` for reading the file `remote` alone: the clone
+/// checks out version.txt, repos.json and the metadata file of `remote`, and
+/// fetches no other blob where the server takes filters. Offline, with
+/// `--at`, when the signature is verified, which needs all of fs/, or with
+/// `sparse_checkout` off, the clone is a full one, and it falls back to one
+/// when the sparse clone fails. Only full clones update the metadata cache,
+/// a partial one lacks the blobs a clone of the cache needs.
+pub fn clone_file_metadata(remote: &str) -> Result<PathBuf> {
+    if offline() || revision().is_some() || signing::verifies() || !settings().sparse_checkout() {
+        return clone_metadata();
+    }
+    check_remote_name(remote)?;
+    #[cfg(feature = "libgit2")]
+    {
+        // libgit2 clones whole repos only
+        let _ = remote;
+        clone_metadata()
+    }
+    #[cfg(not(feature = "libgit2"))]
+    {
+        let metadata_clone_dir = get_metadata_dir();
+        let res = sparse_clone_repo(
+            &metadata_repo_url(),
+            &metadata_clone_dir,
+            &[Path::new("version.txt"), Path::new("repos.json")],
+        )
+        .and_then(|()| {
+            let relpath = file_meta_relpath(remote, shard_count(&metadata_clone_dir)?)?;
+            sparse_checkout_add(&metadata_clone_dir, &[&relpath])
+        });
+        match res {
+            Ok(()) => Ok(metadata_clone_dir),
+            Err(e) if classify(&e) == ErrorClass::Permanent => Err(e),
+            Err(e) if fall_back_offline(&e) => {
+                clone_from_cache(&metadata_clone_dir)?;
+                Ok(metadata_clone_dir)
+            }
+            Err(e) => {
+                eprintln!(
+                    "--- sparse clone of the metadata failed ({:#}), cloning all of it",
+                    e
+                );
+                clone_metadata()
+            }
+        }
+    }
+}
+
+/// Goes offline with a warning when `e`, from reaching the metadata repo,
+/// may pass and the metadata was cached before. Returns whether it did.
+pub fn fall_back_offline(e: &anyhow::Error) -> bool {
+    if classify(e) == ErrorClass::Permanent || cache::metadata_fetched().is_none() {
+        return false;
+    }
+    eprintln!(
+        "--- WARNING: the metadata repo is unreachable ({:#}), reading the cached copy; writes are refused",
+        e
+    );
+    set_offline();
+    true
+}
+
+/// Clones the metadata cache into `dir`, telling once where and how old
+/// the metadata it holds is.
+fn clone_from_cache(dir: &Path) -> Result<()> {
+    cache::clone_cached_metadata(dir, &metadata_repo_url())?;
+    static STAMPED: AtomicBool = AtomicBool::new(false);
+    if !STAMPED.swap(true, Ordering::Relaxed) {
+        let commit = command("git")
+            .arg("-C")
+            .arg(dir)
+            .args(["log", "-1", "--format=%h of %cI"])
+            .output()
+            .map(|output| String::from_utf8_lossy(&output.stdout).trim().to_string())
+            .unwrap_or_default();
+        eprintln!(
+            "--- offline: metadata from the cache, fetched {}, commit {}",
+            cache::metadata_fetched().map_or("never".into(), format_utc),
+            commit
+        );
+    }
+    Ok(())
+}
+
+/// Fails for a remote name its metadata file cannot be stored under: one
+/// reaching out of fs/ with `..`, or with a part longer than a file name.
+pub fn check_remote_name(remote: &str) -> Result<()> {
+    for part in remote.split('/') {
+        if part == ".." {
+            bail!(
+                "invalid remote name {:?}: .. is not allowed",
+                one_line(remote, 80)
+            );
+        }
+        if part.len() > REMOTE_NAME_BYTES {
+            bail!(
+                "invalid remote name {:?}: parts are limited to {} bytes, not {}",
+                one_line(remote, 80),
+                REMOTE_NAME_BYTES,
+                part.len()
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Path of the metadata file of `remote`: `fs/<namespace>/<remote>.json`, or
+/// `fs/<namespace>/<shard>/<remote>.json` once the tree is sharded. Empty and
+/// `.` parts are dropped, `/a//./b` is the file `a/b`.
+pub fn file_meta_path(metadata_clone_dir: &Path, remote: &str) -> Result<PathBuf> {
+    let shards = shard_count(metadata_clone_dir)?;
+    Ok(metadata_clone_dir.join(file_meta_relpath(remote, shards)?))
+}
+
+/// `file_meta_path` relative to the metadata repo, for `shards` shards.
+pub fn file_meta_relpath(remote: &str, shards: u32) -> Result<PathBuf> {
+    namespace_meta_relpath(namespace(), remote, shards)
+}
+
+fn namespace_meta_relpath(ns: &str, remote: &str, shards: u32) -> Result<PathBuf> {
+    check_remote_name(remote)?;
+    // an absolute path would replace the fs root when joined
+    let remote = normalize_remote(remote);
+    let remote_path = Path::new(&remote);
+    let file_name = remote_path
+        .file_name()
+        .context("Remote path must have a file name")?;
+    let meta_file_name = format!("{}.json", file_name.to_string_lossy());
+    let parent = remote_path.parent().unwrap_or(Path::new(""));
+    let mut root = Path::new("fs").join(ns);
+    if shards > 0 {
+        root.push(shard_of(&remote, shards));
+    }
+    Ok(root.join(parent).join(meta_file_name))
+}
+
+/// Number of subtrees the fs/ tree of `metadata_clone_dir` is split into,
+/// 0 for one tree. It is part of the schema version: version.txt reads like
+/// "0.1.1-shards.64", which versions without sharding refuse to write to.
+pub fn shard_count(metadata_clone_dir: &Path) -> Result<u32> {
+    let path = metadata_clone_dir.join("version.txt");
+    if !path.exists() {
+        return Ok(0);
+    }
+    let data = std::fs::read_to_string(&path).context("Failed to read version.txt")?;
+    Ok(split_version(data.trim()).1)
+}
+
+/// Shard count of the fs/ tree whose version.txt reads `version`.
+pub fn shards_of_version(version: &str) -> u32 {
+    split_version(version.trim()).1
+}
+
+/// The version without its shard count, and the shard count.
+fn split_version(version: &str) -> (String, u32) {
+    if let Some((base, shards)) = version.split_once(SHARDS_TAG) {
+        if let Ok(shards) = shards.parse() {
+            return (base.to_string(), shards);
+        }
+    }
+    (version.to_string(), 0)
+}
+
+const SHARDS_TAG: &str = "-shards.";
+
+/// Moves the metadata files of every namespace to where `shards` shards
+/// put them, 0 for one tree, and stamps the count in version.txt. The new
+/// tree is built apart and swapped in, so no move lands in a dir still to be
+/// read. Returns the number of files moved.
+pub fn move_to_shards(metadata_clone_dir: &Path, shards: u32) -> Result<usize> {
+    let old = shard_count(metadata_clone_dir)?;
+    let fs_dir = metadata_clone_dir.join("fs");
+    let staged = metadata_clone_dir.join("fs.reshard");
+    if staged.exists() {
+        std::fs::remove_dir_all(&staged)?;
+    }
+    let mut namespaces = Vec::new();
+    let mut moved = 0;
+    for entry in std::fs::read_dir(&fs_dir).context("Failed to read fs/")? {
+        let entry = entry?;
+        if !entry.file_type()?.is_dir() {
+            continue;
+        }
+        let ns = entry.file_name().to_string_lossy().into_owned();
+        for dir in prefix_dirs(&entry.path(), "", old)? {
+            for file in WalkDir::new(&dir).into_iter().filter_map(|e| e.ok()) {
+                let Some(remote) = file
+                    .path()
+                    .strip_prefix(&dir)?
+                    .to_str()
+                    .and_then(|name| name.strip_suffix(".json"))
+                else {
+                    continue;
+                };
+                if !file.file_type().is_file() {
+                    continue;
+                }
+                let dest =
+                    staged.join(namespace_meta_relpath(&ns, remote, shards)?.strip_prefix("fs")?);
+                std::fs::create_dir_all(dest.parent().context("metadata file without dir")?)?;
+                std::fs::rename(file.path(), &dest)
+                    .with_context(|| format!("Failed to move {}", file.path().display()))?;
+                moved += 1;
+            }
+        }
+        namespaces.push(ns);
+    }
+    for ns in namespaces {
+        std::fs::remove_dir_all(fs_dir.join(&ns))?;
+        let dest = staged.join(&ns);
+        if dest.exists() {
+            std::fs::rename(&dest, fs_dir.join(&ns))?;
+        } else {
+            // a namespace without files keeps its dir
+            std::fs::create_dir_all(fs_dir.join(&ns))?;
+        }
+    }
+    std::fs::remove_dir_all(&staged)?;
+    let (base, _) = split_version(&load_version(metadata_clone_dir)?);
+    let base = if versions_are_compatible(&base, VERSION) {
+        base
+    } else {
+        VERSION.to_string()
+    };
+    let version = match shards {
+        0 => base,
+        n => format!("{}{}{}", base, SHARDS_TAG, n),
+    };
+    save_version(metadata_clone_dir, &version)?;
+    Ok(moved)
+}
+
+/// Splits the fs/ tree into `METADATA_SHARDS` shards once the namespaces
+/// hold `METADATA_SHARD_THRESHOLD` files together, before a commit.
+fn auto_shard(metadata_clone_dir: &Path) -> Result<()> {
+    if shard_count(metadata_clone_dir)? > 0 {
+        return Ok(());
+    }
+    let files: usize = load_namespace_stats(metadata_clone_dir)?
+        .values()
+        .map(|ns| ns.files)
+        .sum();
+    if files < METADATA_SHARD_THRESHOLD {
+        return Ok(());
+    }
+    eprintln!(
+        "--- the metadata holds {} files, splitting fs/ into {} shards; \
+         versions without sharding can no longer write to it",
+        files, METADATA_SHARDS
+    );
+    move_to_shards(metadata_clone_dir, METADATA_SHARDS)?;
+    Ok(())
+}
+
+/// `remote` without empty and `.` parts, the spelling every path naming the
+/// same file shares.
+fn normalize_remote(remote: &str) -> String {
+    remote
+        .split('/')
+        .filter(|part| !part.is_empty() && *part != ".")
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// The shard holding the metadata of `remote`, from the hash of its
+/// normalized path: its number in hex, as many digits as the highest one
+/// takes.
+fn shard_of(remote: &str, shards: u32) -> String {
+    let digest = Sha256::digest(normalize_remote(remote).as_bytes());
+    let n = u32::from_be_bytes([digest[0], digest[1], digest[2], digest[3]]) % shards;
+    let width = format!("{:x}", shards - 1).len();
+    format!("{:0width$x}", n, width = width)
+}
+
+/// The dirs holding the metadata of the files under `prefix` of the
+/// namespace dir `root`: `root/prefix`, or that in every shard.
+fn prefix_dirs(root: &Path, prefix: &str, shards: u32) -> Result<Vec<PathBuf>> {
+    let prefix = prefix.trim_matches('/');
+    if shards == 0 {
+        return Ok(vec![root.join(prefix)]);
+    }
+    let mut dirs = Vec::new();
+    if root.is_dir() {
+        for entry in std::fs::read_dir(root)? {
+            let entry = entry?;
+            if entry.file_type()?.is_dir() {
+                dirs.push(entry.path().join(prefix));
+            }
+        }
+    }
+    dirs.sort();
+    Ok(dirs)
+}
+
+/// `scan_file_metadata` of the metadata under `prefix` of the namespace
+/// dir `root`, every shard merged.
+fn scan_namespace(root: &Path, prefix: &str, shards: u32) -> Result<MetadataScan> {
+    let mut files = BTreeMap::new();
+    let mut corrupt = Vec::new();
+    for dir in prefix_dirs(root, prefix, shards)? {
+        let (shard_files, shard_corrupt) = scan_file_metadata(&dir)?;
+        files.extend(shard_files);
+        corrupt.extend(shard_corrupt);
+    }
+    Ok((files, corrupt))
+}
+
+/// `scan_namespace` of every namespace, keyed by `<namespace>/<path>`.
+fn scan_all(metadata_clone_dir: &Path) -> Result<MetadataScan> {
+    let shards = shard_count(metadata_clone_dir)?;
+    let fs_dir = metadata_clone_dir.join("fs");
+    let mut files = BTreeMap::new();
+    let mut corrupt = Vec::new();
+    if !fs_dir.is_dir() {
+        return Ok((files, corrupt));
+    }
+    for entry in std::fs::read_dir(&fs_dir)? {
+        let entry = entry?;
+        if !entry.file_type()?.is_dir() {
+            continue;
+        }
+        let ns = entry.file_name().to_string_lossy().into_owned();
+        let (ns_files, ns_corrupt) = scan_namespace(&entry.path(), "", shards)?;
+        files.extend(
+            ns_files
+                .into_iter()
+                .map(|(path, meta)| (format!("{}/{}", ns, path), meta)),
+        );
+        corrupt.extend(ns_corrupt);
+    }
+    Ok((files, corrupt))
+}
+
+pub fn load_file_metadata(metadata_clone_dir: &Path, remote: &str) -> Result<FileMetadata> {
+    let path = file_meta_path(metadata_clone_dir, remote)?;
+    if !path.exists() {
+        return Err(FileNotFound {
+            remote: remote.to_string(),
+            at: RESOLVED.get().map(|(_, date)| date.clone()),
+        }
+        .into());
+    }
+    let data = std::fs::read_to_string(&path)?;
+    let mut file_meta: FileMetadata = serde_json::from_str(&data)?;
+    file_meta
+        .check_repo_names()
+        .map_err(|e| anyhow!("corrupt metadata of {}: {}", remote, e))?;
+    file_meta.chunks.sort_by_key(|c| c.index);
+    Ok(file_meta)
+}
+
+/// Loads the metadata of every file under the remote directory `prefix`
+/// (empty for the root) of the current namespace, keyed by the path relative
+/// to `prefix`.
+pub fn list_file_metadata(
+    metadata_clone_dir: &Path,
+    prefix: &str,
+) -> Result<BTreeMap<String, FileMetadata>> {
+    let shards = shard_count(metadata_clone_dir)?;
+    only_intact(scan_namespace(
+        &fs_root(metadata_clone_dir),
+        prefix,
+        shards,
+    )?)
+}
+
+/// Loads the metadata of every file of every namespace, keyed by
+/// `<namespace>/<path>`. Chunk reference checks must look at all of them.
+pub fn list_all_file_metadata(metadata_clone_dir: &Path) -> Result<BTreeMap<String, FileMetadata>> {
+    only_intact(scan_all(metadata_clone_dir)?)
+}
+
+fn walk_file_metadata(dir: &Path) -> Result<BTreeMap<String, FileMetadata>> {
+    only_intact(scan_file_metadata(dir)?)
+}
+
+/// The files of `scan`, failing on its first corrupt metadata file.
+fn only_intact((files, corrupt): MetadataScan) -> Result<BTreeMap<String, FileMetadata>> {
+    if let Some((path, reason)) = corrupt.first() {
+        bail!("corrupt metadata {}: {}", path.display(), reason);
+    }
+    Ok(files)
+}
+
+/// File metadata by path, and the metadata files that could not be read
+/// as (path, reason).
+pub type MetadataScan = (BTreeMap<String, FileMetadata>, Vec<(PathBuf, String)>);
+
+/// Like `list_file_metadata`, but unreadable or unparseable metadata files
+/// are returned apart as (path in the metadata repo, reason) instead of
+/// failing the listing.
+pub fn list_file_metadata_tolerant(
+    metadata_clone_dir: &Path,
+    prefix: &str,
+) -> Result<MetadataScan> {
+    let shards = shard_count(metadata_clone_dir)?;
+    let scan = scan_namespace(&fs_root(metadata_clone_dir), prefix, shards)?;
+    relative_corrupt(metadata_clone_dir, scan)
+}
+
+/// `list_file_metadata_tolerant` over every namespace, keyed like
+/// `list_all_file_metadata`.
+pub fn list_all_file_metadata_tolerant(metadata_clone_dir: &Path) -> Result<MetadataScan> {
+    relative_corrupt(metadata_clone_dir, scan_all(metadata_clone_dir)?)
+}
+
+fn relative_corrupt(
+    metadata_clone_dir: &Path,
+    (files, corrupt): MetadataScan,
+) -> Result<MetadataScan> {
+    let corrupt = corrupt
+        .into_iter()
+        .map(|(path, reason)| Ok((path.strip_prefix(metadata_clone_dir)?.to_path_buf(), reason)))
+        .collect::<Result<_>>()?;
+    Ok((files, corrupt))
+}
+
+fn scan_file_metadata(dir: &Path) -> Result<MetadataScan> {
+    let mut files = BTreeMap::new();
+    let corrupt = visit_file_metadata(dir, |name, meta| {
+        files.insert(name.to_string(), meta);
+        Ok(())
+    })?;
+    Ok((files, corrupt))
+}
+
+/// Calls `visit` with the path relative to `dir` and the metadata of each
+/// file under `dir` as it is read, in path order. Returns the metadata
+/// files that could not be read as (path, reason).
+fn visit_file_metadata(
+    dir: &Path,
+    mut visit: impl FnMut(&str, FileMetadata) -> Result<()>,
+) -> Result<Vec<(PathBuf, String)>> {
+    let mut corrupt = Vec::new();
+    if !dir.is_dir() {
+        return Ok(corrupt);
+    }
+    let walk = WalkDir::new(dir).sort_by_file_name();
+    for entry in walk.into_iter().filter_map(|e| e.ok()) {
+        let Some(name) = entry.path().strip_prefix(dir)?.to_str() else {
+            continue;
+        };
+        let Some(name) = name.strip_suffix(".json") else {
+            continue;
+        };
+        if !entry.file_type().is_file() {
+            continue;
+        }
+        let parsed = std::fs::read_to_string(entry.path())
+            .map_err(|e| e.to_string())
+            .and_then(|data| serde_json::from_str::<FileMetadata>(&data).map_err(|e| e.to_string()))
+            .and_then(|meta| meta.check_repo_names().map(|_| meta));
+        match parsed {
+            Ok(meta) => visit(name, meta)?,
+            Err(reason) => corrupt.push((entry.into_path(), reason)),
+        }
+    }
+    Ok(corrupt)
+}
+
+/// `list_file_metadata_tolerant` handing each file to `visit` as it is read
+/// instead of holding them all, for listings of big drives. Split into
+/// shards, the files come in path order within each shard.
+pub fn for_each_file_metadata(
+    metadata_clone_dir: &Path,
+    prefix: &str,
+    mut visit: impl FnMut(&str, FileMetadata) -> Result<()>,
+) -> Result<Vec<(PathBuf, String)>> {
+    let shards = shard_count(metadata_clone_dir)?;
+    let mut corrupt = Vec::new();
+    for dir in prefix_dirs(&fs_root(metadata_clone_dir), prefix, shards)? {
+        corrupt.extend(visit_file_metadata(&dir, &mut visit)?);
+    }
+    let (_, corrupt) = relative_corrupt(metadata_clone_dir, (BTreeMap::new(), corrupt))?;
+    Ok(corrupt)
+}
+
+pub fn save_file_metadata(
+    metadata_clone_dir: &Path,
+    remote: &str,
+    file_meta: &FileMetadata,
+) -> Result<()> {
+    let path = file_meta_path(metadata_clone_dir, remote)?;
+    std::fs::create_dir_all(
+        path.parent()
+            .context("Failed to get parent for file meta")?,
+    )?;
+    let data = serde_json::to_string_pretty(file_meta).context("Failed to serialize file meta")?;
+    std::fs::write(&path, data).context("Failed to write file meta")
+}
+
+/// Recomputes the file count and size of the current namespace in
+/// namespaces.json, to be called before committing a change to its files.
+pub fn update_namespace_stats(metadata_clone_dir: &Path) -> Result<()> {
+    let mut stats = load_namespace_stats(metadata_clone_dir)?;
+    let files = list_file_metadata(metadata_clone_dir, "")?;
+    stats.insert(
+        namespace().to_string(),
+        NamespaceStats {
+            files: files.len(),
+            bytes: files.values().map(|meta| meta.size).sum(),
+        },
+    );
+    save_namespace_stats(metadata_clone_dir, &stats)
+}
+
+pub fn load_namespace_stats(metadata_clone_dir: &Path) -> Result<BTreeMap<String, NamespaceStats>> {
+    let path = metadata_clone_dir.join("namespaces.json");
+    if !path.exists() {
+        return Ok(BTreeMap::new());
+    }
+    let data = std::fs::read_to_string(&path)?;
+    serde_json::from_str(&data).context("Failed to parse namespaces.json")
+}
+
+pub fn save_namespace_stats(
+    metadata_clone_dir: &Path,
+    stats: &BTreeMap<String, NamespaceStats>,
+) -> Result<()> {
+    let data = serde_json::to_string_pretty(stats).context("Failed to serialize namespaces")?;
+    std::fs::write(metadata_clone_dir.join("namespaces.json"), data)
+        .context("Failed to write namespaces.json")
+}
+
+/// Moves files of the layout before namespaces, directly under fs/, into the
+/// default namespace. namespaces.json marks an already namespaced layout.
+/// Returns whether anything changed.
+pub fn migrate_to_namespaces(metadata_clone_dir: &Path) -> Result<bool> {
+    if metadata_clone_dir.join("namespaces.json").exists() {
+        return Ok(false);
+    }
+    let fs_dir = metadata_clone_dir.join("fs");
+    let default_dir = fs_dir.join(DEFAULT_NAMESPACE);
+    let legacy = metadata_clone_dir.join("fs.legacy");
+    if fs_dir.exists() {
+        std::fs::rename(&fs_dir, &legacy)?;
+    }
+    std::fs::create_dir_all(&fs_dir)?;
+    if legacy.exists() {
+        std::fs::rename(&legacy, &default_dir)?;
+    }
+    let mut stats = BTreeMap::new();
+    let files = walk_file_metadata(&default_dir)?;
+    stats.insert(
+        DEFAULT_NAMESPACE.to_string(),
+        NamespaceStats {
+            files: files.len(),
+            bytes: files.values().map(|meta| meta.size).sum(),
+        },
+    );
+    save_namespace_stats(metadata_clone_dir, &stats)?;
+    Ok(true)
+}
+
+/// Whether the metadata repo cloned in `metadata_clone_dir` holds anything
+/// besides its git dir.
+pub fn has_content(metadata_clone_dir: &Path) -> Result<bool> {
+    for entry in std::fs::read_dir(metadata_clone_dir)? {
+        if entry?.file_name() != ".git" {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// Writes whichever of version.txt, repos.json, the fs/ tree with
+/// namespaces.json and chunks.idx are missing, so that a new metadata repo
+/// gets all of them in the same commit. fs/.keep lets git track fs/ while
+/// it has no files.
+pub fn bootstrap(metadata_clone_dir: &Path) -> Result<()> {
+    if !metadata_clone_dir.join("version.txt").exists() {
+        save_version(metadata_clone_dir, VERSION)?;
+    }
+    if !metadata_clone_dir.join("repos.json").exists() {
+        save_repos_metadata(
+            metadata_clone_dir,
+            &load_repos_metadata(metadata_clone_dir)?,
+        )?;
+    }
+    migrate_to_namespaces(metadata_clone_dir)?;
+    let keep = metadata_clone_dir.join("fs").join(".keep");
+    if !keep.exists() {
+        std::fs::write(&keep, "").context("Failed to write fs/.keep")?;
+    }
+    if !metadata_clone_dir.join("chunks.idx").exists() {
+        save_chunk_index(metadata_clone_dir, &build_chunk_index(metadata_clone_dir)?)?;
+    }
+    Ok(())
 }
 
 pub fn load_repos_metadata(metadata_clone_dir: &Path) -> Result<ReposMetadata> {
@@ -17,7 +710,13 @@ pub fn load_repos_metadata(metadata_clone_dir: &Path) -> Result<ReposMetadata> {
         let data = std::fs::read_to_string(&path).context(
             "Failed to read repos.json, uncompatible versions? repos.json modified manually?",
         )?;
-        serde_json::from_str(&data).context("Failed to parse repos.json")
+        let repos_meta: ReposMetadata =
+            serde_json::from_str(&data).context("Failed to parse repos.json")?;
+        repos_meta
+            .check_repo_names()
+            .map_err(|e| anyhow!("corrupt repos.json: {}", e))?;
+        record_repo_owners(&repos_meta);
+        Ok(repos_meta)
     } else {
         Ok(ReposMetadata {
             next_id: 1,
@@ -33,13 +732,644 @@ pub fn save_repos_metadata(metadata_clone_dir: &Path, repos_meta: &ReposMetadata
     std::fs::write(&path, data).context("Failed to write repos.json")
 }
 
+/// Chunks waiting in pending_delete.json for `gc`, oldest first.
+pub fn load_pending_deletes(metadata_clone_dir: &Path) -> Result<Vec<PendingDelete>> {
+    let path = metadata_clone_dir.join("pending_delete.json");
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+    let data = std::fs::read_to_string(&path).context("Failed to read pending_delete.json")?;
+    serde_json::from_str(&data).context("Failed to parse pending_delete.json")
+}
+
+pub fn save_pending_deletes(metadata_clone_dir: &Path, pending: &[PendingDelete]) -> Result<()> {
+    let data =
+        serde_json::to_string_pretty(pending).context("Failed to serialize pending_delete.json")?;
+    std::fs::write(metadata_clone_dir.join("pending_delete.json"), data)
+        .context("Failed to write pending_delete.json")
+}
+
+/// Queues `chunks`, which no file uses anymore, for `gc` instead of
+/// deleting them, so `--at` can still read the files they belonged to. Their
+/// space stays in `current_size` and is counted as reclaimable.
+pub fn defer_deletes(
+    metadata_clone_dir: &Path,
+    repos_meta: &mut ReposMetadata,
+    chunks: Vec<ChunkInfo>,
+) -> Result<()> {
+    if chunks.is_empty() {
+        return Ok(());
+    }
+    let mut pending = load_pending_deletes(metadata_clone_dir)?;
+    let now = unix_now();
+    for chunk in chunks {
+        if pending
+            .iter()
+            .any(|p| p.repo == chunk.repo && p.path == chunk.path)
+        {
+            continue;
+        }
+        let Some(info) = repos_meta.repos.get_mut(&chunk.repo) else {
+            continue;
+        };
+        info.reclaimable += chunk.size;
+        pending.push(PendingDelete {
+            repo: chunk.repo,
+            path: chunk.path,
+            size: chunk.size,
+            since: now,
+        });
+    }
+    save_pending_deletes(metadata_clone_dir, &pending)
+}
+
+/// Sorts the entries of pending_delete.json for `gc`: by repo those due,
+/// released at least `grace` seconds ago or all of them with `now`, then
+/// those still waiting, then the number only dropped, for chunks a file uses
+/// again or of repos gone from repos.json. A chunk used again is no longer
+/// counted as reclaimable.
+pub fn split_pending_deletes(
+    pending: Vec<PendingDelete>,
+    index: &ChunkIndex,
+    repos_meta: &mut ReposMetadata,
+    grace: u64,
+    now: bool,
+) -> (
+    BTreeMap<String, Vec<PendingDelete>>,
+    Vec<PendingDelete>,
+    usize,
+) {
+    let cutoff = unix_now().saturating_sub(grace);
+    let mut due: BTreeMap<String, Vec<PendingDelete>> = BTreeMap::new();
+    let mut waiting = Vec::new();
+    let mut dropped = 0;
+    for entry in pending {
+        let in_use = index.contains_key(&(entry.repo.clone(), entry.path.clone()));
+        match repos_meta.repos.get_mut(&entry.repo) {
+            Some(info) if in_use => {
+                info.reclaimable = info.reclaimable.saturating_sub(entry.size);
+                dropped += 1;
+            }
+            None => dropped += 1,
+            Some(_) if now || entry.since <= cutoff => {
+                due.entry(entry.repo.clone()).or_default().push(entry)
+            }
+            Some(_) => waiting.push(entry),
+        }
+    }
+    (due, waiting, dropped)
+}
+
+/// The chunk index in chunks.idx, built from the fs/ tree if the repo has
+/// none yet.
+pub fn load_chunk_index(metadata_clone_dir: &Path) -> Result<ChunkIndex> {
+    let path = metadata_clone_dir.join("chunks.idx");
+    if !path.exists() {
+        return build_chunk_index(metadata_clone_dir);
+    }
+    let data = std::fs::read_to_string(&path).context("Failed to read chunks.idx")?;
+    let mut index = ChunkIndex::new();
+    for (n, line) in data.lines().enumerate() {
+        let fields: Vec<&str> = line.split('\t').collect();
+        let [repo, path, refs, content] = fields[..] else {
+            bail!(
+                "chunks.idx line {} is malformed, rebuild it with fsck --rebuild-index",
+                n + 1
+            );
+        };
+        let refs = refs.parse().with_context(|| {
+            format!(
+                "chunks.idx line {} has a bad count, rebuild it with fsck --rebuild-index",
+                n + 1
+            )
+        })?;
+        index.insert(
+            (repo.to_string(), path.to_string()),
+            IndexedChunk {
+                content: (content != "-").then(|| content.to_string()),
+                refs,
+            },
+        );
+    }
+    Ok(index)
+}
+
+/// Writes chunks.idx, one `"<repo>\t<path>\t<refs>\t<content>"` line per
+/// chunk, `-` for an unknown content.
+pub fn save_chunk_index(metadata_clone_dir: &Path, index: &ChunkIndex) -> Result<()> {
+    let mut data = String::new();
+    for ((repo, path), chunk) in index {
+        let content = chunk.content.as_deref().unwrap_or("-");
+        data.push_str(&format!(
+            "{}\t{}\t{}\t{}\n",
+            repo, path, chunk.refs, content
+        ));
+    }
+    std::fs::write(metadata_clone_dir.join("chunks.idx"), data)
+        .context("Failed to write chunks.idx")
+}
+
+/// Counts the references to every chunk from the files of all namespaces.
+pub fn build_chunk_index(metadata_clone_dir: &Path) -> Result<ChunkIndex> {
+    let repos_meta = load_repos_metadata(metadata_clone_dir)?;
+    let mut index = ChunkIndex::new();
+    let files = list_all_file_metadata(metadata_clone_dir).context("Failed to build chunks.idx")?;
+    for meta in files.values() {
+        reference_chunks(&mut index, &repos_meta, meta);
+    }
+    Ok(index)
+}
+
+/// Counts one more reference to each chunk of `meta` in a storage repo.
+/// Adopted chunks live in foreign repos and are never indexed.
+pub fn reference_chunks(index: &mut ChunkIndex, repos_meta: &ReposMetadata, meta: &FileMetadata) {
+    for chunk in &meta.chunks {
+        if !repos_meta.repos.contains_key(&chunk.repo) {
+            continue;
+        }
+        let entry = index
+            .entry((chunk.repo.clone(), chunk.path.clone()))
+            .or_insert(IndexedChunk {
+                content: None,
+                refs: 0,
+            });
+        entry.refs += 1;
+        if let Some(checksum) = &chunk.checksum {
+            entry.content = Some(content_key(meta.checksum_algo, checksum));
+        }
+    }
+}
+
+/// Drops one reference to each chunk of `meta`, returning the chunks no
+/// file references anymore, which are removed from the index.
+pub fn release_chunks(index: &mut ChunkIndex, meta: &FileMetadata) -> Vec<ChunkInfo> {
+    let mut unused = Vec::new();
+    for chunk in &meta.chunks {
+        let key = (chunk.repo.clone(), chunk.path.clone());
+        let Some(entry) = index.get_mut(&key) else {
+            continue;
+        };
+        entry.refs = entry.refs.saturating_sub(1);
+        if entry.refs == 0 {
+            index.remove(&key);
+            unused.push(chunk.clone());
+        }
+    }
+    unused
+}
+
+/// Location (repo, path) of every indexed chunk of repos with the given
+/// visibility, by `content_key`, for uploads to reference instead of
+/// pushing the same content again.
+pub fn stored_chunks(
+    index: &ChunkIndex,
+    repos_meta: &ReposMetadata,
+    public: bool,
+) -> HashMap<String, (String, String)> {
+    let mut stored = HashMap::new();
+    for ((repo, path), chunk) in index {
+        let Some(content) = &chunk.content else {
+            continue;
+        };
+        if repos_meta
+            .repos
+            .get(repo)
+            .is_some_and(|r| r.public == public)
+        {
+            stored
+                .entry(content.clone())
+                .or_insert_with(|| (repo.clone(), path.clone()));
+        }
+    }
+    stored
+}
+
+/// There is no file at a remote path.
+#[derive(Debug)]
+pub struct FileNotFound {
+    pub remote: String,
+    /// Date of the metadata revision read with `--at`
+    pub at: Option<String>,
+}
+
+impl fmt::Display for FileNotFound {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.at {
+            Some(at) => write!(f, "there was no file {} as of {}", self.remote, at),
+            None => write!(f, "File metadata not found for {}", self.remote),
+        }
+    }
+}
+
+impl std::error::Error for FileNotFound {}
+
+/// Writing was refused because the metadata repo is stamped with a version
+/// whose format this one may not understand.
+#[derive(Debug)]
+pub struct IncompatibleVersion {
+    pub found: String,
+    pub current: &'static str,
+}
+
+impl fmt::Display for IncompatibleVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "the metadata repo is at version {}, this gidrive is {}: only read operations \
+             are allowed, upgrade or pass --force-write",
+            self.found, self.current
+        )
+    }
+}
+
+impl std::error::Error for IncompatibleVersion {}
+
+static FORCE_WRITE: AtomicBool = AtomicBool::new(false);
+
+/// Makes `check_write_version` pass whatever the version, for `--force-write`.
+pub fn force_write() {
+    FORCE_WRITE.store(true, Ordering::Relaxed);
+}
+
+/// Fails with `IncompatibleVersion` unless this version may write the
+/// metadata in `metadata_clone_dir`, and with `ReadOnlyMode` in read-only
+/// mode. Call it before changing anything there.
+pub fn check_write_version(metadata_clone_dir: &Path) -> Result<()> {
+    check_writable(|| "change the metadata".to_string())?;
+    if let Some(at) = revision() {
+        bail!("the metadata is read as of {}, nothing can be written", at);
+    }
+    let found = load_version(metadata_clone_dir)?;
+    if versions_are_compatible(&split_version(&found).0, VERSION) {
+        return Ok(());
+    }
+    if FORCE_WRITE.load(Ordering::Relaxed) {
+        eprintln!(
+            "--- writing metadata of version {} with {} because of --force-write",
+            found, VERSION
+        );
+        return Ok(());
+    }
+    Err(IncompatibleVersion {
+        found,
+        current: VERSION,
+    }
+    .into())
+}
+
+/// Commits and pushes a change of the metadata repo. The client making it
+/// is recorded in clients.log as part of the same commit, and the files are
+/// signed when a signing key is configured. When another client pushed a
+/// change of the same files first, the change is made again on top of
+/// theirs by `replay_on_remote`, up to `METADATA_REPLAYS` times.
+pub fn commit_metadata(metadata_clone_dir: &Path, operation: &str) -> Result<()> {
+    commit_change(metadata_clone_dir, operation, operation)
+}
+
+/// `commit_metadata` of `operation` on `files` remote files, `names` saying
+/// which. Those are user strings, so the commit subject is the operation
+/// and the file count only, `names` follow in the body and in clients.log.
+pub fn commit_metadata_for(
+    metadata_clone_dir: &Path,
+    operation: &str,
+    files: usize,
+    names: &str,
+) -> Result<()> {
+    let subject = format!(
+        "{} {} {}",
+        operation,
+        files,
+        if files == 1 { "file" } else { "files" }
+    );
+    let names = one_line(names, COMMIT_MESSAGE_CHARS);
+    commit_change(
+        metadata_clone_dir,
+        &format!("{}\n\n{}", subject, names),
+        &format!("{} {}", operation, names),
+    )
+}
+
+/// Commits `message` as `commit_metadata` does, logging `logged` in
+/// clients.log.
+fn commit_change(metadata_clone_dir: &Path, message: &str, logged: &str) -> Result<()> {
+    let subject = message.lines().next().unwrap_or_default();
+    check_writable(|| format!("commit \"{}\" to the metadata", subject))?;
+    auto_shard(metadata_clone_dir)?;
+    let mut replays = 0;
+    loop {
+        // none before the first commit, which nobody can have changed
+        let base = head_commit(metadata_clone_dir).ok();
+        signing::sign(metadata_clone_dir)?;
+        log_client(metadata_clone_dir, logged)?;
+        git_add_commit(metadata_clone_dir, message)?;
+        match (git_push(metadata_clone_dir), base) {
+            (Err(e), Some(base))
+                if replays < METADATA_REPLAYS && e.chain().any(|c| c.is::<RebaseConflict>()) =>
+            {
+                replays += 1;
+                eprintln!(
+                    "--- another client changed the metadata first, making \"{}\" again on top",
+                    subject
+                );
+                replay_on_remote(metadata_clone_dir, &base)?;
+            }
+            (res, _) => break res?,
+        }
+    }
+    daemon::metadata_changed();
+    Ok(())
+}
+
+/// Files every commit writes again from the others instead of merging
+/// them: the client log, the signature, the chunk index and the namespace
+/// totals.
+const DERIVED_FILES: [&str; 4] = [
+    "clients.log",
+    signing::SIGNATURE_FILE,
+    "chunks.idx",
+    "namespaces.json",
+];
+
+/// Makes the clone `metadata_clone_dir`, whose last commit on top of `base`
+/// could not be rebased onto what another client pushed since, hold the
+/// remote's files with the changes of that commit made again, for
+/// `commit_metadata` to commit once more:
+///
+/// - a file the commit wrote or removed, file metadata under fs/ among
+///   them, is written or removed again. If the other client changed it
+///   too, differently, both changed the same file and this fails.
+/// - repos.json and pending_delete.json are merged by `merge_repos` and
+///   `merge_pending_deletes`. When a repo the commit reserved space in has
+///   no room left for it, this fails with `Overbooked`, the clone holding
+///   the remote's files.
+/// - `DERIVED_FILES` the commit changed are derived again from the merged
+///   files, clients.log and manifest.sig by the next commit.
+fn replay_on_remote(metadata_clone_dir: &Path, base: &str) -> Result<()> {
+    let dir = metadata_clone_dir;
+    let ours: Vec<(String, Option<Vec<u8>>)> = changed_files(dir, base, "HEAD")?
+        .into_iter()
+        .map(|path| {
+            let content = std::fs::read(dir.join(&path)).ok();
+            (path, content)
+        })
+        .collect();
+    let changed = |name: &str| ours.iter().any(|(path, _)| path == name);
+    let base_repos = match file_at(dir, base, "repos.json")? {
+        Some(data) => serde_json::from_slice(&data).context("Failed to parse repos.json")?,
+        None => ReposMetadata {
+            next_id: 1,
+            repos: BTreeMap::new(),
+        },
+    };
+    let ours_repos = load_repos_metadata(dir)?;
+    let base_pending: Vec<PendingDelete> = match file_at(dir, base, "pending_delete.json")? {
+        Some(data) => {
+            serde_json::from_slice(&data).context("Failed to parse pending_delete.json")?
+        }
+        None => Vec::new(),
+    };
+    let ours_pending = load_pending_deletes(dir)?;
+
+    git_refresh(dir)?;
+    let theirs: BTreeSet<String> = changed_files(dir, base, "HEAD")?.into_iter().collect();
+    let merged = [
+        &["repos.json", "pending_delete.json"][..],
+        &DERIVED_FILES[..],
+    ]
+    .concat();
+    let plain: Vec<&(String, Option<Vec<u8>>)> = ours
+        .iter()
+        .filter(|(path, _)| !merged.contains(&path.as_str()))
+        .collect();
+    for (path, content) in &plain {
+        if theirs.contains(path) && std::fs::read(dir.join(path)).ok() != *content {
+            bail!(
+                "another client changed {} at the same time, nothing was written",
+                path
+            );
+        }
+    }
+    if changed("repos.json") {
+        let theirs = load_repos_metadata(dir)?;
+        let repos_meta = merge_repos(&base_repos, &ours_repos, &theirs)?;
+        let repos = overbooked(
+            &base_repos,
+            &ours_repos,
+            &repos_meta,
+            settings().max_repo_size(),
+        );
+        if !repos.is_empty() {
+            return Err(Overbooked { repos }.into());
+        }
+        save_repos_metadata(dir, &repos_meta)?;
+    }
+    if changed("pending_delete.json") {
+        let theirs = load_pending_deletes(dir)?;
+        save_pending_deletes(
+            dir,
+            &merge_pending_deletes(&base_pending, &ours_pending, &theirs),
+        )?;
+    }
+    for (path, content) in plain {
+        let target = dir.join(path);
+        match content {
+            Some(data) => {
+                if let Some(parent) = target.parent() {
+                    std::fs::create_dir_all(parent)?;
+                }
+                std::fs::write(&target, data)
+                    .with_context(|| format!("Failed to write {}", path))?;
+            }
+            None if target.exists() => std::fs::remove_file(&target)
+                .with_context(|| format!("Failed to remove {}", path))?,
+            None => {}
+        }
+    }
+    if changed("chunks.idx") {
+        save_chunk_index(dir, &build_chunk_index(dir)?)?;
+    }
+    if changed("namespaces.json") {
+        update_namespace_stats(dir)?;
+    }
+    Ok(())
+}
+
+/// A reservation in repos.json that another client's reservation, pushed
+/// first, left without room: placing the chunks again is up to the caller.
+#[derive(Debug)]
+pub struct Overbooked {
+    pub repos: Vec<String>,
+}
+
+impl fmt::Display for Overbooked {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "another client reserved space in {} at the same time, leaving no room for this upload",
+            self.repos.join(", ")
+        )
+    }
+}
+
+impl std::error::Error for Overbooked {}
+
+/// repos.json with the changes `ours` made to `base` made again on
+/// `theirs`, which another client pushed meanwhile. Sizes are counters:
+/// the bytes `ours` added to or took from the `current_size` and
+/// `reclaimable` of a repo are added to or taken from theirs, so the
+/// reservations and releases of both clients add up. A visibility, retired
+/// flag or owner `ours` changed is taken from it, else from `theirs`. Repos
+/// `ours` added or removed are added or removed, and `next_id` is the
+/// larger one. Fails when `theirs` removed a repo `ours` changed, or
+/// changed one `ours` removed.
+pub fn merge_repos(
+    base: &ReposMetadata,
+    ours: &ReposMetadata,
+    theirs: &ReposMetadata,
+) -> Result<ReposMetadata> {
+    let mut merged = theirs.clone();
+    merged.next_id = ours.next_id.max(theirs.next_id);
+    let names: BTreeSet<&String> = base.repos.keys().chain(ours.repos.keys()).collect();
+    for name in names {
+        let (before, after) = (base.repos.get(name), ours.repos.get(name));
+        if before == after {
+            continue;
+        }
+        match (before, after, merged.repos.get_mut(name)) {
+            (Some(before), None, Some(repo)) if repo != before => {
+                bail!(
+                    "another client changed repo {} while this one removed it",
+                    name
+                )
+            }
+            (_, None, _) => {
+                merged.repos.remove(name);
+            }
+            (None, Some(after), None) => {
+                merged.repos.insert(name.clone(), after.clone());
+            }
+            (Some(_), Some(_), None) => {
+                bail!(
+                    "another client removed repo {} while this one changed it",
+                    name
+                )
+            }
+            (before, Some(after), Some(repo)) => {
+                let added = RepoInfo {
+                    current_size: 0,
+                    reclaimable: 0,
+                    ..after.clone()
+                };
+                let before = before.unwrap_or(&added);
+                let delta = |theirs: u64, base: u64, ours: u64| {
+                    theirs.saturating_add(ours).saturating_sub(base)
+                };
+                repo.current_size =
+                    delta(repo.current_size, before.current_size, after.current_size);
+                repo.reclaimable = delta(repo.reclaimable, before.reclaimable, after.reclaimable);
+                if after.public != before.public {
+                    repo.public = after.public;
+                }
+                if after.retired != before.retired {
+                    repo.retired = after.retired;
+                }
+                if after.owner != before.owner {
+                    repo.owner = after.owner.clone();
+                }
+            }
+        }
+    }
+    Ok(merged)
+}
+
+/// Repos `ours` reserved space in since `base` that hold more than
+/// `max_repo_size` in `merged`.
+fn overbooked(
+    base: &ReposMetadata,
+    ours: &ReposMetadata,
+    merged: &ReposMetadata,
+    max_repo_size: u64,
+) -> Vec<String> {
+    ours.repos
+        .values()
+        .filter(|repo| {
+            let before = base.repos.get(&repo.name).map_or(0, |r| r.current_size);
+            repo.current_size > before
+                && merged
+                    .repos
+                    .get(&repo.name)
+                    .is_some_and(|r| r.current_size > max_repo_size)
+        })
+        .map(|repo| repo.name.clone())
+        .collect()
+}
+
+/// pending_delete.json with the chunks `ours` queued since `base` added to
+/// `theirs`, and those it took off, collected by `gc`, taken off, a chunk
+/// being its repo and path.
+pub fn merge_pending_deletes(
+    base: &[PendingDelete],
+    ours: &[PendingDelete],
+    theirs: &[PendingDelete],
+) -> Vec<PendingDelete> {
+    let key = |p: &PendingDelete| (p.repo.clone(), p.path.clone());
+    let before: BTreeSet<_> = base.iter().map(key).collect();
+    let after: BTreeSet<_> = ours.iter().map(key).collect();
+    let mut merged: Vec<PendingDelete> = theirs
+        .iter()
+        .filter(|p| !before.contains(&key(p)) || after.contains(&key(p)))
+        .cloned()
+        .collect();
+    let mut present: BTreeSet<_> = merged.iter().map(key).collect();
+    for p in ours {
+        if !before.contains(&key(p)) && present.insert(key(p)) {
+            merged.push(p.clone());
+        }
+    }
+    merged
+}
+
+/// Appends "<time>\t<version>\t<host>\t<operation>" to clients.log, keeping
+/// the last `CLIENTS_LOG_ENTRIES` lines.
+fn log_client(metadata_clone_dir: &Path, operation: &str) -> Result<()> {
+    let mut lines = load_clients_log(metadata_clone_dir)?;
+    let host = std::fs::read_to_string("/proc/sys/kernel/hostname")
+        .or_else(|_| std::env::var("HOSTNAME"))
+        .map_or_else(|_| "unknown".to_string(), |h| h.trim().to_string());
+    lines.push(
+        [&format_utc(unix_now()), VERSION, &host, operation]
+            .map(|field| one_line(field, COMMIT_MESSAGE_CHARS))
+            .join("\t"),
+    );
+    let keep = lines.len().saturating_sub(CLIENTS_LOG_ENTRIES);
+    let mut data = lines[keep..].join("\n");
+    data.push('\n');
+    std::fs::write(metadata_clone_dir.join("clients.log"), data)
+        .context("Failed to write clients.log")
+}
+
+/// Lines of clients.log, oldest first, none if no client has logged yet.
+pub fn load_clients_log(metadata_clone_dir: &Path) -> Result<Vec<String>> {
+    let path = metadata_clone_dir.join("clients.log");
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+    let data = std::fs::read_to_string(&path).context("Failed to read clients.log")?;
+    Ok(data.lines().map(str::to_string).collect())
+}
+
+/// The version stamped in version.txt, stamping the current one if the
+/// repo predates stamps.
 pub fn load_version(metadata_clone_dir: &Path) -> Result<String> {
     let path = metadata_clone_dir.join("version.txt");
     if path.exists() {
         let data = std::fs::read_to_string(&path).context("Failed to read version.txt")?;
         Ok(data.trim().to_string())
     } else {
-        save_version(metadata_clone_dir, VERSION)?;
+        // stamped by the next commit, never in read-only mode or offline
+        if !read_only() && !offline() {
+            save_version(metadata_clone_dir, VERSION)?;
+        }
         Ok(VERSION.to_string())
     }
 }
@@ -49,29 +1379,793 @@ pub fn save_version(metadata_clone_dir: &Path, version: &str) -> Result<()> {
     std::fs::write(&path, version).context("Failed to write version.txt")
 }
 
-pub fn find_or_create_repo_for_chunk(
-    repos_meta: &mut ReposMetadata,
-    chunk_size: u64,
-) -> Result<String> {
-    for (_, repo) in repos_meta.repos.iter_mut() {
-        if repo.current_size + chunk_size <= MAX_SIZE_PER_REPO {
-            repo.current_size += chunk_size;
-            return Ok(repo.name.clone());
-        }
-    }
-    // Create new repo
-    let repo_id = repos_meta.next_id;
-    repos_meta.next_id += 1;
-    let repo_name = format!("storage-{:04}", repo_id);
-    if !repo_exists(&repo_name) {
-        create_repo(&repo_name).context("Failed to create new repo")?;
-    }
-    repos_meta.repos.insert(
-        repo_name.clone(),
+/// Chunk-to-repo assignment for one upload, computed without side effects
+/// by `plan_upload` and carried out by `execute_plan`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct UploadPlan {
+    /// (chunk index, repo name, chunk size)
+    pub assignments: Vec<(usize, String, u64)>,
+    /// Repos that were reserved in repos.json and still have to be created
+    pub new_repos: Vec<String>,
+    /// Visibility of the repos receiving the chunks
+    pub public: bool,
+    /// repos.json with the new repos and the space of every chunk reserved
+    pub repos: ReposMetadata,
+}
+
+/// What `plan_upload` needs besides the repos, `PlanOptions::new` for the
+/// configured values.
+#[derive(Clone, Debug)]
+pub struct PlanOptions {
+    pub chunk_size: u64,
+    pub max_repo_size: u64,
+    /// Start of the names of new repos
+    pub repo_prefix: String,
+    /// Visibility of the repos receiving the chunks
+    pub public: bool,
+    pub strategy: PlacementStrategy,
+    /// Picks the names of new repos, random so two writers planning from
+    /// the same repos.json do not both take a sequential name
+    pub seed: u64,
+    /// Storage accounts new repos may go to, the drive's own first, which
+    /// also holds the repos recording no owner
+    pub accounts: Vec<String>,
+    pub account_policy: AccountPolicy,
+    /// Repos an account may hold, none for no limit
+    pub max_repos: Option<usize>,
+}
+
+impl PlanOptions {
+    pub fn new(public: bool) -> Self {
+        let mut seed = [0u8; 8];
+        if getrandom::getrandom(&mut seed).is_err() {
+            let nanos = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.as_nanos() as u64);
+            seed = (nanos ^ u64::from(std::process::id()).rotate_left(32)).to_le_bytes();
+        }
+        PlanOptions {
+            chunk_size: settings().chunk_size(),
+            max_repo_size: settings().max_repo_size(),
+            repo_prefix: settings().repo_prefix().to_string(),
+            public,
+            strategy: settings().placement(),
+            seed: u64::from_le_bytes(seed),
+            accounts: storage_accounts(),
+            account_policy: settings().account_policy(),
+            max_repos: settings().max_repos,
+        }
+    }
+}
+
+impl UploadPlan {
+    /// Number of distinct repos receiving chunks, i.e. storage pushes.
+    pub fn repos_used(&self) -> usize {
+        let repos: BTreeSet<&str> = self
+            .assignments
+            .iter()
+            .map(|(_, r, _)| r.as_str())
+            .collect();
+        repos.len()
+    }
+
+    /// Temp space the upload pipeline takes at most with `concurrency` push
+    /// workers, as (work dir, staging dir): a clone of each repo being
+    /// pushed, worktree and objects, with the space the plan reserved in it,
+    /// and the staged chunks of the repo batches being pushed or queued
+    /// plus the one being written.
+    pub fn peak_temp_bytes(&self, concurrency: usize) -> (u64, u64) {
+        let mut batches: BTreeMap<&str, u64> = BTreeMap::new();
+        for (_, repo, size) in &self.assignments {
+            *batches.entry(repo).or_default() += size;
+        }
+        let mut staged: Vec<u64> = batches.values().copied().collect();
+        staged.sort_unstable_by(|a, b| b.cmp(a));
+        let mut clones: Vec<u64> = batches
+            .keys()
+            .map(|repo| self.repos.repos.get(*repo).map_or(0, |r| r.current_size) * 2)
+            .collect();
+        clones.sort_unstable_by(|a, b| b.cmp(a));
+        (
+            clones.iter().take(concurrency).sum(),
+            staged
+                .iter()
+                .take(UPLOAD_QUEUE_DEPTH + concurrency + 1)
+                .sum(),
+        )
+    }
+}
+
+/// No storage account may get another repo under `max_repos`, though an
+/// upload needs one.
+#[derive(Debug)]
+pub struct StorageExhausted {
+    /// Repos of every account, with those the upload planned already
+    pub repos: usize,
+    pub accounts: usize,
+    pub max_repos: usize,
+    pub stored: u64,
+    pub max_repo_size: u64,
+    pub reclaimable: u64,
+}
+
+impl fmt::Display for StorageExhausted {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "storage exhausted: {} repos, max_repos {} for each of {} accounts, hold {} \
+             ({} per repo, max_repo_size {}); raise max_repo_size or max_repos, \
+             run `gidrive gc` to reclaim {}, or add an account under [accounts]",
+            self.repos,
+            self.max_repos,
+            self.accounts,
+            human_size(self.stored),
+            human_size(self.stored / self.repos.max(1) as u64),
+            human_size(self.max_repo_size),
+            human_size(self.reclaimable)
+        )
+    }
+}
+
+impl std::error::Error for StorageExhausted {}
+
+/// Assigns every chunk of a `file_size` upload to a repo of `repos_meta`,
+/// or to new repos, with `opts.strategy`. Only repos of the requested
+/// visibility with room for a chunk are used. A pure function: the same
+/// inputs give the same plan, and nothing is read, written or created.
+/// Fails with `StorageExhausted` when a new repo would exceed
+/// `opts.max_repos` in every account.
+pub fn plan_upload(
+    file_size: u64,
+    repos_meta: &ReposMetadata,
+    opts: &PlanOptions,
+) -> Result<UploadPlan> {
+    let mut plan = UploadPlan {
+        assignments: Vec::new(),
+        new_repos: Vec::new(),
+        public: opts.public,
+        repos: repos_meta.clone(),
+    };
+    let mut chunk_sizes = Vec::new();
+    let mut remaining = file_size;
+    while remaining > 0 {
+        let chunk_size = remaining.min(opts.chunk_size);
+        chunk_sizes.push((chunk_sizes.len(), chunk_size));
+        remaining -= chunk_size;
+    }
+    place_chunks(&mut plan, chunk_sizes, opts)?;
+    Ok(plan)
+}
+
+/// `plan` placed again on `repos_meta`, the repos.json another client
+/// pushed while the reservation of `plan` was pushed, after it failed with
+/// `Overbooked`. The repos `plan` created already are kept, empty, for none
+/// to be left out of repos.json, and every chunk keeps its index and size.
+pub fn replan_upload(
+    plan: &UploadPlan,
+    repos_meta: &ReposMetadata,
+    opts: &PlanOptions,
+) -> Result<UploadPlan> {
+    let mut replanned = UploadPlan {
+        assignments: Vec::new(),
+        new_repos: plan.new_repos.clone(),
+        public: plan.public,
+        repos: repos_meta.clone(),
+    };
+    for name in &plan.new_repos {
+        if let Some(repo) = plan.repos.repos.get(name) {
+            replanned
+                .repos
+                .repos
+                .entry(name.clone())
+                .or_insert_with(|| RepoInfo {
+                    current_size: 0,
+                    reclaimable: 0,
+                    ..repo.clone()
+                });
+        }
+    }
+    let chunk_sizes = plan
+        .assignments
+        .iter()
+        .map(|(index, _, size)| (*index, *size))
+        .collect();
+    place_chunks(&mut replanned, chunk_sizes, opts)?;
+    Ok(replanned)
+}
+
+/// Assigns the (index, size) `chunk_sizes` to the repos of `plan` with
+/// `opts.strategy`, adding new repos to it where none has room.
+fn place_chunks(
+    plan: &mut UploadPlan,
+    mut chunk_sizes: Vec<(usize, u64)>,
+    opts: &PlanOptions,
+) -> Result<()> {
+    if opts.strategy == PlacementStrategy::BestFit {
+        // best-fit decreasing: big chunks take the tight holes they still
+        // fit, the short last chunk can then go into a hole no full chunk fits
+        chunk_sizes.sort_by_key(|(_, size)| Reverse(*size));
+    }
+    let mut placed: HashMap<String, usize> = HashMap::new();
+    for (index, chunk_size) in chunk_sizes {
+        let fitting = plan.repos.repos.values().filter(|repo| {
+            !repo.retired
+                && repo.public == opts.public
+                && repo.current_size + chunk_size <= opts.max_repo_size
+        });
+        let chosen = match opts.strategy {
+            PlacementStrategy::BestFit => fitting
+                .min_by_key(|repo| opts.max_repo_size - repo.current_size)
+                .map(|repo| repo.name.clone()),
+            PlacementStrategy::Striped => fitting
+                .min_by_key(|repo| placed.get(&repo.name).copied().unwrap_or(0))
+                .map(|repo| repo.name.clone()),
+        };
+        let repo_name = match chosen {
+            Some(name) => name,
+            None => new_repo(plan, opts)?,
+        };
+        let repo = plan.repos.repos.get_mut(&repo_name).expect("planned repo");
+        repo.current_size += chunk_size;
+        *placed.entry(repo_name.clone()).or_default() += 1;
+        plan.assignments.push((index, repo_name, chunk_size));
+    }
+    plan.assignments.sort_by_key(|(index, _, _)| *index);
+    Ok(())
+}
+
+/// Adds an empty repo named after `opts.seed` to `plan` and returns its name.
+fn new_repo(plan: &mut UploadPlan, opts: &PlanOptions) -> Result<String, StorageExhausted> {
+    let account = new_repo_account(&plan.repos, opts).ok_or_else(|| StorageExhausted {
+        repos: plan.repos.repos.len(),
+        accounts: opts.accounts.len().max(1),
+        max_repos: opts.max_repos.unwrap_or(usize::MAX),
+        stored: plan.repos.repos.values().map(|r| r.current_size).sum(),
+        max_repo_size: opts.max_repo_size,
+        reclaimable: plan.repos.repos.values().map(|r| r.reclaimable).sum(),
+    })?;
+    let mut n = plan.new_repos.len() as u64;
+    let name = loop {
+        let mut input = opts.seed.to_le_bytes().to_vec();
+        input.extend(n.to_le_bytes());
+        let name = format!(
+            "{}{}",
+            opts.repo_prefix,
+            &blake3::hash(&input).to_hex()[..8]
+        );
+        if !plan.repos.repos.contains_key(&name) {
+            break name;
+        }
+        n += 1;
+    };
+    plan.repos.repos.insert(
+        name.clone(),
         RepoInfo {
-            name: repo_name.clone(),
-            current_size: chunk_size,
+            name: name.clone(),
+            current_size: 0,
+            public: opts.public,
+            retired: false,
+            reclaimable: 0,
+            owner: Some(account),
         },
     );
-    Ok(repo_name)
+    plan.new_repos.push(name.clone());
+    Ok(name)
+}
+
+/// The account a new repo goes to with `opts.account_policy`: in turn by
+/// the number of repos, or the one whose repos hold the fewest bytes, of
+/// those holding fewer than `opts.max_repos`. None when every one is full.
+fn new_repo_account(repos_meta: &ReposMetadata, opts: &PlanOptions) -> Option<String> {
+    let own = [owner().to_string()];
+    let accounts = if opts.accounts.is_empty() {
+        &own[..]
+    } else {
+        &opts.accounts[..]
+    };
+    let primary = &accounts[0];
+    let account_of = |repo: &RepoInfo| repo.owner.clone().unwrap_or_else(|| primary.clone());
+    // retired repos still count, they exist on GitHub
+    let mut repos: HashMap<String, usize> = accounts.iter().map(|a| (a.clone(), 0)).collect();
+    let mut used: HashMap<String, u64> = accounts.iter().map(|a| (a.clone(), 0)).collect();
+    for repo in repos_meta.repos.values() {
+        let account = account_of(repo);
+        if let Some(count) = repos.get_mut(&account) {
+            *count += 1;
+        }
+        if let Some(bytes) = used.get_mut(&account) {
+            *bytes += repo.current_size;
+        }
+    }
+    let has_room = |a: &&String| opts.max_repos.is_none_or(|max| repos[*a] < max);
+    match opts.account_policy {
+        AccountPolicy::RoundRobin => {
+            let start = repos_meta.repos.len() % accounts.len();
+            accounts[start..]
+                .iter()
+                .chain(&accounts[..start])
+                .find(has_room)
+                .cloned()
+        }
+        AccountPolicy::LeastUsed => accounts
+            .iter()
+            .filter(has_room)
+            .min_by_key(|a| used[*a])
+            .cloned(),
+    }
+}
+
+/// The accounts new storage repos may go to: the configured ones, or only
+/// the other drive `--metadata-repo` or `--owner` points at.
+pub fn storage_accounts() -> Vec<String> {
+    if remote_overridden() {
+        return vec![owner().to_string()];
+    }
+    settings()
+        .storage_accounts()
+        .into_iter()
+        .map(str::to_string)
+        .collect()
+}
+
+/// Repos the storage accounts may hold together under `max_repos`, none
+/// without a limit.
+pub fn repos_allowed() -> Option<usize> {
+    settings()
+        .max_repos
+        .map(|max| max * storage_accounts().len())
+}
+
+/// Creates the new repos of `plan` and pushes its repos.json with their
+/// space reserved, before any chunk is pushed. When another client reserved
+/// space in the same repos meanwhile, leaving one without room, `plan` is
+/// placed again on the repos.json that client pushed.
+pub fn execute_plan(metadata_clone_dir: &Path, plan: &mut UploadPlan) -> Result<()> {
+    let mut replans = 0;
+    loop {
+        create_planned_repos(plan)?;
+        save_repos_metadata(metadata_clone_dir, &plan.repos)?;
+        match commit_metadata(metadata_clone_dir, "Pre-assign repos for upload") {
+            Err(e) if replans < METADATA_REPLAYS && e.chain().any(|c| c.is::<Overbooked>()) => {
+                replans += 1;
+                eprintln!("--- {}, placing its chunks again", e);
+                let repos_meta = load_repos_metadata(metadata_clone_dir)?;
+                *plan = replan_upload(plan, &repos_meta, &PlanOptions::new(plan.public))?;
+            }
+            res => return res,
+        }
+    }
+}
+
+/// Gives back the space `plan` reserved in `repos_meta`, after its upload
+/// failed. Repos it created stay, empty.
+pub fn release_plan(repos_meta: &mut ReposMetadata, plan: &UploadPlan) {
+    for (_, repo, size) in &plan.assignments {
+        if let Some(info) = repos_meta.repos.get_mut(repo) {
+            info.current_size = info.current_size.saturating_sub(*size);
+        }
+    }
+}
+
+/// Creates the repos reserved by `plan_upload`, each retried with the
+/// `create_repo` policy.
+pub fn create_planned_repos(plan: &UploadPlan) -> Result<()> {
+    record_repo_owners(&plan.repos);
+    for repo_name in &plan.new_repos {
+        if repo_exists(repo_name) {
+            continue;
+        }
+        create_repo(repo_name, plan.public).context("Failed to create new repo")?;
+        // creating repos too quickly trips GitHub's secondary limits
+        sleep(1.3);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CHUNK: u64 = 100;
+
+    fn repo(name: &str, current_size: u64) -> RepoInfo {
+        RepoInfo {
+            name: name.to_string(),
+            current_size,
+            public: false,
+            retired: false,
+            reclaimable: 0,
+            owner: None,
+        }
+    }
+
+    fn repos(sizes: &[(&str, u64)]) -> ReposMetadata {
+        ReposMetadata {
+            next_id: 1,
+            repos: sizes
+                .iter()
+                .map(|(name, size)| (name.to_string(), repo(name, *size)))
+                .collect(),
+        }
+    }
+
+    fn opts(max_repo_chunks: u64) -> PlanOptions {
+        PlanOptions {
+            chunk_size: CHUNK,
+            max_repo_size: max_repo_chunks * CHUNK,
+            repo_prefix: "storage-".to_string(),
+            public: false,
+            strategy: PlacementStrategy::BestFit,
+            seed: 7,
+            accounts: vec!["tester".to_string()],
+            account_policy: AccountPolicy::RoundRobin,
+            max_repos: None,
+        }
+    }
+
+    fn pending(repo: &str, path: &str) -> PendingDelete {
+        PendingDelete {
+            repo: repo.to_string(),
+            path: path.to_string(),
+            size: CHUNK,
+            since: 0,
+        }
+    }
+
+    #[test]
+    fn reservations_of_two_clients_in_the_same_repo_add_up() {
+        let base = repos(&[("a", 2 * CHUNK)]);
+        let ours = repos(&[("a", 3 * CHUNK)]);
+        let theirs = repos(&[("a", 4 * CHUNK), ("b", CHUNK)]);
+        let merged = merge_repos(&base, &ours, &theirs).unwrap();
+        assert_eq!(merged.repos["a"].current_size, 5 * CHUNK);
+        assert_eq!(merged.repos["b"].current_size, CHUNK);
+        assert_eq!(overbooked(&base, &ours, &merged, 4 * CHUNK), ["a"]);
+        assert!(overbooked(&base, &ours, &merged, 5 * CHUNK).is_empty());
+    }
+
+    #[test]
+    fn a_release_never_overbooks_and_flags_come_from_the_side_changing_them() {
+        let mut base = repos(&[("a", 4 * CHUNK), ("b", CHUNK)]);
+        base.repos.get_mut("a").unwrap().reclaimable = CHUNK;
+        let mut ours = base.clone();
+        ours.repos.get_mut("a").unwrap().current_size = 3 * CHUNK;
+        ours.repos.get_mut("a").unwrap().reclaimable = 0;
+        ours.repos.get_mut("b").unwrap().retired = true;
+        let mut theirs = base.clone();
+        theirs.repos.get_mut("a").unwrap().current_size = 5 * CHUNK;
+        theirs.repos.get_mut("b").unwrap().owner = Some("other".to_string());
+        let merged = merge_repos(&base, &ours, &theirs).unwrap();
+        assert_eq!(merged.repos["a"].current_size, 4 * CHUNK);
+        assert_eq!(merged.repos["a"].reclaimable, 0);
+        assert!(merged.repos["b"].retired);
+        assert_eq!(merged.repos["b"].owner.as_deref(), Some("other"));
+        assert!(overbooked(&base, &ours, &merged, 4 * CHUNK).is_empty());
+    }
+
+    #[test]
+    fn repos_added_or_removed_on_either_side_stay_so() {
+        let base = repos(&[("a", CHUNK), ("gone", 0)]);
+        let mut ours = repos(&[("a", CHUNK), ("ours", CHUNK)]);
+        ours.next_id = 3;
+        let mut theirs = repos(&[("a", 2 * CHUNK), ("gone", 0), ("theirs", CHUNK)]);
+        theirs.next_id = 2;
+        let merged = merge_repos(&base, &ours, &theirs).unwrap();
+        let names: Vec<&str> = merged.repos.keys().map(String::as_str).collect();
+        assert_eq!(names, ["a", "ours", "theirs"]);
+        assert_eq!(merged.repos["a"].current_size, 2 * CHUNK);
+        assert_eq!(merged.next_id, 3);
+    }
+
+    #[test]
+    fn a_reservation_in_a_repo_the_other_client_removed_fails() {
+        let base = repos(&[("a", CHUNK)]);
+        let ours = repos(&[("a", 2 * CHUNK)]);
+        let theirs = repos(&[]);
+        assert!(merge_repos(&base, &ours, &theirs).is_err());
+    }
+
+    #[test]
+    fn repo_and_account_names_that_could_reach_a_shell_are_refused() {
+        let mut repos_meta = repos(&[("storage-1", 0), (".github", 0)]);
+        assert!(repos_meta.check_repo_names().is_ok());
+        repos_meta.repos.get_mut("storage-1").unwrap().owner = Some("x;rm -rf ~".into());
+        assert!(repos_meta.check_repo_names().is_err());
+        for bad in ["$(touch pwned)", "-oProxyCommand=x", "a/b/c", "..", ""] {
+            assert!(repos(&[(bad, 0)]).check_repo_names().is_err(), "{}", bad);
+        }
+        let chunk = |repo: &str| ChunkInfo {
+            repo: repo.to_string(),
+            path: "c_00000000.chunk".to_string(),
+            size: CHUNK,
+            index: 0,
+            offset: 0,
+            checksum: None,
+            owner: None,
+        };
+        let mut meta = FileMetadata {
+            checksum: String::new(),
+            checksum_algo: crate::models::ChecksumAlgo::Sha256,
+            size: CHUNK,
+            chunks: vec![chunk("storage-1"), chunk("someone/adopted.repo")],
+            public: false,
+            mtime: None,
+            archive: None,
+            symlink_target: None,
+            xattrs: None,
+            source_url: None,
+        };
+        assert!(meta.check_repo_names().is_ok());
+        meta.chunks.push(chunk("`id`"));
+        assert!(meta.check_repo_names().is_err());
+    }
+
+    #[test]
+    fn removing_a_repo_the_other_client_reserved_space_in_fails() {
+        let base = repos(&[("a", 0), ("b", CHUNK)]);
+        let ours = repos(&[("b", CHUNK)]);
+        let theirs = repos(&[("a", CHUNK), ("b", CHUNK)]);
+        assert!(merge_repos(&base, &ours, &theirs).is_err());
+        // unchanged on their side, the removal goes through
+        let merged = merge_repos(&base, &ours, &base).unwrap();
+        assert!(!merged.repos.contains_key("a"));
+    }
+
+    #[test]
+    fn an_overbooked_plan_is_placed_again_on_the_repos_the_other_client_pushed() {
+        let opts = opts(4);
+        let base = repos(&[("a", 2 * CHUNK)]);
+        let plan = plan_upload(4 * CHUNK, &base, &opts).unwrap();
+        let new = plan.new_repos.clone();
+        assert_eq!(new.len(), 1);
+        assert_eq!(plan.repos.repos["a"].current_size, 4 * CHUNK);
+
+        // the other client took the two free chunks of a first
+        let theirs = repos(&[("a", 4 * CHUNK)]);
+        let merged = merge_repos(&base, &plan.repos, &theirs).unwrap();
+        assert_eq!(
+            overbooked(&base, &plan.repos, &merged, opts.max_repo_size),
+            ["a"]
+        );
+        let replanned = replan_upload(&plan, &theirs, &opts).unwrap();
+        assert_eq!(replanned.new_repos, new);
+        let expected: Vec<(usize, String, u64)> =
+            (0..4).map(|index| (index, new[0].clone(), CHUNK)).collect();
+        assert_eq!(replanned.assignments, expected);
+        assert_eq!(replanned.repos.repos["a"].current_size, 4 * CHUNK);
+        assert_eq!(replanned.repos.repos[&new[0]].current_size, 4 * CHUNK);
+    }
+
+    #[test]
+    fn a_replan_keeps_the_chunk_indexes_of_an_append() {
+        let opts = opts(2);
+        let mut plan = plan_upload(3 * CHUNK, &repos(&[]), &opts).unwrap();
+        for (index, _, _) in plan.assignments.iter_mut() {
+            *index += 10;
+        }
+        let theirs = repos(&[("full", 2 * CHUNK)]);
+        let replanned = replan_upload(&plan, &theirs, &opts).unwrap();
+        let indexes: Vec<usize> = replanned.assignments.iter().map(|a| a.0).collect();
+        assert_eq!(indexes, [10, 11, 12]);
+        assert!(replanned
+            .assignments
+            .iter()
+            .all(|(_, repo, _)| repo != "full"));
+        assert!(plan
+            .new_repos
+            .iter()
+            .all(|name| replanned.new_repos.contains(name)));
+    }
+
+    #[test]
+    fn the_short_last_chunk_goes_into_a_hole_no_full_chunk_fits() {
+        let opts = opts(10);
+        let base = repos(&[("a", 10 * CHUNK - 40), ("b", 9 * CHUNK)]);
+        let plan = plan_upload(2 * CHUNK + 30, &base, &opts).unwrap();
+        assert_eq!(plan.new_repos.len(), 1);
+        let new = plan.new_repos[0].clone();
+        assert_eq!(
+            plan.assignments,
+            [
+                (0, "b".to_string(), CHUNK),
+                (1, new.clone(), CHUNK),
+                (2, "a".to_string(), 30),
+            ]
+        );
+        assert_eq!(plan.repos.repos["a"].current_size, 10 * CHUNK - 10);
+        assert_eq!(plan.repos.repos["b"].current_size, 10 * CHUNK);
+        assert_eq!(plan.repos.repos[&new].current_size, CHUNK);
+    }
+
+    #[test]
+    fn holes_left_by_deletes_are_filled_before_a_repo_is_created() {
+        let opts = opts(4);
+        let mut base = repos(&[
+            ("a", 4 * CHUNK - 50),
+            ("b", 2 * CHUNK),
+            ("c", 4 * CHUNK - 160),
+            ("old", 0),
+        ]);
+        base.repos.get_mut("old").unwrap().retired = true;
+        let plan = plan_upload(3 * CHUNK + 40, &base, &opts).unwrap();
+        assert!(plan.new_repos.is_empty());
+        let used: Vec<&str> = plan.assignments.iter().map(|a| a.1.as_str()).collect();
+        assert_eq!(used, ["c", "b", "b", "a"]);
+        let sizes: Vec<u64> = ["a", "b", "c", "old"]
+            .iter()
+            .map(|name| plan.repos.repos[*name].current_size)
+            .collect();
+        assert_eq!(sizes, [4 * CHUNK - 10, 4 * CHUNK, 4 * CHUNK - 60, 0]);
+    }
+
+    #[test]
+    fn as_many_repos_are_created_as_the_chunks_fill() {
+        for (size, created) in [
+            (0, 0),
+            (1, 1),
+            (4 * CHUNK, 1),
+            (4 * CHUNK + 1, 2),
+            (10 * CHUNK, 3),
+        ] {
+            let plan = plan_upload(size, &repos(&[]), &opts(4)).unwrap();
+            assert_eq!(plan.new_repos.len(), created, "{} bytes", size);
+            assert_eq!(plan.repos.repos.len(), created, "{} bytes", size);
+            let reserved: u64 = plan.repos.repos.values().map(|r| r.current_size).sum();
+            assert_eq!(reserved, size);
+        }
+        let mut striped = opts(4);
+        striped.strategy = PlacementStrategy::Striped;
+        let base = repos(&[("a", 0), ("b", 0), ("c", 0)]);
+        let plan = plan_upload(6 * CHUNK, &base, &striped).unwrap();
+        assert!(plan.new_repos.is_empty());
+        let used: Vec<&str> = plan.assignments.iter().map(|a| a.1.as_str()).collect();
+        assert_eq!(used, ["a", "b", "c", "a", "b", "c"]);
+    }
+
+    #[test]
+    fn a_file_exactly_filling_a_repo_creates_none() {
+        for strategy in [PlacementStrategy::BestFit, PlacementStrategy::Striped] {
+            let mut opts = opts(4);
+            opts.strategy = strategy;
+            let plan = plan_upload(3 * CHUNK, &repos(&[("a", CHUNK)]), &opts).unwrap();
+            assert!(plan.new_repos.is_empty(), "{}", strategy);
+            assert!(plan.assignments.iter().all(|(_, repo, _)| repo == "a"));
+            assert_eq!(plan.repos.repos["a"].current_size, opts.max_repo_size);
+        }
+    }
+
+    #[test]
+    fn a_chunk_no_repo_has_room_for_goes_to_a_new_repo_or_fails_at_max_repos() {
+        let mut opts = opts(4);
+        let base = repos(&[("a", 4 * CHUNK - 50), ("b", 4 * CHUNK - 99)]);
+        let plan = plan_upload(CHUNK, &base, &opts).unwrap();
+        assert_eq!(plan.new_repos.len(), 1);
+        assert_eq!(plan.assignments, [(0, plan.new_repos[0].clone(), CHUNK)]);
+        assert_eq!(plan.repos.repos["a"].current_size, 4 * CHUNK - 50);
+        assert_eq!(plan.repos.repos["b"].current_size, 4 * CHUNK - 99);
+
+        opts.max_repos = Some(2);
+        let err = plan_upload(CHUNK, &base, &opts).unwrap_err();
+        let exhausted = err.downcast_ref::<StorageExhausted>().unwrap();
+        assert_eq!(exhausted.repos, 2);
+        // the short chunk still fits
+        let plan = plan_upload(50, &base, &opts).unwrap();
+        assert_eq!(plan.assignments, [(0, "a".to_string(), 50)]);
+    }
+
+    #[test]
+    fn an_empty_file_takes_no_chunk_and_no_repo() {
+        let base = repos(&[("a", 4 * CHUNK)]);
+        for strategy in [PlacementStrategy::BestFit, PlacementStrategy::Striped] {
+            let mut opts = opts(4);
+            opts.strategy = strategy;
+            opts.max_repos = Some(1);
+            let plan = plan_upload(0, &base, &opts).unwrap();
+            assert!(plan.assignments.is_empty());
+            assert!(plan.new_repos.is_empty());
+            assert_eq!(plan.repos, base);
+        }
+    }
+
+    #[test]
+    fn the_chunks_of_a_file_of_more_than_10k_chunks_get_8_digit_names_in_order() {
+        // a sparse file: the planner only sees its size
+        let plan = plan_upload(10_001 * CHUNK + 1, &repos(&[]), &opts(4096)).unwrap();
+        assert_eq!(plan.assignments.len(), 10_002);
+        assert_eq!(plan.new_repos.len(), 3);
+        let names: Vec<String> = plan
+            .assignments
+            .iter()
+            .map(|(index, _, _)| crate::chunks::chunk_dest_path("c0ffee", *index))
+            .collect();
+        assert_eq!(names[0], "c0ffee_00000000.chunk");
+        assert_eq!(names[10_001], "c0ffee_00010001.chunk");
+        assert!(names.windows(2).all(|pair| pair[0] < pair[1]));
+    }
+
+    #[test]
+    fn spellings_of_the_same_remote_share_a_shard_and_a_path() {
+        let path = namespace_meta_relpath("default", "docs/a.txt", 64).unwrap();
+        for spelling in [
+            "/docs/a.txt",
+            "docs//a.txt",
+            "./docs/./a.txt",
+            "docs/a.txt/",
+        ] {
+            assert_eq!(shard_of(spelling, 64), shard_of("docs/a.txt", 64));
+            let spelled = namespace_meta_relpath("default", spelling, 64).unwrap();
+            assert_eq!(spelled, path, "{}", spelling);
+        }
+        assert_ne!(shard_of("docs/a.txt", 64), shard_of("docs/b.txt", 64));
+    }
+
+    #[test]
+    fn pending_deletes_merge_by_repo_and_path() {
+        let base = [pending("a", "0"), pending("a", "1")];
+        // ours collected a/0 and queued a/2, theirs queued a/3 and a/2
+        let ours = [pending("a", "1"), pending("a", "2")];
+        let theirs = [
+            pending("a", "0"),
+            pending("a", "1"),
+            pending("a", "3"),
+            pending("a", "2"),
+        ];
+        let merged = merge_pending_deletes(&base, &ours, &theirs);
+        let paths: Vec<&str> = merged.iter().map(|p| p.path.as_str()).collect();
+        assert_eq!(paths, ["1", "3", "2"]);
+    }
+
+    #[test]
+    fn gc_deletes_a_chunk_once_its_grace_period_is_over() {
+        use crate::clock::with_mock_clock;
+        use std::time::Duration;
+
+        const GRACE: u64 = 7 * 86_400;
+        let mut repos_meta = repos(&[("a", 4 * CHUNK), ("b", CHUNK)]);
+        repos_meta.repos.get_mut("a").unwrap().reclaimable = 3 * CHUNK;
+        let index = ChunkIndex::from([(
+            ("a".to_string(), "used".to_string()),
+            IndexedChunk {
+                content: None,
+                refs: 1,
+            },
+        )]);
+        let ((), _) = with_mock_clock(|mock| {
+            mock.advance(Duration::from_secs(1_700_000_000));
+            let released = |repo, path| PendingDelete {
+                since: unix_now(),
+                ..pending(repo, path)
+            };
+            let mut entries = vec![
+                released("a", "old"),
+                released("a", "used"),
+                released("gone", "0"),
+            ];
+            mock.advance(Duration::from_secs(GRACE / 2));
+            entries.extend([released("a", "new"), released("b", "new")]);
+
+            mock.advance(Duration::from_secs(GRACE / 2 - 1));
+            let (due, waiting, dropped) =
+                split_pending_deletes(entries.clone(), &index, &mut repos_meta, GRACE, false);
+            assert!(due.is_empty(), "{:?}", due);
+            assert_eq!(waiting.len(), 3);
+            assert_eq!(dropped, 2);
+            assert_eq!(repos_meta.repos["a"].reclaimable, 2 * CHUNK);
+
+            mock.advance(Duration::from_secs(1));
+            let (due, waiting, _) =
+                split_pending_deletes(entries.clone(), &index, &mut repos_meta, GRACE, false);
+            assert_eq!(due.keys().collect::<Vec<_>>(), ["a"]);
+            assert_eq!(due["a"], entries[..1]);
+            let waiting: Vec<&str> = waiting.iter().map(|p| p.path.as_str()).collect();
+            assert_eq!(waiting, ["new", "new"]);
+
+            let (due, waiting, dropped) =
+                split_pending_deletes(entries, &index, &mut repos_meta, GRACE, true);
+            assert_eq!(due["a"].len(), 2);
+            assert_eq!(due["b"].len(), 1);
+            assert!(waiting.is_empty());
+            assert_eq!(dropped, 2);
+        });
+    }
 }
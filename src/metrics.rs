@@ -0,0 +1,196 @@
+use anyhow::{Context, Result};
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+use crate::metadata::{FileNotFound, IncompatibleVersion};
+use crate::utils::is_no_space;
+
+/// Counters of the transfers of this process, exposed by the long-running
+/// modes in the Prometheus text format: `serve` on `/metrics`, `watch` with
+/// `--metrics-file`.
+struct Registry {
+    in_flight: AtomicU64,
+    upload_queue: AtomicU64,
+    watch_pending: AtomicU64,
+    /// (direction) -> (transfers, bytes)
+    transfers: Mutex<BTreeMap<String, (u64, u64)>>,
+    /// (direction, kind) -> failures
+    failures: Mutex<BTreeMap<(String, &'static str), u64>>,
+    /// operation -> retries
+    retries: Mutex<BTreeMap<&'static str, u64>>,
+}
+
+static REGISTRY: Registry = Registry {
+    in_flight: AtomicU64::new(0),
+    upload_queue: AtomicU64::new(0),
+    watch_pending: AtomicU64::new(0),
+    transfers: Mutex::new(BTreeMap::new()),
+    failures: Mutex::new(BTreeMap::new()),
+    retries: Mutex::new(BTreeMap::new()),
+};
+
+/// Counts a transfer as in flight until dropped.
+pub struct InFlight;
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        REGISTRY.in_flight.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+pub fn transfer_started() -> InFlight {
+    REGISTRY.in_flight.fetch_add(1, Ordering::Relaxed);
+    InFlight
+}
+
+pub fn transfer_succeeded(direction: &str, bytes: u64) {
+    let mut transfers = REGISTRY.transfers.lock().unwrap();
+    let entry = transfers.entry(direction.to_string()).or_default();
+    entry.0 += 1;
+    entry.1 += bytes;
+}
+
+pub fn transfer_failed(direction: &str, e: &anyhow::Error) {
+    let mut failures = REGISTRY.failures.lock().unwrap();
+    *failures
+        .entry((direction.to_string(), failure_kind(e)))
+        .or_default() += 1;
+}
+
+/// Rough cause of a failed transfer, for the `kind` label.
+fn failure_kind(e: &anyhow::Error) -> &'static str {
+    if is_no_space(e) {
+        "no_space"
+    } else if e.downcast_ref::<FileNotFound>().is_some() {
+        "not_found"
+    } else if e.downcast_ref::<IncompatibleVersion>().is_some() {
+        "incompatible_version"
+    } else {
+        "other"
+    }
+}
+
+/// Counts one more retry of `operation`, like "push" or "create_repo".
+pub fn retried(operation: &'static str) {
+    *REGISTRY
+        .retries
+        .lock()
+        .unwrap()
+        .entry(operation)
+        .or_default() += 1;
+}
+
+/// Repo batches of an upload written and waiting for a push worker.
+pub fn upload_queue_changed(queued: bool) {
+    if queued {
+        REGISTRY.upload_queue.fetch_add(1, Ordering::Relaxed);
+    } else {
+        REGISTRY.upload_queue.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Changed files `watch` waits on to settle before uploading them.
+pub fn set_watch_pending(files: usize) {
+    REGISTRY
+        .watch_pending
+        .store(files as u64, Ordering::Relaxed);
+}
+
+/// The metrics in the Prometheus text exposition format.
+pub fn render() -> String {
+    let mut out = String::new();
+    let mut metric = |name: &str, kind: &str, help: &str, samples: &[(String, u64)]| {
+        let _ = writeln!(out, "# HELP gidrive_{} {}", name, help);
+        let _ = writeln!(out, "# TYPE gidrive_{} {}", name, kind);
+        for (labels, value) in samples {
+            let _ = writeln!(out, "gidrive_{}{} {}", name, labels, value);
+        }
+    };
+    let gauge = |value: &AtomicU64| vec![(String::new(), value.load(Ordering::Relaxed))];
+    metric(
+        "transfers_in_flight",
+        "gauge",
+        "Uploads and downloads running now.",
+        &gauge(&REGISTRY.in_flight),
+    );
+    let transfers = REGISTRY.transfers.lock().unwrap().clone();
+    let by_direction = |pick: fn(&(u64, u64)) -> u64| {
+        ["upload", "download"]
+            .iter()
+            .map(|d| {
+                let value = transfers.get(*d).map_or(0, pick);
+                (format!("{{direction=\"{}\"}}", d), value)
+            })
+            .collect::<Vec<_>>()
+    };
+    metric(
+        "transfers_total",
+        "counter",
+        "Finished uploads and downloads.",
+        &by_direction(|t| t.0),
+    );
+    metric(
+        "transferred_bytes_total",
+        "counter",
+        "Bytes of the finished uploads and downloads.",
+        &by_direction(|t| t.1),
+    );
+    let failures: Vec<_> = REGISTRY
+        .failures
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|((direction, kind), n)| {
+            (
+                format!("{{direction=\"{}\",kind=\"{}\"}}", direction, kind),
+                *n,
+            )
+        })
+        .collect();
+    metric(
+        "transfer_failures_total",
+        "counter",
+        "Failed uploads and downloads by cause.",
+        &failures,
+    );
+    let retries: Vec<_> = REGISTRY
+        .retries
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(operation, n)| (format!("{{operation=\"{}\"}}", operation), *n))
+        .collect();
+    metric(
+        "retries_total",
+        "counter",
+        "Pushes, repo creations and chunk downloads tried again.",
+        &retries,
+    );
+    metric(
+        "upload_queue_depth",
+        "gauge",
+        "Repo batches of chunks waiting for a push worker.",
+        &gauge(&REGISTRY.upload_queue),
+    );
+    metric(
+        "watch_pending_files",
+        "gauge",
+        "Changed files watch waits on before uploading them.",
+        &gauge(&REGISTRY.watch_pending),
+    );
+    out
+}
+
+/// Replaces `path` with the current metrics through a rename, so the
+/// textfile collector never reads a half-written file.
+pub fn write_file(path: &Path) -> Result<()> {
+    let name = path
+        .file_name()
+        .with_context(|| format!("{} is not a file path", path.display()))?;
+    let tmp = path.with_file_name(format!(".{}.gidrive-tmp", name.to_string_lossy()));
+    std::fs::write(&tmp, render()).with_context(|| format!("Failed to write {}", tmp.display()))?;
+    std::fs::rename(&tmp, path).with_context(|| format!("Failed to replace {}", path.display()))
+}
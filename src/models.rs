@@ -1,29 +1,271 @@
 use serde::{Deserialize, Serialize};
 use std::collections::BTreeMap;
+use std::fmt;
+use std::str::FromStr;
 
-#[derive(Serialize, Deserialize, Clone)]
+use crate::git::qualified_repo;
+
+// Types of the metadata repo files. Unlike the config they accept unknown
+// fields, so an older gidrive reads metadata a newer one wrote, and every
+// field added after the first format is optional with a default.
+
+/// Hash used for a file's checksum and its chunk checksums.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ChecksumAlgo {
+    #[default]
+    Sha256,
+    Blake3,
+}
+
+impl fmt::Display for ChecksumAlgo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ChecksumAlgo::Sha256 => write!(f, "sha256"),
+            ChecksumAlgo::Blake3 => write!(f, "blake3"),
+        }
+    }
+}
+
+impl FromStr for ChecksumAlgo {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "sha256" => Ok(ChecksumAlgo::Sha256),
+            "blake3" => Ok(ChecksumAlgo::Blake3),
+            _ => Err(format!(
+                "unknown checksum algorithm {:?}, use sha256 or blake3",
+                s
+            )),
+        }
+    }
+}
+
+/// Key of chunk content in chunks.idx, like `sha256:<hex>`: the same bytes
+/// hash differently with another algorithm.
+pub fn content_key(algo: ChecksumAlgo, checksum: &str) -> String {
+    format!("{}:{}", algo, checksum)
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
 pub struct ChunkInfo {
     pub repo: String,
     pub path: String,
     pub size: u64,
     pub index: usize,
+    /// Start of the chunk inside the file at `path`, non-zero only for
+    /// ranges of adopted files
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub offset: u64,
+    /// Checksum of the chunk content, absent in metadata from older versions
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub checksum: Option<String>,
+    /// Account `repo` is under, the drive's own when absent as in metadata
+    /// from older versions
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub owner: Option<String>,
 }
 
-#[derive(Serialize, Deserialize)]
+impl ChunkInfo {
+    /// The name clones and fetches of its repo go through.
+    pub fn location(&self) -> String {
+        qualified_repo(&self.repo, self.owner.as_deref())
+    }
+}
+
+/// Whether `name` can be an account or repo name. Names from the metadata
+/// reach git and gh, and the metadata of another drive is not trusted.
+pub fn valid_repo_name(name: &str) -> bool {
+    !name.is_empty()
+        && !name.starts_with('-')
+        && name != "."
+        && name != ".."
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-'))
+}
+
+/// Fails for a repo `name`, `owner/name` for adopted chunks, or `owner`
+/// that `valid_repo_name` refuses.
+fn check_repo(name: &str, owner: Option<&str>) -> Result<(), String> {
+    let parts = match name.split_once('/') {
+        Some((repo_owner, repo)) => vec![repo_owner, repo],
+        None => vec![name],
+    };
+    match parts.into_iter().chain(owner).find(|p| !valid_repo_name(p)) {
+        Some(bad) => Err(format!("invalid repo or account name {:?}", bad)),
+        None => Ok(()),
+    }
+}
+
+fn is_zero(n: &u64) -> bool {
+    *n == 0
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
 pub struct FileMetadata {
     pub checksum: String,
+    /// Algorithm of `checksum` and the chunk checksums, sha256 when absent
+    #[serde(default)]
+    pub checksum_algo: ChecksumAlgo,
     pub size: u64,
     pub chunks: Vec<ChunkInfo>,
+    /// Chunks live in public repos, readable without a token
+    #[serde(default)]
+    pub public: bool,
+    /// Last upload or append, in seconds since the epoch, absent in metadata
+    /// from older versions
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mtime: Option<u64>,
+    /// Set for a directory uploaded with `upload --archive`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub archive: Option<ArchiveInfo>,
+    /// Set for a symlink uploaded with `--preserve-symlinks`, which has no
+    /// chunks
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub symlink_target: Option<String>,
+    /// Extended attributes of a file uploaded with `--xattrs`, values in
+    /// base64, restored by `download --xattrs`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub xattrs: Option<BTreeMap<String, String>>,
+    /// The URL a file added with `fetch` was downloaded from, without
+    /// credentials
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source_url: Option<String>,
+}
+
+/// A directory stored as one archive file.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct ArchiveInfo {
+    /// "tar+zstd", the only format so far
+    pub format: String,
+    pub entries: usize,
+    /// Size of the archived files before compression, absent in metadata
+    /// from older versions
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bytes: Option<u64>,
+}
+
+/// A remote file as listed by `ls --json` and `GET /files`.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct FileEntry {
+    pub path: String,
+    pub size: u64,
+    pub checksum: String,
+    #[serde(default)]
+    pub checksum_algo: ChecksumAlgo,
+    pub mtime: Option<u64>,
+    /// Number of chunks the file is stored in
+    #[serde(default)]
+    pub chunks: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub archive: Option<ArchiveInfo>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub symlink_target: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub source_url: Option<String>,
+}
+
+impl FileEntry {
+    pub fn new(path: String, meta: FileMetadata) -> Self {
+        FileEntry {
+            path,
+            size: meta.size,
+            checksum: meta.checksum,
+            checksum_algo: meta.checksum_algo,
+            mtime: meta.mtime,
+            chunks: meta.chunks.len(),
+            archive: meta.archive,
+            symlink_target: meta.symlink_target,
+            source_url: meta.source_url,
+        }
+    }
 }
 
-#[derive(Serialize, Deserialize)]
+/// A chunk of a storage repo as recorded in chunks.idx.
+#[derive(Clone, PartialEq, Debug)]
+pub struct IndexedChunk {
+    /// `content_key` of the chunk, absent for chunks from older versions
+    pub content: Option<String>,
+    /// Files of any namespace referencing the chunk
+    pub refs: usize,
+}
+
+impl FileMetadata {
+    /// Fails for a chunk whose repo or owner is not a valid name.
+    pub fn check_repo_names(&self) -> Result<(), String> {
+        self.chunks
+            .iter()
+            .try_for_each(|c| check_repo(&c.repo, c.owner.as_deref()))
+    }
+}
+
+/// chunks.idx: every chunk of the storage repos by (repo, path).
+pub type ChunkIndex = BTreeMap<(String, String), IndexedChunk>;
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
 pub struct RepoInfo {
     pub name: String,
     pub current_size: u64,
+    /// Public repos only hold chunks of public files, private ones never do
+    #[serde(default)]
+    pub public: bool,
+    /// Retired repos keep their chunks but receive no new ones
+    #[serde(default)]
+    pub retired: bool,
+    /// Part of `current_size` taken by chunks waiting in pending_delete.json,
+    /// given back by `gc`
+    #[serde(default)]
+    pub reclaimable: u64,
+    /// Account the repo is under, the drive's own when absent
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub owner: Option<String>,
 }
 
-#[derive(Serialize, Deserialize)]
+impl RepoInfo {
+    /// The name clones, pushes and deletions of the repo go through.
+    pub fn location(&self) -> String {
+        qualified_repo(&self.name, self.owner.as_deref())
+    }
+}
+
+/// A chunk no file uses anymore, kept in pending_delete.json until `gc`
+/// deletes it once the grace period is over.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct PendingDelete {
+    pub repo: String,
+    pub path: String,
+    pub size: u64,
+    /// Unix seconds the last file using it was overwritten or removed
+    pub since: u64,
+}
+
+/// Per-namespace totals kept in namespaces.json.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct NamespaceStats {
+    pub files: usize,
+    pub bytes: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
 pub struct ReposMetadata {
+    /// Number of the next repo for clients naming repos `<prefix><0001>`,
+    /// kept for them: new repos get random names that cannot collide
+    #[serde(default)]
     pub next_id: usize,
     pub repos: BTreeMap<String, RepoInfo>,
 }
+
+impl ReposMetadata {
+    /// Fails for a repo whose name or owner is not a valid name.
+    pub fn check_repo_names(&self) -> Result<(), String> {
+        self.repos.iter().try_for_each(|(key, info)| {
+            if key.contains('/') || info.name.contains('/') {
+                return Err(format!("invalid repo name {:?}", key));
+            }
+            check_repo(key, None)?;
+            check_repo(&info.name, info.owner.as_deref())
+        })
+    }
+}
@@ -0,0 +1,97 @@
+use serde::Serialize;
+use std::io::Write;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Sender};
+use std::sync::Mutex;
+use std::thread::{self, JoinHandle};
+
+/// A transfer progress event, written as one JSON line with `--progress json`.
+#[derive(Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event<'a> {
+    TransferStarted {
+        direction: &'a str,
+        remote: &'a str,
+        bytes: u64,
+        total_chunks: usize,
+    },
+    RepoStarted {
+        repo: &'a str,
+        chunks: usize,
+    },
+    ChunkUploaded {
+        index: usize,
+        repo: &'a str,
+        bytes: u64,
+        total_chunks: usize,
+    },
+    ChunkDownloaded {
+        index: usize,
+        repo: &'a str,
+        bytes: u64,
+        total_chunks: usize,
+    },
+    RepoFinished {
+        repo: &'a str,
+        chunks: usize,
+        seconds: f64,
+    },
+    /// Objects libgit2 fetched or pushed, with the `libgit2` feature
+    GitTransfer {
+        direction: &'a str,
+        repo: &'a str,
+        objects: usize,
+        total_objects: usize,
+        bytes: u64,
+    },
+    TransferFinished {
+        direction: &'a str,
+        remote: &'a str,
+        bytes: u64,
+        chunks: usize,
+        seconds: f64,
+    },
+}
+
+static JSON: AtomicBool = AtomicBool::new(false);
+static WRITER: Mutex<Option<(Sender<String>, JoinHandle<()>)>> = Mutex::new(None);
+
+/// Starts writing events to stderr as newline-delimited JSON. A single writer
+/// thread owns stderr so lines from parallel workers never interleave.
+pub fn enable_json() {
+    let (tx, rx) = mpsc::channel::<String>();
+    let handle = thread::spawn(move || {
+        let mut stderr = std::io::stderr();
+        for line in rx {
+            let _ = writeln!(stderr, "{}", line);
+            let _ = stderr.flush();
+        }
+    });
+    *WRITER.lock().unwrap() = Some((tx, handle));
+    JSON.store(true, Ordering::Relaxed);
+}
+
+/// Whether events are written, in which case human progress output is not.
+pub fn json_enabled() -> bool {
+    JSON.load(Ordering::Relaxed)
+}
+
+pub fn emit(event: Event) {
+    if !json_enabled() {
+        return;
+    }
+    let Ok(line) = serde_json::to_string(&event) else {
+        return;
+    };
+    if let Some((tx, _)) = WRITER.lock().unwrap().as_ref() {
+        let _ = tx.send(line);
+    }
+}
+
+/// Waits until every emitted event is written, before the process exits.
+pub fn finish() {
+    if let Some((tx, handle)) = WRITER.lock().unwrap().take() {
+        drop(tx);
+        let _ = handle.join();
+    }
+}
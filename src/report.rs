@@ -0,0 +1,139 @@
+use std::time::{Duration, Instant};
+
+use crate::utils::human_size;
+
+/// Where the time of a single upload/download went.
+#[derive(Debug, Default, Clone)]
+pub struct TransferReport {
+    /// Bytes of file data transferred
+    pub bytes: u64,
+    /// Wall clock time of the whole operation
+    pub total: Duration,
+    /// (phase, time) in the order the phases first ran, repeated phases accumulate
+    pub phases: Vec<(String, Duration)>,
+    /// Per storage repo breakdown, these run in parallel so they overlap
+    pub repos: Vec<RepoTimings>,
+    /// Chunks an upload pushed to storage repos
+    pub chunks_pushed: usize,
+    /// Chunks an upload did not push because the same content was stored
+    pub chunks_reused: usize,
+    /// Bytes of the pushed chunks, at most `bytes`
+    pub bytes_pushed: u64,
+    /// Size of the archived files before compression, for archive uploads
+    pub bytes_uncompressed: Option<u64>,
+    /// Checksum of the whole file transferred
+    pub checksum: Option<String>,
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct RepoTimings {
+    pub repo: String,
+    pub chunks: usize,
+    /// Bytes of the chunks pushed, those the repo already had excluded
+    pub bytes: u64,
+    /// Indexes of the chunks the repo already had, which were not pushed
+    pub skipped: Vec<usize>,
+    pub clone: Duration,
+    pub copy: Duration,
+    pub commit: Duration,
+    pub push: Duration,
+    /// Reading the pushed chunks back with `verify_upload`
+    pub verify: Duration,
+    /// Bytes read back with `verify_upload`
+    pub bytes_verified: u64,
+}
+
+impl TransferReport {
+    pub fn add(&mut self, phase: &str, elapsed: Duration) {
+        match self.phases.iter_mut().find(|(p, _)| p == phase) {
+            Some((_, total)) => *total += elapsed,
+            None => self.phases.push((phase.to_string(), elapsed)),
+        }
+    }
+
+    /// Runs `f` and accounts its duration to `phase`.
+    pub fn time<T>(&mut self, phase: &str, f: impl FnOnce() -> T) -> T {
+        let started = Instant::now();
+        let res = f();
+        self.add(phase, started.elapsed());
+        res
+    }
+
+    /// Adds the byte and chunk counts and time of `other`, for the totals of
+    /// several transfers.
+    pub fn merge(&mut self, other: &TransferReport) {
+        self.bytes += other.bytes;
+        self.total += other.total;
+        self.chunks_pushed += other.chunks_pushed;
+        self.chunks_reused += other.chunks_reused;
+        self.bytes_pushed += other.bytes_pushed;
+        self.bytes_uncompressed = match (self.bytes_uncompressed, other.bytes_uncompressed) {
+            (None, None) => None,
+            (a, b) => Some(a.unwrap_or(0) + b.unwrap_or(0)),
+        };
+    }
+
+    /// "pushed X of Y in N chunks, M reused", what an upload actually sent.
+    pub fn savings(&self) -> String {
+        format!(
+            "pushed {} of {} in {} chunks, {} reused ({} saved)",
+            human_size(self.bytes_pushed),
+            human_size(self.bytes),
+            self.chunks_pushed,
+            self.chunks_reused,
+            human_size(self.bytes.saturating_sub(self.bytes_pushed))
+        )
+    }
+
+    /// Bytes of pushed chunks read back from GitHub with `verify_upload`.
+    pub fn bytes_verified(&self) -> u64 {
+        self.repos.iter().map(|r| r.bytes_verified).sum()
+    }
+
+    /// Bytes per second over the whole operation.
+    pub fn throughput(&self) -> u64 {
+        (self.bytes as f64 / self.total.as_secs_f64().max(0.001)) as u64
+    }
+
+    /// Prints the breakdown table to stderr.
+    pub fn print(&self) {
+        eprintln!("{:<24} {:>9}", "phase", "time");
+        for (phase, elapsed) in &self.phases {
+            eprintln!("{:<24} {:>8.2}s", phase, elapsed.as_secs_f64());
+        }
+        if !self.repos.is_empty() {
+            // the verify column only when the chunks were read back
+            let verified = self.repos.iter().any(|r| r.bytes_verified > 0);
+            let mut header = format!(
+                "{:<16} {:>6} {:>9} {:>9} {:>9} {:>9}",
+                "repo", "chunks", "clone", "copy", "commit", "push"
+            );
+            if verified {
+                header.push_str(&format!(" {:>9}", "verify"));
+            }
+            eprintln!("{}", header);
+            for r in &self.repos {
+                let mut line = format!(
+                    "{:<16} {:>6} {:>8.2}s {:>8.2}s {:>8.2}s {:>8.2}s",
+                    r.repo,
+                    r.chunks,
+                    r.clone.as_secs_f64(),
+                    r.copy.as_secs_f64(),
+                    r.commit.as_secs_f64(),
+                    r.push.as_secs_f64()
+                );
+                if verified {
+                    line.push_str(&format!(" {:>8.2}s", r.verify.as_secs_f64()));
+                }
+                eprintln!("{}", line);
+            }
+        }
+        eprintln!(
+            "{:<24} {:>8.2}s ({} in {}/s)",
+            "total",
+            self.total.as_secs_f64(),
+            human_size(self.bytes),
+            human_size(self.throughput())
+        );
+    }
+}
@@ -0,0 +1,134 @@
+use anyhow::{bail, Result};
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::fs;
+
+use crate::config::settings;
+use crate::git::{list_account_repos, list_repos, owner};
+use crate::metadata::{
+    check_write_version, clone_metadata, commit_metadata, list_all_file_metadata,
+    load_repos_metadata, save_repos_metadata,
+};
+use crate::models::RepoInfo;
+use crate::utils::human_size;
+
+/// Prints every storage repo with its recorded size, the part of it `gc`
+/// will give back, fill level, number of chunks referenced by files and
+/// whether it exists on GitHub, grouped by account. An account whose repos
+/// cannot be listed shows `?` for them.
+pub fn list() -> Result<()> {
+    let metadata_clone_dir = clone_metadata()?;
+    let repos_meta = load_repos_metadata(&metadata_clone_dir)?;
+    let mut chunks: BTreeMap<String, BTreeSet<String>> = BTreeMap::new();
+    for meta in list_all_file_metadata(&metadata_clone_dir)?.into_values() {
+        for chunk in meta.chunks {
+            chunks.entry(chunk.repo).or_default().insert(chunk.path);
+        }
+    }
+    fs::remove_dir_all(&metadata_clone_dir)?;
+    let mut by_account: BTreeMap<&str, Vec<&RepoInfo>> = BTreeMap::new();
+    for repo in repos_meta.repos.values() {
+        let account = repo.owner.as_deref().unwrap_or(owner());
+        by_account.entry(account).or_default().push(repo);
+    }
+    for (account, repos) in by_account {
+        let listed = if account == owner() {
+            list_repos()
+        } else {
+            list_account_repos(account)
+        };
+        let on_github: Option<HashSet<String>> = match listed {
+            Ok(names) => Some(names.into_iter().collect()),
+            Err(e) => {
+                eprintln!("--- could not list the repos of {}: {:#}", account, e);
+                None
+            }
+        };
+        let size: u64 = repos.iter().map(|r| r.current_size).sum();
+        println!("{}: {} repos, {}", account, repos.len(), human_size(size));
+        println!(
+            "{:<16} {:>10} {:>11} {:>6} {:>7} {:>7}  flags",
+            "repo", "size", "reclaimable", "fill", "chunks", "exists"
+        );
+        for repo in repos {
+            let mut flags = Vec::new();
+            if repo.public {
+                flags.push("public");
+            }
+            if repo.retired {
+                flags.push("retired");
+            }
+            let exists = match &on_github {
+                Some(names) if names.contains(&repo.name) => "yes",
+                Some(_) => "NO",
+                None => "?",
+            };
+            let line = format!(
+                "{:<16} {:>10} {:>11} {:>5.1}% {:>7} {:>7}  {}",
+                repo.name,
+                human_size(repo.current_size),
+                human_size(repo.reclaimable),
+                repo.current_size as f64 * 100.0 / settings().max_repo_size() as f64,
+                chunks.get(&repo.name).map_or(0, |c| c.len()),
+                exists,
+                flags.join(",")
+            );
+            println!("{}", line.trim_end());
+        }
+    }
+    Ok(())
+}
+
+/// Prints the chunks stored in `repo` and the files, as
+/// `<namespace>/<path>`, that reference them.
+pub fn show(repo: &str) -> Result<()> {
+    let metadata_clone_dir = clone_metadata()?;
+    let repos_meta = load_repos_metadata(&metadata_clone_dir)?;
+    let files = list_all_file_metadata(&metadata_clone_dir)?;
+    fs::remove_dir_all(&metadata_clone_dir)?;
+    let Some(info) = repos_meta.repos.get(repo) else {
+        bail!("no storage repo {} in repos.json", repo);
+    };
+    // chunk path -> (size, referencing files)
+    let mut chunks: BTreeMap<String, (u64, Vec<String>)> = BTreeMap::new();
+    for (remote, meta) in &files {
+        for chunk in meta.chunks.iter().filter(|c| c.repo == repo) {
+            chunks
+                .entry(chunk.path.clone())
+                .or_insert((chunk.size, Vec::new()))
+                .1
+                .push(format!("{} #{}", remote, chunk.index));
+        }
+    }
+    println!(
+        "{}: {} recorded, {} reclaimable by gc, {} chunks{}",
+        info.name,
+        human_size(info.current_size),
+        human_size(info.reclaimable),
+        chunks.len(),
+        if info.retired { ", retired" } else { "" }
+    );
+    for (path, (size, refs)) in &chunks {
+        println!("{} {} {}", path, human_size(*size), refs.join(", "));
+    }
+    Ok(())
+}
+
+/// Marks `repo` retired in repos.json, so no new chunks are assigned to it.
+pub fn retire(repo: &str) -> Result<()> {
+    let metadata_clone_dir = clone_metadata()?;
+    check_write_version(&metadata_clone_dir)?;
+    let mut repos_meta = load_repos_metadata(&metadata_clone_dir)?;
+    let Some(info) = repos_meta.repos.get_mut(repo) else {
+        bail!("no storage repo {} in repos.json", repo);
+    };
+    if info.retired {
+        println!("{} is already retired", repo);
+    } else {
+        info.retired = true;
+        save_repos_metadata(&metadata_clone_dir, &repos_meta)?;
+        commit_metadata(&metadata_clone_dir, &format!("Retire {}", repo))?;
+        println!("{} retired", repo);
+    }
+    fs::remove_dir_all(&metadata_clone_dir)?;
+    Ok(())
+}
@@ -0,0 +1,637 @@
+use anyhow::Result;
+use serde::Deserialize;
+use std::fmt;
+use std::io;
+use std::time::Duration;
+
+use crate::clock::clock;
+use crate::constants::DROPPED_CONNECTION_DELAY;
+use crate::git::ReadOnlyMode;
+use crate::metrics;
+use crate::usage;
+use crate::utils::is_no_space;
+
+/// Remote operations retried with a policy of their own, set in the
+/// `[retry.<operation>]` table of the config file.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Operation {
+    Push,
+    Clone,
+    /// gh calls other than creating repos
+    Api,
+    CreateRepo,
+}
+
+impl Operation {
+    pub const ALL: [Operation; 4] = [
+        Operation::Push,
+        Operation::Clone,
+        Operation::Api,
+        Operation::CreateRepo,
+    ];
+
+    /// Its name in the config file and the metrics.
+    pub fn name(self) -> &'static str {
+        match self {
+            Operation::Push => "push",
+            Operation::Clone => "clone",
+            Operation::Api => "api",
+            Operation::CreateRepo => "create_repo",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Operation> {
+        Operation::ALL.into_iter().find(|op| op.name() == name)
+    }
+
+    /// The policy used for the keys the config file leaves unset.
+    pub fn default_policy(self) -> RetryPolicy {
+        let (max_attempts, base_delay, max_delay) = match self {
+            // GitHub throttles pushes of big batches for minutes at times
+            Operation::Push => (20, 1, 60),
+            Operation::Clone => (4, 2, 30),
+            Operation::Api => (3, 2, 30),
+            // creating repos too quickly trips GitHub's secondary limits
+            Operation::CreateRepo => (6, 3, 60),
+        };
+        RetryPolicy {
+            operation: self,
+            max_attempts,
+            base_delay: Duration::from_secs(base_delay),
+            max_delay: Duration::from_secs(max_delay),
+            jitter: 0.2,
+            retry_on: RetryOn::Transient,
+        }
+    }
+}
+
+impl fmt::Display for Operation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+/// Which failures a policy retries.
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum RetryOn {
+    /// Transient errors, unknown ones once
+    #[default]
+    Transient,
+    /// Every error, permanent ones too
+    Any,
+}
+
+impl fmt::Display for RetryOn {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RetryOn::Transient => write!(f, "transient"),
+            RetryOn::Any => write!(f, "any"),
+        }
+    }
+}
+
+/// How often and how long apart an operation is attempted.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RetryPolicy {
+    pub operation: Operation,
+    /// Attempts in all, 1 to never retry
+    pub max_attempts: u32,
+    /// Delay after the first failure, doubled after each further one
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+    /// Fraction of the delay it is randomly moved by, either way
+    pub jitter: f64,
+    pub retry_on: RetryOn,
+}
+
+impl RetryPolicy {
+    /// The delay after `failures` failures, `random` in [0, 1) placing it
+    /// within the jitter.
+    pub fn delay(&self, failures: u32, random: f64) -> Duration {
+        let doubled = self
+            .base_delay
+            .saturating_mul(2u32.saturating_pow(failures.saturating_sub(1)));
+        let delay = doubled.min(self.max_delay);
+        delay.mul_f64((1.0 + self.jitter * (2.0 * random - 1.0)).max(0.0))
+    }
+
+    /// Whether an attempt failing with a `class` error is followed by
+    /// another, `unknown_retries` of them having been made for unknown errors.
+    pub fn retries(&self, class: ErrorClass, unknown_retries: u32) -> bool {
+        match class {
+            _ if self.retry_on == RetryOn::Any => true,
+            ErrorClass::Transient | ErrorClass::ConnectionDropped => true,
+            ErrorClass::Unknown => unknown_retries == 0,
+            // a rebase is up to the operation, the same push fails again
+            ErrorClass::NeedsRebase | ErrorClass::Permanent => false,
+        }
+    }
+}
+
+/// A command that exited with an error. Displays as `message`, `stderr`
+/// is kept for `classify`.
+#[derive(Debug)]
+pub struct CommandFailed {
+    pub message: String,
+    pub stderr: String,
+}
+
+impl fmt::Display for CommandFailed {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for CommandFailed {}
+
+/// What retrying a failed operation can achieve.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ErrorClass {
+    /// May work on the next attempt: the network, GitHub errors and limits
+    Transient,
+    /// The connection itself was dropped, like GitHub does with too many
+    /// concurrent SSH connections: retrying right away mostly gets dropped
+    /// again
+    ConnectionDropped,
+    /// A push the remote rejected because it has commits the clone lacks,
+    /// only a rebase helps
+    NeedsRebase,
+    /// Fails the same way every time: authentication, a missing repo,
+    /// invalid input, a full disk or read-only mode
+    Permanent,
+    /// No known pattern, retried once
+    Unknown,
+}
+
+/// Known stderr of git, ssh and gh, and messages of this crate, first
+/// match wins: the rate limits come with an HTTP 403 but pass.
+const PATTERNS: &[(&str, ErrorClass)] = &[
+    ("rate limit", ErrorClass::Transient),
+    // authentication
+    ("Permission denied (publickey)", ErrorClass::Permanent),
+    ("Authentication failed", ErrorClass::Permanent),
+    ("HTTP 401", ErrorClass::Permanent),
+    ("HTTP 403", ErrorClass::Permanent),
+    ("lacks the", ErrorClass::Permanent),
+    // not found
+    ("Repository not found", ErrorClass::Permanent),
+    ("HTTP 404", ErrorClass::Permanent),
+    ("Could not resolve to a Repository", ErrorClass::Permanent),
+    ("repo not found", ErrorClass::Permanent),
+    // invalid input and refused writes
+    ("HTTP 422", ErrorClass::Permanent),
+    ("already exists", ErrorClass::Permanent),
+    ("is not a valid", ErrorClass::Permanent),
+    ("protected branch", ErrorClass::Permanent),
+    (
+        "refusing to merge unrelated histories",
+        ErrorClass::Permanent,
+    ),
+    ("CONFLICT", ErrorClass::Permanent),
+    ("conflicts with the remote", ErrorClass::Permanent),
+    ("exceeds GitHub's file size limit", ErrorClass::Permanent),
+    // another client pushed first
+    ("non-fast-forward", ErrorClass::NeedsRebase),
+    ("(fetch first)", ErrorClass::NeedsRebase),
+    (
+        "Updates were rejected because the remote contains work",
+        ErrorClass::NeedsRebase,
+    ),
+    // the connection
+    ("Connection reset", ErrorClass::ConnectionDropped),
+    ("kex_exchange_identification", ErrorClass::ConnectionDropped),
+    (
+        "Connection closed by remote host",
+        ErrorClass::ConnectionDropped,
+    ),
+    ("Broken pipe", ErrorClass::ConnectionDropped),
+    ("Could not resolve hostname", ErrorClass::Transient),
+    (
+        "Temporary failure in name resolution",
+        ErrorClass::Transient,
+    ),
+    ("Connection timed out", ErrorClass::Transient),
+    ("Operation timed out", ErrorClass::Transient),
+    ("Connection refused", ErrorClass::Transient),
+    ("early EOF", ErrorClass::Transient),
+    ("unexpected disconnect", ErrorClass::Transient),
+    ("remote end hung up unexpectedly", ErrorClass::Transient),
+    ("RPC failed", ErrorClass::Transient),
+    ("GnuTLS recv error", ErrorClass::Transient),
+    ("SSL_read", ErrorClass::Transient),
+    ("Internal Server Error", ErrorClass::Transient),
+    ("HTTP 500", ErrorClass::Transient),
+    ("HTTP 502", ErrorClass::Transient),
+    ("HTTP 503", ErrorClass::Transient),
+    ("HTTP 504", ErrorClass::Transient),
+    ("The requested URL returned error: 5", ErrorClass::Transient),
+];
+
+/// Classifies `e` by its error types, then by `PATTERNS` over the messages
+/// and captured stderr of its causes.
+pub fn classify(e: &anyhow::Error) -> ErrorClass {
+    if is_no_space(e) || e.chain().any(|c| c.is::<ReadOnlyMode>()) {
+        return ErrorClass::Permanent;
+    }
+    #[cfg(feature = "libgit2")]
+    if let Some(class) = crate::libgit2::classify(e) {
+        return class;
+    }
+    if let Some(class) = crate::github::classify(e) {
+        return class;
+    }
+    let mut text = format!("{:#}", e);
+    if let Some(failed) = command_failed(e) {
+        text.push('\n');
+        text.push_str(&failed.stderr);
+    }
+    classify_text(&text)
+}
+
+/// The class of the first of `PATTERNS` in `text`, a message or stderr.
+pub fn classify_text(text: &str) -> ErrorClass {
+    PATTERNS
+        .iter()
+        .find(|(pattern, _)| text.contains(pattern))
+        .map_or(ErrorClass::Unknown, |(_, class)| *class)
+}
+
+/// Runs `op` until it succeeds, `policy` gives up or an error it does not
+/// retry, returning the last error then. Retries are counted in the metrics
+/// under the operation name.
+pub fn with_retry<T>(policy: &RetryPolicy, mut op: impl FnMut() -> Result<T>) -> Result<T> {
+    let mut failures = 0;
+    let mut unknown_retries = 0;
+    loop {
+        usage::attempted(policy.operation);
+        let e = match op() {
+            Ok(value) => return Ok(value),
+            Err(e) => e,
+        };
+        failures += 1;
+        let class = classify(&e);
+        if failures >= policy.max_attempts || !policy.retries(class, unknown_retries) {
+            return Err(e);
+        }
+        if class == ErrorClass::Unknown {
+            unknown_retries += 1;
+        }
+        let mut delay = policy.delay(failures, random_fraction());
+        if class == ErrorClass::ConnectionDropped {
+            delay = delay.max(Duration::from_secs(DROPPED_CONNECTION_DELAY));
+        }
+        eprintln!(
+            "{} failed: {}. Retrying in {:.1}s ({} of {})...",
+            policy.operation,
+            reason(&e),
+            delay.as_secs_f64(),
+            failures + 1,
+            policy.max_attempts
+        );
+        metrics::retried(policy.operation.name());
+        clock().sleep(delay);
+    }
+}
+
+/// What git and ssh print when they could not authenticate, prompts being
+/// disabled: a key that needs a passphrase without an agent fails like a
+/// refused one.
+const AUTH_FAILURES: &[&str] = &[
+    "Permission denied (publickey",
+    "Authentication failed",
+    "terminal prompts disabled",
+    "could not read Username",
+    "could not read Password",
+];
+
+/// Whether `e` comes from git or ssh failing to authenticate.
+pub fn is_auth_failure(e: &anyhow::Error) -> bool {
+    #[cfg(feature = "libgit2")]
+    if crate::libgit2::is_auth_failure(e) {
+        return true;
+    }
+    let mut text = format!("{:#}", e);
+    if let Some(failed) = command_failed(e) {
+        text.push_str(&failed.stderr);
+    }
+    AUTH_FAILURES.iter().any(|pattern| text.contains(pattern))
+}
+
+/// The failed command among the causes of `e`, also when `utils::run`
+/// wrapped it in an `io::Error`.
+fn command_failed(e: &anyhow::Error) -> Option<&CommandFailed> {
+    e.chain().find_map(|cause| {
+        cause.downcast_ref::<CommandFailed>().or_else(|| {
+            cause
+                .downcast_ref::<io::Error>()
+                .and_then(|e| e.get_ref())
+                .and_then(|inner| inner.downcast_ref::<CommandFailed>())
+        })
+    })
+}
+
+/// The line of the stderr `e` captured that says why: the first error git
+/// or gh printed, else the last line. Without stderr, the message of `e`.
+fn reason(e: &anyhow::Error) -> String {
+    let stderr = command_failed(e).map(|failed| failed.stderr.trim());
+    let line = stderr.and_then(|s| {
+        s.lines()
+            .find(|l| {
+                ["fatal:", "error:", "ERROR:"]
+                    .iter()
+                    .any(|p| l.starts_with(p))
+            })
+            .or_else(|| s.lines().last())
+    });
+    match line {
+        Some(line) => line.trim_end_matches('.').to_string(),
+        None => format!("{:#}", e),
+    }
+}
+
+fn random_fraction() -> f64 {
+    let mut bytes = [0u8; 8];
+    let _ = getrandom::getrandom(&mut bytes);
+    (u64::from_le_bytes(bytes) >> 11) as f64 / (1u64 << 53) as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::with_mock_clock;
+    use anyhow::anyhow;
+    use std::cell::Cell;
+
+    /// A command failing with `stderr`, as `utils::run` reports it.
+    fn failed(stderr: &str) -> anyhow::Error {
+        anyhow::Error::new(CommandFailed {
+            message: "git exited with 128".to_string(),
+            stderr: stderr.to_string(),
+        })
+    }
+
+    #[test]
+    fn git_and_gh_stderr_is_classified() {
+        let cases: &[(&str, ErrorClass)] = &[
+            (
+                "remote: Internal Server Error\n\
+                 fatal: unable to access 'https://github.com/u/r.git/': The requested URL returned error: 500",
+                ErrorClass::Transient,
+            ),
+            (
+                "error: RPC failed; HTTP 502 curl 22 The requested URL returned error: 502\n\
+                 send-pack: unexpected disconnect while reading sideband packet\n\
+                 fatal: the remote end hung up unexpectedly",
+                ErrorClass::Transient,
+            ),
+            (
+                "ssh: Could not resolve hostname github.com: Temporary failure in name resolution\n\
+                 fatal: Could not read from remote repository.",
+                ErrorClass::Transient,
+            ),
+            (
+                "fatal: unable to access 'https://github.com/u/r.git/': Failed to connect to \
+                 github.com port 443 after 130 ms: Connection refused",
+                ErrorClass::Transient,
+            ),
+            (
+                "fetch-pack: unexpected disconnect while reading sideband packet\n\
+                 fatal: early EOF\nfatal: fetch-pack: invalid index-pack output",
+                ErrorClass::Transient,
+            ),
+            (
+                "HTTP 503: Service Unavailable (https://api.github.com/user/repos)",
+                ErrorClass::Transient,
+            ),
+            (
+                "HTTP 403: API rate limit exceeded for user ID 1. \
+                 (https://api.github.com/repos/u/r)",
+                ErrorClass::Transient,
+            ),
+            (
+                "kex_exchange_identification: read: Connection reset by peer\n\
+                 Connection reset by 140.82.121.4 port 22\n\
+                 fatal: Could not read from remote repository.",
+                ErrorClass::ConnectionDropped,
+            ),
+            (
+                "Connection closed by remote host\n\
+                 fatal: Could not read from remote repository.",
+                ErrorClass::ConnectionDropped,
+            ),
+            (
+                "client_loop: send disconnect: Broken pipe\n\
+                 fatal: sha1 file '<stdout>' write error: Broken pipe",
+                ErrorClass::ConnectionDropped,
+            ),
+            (
+                "To github.com:u/r.git\n \
+                 ! [rejected]        main -> main (fetch first)\n\
+                 error: failed to push some refs to 'github.com:u/r.git'\n\
+                 hint: Updates were rejected because the remote contains work that you do not\n\
+                 hint: have locally.",
+                ErrorClass::NeedsRebase,
+            ),
+            (
+                "To github.com:u/r.git\n \
+                 ! [rejected]        main -> main (non-fast-forward)\n\
+                 error: failed to push some refs to 'github.com:u/r.git'",
+                ErrorClass::NeedsRebase,
+            ),
+            (
+                "git@github.com: Permission denied (publickey).\n\
+                 fatal: Could not read from remote repository.",
+                ErrorClass::Permanent,
+            ),
+            (
+                "remote: Invalid username or password.\n\
+                 fatal: Authentication failed for 'https://github.com/u/r.git/'",
+                ErrorClass::Permanent,
+            ),
+            (
+                "remote: Repository not found.\n\
+                 fatal: repository 'https://github.com/u/r.git/' not found",
+                ErrorClass::Permanent,
+            ),
+            (
+                "GraphQL: Could not resolve to a Repository with the name 'u/r'. (repository)",
+                ErrorClass::Permanent,
+            ),
+            (
+                "HTTP 422: Validation Failed (https://api.github.com/user/repos)\n\
+                 Repository creation failed.: name already exists on this account",
+                ErrorClass::Permanent,
+            ),
+            (
+                "remote: error: GH006: Protected branch update failed for refs/heads/main.\n \
+                 ! [remote rejected] main -> main (protected branch hook declined)",
+                ErrorClass::Permanent,
+            ),
+            (
+                "remote: error: File big.bin is 120.00 MB; this exceeds GitHub's file size \
+                 limit of 100.00 MB\n \
+                 ! [remote rejected] main -> main (pre-receive hook declined)",
+                ErrorClass::Permanent,
+            ),
+            (
+                "Auto-merging repos.json\n\
+                 CONFLICT (content): Merge conflict in repos.json\n\
+                 error: could not apply 1a2b3c4... Add metadata for a.bin",
+                ErrorClass::Permanent,
+            ),
+            (
+                "fatal: refusing to merge unrelated histories",
+                ErrorClass::Permanent,
+            ),
+            (
+                "fatal: the index file is locked by another process",
+                ErrorClass::Unknown,
+            ),
+            ("", ErrorClass::Unknown),
+        ];
+        for (stderr, class) in cases {
+            assert_eq!(classify(&failed(stderr)), *class, "{}", stderr);
+            assert_eq!(classify_text(stderr), *class, "{}", stderr);
+        }
+    }
+
+    #[test]
+    fn messages_of_this_crate_and_their_context_are_classified() {
+        let conflict = anyhow::Error::new(crate::git::RebaseConflict {
+            dir: "metadata".into(),
+        });
+        assert_eq!(classify(&conflict), ErrorClass::Permanent);
+        let scope =
+            anyhow!("the GitHub token lacks the `repo` scope needed to create storage repos");
+        assert_eq!(classify(&scope), ErrorClass::Permanent);
+        // stderr wrapped in context, and in an io::Error as `utils::run` does
+        let wrapped = failed("fatal: early EOF").context("Failed to clone r");
+        assert_eq!(classify(&wrapped), ErrorClass::Transient);
+        let io = anyhow::Error::new(io::Error::other(CommandFailed {
+            message: "git push failed".to_string(),
+            stderr: "Connection reset by peer".to_string(),
+        }));
+        assert_eq!(classify(&io), ErrorClass::ConnectionDropped);
+    }
+
+    fn policy(max_attempts: u32) -> RetryPolicy {
+        RetryPolicy {
+            operation: Operation::Push,
+            max_attempts,
+            base_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(5),
+            jitter: 0.0,
+            retry_on: RetryOn::Transient,
+        }
+    }
+
+    fn secs(secs: &[u64]) -> Vec<Duration> {
+        secs.iter().map(|s| Duration::from_secs(*s)).collect()
+    }
+
+    /// `with_retry` of an operation failing with `stderr` its first
+    /// `failures` attempts: whether it succeeded, the attempts and the
+    /// sleeps in between.
+    fn run(policy: &RetryPolicy, failures: u32, stderr: &str) -> (bool, u32, Vec<Duration>) {
+        let attempts = Cell::new(0);
+        let (result, slept) = with_mock_clock(|_| {
+            with_retry(policy, || {
+                attempts.set(attempts.get() + 1);
+                if attempts.get() <= failures {
+                    Err(failed(stderr))
+                } else {
+                    Ok(())
+                }
+            })
+        });
+        (result.is_ok(), attempts.get(), slept)
+    }
+
+    #[test]
+    fn refused_and_prompting_credentials_are_auth_failures() {
+        for stderr in [
+            "git@github.com: Permission denied (publickey).\n\
+             fatal: Could not read from remote repository.",
+            "remote: Invalid username or password.\n\
+             fatal: Authentication failed for 'https://github.com/u/r.git/'",
+            "fatal: could not read Username for 'https://github.com': terminal prompts disabled",
+            "fatal: could not read Password for 'https://u@github.com': No such device or address",
+        ] {
+            assert!(is_auth_failure(&failed(stderr)), "{}", stderr);
+            let wrapped = anyhow::Error::new(io::Error::other(CommandFailed {
+                message: "Command failed: git clone".to_string(),
+                stderr: stderr.to_string(),
+            }))
+            .context("Failed to clone");
+            assert!(is_auth_failure(&wrapped), "{}", stderr);
+        }
+        for stderr in [
+            "remote: Repository not found.\n\
+             fatal: repository 'https://github.com/u/r.git/' not found",
+            "ssh: Could not resolve hostname github.com: Temporary failure in name resolution",
+            "kex_exchange_identification: read: Connection reset by peer",
+        ] {
+            assert!(!is_auth_failure(&failed(stderr)), "{}", stderr);
+        }
+    }
+
+    #[test]
+    fn delays_double_up_to_the_maximum_and_retries_stop_at_max_attempts() {
+        let (ok, attempts, slept) = run(&policy(5), u32::MAX, "fatal: early EOF");
+        assert!(!ok);
+        assert_eq!(attempts, 5);
+        assert_eq!(slept, secs(&[1, 2, 4, 5]));
+
+        let (ok, attempts, slept) = run(&policy(5), 2, "fatal: early EOF");
+        assert!(ok);
+        assert_eq!(attempts, 3);
+        assert_eq!(slept, secs(&[1, 2]));
+
+        let (ok, attempts, slept) = run(&policy(1), u32::MAX, "fatal: early EOF");
+        assert!(!ok);
+        assert_eq!(attempts, 1);
+        assert!(slept.is_empty());
+    }
+
+    #[test]
+    fn each_class_is_retried_as_its_policy_says() {
+        let permanent = "remote: Repository not found.";
+        let (ok, attempts, slept) = run(&policy(5), u32::MAX, permanent);
+        assert_eq!((ok, attempts), (false, 1));
+        assert!(slept.is_empty());
+
+        let rebase = " ! [rejected]        main -> main (fetch first)";
+        assert_eq!(run(&policy(5), u32::MAX, rebase).1, 1);
+
+        // unknown errors are retried once
+        let unknown = "fatal: something new";
+        let (ok, attempts, slept) = run(&policy(5), u32::MAX, unknown);
+        assert_eq!((ok, attempts), (false, 2));
+        assert_eq!(slept, secs(&[1]));
+
+        // a dropped connection waits long enough for GitHub to take it again
+        let dropped = "Connection reset by peer";
+        let (ok, attempts, slept) = run(&policy(3), 2, dropped);
+        assert_eq!((ok, attempts), (true, 3));
+        assert_eq!(slept, secs(&[DROPPED_CONNECTION_DELAY; 2]));
+
+        let mut any = policy(4);
+        any.retry_on = RetryOn::Any;
+        let (ok, attempts, slept) = run(&any, u32::MAX, permanent);
+        assert_eq!((ok, attempts), (false, 4));
+        assert_eq!(slept, secs(&[1, 2, 4]));
+    }
+
+    #[test]
+    fn jitter_moves_the_delay_by_at_most_its_fraction() {
+        let mut policy = policy(5);
+        policy.jitter = 0.2;
+        assert_eq!(policy.delay(1, 0.0), Duration::from_millis(800));
+        assert_eq!(policy.delay(1, 0.5), Duration::from_secs(1));
+        assert_eq!(policy.delay(3, 0.0), Duration::from_millis(3200));
+        assert!(policy.delay(10, 0.999_999) < Duration::from_secs(6));
+        // no overflow however many failures
+        assert_eq!(policy.delay(u32::MAX, 0.5), Duration::from_secs(5));
+    }
+}
@@ -0,0 +1,633 @@
+use anyhow::{anyhow, bail, Result};
+use base64::Engine;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write as _;
+use std::fs;
+use std::io;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use tiny_http::{Header, Method, Request, Response, Server};
+
+use crate::api::{self, UploadOptions};
+use crate::git::{clone_repo, git_refresh, head_commit, metadata_repo_url, read_only};
+use crate::metadata::{file_meta_path, list_file_metadata, load_file_metadata};
+use crate::metrics;
+use crate::models::{FileEntry, FileMetadata};
+use crate::report::TransferReport;
+use crate::signing;
+use crate::usage;
+use crate::utils::{ensure_temp_dirs, format_http_date, temp_dirs};
+
+/// Requests handled at the same time.
+const SERVE_THREADS: usize = 4;
+/// How long reads trust the cached metadata clone before fetching again.
+const CACHE_TTL: Duration = Duration::from_secs(30);
+/// Methods of the WebDAV endpoint, which is read-only.
+const DAV_METHODS: &str = "OPTIONS, PROPFIND, GET, HEAD";
+
+/// A metadata clone kept across requests, refreshed with a fetch once it
+/// is older than `CACHE_TTL` or after this server changed the metadata.
+struct MetadataCache {
+    dir: PathBuf,
+    fetched: Option<Instant>,
+    /// Commit of the clone as of the last fetch
+    head: String,
+    index: Option<Arc<DavIndex>>,
+}
+
+impl MetadataCache {
+    fn dir(&mut self) -> Result<&Path> {
+        if self.fetched.is_some_and(|at| at.elapsed() < CACHE_TTL) {
+            return Ok(&self.dir);
+        }
+        if self.dir.join(".git").exists() {
+            git_refresh(&self.dir)?;
+        } else {
+            if self.dir.exists() {
+                fs::remove_dir_all(&self.dir)?;
+            }
+            clone_repo(&metadata_repo_url(), &self.dir)?;
+        }
+        signing::verify(&self.dir)?;
+        self.head = head_commit(&self.dir)?;
+        self.fetched = Some(Instant::now());
+        Ok(&self.dir)
+    }
+
+    /// The tree of the clone, built again only when a fetch changed it.
+    fn index(&mut self) -> Result<Arc<DavIndex>> {
+        self.dir()?;
+        if let Some(index) = self.index.as_ref().filter(|i| i.head == self.head) {
+            return Ok(index.clone());
+        }
+        let files = list_file_metadata(&self.dir, "")?;
+        let index = Arc::new(DavIndex::build(self.head.clone(), files));
+        self.index = Some(index.clone());
+        Ok(index)
+    }
+
+    fn file(&mut self, remote: &str) -> Result<Option<FileMetadata>> {
+        let dir = self.dir()?;
+        if !file_meta_path(dir, remote)?.exists() {
+            return Ok(None);
+        }
+        load_file_metadata(dir, remote).map(Some)
+    }
+}
+
+/// The remote tree by directory, so that a PROPFIND answers from memory
+/// instead of reading the metadata files of a directory on every request.
+struct DavIndex {
+    head: String,
+    /// Entries of every directory by name, the root is ""
+    dirs: HashMap<String, BTreeMap<String, DavEntry>>,
+}
+
+enum DavEntry {
+    Dir,
+    File {
+        size: u64,
+        mtime: Option<u64>,
+        checksum: String,
+    },
+}
+
+impl DavIndex {
+    /// Symlinks are left out, they have no content to serve.
+    fn build(head: String, files: BTreeMap<String, FileMetadata>) -> Self {
+        let mut dirs: HashMap<String, BTreeMap<String, DavEntry>> = HashMap::new();
+        dirs.insert(String::new(), BTreeMap::new());
+        for (path, meta) in files {
+            if meta.symlink_target.is_some() {
+                continue;
+            }
+            let (parent, name) = path.rsplit_once('/').unwrap_or(("", &path));
+            let mut dir = String::new();
+            for part in parent.split('/').filter(|p| !p.is_empty()) {
+                let children = dirs.entry(dir.clone()).or_default();
+                children.entry(part.to_string()).or_insert(DavEntry::Dir);
+                dir = join(&dir, part);
+            }
+            dirs.entry(dir).or_default().insert(
+                name.to_string(),
+                DavEntry::File {
+                    size: meta.size,
+                    mtime: meta.mtime,
+                    checksum: meta.checksum,
+                },
+            );
+        }
+        DavIndex { head, dirs }
+    }
+
+    fn entry(&self, path: &str) -> Option<&DavEntry> {
+        if self.dirs.contains_key(path) {
+            return Some(&DavEntry::Dir);
+        }
+        let (parent, name) = path.rsplit_once('/').unwrap_or(("", path));
+        self.dirs.get(parent)?.get(name)
+    }
+}
+
+fn join(dir: &str, name: &str) -> String {
+    if dir.is_empty() {
+        name.to_string()
+    } else {
+        format!("{}/{}", dir, name)
+    }
+}
+
+struct State {
+    token: String,
+    cache: Mutex<MetadataCache>,
+    /// uploads and removals share the api's metadata clone dir
+    writes: Mutex<()>,
+    downloads: AtomicUsize,
+}
+
+/// Serves the remote files over HTTP on `listen` until killed:
+/// `GET /files` lists them as JSON, `GET`, `PUT` and `DELETE` on
+/// `/files/<path>` download, upload and remove one file, `GET /metrics`
+/// returns the transfer metrics for Prometheus, and `/dav/` mounts the
+/// files read-only over WebDAV. Every request must carry
+/// `Authorization: Bearer <token>`, or the token as Basic password for
+/// WebDAV clients. Bodies are streamed, a `PUT` needs a `Content-Length`,
+/// downloads honor a single `Range`.
+pub fn serve(listen: &str, token: &str) -> Result<()> {
+    if token.trim().is_empty() {
+        bail!("serve needs a non-empty bearer token");
+    }
+    ensure_temp_dirs()?;
+    let server = Server::http(listen).map_err(|e| anyhow!("cannot listen on {}: {}", listen, e))?;
+    let state = State {
+        token: token.to_string(),
+        cache: Mutex::new(MetadataCache {
+            dir: temp_dirs().work.join("serve_metadata"),
+            fetched: None,
+            head: String::new(),
+            index: None,
+        }),
+        writes: Mutex::new(()),
+        downloads: AtomicUsize::new(0),
+    };
+    eprintln!("--- serving on http://{}", listen);
+    thread::scope(|scope| {
+        for _ in 0..SERVE_THREADS {
+            scope.spawn(|| loop {
+                match server.recv() {
+                    Ok(request) => handle(&state, request),
+                    Err(e) => eprintln!("--- serve: accept failed: {e}"),
+                }
+            });
+        }
+    });
+    Ok(())
+}
+
+/// Whether `given` is `token`, in a time that does not tell how much of it
+/// was right.
+fn token_matches(given: &str, token: &str) -> bool {
+    given.len() == token.len()
+        && given
+            .bytes()
+            .zip(token.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+fn handle(state: &State, request: Request) {
+    let method = request.method().clone();
+    let url = request.url().to_string();
+    let authorized = header(&request, "Authorization").is_some_and(|value| {
+        let bearer = value.strip_prefix("Bearer ");
+        let basic = value.strip_prefix("Basic ").and_then(basic_password);
+        bearer
+            .or(basic.as_deref())
+            .is_some_and(|given| token_matches(given, &state.token))
+    });
+    let result = if !authorized {
+        let challenge =
+            Header::from_bytes(&b"WWW-Authenticate"[..], &b"Basic realm=\"gidrive\""[..])
+                .expect("static header is valid");
+        request
+            .respond(
+                Response::from_string("missing or wrong bearer token")
+                    .with_status_code(401)
+                    .with_header(challenge),
+            )
+            .map_err(Into::into)
+    } else {
+        route(state, request, &method)
+    };
+    if let Err(e) = result {
+        eprintln!("--- serve: {} {} failed: {e}", method, url);
+    }
+    usage::flush();
+}
+
+/// The password of a Basic `Authorization` value.
+fn basic_password(credentials: &str) -> Option<String> {
+    let decoded = base64::engine::general_purpose::STANDARD
+        .decode(credentials.trim())
+        .ok()?;
+    let decoded = String::from_utf8(decoded).ok()?;
+    let (_, password) = decoded.split_once(':')?;
+    Some(password.to_string())
+}
+
+fn header<'a>(request: &'a Request, name: &'static str) -> Option<&'a str> {
+    request
+        .headers()
+        .iter()
+        .find(|h| h.field.equiv(name))
+        .map(|h| h.value.as_str())
+}
+
+fn route(state: &State, request: Request, method: &Method) -> Result<()> {
+    let path = request.url().split('?').next().unwrap_or_default();
+    if path == "/dav" || path.starts_with("/dav/") {
+        let path = path.to_string();
+        return dav(state, request, method, &path);
+    }
+    if path == "/metrics" {
+        return match method {
+            Method::Get => metrics(request),
+            _ => respond_text(request, 405, "method not allowed"),
+        };
+    }
+    if path == "/files" || path == "/files/" {
+        return match method {
+            Method::Get => list(state, request),
+            _ => respond_text(request, 405, "method not allowed"),
+        };
+    }
+    let Some(remote) = path.strip_prefix("/files/").and_then(percent_decode) else {
+        return respond_text(request, 404, "not found");
+    };
+    if !valid_remote(&remote) {
+        return respond_text(request, 400, "invalid path");
+    }
+    match method {
+        Method::Get => get(state, request, &remote),
+        Method::Put | Method::Delete if read_only() => respond_text(request, 403, "read-only mode"),
+        Method::Put => put(state, request, &remote),
+        Method::Delete => delete(state, request, &remote),
+        _ => respond_text(request, 405, "method not allowed"),
+    }
+}
+
+fn valid_remote(remote: &str) -> bool {
+    !remote
+        .split('/')
+        .any(|part| part.is_empty() || part == "." || part == "..")
+}
+
+/// The WebDAV endpoint: `PROPFIND` answers from the index of the tree,
+/// `GET` and `HEAD` serve files like `/files/<path>`, anything that would
+/// write gets a 405.
+fn dav(state: &State, request: Request, method: &Method, path: &str) -> Result<()> {
+    let Some(remote) = percent_decode(path.trim_start_matches("/dav").trim_matches('/')) else {
+        return respond_text(request, 400, "invalid path");
+    };
+    if !remote.is_empty() && !valid_remote(&remote) {
+        return respond_text(request, 400, "invalid path");
+    }
+    let allow =
+        Header::from_bytes(&b"Allow"[..], DAV_METHODS.as_bytes()).expect("static header is valid");
+    match method.as_str() {
+        "OPTIONS" => {
+            let dav = Header::from_bytes(&b"DAV"[..], &b"1"[..]).expect("static header is valid");
+            request.respond(Response::empty(200).with_header(dav).with_header(allow))?;
+            Ok(())
+        }
+        "PROPFIND" => propfind(state, request, &remote),
+        "GET" | "HEAD" => {
+            let index = state.cache.lock().unwrap().index()?;
+            match index.entry(&remote) {
+                Some(DavEntry::File { .. }) => get(state, request, &remote),
+                Some(DavEntry::Dir) => {
+                    let res = Response::from_string("a collection, list it with PROPFIND")
+                        .with_status_code(405)
+                        .with_header(allow);
+                    request.respond(res)?;
+                    Ok(())
+                }
+                None => respond_text(request, 404, "no such file"),
+            }
+        }
+        _ => {
+            let res = Response::from_string("read-only WebDAV")
+                .with_status_code(405)
+                .with_header(allow);
+            request.respond(res)?;
+            Ok(())
+        }
+    }
+}
+
+/// Answers a `PROPFIND` of `remote` with depth 0 or 1 with every property
+/// of it, and of its entries for a directory at depth 1, whatever the body
+/// asked for. An infinite depth, the default, is refused.
+fn propfind(state: &State, request: Request, remote: &str) -> Result<()> {
+    let depth_one = match header(&request, "Depth") {
+        Some("0") => false,
+        Some("1") => true,
+        _ => {
+            let body = "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n\
+                        <D:error xmlns:D=\"DAV:\"><D:propfind-finite-depth/></D:error>\n";
+            return respond_xml(request, 403, body.to_string());
+        }
+    };
+    let index = state.cache.lock().unwrap().index()?;
+    let Some(entry) = index.entry(remote) else {
+        return respond_text(request, 404, "no such file");
+    };
+    let mut body = String::from(
+        "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n<D:multistatus xmlns:D=\"DAV:\">\n",
+    );
+    dav_response(&mut body, remote, entry);
+    if let (true, DavEntry::Dir) = (depth_one, entry) {
+        for (name, child) in index.dirs.get(remote).into_iter().flatten() {
+            dav_response(&mut body, &join(remote, name), child);
+        }
+    }
+    body.push_str("</D:multistatus>\n");
+    respond_xml(request, 207, body)
+}
+
+/// Appends the `<D:response>` of `path` to a multistatus `body`.
+fn dav_response(body: &mut String, path: &str, entry: &DavEntry) {
+    let name = path.rsplit('/').next().unwrap_or_default();
+    let _ = write!(
+        body,
+        "<D:response><D:href>/dav/{}{}</D:href><D:propstat><D:prop>\
+         <D:displayname>{}</D:displayname>",
+        percent_encode(path),
+        if matches!(entry, DavEntry::Dir) && !path.is_empty() {
+            "/"
+        } else {
+            ""
+        },
+        xml_escape(name)
+    );
+    match entry {
+        DavEntry::Dir => body.push_str("<D:resourcetype><D:collection/></D:resourcetype>"),
+        DavEntry::File {
+            size,
+            mtime,
+            checksum,
+        } => {
+            let _ = write!(
+                body,
+                "<D:resourcetype/><D:getcontentlength>{}</D:getcontentlength>\
+                 <D:getcontenttype>application/octet-stream</D:getcontenttype>\
+                 <D:getetag>\"{}\"</D:getetag>",
+                size,
+                xml_escape(checksum)
+            );
+            if let Some(mtime) = mtime {
+                let _ = write!(
+                    body,
+                    "<D:getlastmodified>{}</D:getlastmodified>",
+                    format_http_date(*mtime)
+                );
+            }
+        }
+    }
+    body.push_str("</D:prop><D:status>HTTP/1.1 200 OK</D:status></D:propstat></D:response>\n");
+}
+
+fn respond_xml(request: Request, status: u16, body: String) -> Result<()> {
+    let header = Header::from_bytes(&b"Content-Type"[..], &b"application/xml; charset=utf-8"[..])
+        .expect("static header is valid");
+    request.respond(
+        Response::from_string(body)
+            .with_status_code(status)
+            .with_header(header),
+    )?;
+    Ok(())
+}
+
+fn list(state: &State, request: Request) -> Result<()> {
+    let files: Vec<FileEntry> = {
+        let mut cache = state.cache.lock().unwrap();
+        list_file_metadata(cache.dir()?, "")?
+            .into_iter()
+            .map(|(path, meta)| FileEntry::new(path, meta))
+            .collect()
+    };
+    let body = serde_json::to_string(&files)?;
+    let header = Header::from_bytes(&b"Content-Type"[..], &b"application/json"[..])
+        .expect("static header is valid");
+    request.respond(Response::from_string(body).with_header(header))?;
+    Ok(())
+}
+
+fn metrics(request: Request) -> Result<()> {
+    let header = Header::from_bytes(&b"Content-Type"[..], &b"text/plain; version=0.0.4"[..])
+        .expect("static header is valid");
+    request.respond(Response::from_string(metrics::render()).with_header(header))?;
+    Ok(())
+}
+
+fn get(state: &State, request: Request, remote: &str) -> Result<()> {
+    let file_meta = state.cache.lock().unwrap().file(remote)?;
+    let Some(file_meta) = file_meta else {
+        return respond_text(request, 404, "no such file");
+    };
+    let Some(range) = requested_range(&request, file_meta.size) else {
+        let header = Header::from_bytes(
+            &b"Content-Range"[..],
+            format!("bytes */{}", file_meta.size).as_bytes(),
+        )
+        .expect("header is valid");
+        request.respond(
+            Response::from_string("range not satisfiable")
+                .with_status_code(416)
+                .with_header(header),
+        )?;
+        return Ok(());
+    };
+    let mut headers =
+        vec![Header::from_bytes(&b"Accept-Ranges"[..], &b"bytes"[..])
+            .expect("static header is valid")];
+    let (status, length) = match &range {
+        Some(range) => {
+            let content_range =
+                format!("bytes {}-{}/{}", range.start, range.end - 1, file_meta.size);
+            headers.push(
+                Header::from_bytes(&b"Content-Range"[..], content_range.as_bytes())
+                    .expect("header is valid"),
+            );
+            (206, range.end - range.start)
+        }
+        None => (200, file_meta.size),
+    };
+    if request.method() == &Method::Head {
+        let response = Response::new(
+            status.into(),
+            headers,
+            io::empty(),
+            usize::try_from(length).ok(),
+            None,
+        );
+        request.respond(response.with_chunked_threshold(usize::MAX))?;
+        return Ok(());
+    }
+    let n = state.downloads.fetch_add(1, Ordering::Relaxed);
+    let temp_dir = temp_dirs().staging.join(format!("serve_dl_{}", n));
+    let _in_flight = metrics::transfer_started();
+    let (reader, mut writer) = io::pipe()?;
+    // the response is only as long as what was written: a failed download
+    // shows up as a body shorter than its Content-Length
+    thread::scope(|scope| {
+        let fetch = scope.spawn(|| {
+            let mut report = TransferReport::default();
+            let res = match &range {
+                Some(range) => api::fetch_range_to_writer(
+                    &file_meta,
+                    range.clone(),
+                    &temp_dir,
+                    &mut writer,
+                    &mut report,
+                ),
+                None => api::fetch_to_writer(&file_meta, &temp_dir, &mut writer, &mut report)
+                    .map(|_| ()),
+            };
+            drop(writer);
+            res
+        });
+        let size = usize::try_from(length).ok();
+        let response = Response::new(status.into(), headers, reader, size, None)
+            // a Content-Length lets players seek and size the download
+            .with_chunked_threshold(usize::MAX);
+        let sent = request.respond(response);
+        let fetched = fetch.join().expect("fetch thread panicked");
+        let _ = fs::remove_dir_all(&temp_dir);
+        match &fetched {
+            Ok(_) => metrics::transfer_succeeded("download", length),
+            Err(e) => metrics::transfer_failed("download", e),
+        }
+        fetched?;
+        sent.map_err(Into::into)
+    })
+}
+
+/// The byte range a `Range` header asks for within `size` bytes: `Some(None)`
+/// for the whole file, without the header or with several ranges, `None`
+/// when the range starts past the end.
+fn requested_range(request: &Request, size: u64) -> Option<Option<Range<u64>>> {
+    let Some(spec) = header(request, "Range").and_then(|v| v.trim().strip_prefix("bytes=")) else {
+        return Some(None);
+    };
+    let Some((start, end)) = spec.split_once('-').filter(|_| !spec.contains(',')) else {
+        return Some(None);
+    };
+    let range = match (start.trim().parse::<u64>(), end.trim().parse::<u64>()) {
+        (Ok(start), Ok(end)) if start <= end => start..size.min(end.saturating_add(1)),
+        (Ok(start), Err(_)) if end.trim().is_empty() => start..size,
+        (Err(_), Ok(len)) if start.trim().is_empty() => size.saturating_sub(len)..size,
+        _ => return Some(None),
+    };
+    (range.start < range.end).then_some(Some(range))
+}
+
+fn put(state: &State, mut request: Request, remote: &str) -> Result<()> {
+    let Some(size) = request.body_length() else {
+        return respond_text(request, 411, "Content-Length required");
+    };
+    let uploaded = {
+        let _writes = state.writes.lock().unwrap();
+        let _in_flight = metrics::transfer_started();
+        // the request body is not Send, feed it to the upload through a pipe
+        let (mut reader, mut writer) = io::pipe()?;
+        thread::scope(|scope| {
+            let upload = scope.spawn(move || {
+                api::upload_from_reader(remote, &mut reader, size as u64, &UploadOptions::default())
+            });
+            let copied = io::copy(&mut request.as_reader(), &mut writer);
+            drop(writer);
+            let uploaded = upload.join().expect("upload thread panicked");
+            // a failed upload closes the pipe early, its error is the cause
+            uploaded.and(copied.map_err(Into::into))
+        })
+    };
+    state.cache.lock().unwrap().fetched = None;
+    match uploaded {
+        Ok(_) => {
+            metrics::transfer_succeeded("upload", size as u64);
+            respond_text(request, 201, "created")
+        }
+        Err(e) => {
+            metrics::transfer_failed("upload", &e);
+            respond_text(request, 500, &e.to_string())
+        }
+    }
+}
+
+fn delete(state: &State, request: Request, remote: &str) -> Result<()> {
+    let removed = {
+        let _writes = state.writes.lock().unwrap();
+        let exists = {
+            let mut cache = state.cache.lock().unwrap();
+            cache.fetched = None;
+            cache.file(remote)?.is_some()
+        };
+        if !exists {
+            return respond_text(request, 404, "no such file");
+        }
+        api::remove(remote)
+    };
+    state.cache.lock().unwrap().fetched = None;
+    match removed {
+        Ok(_) => respond_text(request, 204, ""),
+        Err(e) => respond_text(request, 500, &e.to_string()),
+    }
+}
+
+fn respond_text(request: Request, status: u16, text: &str) -> Result<()> {
+    request.respond(Response::from_string(text).with_status_code(status))?;
+    Ok(())
+}
+
+/// Escapes the bytes of `path` that may not appear in a URL path as `%XX`.
+fn percent_encode(path: &str) -> String {
+    let mut out = String::with_capacity(path.len());
+    for byte in path.bytes() {
+        if byte.is_ascii_alphanumeric() || b"/-._~".contains(&byte) {
+            out.push(byte as char);
+        } else {
+            let _ = write!(out, "%{:02X}", byte);
+        }
+    }
+    out
+}
+
+fn xml_escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Decodes `%XX` escapes of a URL path, `None` if they are malformed.
+fn percent_decode(path: &str) -> Option<String> {
+    let bytes = path.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = std::str::from_utf8(bytes.get(i + 1..i + 3)?).ok()?;
+            out.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            out.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(out).ok()
+}
@@ -0,0 +1,168 @@
+use anyhow::{anyhow, bail, Context, Result};
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use serde::{Deserialize, Serialize};
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use walkdir::WalkDir;
+
+use crate::config::{self, config_path, settings};
+use crate::utils::get_file_sha256;
+
+/// File of the metadata repo holding the signature of its `fs/` tree.
+pub const SIGNATURE_FILE: &str = "manifest.sig";
+
+/// Contents of manifest.sig.
+#[derive(Serialize, Deserialize)]
+struct ManifestSignature {
+    /// Hex public key of the signing key
+    public_key: String,
+    /// Hex ed25519 signature of the manifest
+    signature: String,
+}
+
+static NO_VERIFY: AtomicBool = AtomicBool::new(false);
+
+/// Makes `verify` pass whatever the signature, for `--no-verify`.
+pub fn skip_verify() {
+    NO_VERIFY.store(true, Ordering::Relaxed);
+}
+
+/// Whether `verify` checks the signature, which needs all of fs/.
+pub fn verifies() -> bool {
+    !settings().signing_public_keys().is_empty() && !NO_VERIFY.load(Ordering::Relaxed)
+}
+
+/// One "<sha256>  <path>" line per file under fs/, sorted by path. The
+/// manifest is recomputed from the files and never stored.
+fn manifest(metadata_clone_dir: &Path) -> Result<String> {
+    let mut lines = Vec::new();
+    for entry in WalkDir::new(metadata_clone_dir.join("fs")).sort_by_file_name() {
+        let entry = entry?;
+        if !entry.file_type().is_file() {
+            continue;
+        }
+        let path = entry.path().strip_prefix(metadata_clone_dir)?;
+        lines.push(format!(
+            "{}  {}\n",
+            get_file_sha256(entry.path())?,
+            path.to_string_lossy()
+        ));
+    }
+    lines.sort_by(|a, b| a[66..].cmp(&b[66..]));
+    Ok(lines.concat())
+}
+
+fn load_signing_key(path: &Path) -> Result<SigningKey> {
+    let data = fs::read_to_string(path)
+        .with_context(|| format!("Failed to read signing key {}", path.display()))?;
+    let bytes: [u8; 32] = hex::decode(data.trim())
+        .ok()
+        .and_then(|b| b.try_into().ok())
+        .with_context(|| format!("{} is not a gidrive signing key", path.display()))?;
+    Ok(SigningKey::from_bytes(&bytes))
+}
+
+fn parse_public_key(key: &str) -> Result<VerifyingKey> {
+    let bytes: [u8; 32] = hex::decode(key)
+        .ok()
+        .and_then(|b| b.try_into().ok())
+        .with_context(|| format!("{:?} is not a hex ed25519 public key", key))?;
+    VerifyingKey::from_bytes(&bytes).with_context(|| format!("{:?} is not a public key", key))
+}
+
+/// Signs the manifest of the metadata in `metadata_clone_dir` with the
+/// configured key, to be called before committing a change there. Without
+/// a key, fails if the repo is signed: the change would break its signature.
+pub fn sign(metadata_clone_dir: &Path) -> Result<()> {
+    let path = metadata_clone_dir.join(SIGNATURE_FILE);
+    let Some(key_path) = settings().signing_key() else {
+        if path.exists() {
+            bail!("the metadata repo is signed, set signing.key to write to it");
+        }
+        return Ok(());
+    };
+    let key = load_signing_key(&key_path)?;
+    let signature = key.sign(manifest(metadata_clone_dir)?.as_bytes());
+    let sig = ManifestSignature {
+        public_key: hex::encode(key.verifying_key().as_bytes()),
+        signature: hex::encode(signature.to_bytes()),
+    };
+    let data = serde_json::to_string_pretty(&sig).context("Failed to serialize signature")?;
+    fs::write(&path, data).context("Failed to write manifest.sig")
+}
+
+/// Checks that the metadata in `metadata_clone_dir` is signed by one of the
+/// configured public keys. Passes when none is configured or after
+/// `skip_verify`.
+pub fn verify(metadata_clone_dir: &Path) -> Result<()> {
+    if !verifies() {
+        return Ok(());
+    }
+    let trusted = settings().signing_public_keys();
+    let trusted = trusted
+        .into_iter()
+        .map(parse_public_key)
+        .collect::<Result<Vec<_>>>()
+        .context("Invalid `signing.public_key`")?;
+    let tampered = |reason: &str| {
+        anyhow!(
+            "metadata tampered or signed by unknown key: {}, pass --no-verify to read it anyway",
+            reason
+        )
+    };
+    let path = metadata_clone_dir.join(SIGNATURE_FILE);
+    if !path.exists() {
+        return Err(tampered("manifest.sig is missing"));
+    }
+    let data = fs::read_to_string(&path).context("Failed to read manifest.sig")?;
+    let sig: ManifestSignature =
+        serde_json::from_str(&data).map_err(|_| tampered("manifest.sig is unreadable"))?;
+    let key = parse_public_key(&sig.public_key).map_err(|_| tampered("bad public key"))?;
+    if !trusted.contains(&key) {
+        return Err(tampered(&format!("signed by {}", sig.public_key)));
+    }
+    let signature = hex::decode(&sig.signature)
+        .ok()
+        .and_then(|b| Signature::from_slice(&b).ok())
+        .ok_or_else(|| tampered("bad signature"))?;
+    key.verify(manifest(metadata_clone_dir)?.as_bytes(), &signature)
+        .map_err(|_| tampered("fs/ does not match the signature"))
+}
+
+/// Writes a new signing key next to the config file, and configures it and
+/// its public key. An existing key is only replaced with `rotate`.
+pub fn keygen(rotate: bool) -> Result<()> {
+    let path = config_path().with_file_name("signing.key");
+    if path.exists() && !rotate {
+        bail!(
+            "{} already exists, pass --rotate to replace it",
+            path.display()
+        );
+    }
+    let mut secret = [0u8; 32];
+    getrandom::getrandom(&mut secret).map_err(|e| anyhow!("Failed to generate a key: {}", e))?;
+    let key = SigningKey::from_bytes(&secret);
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).context("Failed to create config dir")?;
+    }
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(&path)
+        .with_context(|| format!("Failed to write {}", path.display()))?;
+    writeln!(file, "{}", hex::encode(secret))?;
+    let public_key = hex::encode(key.verifying_key().as_bytes());
+    config::set("signing.key", &path.to_string_lossy())?;
+    config::set("signing.public_key", &public_key)?;
+    eprintln!(
+        "--- run `gidrive sign` to sign the metadata with the new key, and set \
+         signing.public_key = {} on every machine reading it",
+        public_key
+    );
+    Ok(())
+}
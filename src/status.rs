@@ -0,0 +1,253 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::PathBuf;
+use walkdir::WalkDir;
+
+use crate::cache::{self, cache_dir, repo_cache_dir};
+use crate::clock::clock;
+use crate::config::{config_path, settings};
+use crate::constants::STALE_LOCK_AGE;
+use crate::git::{metadata_repo_url, offline, setup_auth, shallow_clone_repo};
+use crate::metadata::{
+    get_metadata_dir, load_clients_log, load_namespace_stats, load_repos_metadata, namespace,
+    repos_allowed,
+};
+use crate::signing;
+use crate::utils::{ensure_temp_dirs, format_utc, human_size, unix_now};
+
+/// Outcome of the last `fsck`, kept in the cache dir for `status`.
+#[derive(Serialize, Deserialize)]
+pub struct FsckRecord {
+    /// Unix time the check finished
+    pub time: u64,
+    pub ok: bool,
+}
+
+fn fsck_record_path() -> PathBuf {
+    cache_dir().join("fsck.json")
+}
+
+/// Remembers the outcome of an `fsck` run for the next `status`.
+pub fn record_fsck(ok: bool) -> Result<()> {
+    let path = fsck_record_path();
+    fs::create_dir_all(cache_dir()).context("Failed to create cache dir")?;
+    let record = FsckRecord {
+        time: unix_now(),
+        ok,
+    };
+    fs::write(&path, serde_json::to_string(&record)?).context("Failed to write fsck.json")
+}
+
+fn last_fsck() -> Option<FsckRecord> {
+    let data = fs::read_to_string(fsck_record_path()).ok()?;
+    serde_json::from_str(&data).ok()
+}
+
+/// Last line of clients.log.
+#[derive(Serialize)]
+pub struct LastWrite {
+    pub time: String,
+    pub version: String,
+    pub host: String,
+    pub operation: String,
+}
+
+/// What `status` reports, also its `--json` output.
+#[derive(Serialize)]
+pub struct Status {
+    pub config: Option<PathBuf>,
+    pub account: String,
+    pub namespace: String,
+    pub metadata_reachable: bool,
+    /// Read from the metadata cache with `--offline`, GitHub left alone
+    pub offline: bool,
+    /// Unix time the metadata cache was last updated, the copy reads fall
+    /// back on when the metadata repo is unreachable
+    pub metadata_cached: Option<u64>,
+    /// Why the metadata could not be read
+    pub metadata_error: Option<String>,
+    pub last_write: Option<LastWrite>,
+    /// Files and bytes of every namespace
+    pub files: usize,
+    pub bytes: u64,
+    pub repos: usize,
+    /// Repos `max_repos` lets the storage accounts hold, none for no limit
+    pub repos_allowed: Option<usize>,
+    /// Bytes stored in the repos against what they may hold, in percent
+    pub fill_percent: f64,
+    /// Bytes of chunks no file uses, deleted by `gc` after the grace period
+    pub reclaimable: u64,
+    /// Git lock files in the repo cache left behind by an interrupted run
+    pub stale_locks: Vec<PathBuf>,
+    pub last_fsck: Option<FsckRecord>,
+}
+
+/// Collects the status from the totals kept in the metadata repo, read from a
+/// shallow clone, and from the local cache, without walking the file
+/// metadata. Returns whether the metadata repo could be read.
+pub fn status(json: bool) -> Result<bool> {
+    let config = settings();
+    let path = config_path();
+    let mut status = Status {
+        config: path.exists().then_some(path),
+        account: config.github_username().to_string(),
+        namespace: namespace().to_string(),
+        metadata_reachable: false,
+        offline: offline(),
+        metadata_cached: cache::metadata_fetched(),
+        metadata_error: None,
+        last_write: None,
+        files: 0,
+        bytes: 0,
+        repos: 0,
+        repos_allowed: repos_allowed(),
+        fill_percent: 0.0,
+        reclaimable: 0,
+        stale_locks: stale_locks(),
+        last_fsck: last_fsck(),
+    };
+    if let Err(e) = read_metadata(&mut status) {
+        status.metadata_error = Some(format!("{:#}", e));
+    }
+    if json {
+        println!("{}", serde_json::to_string_pretty(&status)?);
+    } else {
+        print(&status);
+    }
+    Ok(status.metadata_reachable || status.offline && status.metadata_error.is_none())
+}
+
+fn read_metadata(status: &mut Status) -> Result<()> {
+    ensure_temp_dirs()?;
+    setup_auth(settings())?;
+    let metadata_clone_dir = get_metadata_dir();
+    if metadata_clone_dir.exists() {
+        fs::remove_dir_all(&metadata_clone_dir)?;
+    }
+    if status.offline {
+        cache::clone_cached_metadata(&metadata_clone_dir, &metadata_repo_url())?;
+    } else {
+        shallow_clone_repo(&metadata_repo_url(), &metadata_clone_dir)?;
+        status.metadata_reachable = true;
+    }
+    let res = (|| {
+        signing::verify(&metadata_clone_dir)?;
+        status.last_write = load_clients_log(&metadata_clone_dir)?
+            .last()
+            .and_then(|line| {
+                let [time, version, host, operation] = line.splitn(4, '\t').collect::<Vec<_>>()[..]
+                else {
+                    return None;
+                };
+                Some(LastWrite {
+                    time: time.to_string(),
+                    version: version.to_string(),
+                    host: host.to_string(),
+                    operation: operation.to_string(),
+                })
+            });
+        let stats = load_namespace_stats(&metadata_clone_dir)?;
+        status.files = stats.values().map(|ns| ns.files).sum();
+        status.bytes = stats.values().map(|ns| ns.bytes).sum();
+        let repos_meta = load_repos_metadata(&metadata_clone_dir)?;
+        status.repos = repos_meta.repos.len();
+        let stored: u64 = repos_meta.repos.values().map(|r| r.current_size).sum();
+        let capacity = status.repos as u64 * settings().max_repo_size();
+        status.fill_percent = stored as f64 * 100.0 / capacity.max(1) as f64;
+        status.reclaimable = repos_meta.repos.values().map(|r| r.reclaimable).sum();
+        Ok(())
+    })();
+    fs::remove_dir_all(&metadata_clone_dir)?;
+    res
+}
+
+/// Lock files git leaves in a cached clone when it is killed, older than
+/// `STALE_LOCK_AGE` so that those of a running gidrive are not reported.
+fn stale_locks() -> Vec<PathBuf> {
+    let now = clock().now();
+    WalkDir::new(repo_cache_dir())
+        .max_depth(3)
+        .into_iter()
+        .filter_map(|e| e.ok())
+        .filter(|e| e.file_type().is_file() && e.file_name().to_string_lossy().ends_with(".lock"))
+        .filter(|e| {
+            e.metadata()
+                .ok()
+                .and_then(|m| m.modified().ok())
+                .and_then(|modified| now.duration_since(modified).ok())
+                .is_some_and(|age| age.as_secs() >= STALE_LOCK_AGE)
+        })
+        .map(|e| e.into_path())
+        .collect()
+}
+
+/// `secs` in its largest whole unit, like "3h".
+fn age(secs: u64) -> String {
+    match secs {
+        s if s >= 24 * 60 * 60 => format!("{}d", s / (24 * 60 * 60)),
+        s if s >= 60 * 60 => format!("{}h", s / (60 * 60)),
+        s if s >= 60 => format!("{}m", s / 60),
+        s => format!("{}s", s),
+    }
+}
+
+fn print(status: &Status) {
+    let config = status
+        .config
+        .as_ref()
+        .map_or("no file, defaults".to_string(), |p| p.display().to_string());
+    println!(
+        "profile:    {} as {}, namespace {}",
+        config, status.account, status.namespace
+    );
+    match (&status.metadata_error, status.metadata_reachable) {
+        (None, _) if status.offline => println!("metadata:   offline, read from the cache"),
+        (None, _) => println!("metadata:   reachable"),
+        (Some(e), true) => println!("metadata:   reachable but unreadable: {}", e),
+        (Some(e), false) => println!("metadata:   UNREACHABLE: {}", e),
+    }
+    match status.metadata_cached {
+        Some(time) => println!(
+            "cache:      metadata fetched {}, {} ago",
+            format_utc(time),
+            age(unix_now().saturating_sub(time))
+        ),
+        None => println!("cache:      no metadata cached, offline reads fail"),
+    }
+    match &status.last_write {
+        Some(w) => println!(
+            "last write: {} by {} (gidrive {}): {}",
+            w.time, w.host, w.version, w.operation
+        ),
+        None => println!("last write: none logged"),
+    }
+    println!(
+        "files:      {} in every namespace, {}",
+        status.files,
+        human_size(status.bytes)
+    );
+    println!(
+        "repos:      {}{}, {:.1}% full, {} reclaimable by gc",
+        status.repos,
+        status
+            .repos_allowed
+            .map_or(String::new(), |max| format!(" of {} allowed", max)),
+        status.fill_percent,
+        human_size(status.reclaimable)
+    );
+    if status.stale_locks.is_empty() {
+        println!("locks:      no stale locks");
+    }
+    for lock in &status.stale_locks {
+        println!("locks:      stale {}", lock.display());
+    }
+    match &status.last_fsck {
+        Some(r) => println!(
+            "fsck:       {} at {}",
+            if r.ok { "ok" } else { "FAILED" },
+            format_utc(r.time)
+        ),
+        None => println!("fsck:       never run here"),
+    }
+}
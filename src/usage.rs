@@ -0,0 +1,210 @@
+//! How much each profile asks of GitHub: bytes of chunks pushed and
+//! fetched, git operations and API calls, by UTC day, for `gidrive stats`.
+//!
+//! A process counts in memory and writes its counters to a file of its own
+//! in `state_dir()/usage/<account>` at the end of each operation, replacing
+//! it through a rename. No two processes write the same file, so concurrent
+//! invocations never lose each other's updates; reading merges the files.
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::{Mutex, OnceLock};
+
+use crate::config::settings;
+use crate::journal::state_dir;
+use crate::retry::Operation;
+use crate::utils::{format_day, human_size, parse_duration, unix_now};
+
+/// What one day, or a sum of days, took.
+#[derive(Serialize, Deserialize, Default, Clone, Debug, PartialEq)]
+pub struct Usage {
+    /// Bytes of chunks pushed to storage repos
+    pub uploaded: u64,
+    /// Bytes of chunks read from storage repos
+    pub downloaded: u64,
+    /// Attempts by operation, like "push" or "api"
+    pub operations: BTreeMap<String, u64>,
+}
+
+impl Usage {
+    fn add(&mut self, other: &Usage) {
+        self.uploaded += other.uploaded;
+        self.downloaded += other.downloaded;
+        for (operation, n) in &other.operations {
+            *self.operations.entry(operation.clone()).or_default() += n;
+        }
+    }
+
+    fn count(&self, operations: &[Operation]) -> u64 {
+        operations
+            .iter()
+            .filter_map(|op| self.operations.get(op.name()))
+            .sum()
+    }
+
+    /// Clones, fetches and pushes of git repos.
+    pub fn repo_operations(&self) -> u64 {
+        self.count(&[Operation::Clone, Operation::Push])
+    }
+
+    /// Calls of the GitHub API, through gh or REST.
+    pub fn api_calls(&self) -> u64 {
+        self.count(&[Operation::Api, Operation::CreateRepo])
+    }
+}
+
+/// The counters of this process by day, and whether they changed since
+/// they were last written.
+struct Counters {
+    days: BTreeMap<String, Usage>,
+    dirty: bool,
+}
+
+static COUNTERS: Mutex<Counters> = Mutex::new(Counters {
+    days: BTreeMap::new(),
+    dirty: false,
+});
+
+fn record(update: impl FnOnce(&mut Usage)) {
+    let mut counters = COUNTERS.lock().unwrap();
+    update(counters.days.entry(format_day(unix_now())).or_default());
+    counters.dirty = true;
+}
+
+pub fn uploaded(bytes: u64) {
+    record(|usage| usage.uploaded += bytes);
+}
+
+pub fn downloaded(bytes: u64) {
+    record(|usage| usage.downloaded += bytes);
+}
+
+/// Counts one attempt of `operation`, retries included.
+pub fn attempted(operation: Operation) {
+    record(|usage| *usage.operations.entry(operation.name().into()).or_default() += 1);
+}
+
+/// `state_dir()/usage/<account>`, the counters of the profile.
+fn usage_dir() -> PathBuf {
+    state_dir().join("usage").join(settings().github_username())
+}
+
+/// Writes the counters of this process when they changed, unless
+/// `accounting` is off. A failure is only warned about, it never fails the
+/// operation that was counted.
+pub fn flush() {
+    if !settings().accounting() {
+        return;
+    }
+    let mut counters = COUNTERS.lock().unwrap();
+    if !counters.dirty {
+        return;
+    }
+    match write(&counters.days) {
+        Ok(()) => counters.dirty = false,
+        Err(e) => eprintln!("--- could not record usage: {:#}", e),
+    }
+}
+
+fn write(days: &BTreeMap<String, Usage>) -> Result<()> {
+    static STARTED: OnceLock<u64> = OnceLock::new();
+    let dir = usage_dir();
+    fs::create_dir_all(&dir).context("Failed to create the usage dir")?;
+    let name = format!(
+        "{}-{}.json",
+        STARTED.get_or_init(unix_now),
+        std::process::id()
+    );
+    let tmp = dir.join(format!(".{}.gidrive-tmp", name));
+    fs::write(&tmp, serde_json::to_vec(days)?).context("Failed to write usage")?;
+    fs::rename(&tmp, dir.join(name)).context("Failed to write usage")
+}
+
+/// Flushes the counters when dropped, so a failed operation that unwinds
+/// is counted too.
+pub struct FlushOnDrop;
+
+impl Drop for FlushOnDrop {
+    fn drop(&mut self) {
+        flush();
+    }
+}
+
+/// The usage of the profile by day since the day `since` falls on, every
+/// process's files merged. Files that do not parse are skipped.
+pub fn load(since: u64) -> Result<BTreeMap<String, Usage>> {
+    flush();
+    let first = format_day(since);
+    let mut days: BTreeMap<String, Usage> = BTreeMap::new();
+    let entries = match fs::read_dir(usage_dir()) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(days),
+        Err(e) => return Err(e).context("Failed to read the usage dir"),
+    };
+    for entry in entries {
+        let path = entry?.path();
+        if path.extension().is_none_or(|ext| ext != "json") {
+            continue;
+        }
+        let Ok(process) = fs::read(&path)
+            .map_err(anyhow::Error::from)
+            .and_then(|data| Ok(serde_json::from_slice::<BTreeMap<String, Usage>>(&data)?))
+        else {
+            eprintln!("--- skipping unreadable usage file {}", path.display());
+            continue;
+        };
+        for (day, usage) in process.range(first.clone()..) {
+            days.entry(day.clone()).or_default().add(usage);
+        }
+    }
+    Ok(days)
+}
+
+/// `gidrive stats`: the usage of the profile by day since `since` ago,
+/// like "30d", with the total, as a table or one JSON object.
+pub fn stats(since: &str, json: bool) -> Result<()> {
+    let from = unix_now().saturating_sub(parse_duration(since)?);
+    let days = load(from)?;
+    let mut total = Usage::default();
+    for usage in days.values() {
+        total.add(usage);
+    }
+    let profile = settings().github_username();
+    if json {
+        let out = serde_json::json!({
+            "profile": profile,
+            "since": format_day(from),
+            "accounting": settings().accounting(),
+            "days": days,
+            "total": total,
+        });
+        println!("{}", serde_json::to_string_pretty(&out)?);
+        return Ok(());
+    }
+    println!("profile:    {}, since {}", profile, format_day(from));
+    if !settings().accounting() {
+        println!("accounting is off, nothing new is counted");
+    }
+    println!(
+        "{:<12} {:>12} {:>12} {:>9} {:>9}",
+        "day", "uploaded", "downloaded", "repo ops", "api calls"
+    );
+    let row = |label: &str, usage: &Usage| {
+        println!(
+            "{:<12} {:>12} {:>12} {:>9} {:>9}",
+            label,
+            human_size(usage.uploaded),
+            human_size(usage.downloaded),
+            usage.repo_operations(),
+            usage.api_calls()
+        )
+    };
+    for (day, usage) in &days {
+        row(day, usage);
+    }
+    row("total", &total);
+    Ok(())
+}
@@ -1,117 +1,867 @@
-use anyhow::{Context, Result};
+use anyhow::{bail, Context, Result};
+use semver::Version;
 use sha2::{Digest, Sha256};
+use std::cell::Cell;
+use std::ffi::{OsStr, OsString};
 use std::fs::File;
-use std::io::{self, Read, Write};
-use std::path::Path;
-use std::process::Command;
+use std::io::{self, BufRead, IsTerminal, Read, Write};
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+use std::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+use std::sync::{Condvar, Mutex, OnceLock, RwLock};
+use std::time::UNIX_EPOCH;
 
-use crate::constants::{CHUNK_SIZE, TMPFS_DIR};
+use crate::clock::clock;
+use crate::config::settings;
+use crate::constants::DEFAULT_TRANSFER_CONCURRENCY;
+use crate::models::ChecksumAlgo;
+use crate::progress;
+use crate::retry::CommandFailed;
 
 pub fn sleep(seconds: f64) {
     if seconds <= 0.0 {
         return; // no sleeping for zero or negative values
     }
-    std::thread::sleep(std::time::Duration::from_secs_f64(seconds));
-}
-
-/// Retries a fallible operation indefinitely, with a configurable starting delay
-/// and delay increment per retry. If increment is 0, delay is fixed.
-pub fn retry<T, E, F>(mut operation: F, start_delay_secs: u64, delay_increment_secs: u64) -> T
-where
-    F: FnMut() -> Result<T, E>,
-    E: std::fmt::Debug,
-{
-    let mut delay = start_delay_secs;
-
-    loop {
-        match operation() {
-            Ok(val) => return val,
-            Err(e) => {
-                eprintln!("Operation failed: {:?}. Retrying in {}s...", e, delay);
-                sleep(delay as f64);
-                delay = delay.saturating_add(delay_increment_secs);
-            }
+    clock().sleep(std::time::Duration::from_secs_f64(seconds));
+}
+
+/// Counting semaphore bounding how many threads hold a slot at once.
+pub struct Semaphore {
+    /// (free slots, next ticket, ticket served next)
+    state: Mutex<(usize, u64, u64)>,
+    released: Condvar,
+}
+
+pub struct SemaphoreGuard<'a>(&'a Semaphore);
+
+impl Semaphore {
+    pub fn new(slots: usize) -> Self {
+        Semaphore {
+            state: Mutex::new((slots.max(1), 0, 0)),
+            released: Condvar::new(),
         }
     }
+
+    /// Blocks until a slot is free, the slot is released with the guard.
+    /// Slots go to waiters in the order they asked, so the repo batches of
+    /// small files are not overtaken by those of a big one queued after them.
+    pub fn acquire(&self) -> SemaphoreGuard<'_> {
+        let mut state = self.state.lock().unwrap();
+        let ticket = state.1;
+        state.1 += 1;
+        while state.0 == 0 || state.2 != ticket {
+            state = self.released.wait(state).unwrap();
+        }
+        state.0 -= 1;
+        state.2 += 1;
+        // the next ticket may find a slot free too
+        self.released.notify_all();
+        SemaphoreGuard(self)
+    }
+}
+
+impl Drop for SemaphoreGuard<'_> {
+    fn drop(&mut self) {
+        self.0.state.lock().unwrap().0 += 1;
+        self.0.released.notify_all();
+    }
+}
+
+static TRANSFERS: OnceLock<(usize, Semaphore)> = OnceLock::new();
+
+/// Sets how many repo transfers `transfer_slot` lets run at once, before the
+/// first transfer. Later calls are ignored.
+pub fn set_transfer_concurrency(slots: usize) {
+    let _ = TRANSFERS.set((slots.max(1), Semaphore::new(slots)));
+}
+
+pub fn transfer_concurrency() -> usize {
+    transfers().0
+}
+
+/// Waits for a free transfer slot, to be held while cloning or pushing a repo.
+pub fn transfer_slot() -> SemaphoreGuard<'static> {
+    transfers().1.acquire()
+}
+
+fn transfers() -> &'static (usize, Semaphore) {
+    TRANSFERS.get_or_init(|| {
+        (
+            DEFAULT_TRANSFER_CONCURRENCY,
+            Semaphore::new(DEFAULT_TRANSFER_CONCURRENCY),
+        )
+    })
+}
+
+static DETACH_CHILDREN: AtomicBool = AtomicBool::new(false);
+
+/// Starts later `run` children in their own process group, so a Ctrl-C
+/// handled by gidrive does not also kill the git commands it is waiting on.
+pub fn detach_children_from_sigint() {
+    DETACH_CHILDREN.store(true, Ordering::Relaxed);
+}
+
+/// Variables of `setup_auth` that only `command` children get, so hooks and
+/// the editor never see the credentials in them.
+static CHILD_ENV: RwLock<Vec<(String, OsString)>> = RwLock::new(Vec::new());
+
+/// Sets `key` to `value` in the environment of later `command` children,
+/// leaving the one of gidrive alone.
+pub fn set_child_env(key: &str, value: impl AsRef<OsStr>) {
+    let mut env = CHILD_ENV.write().unwrap();
+    env.retain(|(k, _)| k != key);
+    env.push((key.to_string(), value.as_ref().to_os_string()));
+}
+
+/// `key` as `command` children see it: set by `set_child_env`, else
+/// inherited.
+pub fn child_env(key: &str) -> Option<OsString> {
+    CHILD_ENV
+        .read()
+        .unwrap()
+        .iter()
+        .find(|(k, _)| k == key)
+        .map(|(_, v)| v.clone())
+        .or_else(|| std::env::var_os(key))
+}
+
+/// A `Command` for `program` in the environment every git, gh and tool child
+/// gets: the C locale, so the output matched against is English whatever the
+/// user's, no terminal prompt, so a missing credential fails instead of
+/// waiting on a prompt nobody sees, and the credentials of `setup_auth`.
+/// Without an askpass helper of `https_askpass`, git asks `true`, which
+/// answers nothing. Started in its own process group after
+/// `detach_children_from_sigint`.
+pub fn command(program: impl AsRef<OsStr>) -> Command {
+    let mut command = Command::new(program);
+    command
+        .env("LC_ALL", "C")
+        .env("LANG", "C")
+        .env_remove("LANGUAGE")
+        .env("GIT_TERMINAL_PROMPT", "0")
+        .envs(CHILD_ENV.read().unwrap().iter().cloned());
+    if child_env("GIT_ASKPASS").is_none() {
+        command.env("GIT_ASKPASS", "true");
+    }
+    if DETACH_CHILDREN.load(Ordering::Relaxed) {
+        command.process_group(0);
+    }
+    command
+}
+
+static VERBOSITY: AtomicU8 = AtomicU8::new(0);
+
+/// Sets how many `-v` were given: one prints details like the files excluded
+/// from a recursive operation, two also scheduling decisions.
+pub fn set_verbosity(level: u8) {
+    VERBOSITY.store(level, Ordering::Relaxed);
+}
+
+pub fn verbose() -> bool {
+    VERBOSITY.load(Ordering::Relaxed) >= 1
+}
+
+/// `-vv`: also the order chunks are fetched and written in.
+pub fn very_verbose() -> bool {
+    VERBOSITY.load(Ordering::Relaxed) >= 2
+}
+
+static ASSUME_YES: AtomicBool = AtomicBool::new(false);
+
+/// Makes every later `confirm` pass without asking, for `--yes`.
+pub fn assume_yes() {
+    ASSUME_YES.store(true, Ordering::Relaxed);
+}
+
+/// Asks before an irreversible operation: prints `what` and waits for the
+/// user to type `answer`. Passes without asking after `assume_yes` or with
+/// GIDRIVE_ASSUME_YES=1, and fails rather than hang when stdin is not a
+/// terminal.
+pub fn confirm(what: &str, answer: &str) -> Result<()> {
+    if ASSUME_YES.load(Ordering::Relaxed)
+        || std::env::var("GIDRIVE_ASSUME_YES").is_ok_and(|v| v == "1")
+    {
+        return Ok(());
+    }
+    if !io::stdin().is_terminal() {
+        bail!("refusing to {what} without --yes, stdin is not a terminal");
+    }
+    eprint!("--- about to {what}\n--- type {answer:?} to continue: ");
+    io::stderr().flush()?;
+    let mut line = String::new();
+    io::stdin().lock().read_line(&mut line)?;
+    if line.trim() != answer {
+        bail!("aborted, nothing was deleted");
+    }
+    Ok(())
 }
 
 pub fn run(cmd: &str) -> io::Result<String> {
-    let output = Command::new("sh").arg("-c").arg(cmd).output()?;
-    io::stdout().write_all(&output.stdout)?;
-    io::stderr().write_all(&output.stderr)?;
+    check_output(cmd, run_output(cmd)?, true)
+}
+
+/// The stdout of `cmd` that finished with `output`, or its failure as `run`
+/// reports it. With `echo`, its output is copied to stderr first.
+pub fn check_output(cmd: &str, output: Output, echo: bool) -> io::Result<String> {
+    // child output is diagnostics only, stdout is reserved for data (cat, ls)
+    // and stderr for events with `--progress json`
+    if echo && !progress::json_enabled() {
+        io::stderr().write_all(&output.stdout)?;
+        io::stderr().write_all(&output.stderr)?;
+    }
+    let stderr = String::from_utf8_lossy(&output.stderr);
     if output.status.success() {
         Ok(String::from_utf8_lossy(&output.stdout).into())
-    } else {
+    } else if stderr.contains("No space left on device") {
         Err(io::Error::new(
-            io::ErrorKind::Other,
-            format!("Command failed: {}", cmd),
+            io::ErrorKind::StorageFull,
+            format!("Command failed: {}: no space left on device", cmd),
         ))
+    } else {
+        let message = if progress::json_enabled() {
+            format!("Command failed: {}: {}", cmd, stderr.trim())
+        } else {
+            format!("Command failed: {}", cmd)
+        };
+        Err(io::Error::other(CommandFailed {
+            message,
+            stderr: stderr.into_owned(),
+        }))
     }
 }
 
+/// Runs `cmd` like `run` but hands back its output without echoing it,
+/// whatever the exit status.
+pub fn run_output(cmd: &str) -> io::Result<Output> {
+    command("sh").arg("-c").arg(cmd).output()
+}
+
+/// Like `run`, with `args` passed to `program` as they are instead of
+/// through a shell, for arguments holding user strings.
+pub fn run_args(program: &str, args: &[&OsStr]) -> io::Result<String> {
+    let output = command(program).args(args).output()?;
+    if !progress::json_enabled() {
+        io::stderr().write_all(&output.stdout)?;
+        io::stderr().write_all(&output.stderr)?;
+    }
+    if output.status.success() {
+        Ok(String::from_utf8_lossy(&output.stdout).into())
+    } else {
+        Err(io::Error::other(format!(
+            "Command failed: {} {}: {}",
+            program,
+            args.first().map_or("".into(), |a| a.to_string_lossy()),
+            String::from_utf8_lossy(&output.stderr).trim()
+        )))
+    }
+}
+
+/// `s` on one line of at most `max_chars` characters, for commit messages
+/// and logs: line breaks and tabs become spaces, other control characters
+/// and the bidi overrides that reorder the text on screen become `?`.
+pub fn one_line(s: &str, max_chars: usize) -> String {
+    let mut out: String = s
+        .chars()
+        .map(|c| match c {
+            '\t' | '\n' | '\r' => ' ',
+            '\u{202a}'..='\u{202e}' | '\u{2066}'..='\u{2069}' => '?',
+            c if c.is_control() => '?',
+            c => c,
+        })
+        .collect();
+    if let Some((cut, _)) = out.char_indices().nth(max_chars) {
+        out.truncate(cut);
+        out.push_str("...");
+    }
+    out
+}
+
+/// Incremental hasher for any supported `ChecksumAlgo`.
+#[derive(Clone)]
+pub enum Hasher {
+    Sha256(Sha256),
+    Blake3(Box<blake3::Hasher>),
+}
+
+impl Hasher {
+    pub fn new(algo: ChecksumAlgo) -> Self {
+        match algo {
+            ChecksumAlgo::Sha256 => Hasher::Sha256(Sha256::new()),
+            ChecksumAlgo::Blake3 => Hasher::Blake3(Box::new(blake3::Hasher::new())),
+        }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        match self {
+            Hasher::Sha256(h) => h.update(data),
+            // spreads large inputs over the rayon pool
+            Hasher::Blake3(h) => {
+                h.update_rayon(data);
+            }
+        }
+    }
+
+    pub fn finalize_hex(self) -> String {
+        match self {
+            Hasher::Sha256(h) => format!("{:x}", h.finalize()),
+            Hasher::Blake3(h) => h.finalize().to_hex().to_string(),
+        }
+    }
+}
+
+pub fn checksum_hex(algo: ChecksumAlgo, data: &[u8]) -> String {
+    let mut hasher = Hasher::new(algo);
+    hasher.update(data);
+    hasher.finalize_hex()
+}
+
 pub fn get_file_sha256(path: &Path) -> Result<String> {
+    get_file_checksum(path, ChecksumAlgo::Sha256)
+}
+
+pub fn get_file_checksum(path: &Path, algo: ChecksumAlgo) -> Result<String> {
     let mut file = File::open(path).context("Failed to open file for hashing")?;
-    let mut hasher = Sha256::new();
-    let mut buffer = vec![0u8; CHUNK_SIZE];
-    loop {
-        let bytes_read = file.read(&mut buffer).context("Failed to read for hash")?;
-        if bytes_read == 0 {
-            break;
+    let mut hasher = Hasher::new(algo);
+    for_each_block(&mut file, |data| {
+        hasher.update(data);
+        Ok(())
+    })
+    .context("Failed to read for hash")?;
+    Ok(hasher.finalize_hex())
+}
+
+thread_local! {
+    /// Buffer of `for_each_block`, allocated once per thread
+    static IO_BUFFER: Cell<Vec<u8>> = const { Cell::new(Vec::new()) };
+}
+
+/// Reads `reader` to the end in blocks of at most `io_buffer_size` bytes,
+/// handing each to `f`, and returns the bytes read.
+pub fn for_each_block(
+    reader: &mut impl Read,
+    mut f: impl FnMut(&[u8]) -> io::Result<()>,
+) -> io::Result<u64> {
+    // taken rather than borrowed: a rayon thread waiting in `f` may run
+    // another task reading on the same thread
+    let mut buf = IO_BUFFER.take();
+    buf.resize(settings().io_buffer_size(), 0);
+    let mut total = 0;
+    let res = loop {
+        match reader.read(&mut buf) {
+            Ok(0) => break Ok(total),
+            Ok(n) => {
+                if let Err(e) = f(&buf[..n]) {
+                    break Err(e);
+                }
+                total += n as u64;
+            }
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => break Err(e),
+        }
+    };
+    IO_BUFFER.set(buf);
+    res
+}
+
+/// Reads until `buf` is full or EOF, at most `io_buffer_size` bytes at a
+/// time, returning the bytes read.
+pub fn read_full(reader: &mut impl Read, buf: &mut [u8]) -> io::Result<usize> {
+    let block = settings().io_buffer_size();
+    let mut filled = 0;
+    while filled < buf.len() {
+        let end = buf.len().min(filled + block);
+        match reader.read(&mut buf[filled..end]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
         }
-        hasher.update(&buffer[..bytes_read]);
     }
-    Ok(format!("{:x}", hasher.finalize()))
+    Ok(filled)
 }
 
-pub fn ensure_tmpfs_dir() -> Result<()> {
-    std::fs::create_dir_all(TMPFS_DIR).context("Failed to create TMPFS_DIR")
+/// Writer adapter that forwards everything to `inner` while computing the
+/// checksum and length of the bytes written.
+pub struct HashingWriter<W: Write> {
+    inner: W,
+    hasher: Hasher,
+    written: u64,
 }
 
-pub fn human_size(bytes: u64) -> String {
-    const KB: f64 = 1024.0;
-    const MB: f64 = KB * 1024.0;
-    const GB: f64 = MB * 1024.0;
-    const TB: f64 = GB * 1024.0;
-    let b = bytes as f64;
-    if b < KB {
-        format!("{} B", bytes)
-    } else if b < MB {
-        format!("{:.2} KB", b / KB)
-    } else if b < GB {
-        format!("{:.2} MB", b / MB)
-    } else if b < TB {
-        format!("{:.2} GB", b / GB)
+impl<W: Write> HashingWriter<W> {
+    pub fn new(inner: W, algo: ChecksumAlgo) -> Self {
+        HashingWriter {
+            inner,
+            hasher: Hasher::new(algo),
+            written: 0,
+        }
+    }
+
+    /// Returns (bytes written, hex checksum).
+    pub fn finish(self) -> (u64, String) {
+        (self.written, self.hasher.finalize_hex())
+    }
+
+    /// Returns the bytes written and the hasher state, to keep hashing more data.
+    pub fn into_hasher(self) -> (u64, Hasher) {
+        (self.written, self.hasher)
+    }
+}
+
+impl<W: Write> Write for HashingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.hasher.update(&buf[..n]);
+        self.written += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Where a command keeps its temporary files: `work_dir` and `staging_dir`
+/// of the config, each defaulting to one directory per invocation under the
+/// system temp dir.
+pub struct TempDirs {
+    /// Clones of the metadata and storage repos, and git helpers
+    pub work: PathBuf,
+    /// Staged chunks, spooled uploads and downloads being assembled
+    pub staging: PathBuf,
+    /// Directory of this invocation, removed once the command succeeded
+    invocation: PathBuf,
+}
+
+static TEMP_DIRS: OnceLock<TempDirs> = OnceLock::new();
+
+/// Puts the work and staging dirs under `root` instead of the configured
+/// ones, for tests to run in a dir of their own. Fails once they are in use.
+pub fn set_temp_dirs(root: &Path) -> Result<()> {
+    TEMP_DIRS
+        .set(TempDirs {
+            work: root.join("work"),
+            staging: root.join("staging"),
+            invocation: root.to_path_buf(),
+        })
+        .map_err(|_| anyhow::anyhow!("the temp dirs are already in use"))
+}
+
+pub fn temp_dirs() -> &'static TempDirs {
+    TEMP_DIRS.get_or_init(|| {
+        let invocation = std::env::temp_dir().join(format!("gidrive-{}", std::process::id()));
+        TempDirs {
+            work: settings().work_dir().unwrap_or_else(|| invocation.clone()),
+            staging: settings()
+                .staging_dir()
+                .unwrap_or_else(|| invocation.clone()),
+            invocation,
+        }
+    })
+}
+
+/// Creates the work and staging dirs.
+pub fn ensure_temp_dirs() -> Result<()> {
+    let dirs = temp_dirs();
+    for dir in [&dirs.work, &dirs.staging] {
+        std::fs::create_dir_all(dir)
+            .with_context(|| format!("Failed to create temp dir {}", dir.display()))?;
+    }
+    Ok(())
+}
+
+/// Removes the directory of this invocation, if one was used. Configured
+/// dirs are left alone, staged chunks there let a later download resume.
+pub fn remove_invocation_dir() {
+    if let Some(dirs) = TEMP_DIRS.get() {
+        if dirs.work == dirs.invocation || dirs.staging == dirs.invocation {
+            let _ = std::fs::remove_dir_all(&dirs.invocation);
+        }
+    }
+}
+
+/// Bytes available to this user on the filesystem of `dir`, from `df`.
+pub fn free_space(dir: &Path) -> Result<u64> {
+    let output = command("df")
+        .arg("-Pk")
+        .arg(dir)
+        .output()
+        .context("Failed to run df")?;
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    // POSIX format: a header line, then "fs blocks used available capacity mount"
+    stdout
+        .lines()
+        .nth(1)
+        .and_then(|line| line.split_whitespace().nth(3))
+        .and_then(|kib| kib.parse::<u64>().ok())
+        .map(|kib| kib * 1024)
+        .with_context(|| format!("Failed to read the free space of {}", dir.display()))
+}
+
+/// Fails early when the work dir has less than `work` bytes free or the
+/// staging dir less than `staging` for `what`, counting both against one
+/// disk when they share it. A filesystem `df` cannot measure is not checked.
+pub fn ensure_free_space(work: u64, staging: u64, what: &str) -> Result<()> {
+    let dirs = temp_dirs();
+    let needs = if dirs.work == dirs.staging {
+        vec![(&dirs.work, "GIDRIVE_WORK_DIR", work + staging)]
     } else {
-        format!("{:.2} TB", b / TB)
+        vec![
+            (&dirs.work, "GIDRIVE_WORK_DIR", work),
+            (&dirs.staging, "GIDRIVE_STAGING_DIR", staging),
+        ]
+    };
+    for (dir, env, needed) in needs {
+        let Ok(free) = free_space(dir) else {
+            continue;
+        };
+        if free < needed {
+            bail!(
+                "{} needs ~{} free in {}, have {}: set {} to a larger disk",
+                what,
+                human_size(needed),
+                dir.display(),
+                human_size(free),
+                env
+            );
+        }
     }
+    Ok(())
 }
 
-pub fn versions_are_compatible(found: &str, current: &str) -> bool {
-    let found_parts: Vec<&str> = found.split('.').collect();
-    let current_parts: Vec<&str> = current.split('.').collect();
+/// Whether `e` comes from a filesystem filling up, in this process or in a
+/// command run with `run`.
+pub fn is_no_space(e: &anyhow::Error) -> bool {
+    e.chain().any(|cause| {
+        cause
+            .downcast_ref::<io::Error>()
+            .is_some_and(|e| e.kind() == io::ErrorKind::StorageFull)
+    })
+}
 
-    if found_parts.len() != 3 || current_parts.len() != 3 {
-        return false;
+/// Adds a hint to move the temp dirs to `e` when it comes from one of them
+/// filling up.
+pub fn explain_no_space(e: anyhow::Error) -> anyhow::Error {
+    if !is_no_space(&e) {
+        return e;
     }
+    let dirs = temp_dirs();
+    let hint = if dirs.work == dirs.staging {
+        format!(
+            "{} is full, free space or set GIDRIVE_WORK_DIR and GIDRIVE_STAGING_DIR to a larger disk",
+            dirs.work.display()
+        )
+    } else {
+        format!(
+            "{} or {} is full, free space or set GIDRIVE_WORK_DIR or GIDRIVE_STAGING_DIR to a larger disk",
+            dirs.work.display(),
+            dirs.staging.display()
+        )
+    };
+    e.context(hint)
+}
 
-    let Ok(found_maj) = found_parts[0].parse::<u32>() else {
-        return false;
+/// Moves `src` to `dst`, which is replaced at once: when they are on
+/// different filesystems, `src` is copied next to `dst` first and renamed
+/// over it from there.
+pub fn move_file(src: &Path, dst: &Path) -> Result<()> {
+    match std::fs::rename(src, dst) {
+        Err(e) if e.kind() == io::ErrorKind::CrossesDevices => {}
+        res => return res.context("Failed to move file"),
+    }
+    let name = dst
+        .file_name()
+        .context("Destination must have a file name")?;
+    let tmp = dst.with_file_name(format!(".{}.gidrive-move", name.to_string_lossy()));
+    let copied = std::fs::copy(src, &tmp)
+        .and_then(|_| File::open(&tmp)?.sync_all())
+        .and_then(|_| std::fs::rename(&tmp, dst));
+    if let Err(e) = copied {
+        let _ = std::fs::remove_file(&tmp);
+        return Err(e).context("Failed to copy file across filesystems");
+    }
+    std::fs::remove_file(src).context("Failed to remove moved file")
+}
+
+/// Seconds since the epoch, as stored in the file metadata.
+pub fn unix_now() -> u64 {
+    clock()
+        .now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+static SI_SIZES: AtomicBool = AtomicBool::new(false);
+
+/// Makes `human_size` use powers of 1000 (kB, MB, ...) instead of 1024
+/// (KiB, MiB, ...), for `--si`.
+pub fn use_si_sizes() {
+    SI_SIZES.store(true, Ordering::Relaxed);
+}
+
+/// (year, month, day) of the UTC date of `secs` since the epoch.
+fn civil_date(secs: u64) -> (i64, i64, i64) {
+    // civil_from_days from Howard Hinnant's date algorithms
+    let days = (secs / 86400) as i64 + 719468;
+    let era = days.div_euclid(146097);
+    let doe = days.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    (yoe + era * 400 + i64::from(month <= 2), month, day)
+}
+
+/// Formats seconds since the epoch as the UTC day "YYYY-MM-DD".
+pub fn format_day(secs: u64) -> String {
+    let (year, month, day) = civil_date(secs);
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+/// Formats seconds since the epoch as "YYYY-MM-DD HH:MM:SSZ" in UTC.
+pub fn format_utc(secs: u64) -> String {
+    let (year, month, day) = civil_date(secs);
+    let time = secs % 86400;
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        time / 3600,
+        time / 60 % 60,
+        time % 60
+    )
+}
+
+/// Formats seconds since the epoch as an HTTP date, like
+/// "Sun, 06 Nov 1994 08:49:37 GMT".
+pub fn format_http_date(secs: u64) -> String {
+    const WEEKDAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+    let (year, month, day) = civil_date(secs);
+    let time = secs % 86400;
+    format!(
+        "{}, {:02} {} {:04} {:02}:{:02}:{:02} GMT",
+        WEEKDAYS[(secs / 86400 % 7) as usize],
+        day,
+        MONTHS[month as usize - 1],
+        year,
+        time / 3600,
+        time / 60 % 60,
+        time % 60
+    )
+}
+
+pub fn human_size(bytes: u64) -> String {
+    let (base, units) = if SI_SIZES.load(Ordering::Relaxed) {
+        (1000.0, ["kB", "MB", "GB", "TB", "PB"])
+    } else {
+        (1024.0, ["KiB", "MiB", "GiB", "TiB", "PiB"])
     };
-    let Ok(found_min) = found_parts[1].parse::<u32>() else {
-        return false;
+    let mut value = bytes as f64;
+    if value < base {
+        return format!("{} B", bytes);
+    }
+    let mut unit = 0;
+    value /= base;
+    while value >= base && unit < units.len() - 1 {
+        value /= base;
+        unit += 1;
+    }
+    format!("{:.2} {}", value, units[unit])
+}
+
+/// Parses a size like "2MiB", "1.5G", "500KB" or "4096" into bytes, case
+/// insensitively. Single letters and the iB suffixes are binary, the B
+/// suffixes decimal: K and KiB are 1024, KB is 1000. Fractions of a byte
+/// are dropped.
+pub fn parse_size(s: &str) -> Result<u64> {
+    let t = s.trim();
+    let (number, unit) = t.split_at(
+        t.find(|c: char| !c.is_ascii_digit() && c != '.')
+            .unwrap_or(t.len()),
+    );
+    let multiplier: u128 = match unit.trim().to_ascii_lowercase().as_str() {
+        "" | "b" => 1,
+        "k" | "kib" => 1 << 10,
+        "m" | "mib" => 1 << 20,
+        "g" | "gib" => 1 << 30,
+        "t" | "tib" => 1 << 40,
+        "p" | "pib" => 1 << 50,
+        "kb" => 1_000,
+        "mb" => 1_000_000,
+        "gb" => 1_000_000_000,
+        "tb" => 1_000_000_000_000,
+        "pb" => 1_000_000_000_000_000,
+        _ => bail!(
+            "invalid size {:?}: unknown unit {:?}, use B, K, M, G, T or P, optionally followed by iB or B",
+            s,
+            unit.trim()
+        ),
     };
-    let Ok(curr_maj) = current_parts[0].parse::<u32>() else {
-        return false;
+    let (whole, fraction) = number.split_once('.').unwrap_or((number, ""));
+    let digits = |part: &str| {
+        !part.is_empty() && part.len() <= 20 && part.bytes().all(|b| b.is_ascii_digit())
     };
-    let Ok(curr_min) = current_parts[1].parse::<u32>() else {
-        return false;
+    if !digits(whole) || !(fraction.is_empty() || digits(fraction)) || number.ends_with('.') {
+        bail!(
+            "invalid size {:?}: expected a number like 2, 2.5 or 4096, with an optional unit",
+            s
+        );
+    }
+    let mut bytes = whole.parse::<u128>()? * multiplier;
+    if !fraction.is_empty() {
+        bytes += fraction.parse::<u128>()? * multiplier / 10u128.pow(fraction.len() as u32);
+    }
+    u64::try_from(bytes)
+        .map_err(|_| anyhow::anyhow!("invalid size {:?}: more than {} bytes", s, u64::MAX))
+}
+
+/// Parses a duration like "7d", "12h", "30m" or "45s", or a plain number of
+/// seconds, into seconds.
+pub fn parse_duration(s: &str) -> Result<u64> {
+    let t = s.trim();
+    let (number, unit) = t.split_at(t.find(|c: char| !c.is_ascii_digit()).unwrap_or(t.len()));
+    let multiplier = match unit.trim() {
+        "" | "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        "w" => 7 * 24 * 60 * 60,
+        unit => bail!(
+            "invalid duration {:?}: unknown unit {:?}, use s, m, h, d or w",
+            s,
+            unit
+        ),
     };
+    let n: u64 = number
+        .parse()
+        .with_context(|| format!("invalid duration {:?}: expected a number like 7d", s))?;
+    n.checked_mul(multiplier)
+        .with_context(|| format!("invalid duration {:?}: too long", s))
+}
 
-    if found_maj != curr_maj {
+/// Whether a client at version `current` may write metadata stamped with
+/// `found`. Releases are compatible within a major version, or a minor one
+/// before 1.0, whatever their patch level. A pre-release may still change
+/// the format, so metadata stamped by one is only written by that exact
+/// pre-release. Unparseable versions are never compatible.
+pub fn versions_are_compatible(found: &str, current: &str) -> bool {
+    let (Ok(found), Ok(current)) = (Version::parse(found), Version::parse(current)) else {
         return false;
+    };
+    let same_line =
+        found.major == current.major && (found.major != 0 || found.minor == current.minor);
+    same_line && (found.pre.is_empty() || found.pre == current.pre && found.patch == current.patch)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sizes_parse_with_binary_and_decimal_units() {
+        for (input, bytes) in [
+            ("0", 0),
+            ("0B", 0),
+            ("4096", 4096),
+            ("2MiB", 2 << 20),
+            (" 2 mib ", 2 << 20),
+            ("1.5G", 3 << 29),
+            ("500KB", 500_000),
+            ("1.5kb", 1_500),
+            ("0.5", 0),
+            ("1.0009K", 1024),
+            ("7P", 7 << 50),
+            ("18446744073709551615", u64::MAX),
+            ("16383.99999PiB", 18_446_744_062_450_552_547),
+        ] {
+            assert_eq!(parse_size(input).unwrap(), bytes, "{:?}", input);
+        }
     }
-    if curr_maj == 0 && found_min != curr_min {
-        return false;
+
+    #[test]
+    fn sizes_over_u64_max_and_garbage_are_refused() {
+        for input in [
+            "18446744073709551616",
+            "16384P",
+            "16777216T",
+            "18446744073709551615K",
+            "99999999999999999999999",
+            "",
+            " ",
+            "abc",
+            "MiB",
+            "1.2.3",
+            "1.",
+            ".5",
+            "-1",
+            "+1",
+            "1e3",
+            "12 KBs",
+            "3 EiB",
+            "١٢",
+            "1 0",
+        ] {
+            assert!(parse_size(input).is_err(), "{:?} parsed", input);
+        }
+    }
+
+    #[test]
+    fn human_sizes_read_back_as_the_size() {
+        for (bytes, human) in [
+            (0, "0 B"),
+            (1023, "1023 B"),
+            (1024, "1.00 KiB"),
+            (1536, "1.50 KiB"),
+            (5 << 30, "5.00 GiB"),
+            (u64::MAX, "16384.00 PiB"),
+        ] {
+            assert_eq!(human_size(bytes), human);
+        }
+        for bytes in [0, 1, 1023, 1024, 3 << 20, 5 << 30, 3 << 40, 7 << 50] {
+            assert_eq!(parse_size(&human_size(bytes)).unwrap(), bytes);
+        }
+        // two decimals lose at most half a hundredth of the unit
+        for bytes in [1025, 123_456_789, 987_654_321_012, u64::MAX / 3] {
+            let back = parse_size(&human_size(bytes)).unwrap();
+            assert!(
+                back.abs_diff(bytes) <= bytes / 200,
+                "{} read back as {}",
+                bytes,
+                back
+            );
+        }
+        assert!(parse_size(&human_size(u64::MAX)).is_err());
+    }
+
+    #[test]
+    fn children_run_in_the_c_locale_without_terminal_prompts() {
+        let git = command("git");
+        let envs: Vec<(&OsStr, Option<&OsStr>)> = git.get_envs().collect();
+        let env = |name: &str| {
+            envs.iter()
+                .find(|(key, _)| *key == name)
+                .map(|(_, value)| *value)
+        };
+        assert_eq!(env("LC_ALL"), Some(Some(OsStr::new("C"))));
+        assert_eq!(env("LANG"), Some(Some(OsStr::new("C"))));
+        assert_eq!(env("LANGUAGE"), Some(None));
+        assert_eq!(env("GIT_TERMINAL_PROMPT"), Some(Some(OsStr::new("0"))));
+        // an askpass helper of the user's is kept
+        let askpass = std::env::var_os("GIT_ASKPASS")
+            .is_none()
+            .then_some(Some(OsStr::new("true")));
+        assert_eq!(env("GIT_ASKPASS"), askpass);
+
+        let output = run_output(
+            "echo \"$LC_ALL $LANG ${LANGUAGE-unset} $GIT_TERMINAL_PROMPT ${GIT_ASKPASS:+set}\"",
+        )
+        .unwrap();
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "C C unset 0 set\n");
     }
-    true
 }
@@ -0,0 +1,254 @@
+use anyhow::{Context, Result};
+use notify::{Event, EventKind, RecursiveMode, Watcher};
+use std::collections::{BTreeSet, HashMap};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc};
+use std::time::{Duration, Instant, SystemTime};
+use walkdir::WalkDir;
+
+use crate::api::{self, UploadOptions};
+use crate::exclude::{special_kind, ExcludeOptions, Excludes, LinkPolicy};
+use crate::metadata::{clone_metadata, list_file_metadata};
+use crate::metrics;
+use crate::report::TransferReport;
+use crate::utils::{detach_children_from_sigint, human_size, verbose};
+
+/// How often pending changes are checked for quiescence.
+const TICK: Duration = Duration::from_millis(200);
+
+/// Last observed state of a changed path, it is acted on once it stayed the
+/// same for a whole debounce period.
+struct Pending {
+    since: Instant,
+    state: Option<(u64, SystemTime)>,
+}
+
+fn file_state(path: &Path, links: LinkPolicy) -> Option<(u64, SystemTime)> {
+    let meta = match links {
+        LinkPolicy::Follow => fs::metadata(path),
+        _ => fs::symlink_metadata(path),
+    };
+    let meta = meta
+        .ok()
+        .filter(|m| m.is_file() || (links == LinkPolicy::Preserve && m.is_symlink()))?;
+    Some((meta.len(), meta.modified().ok()?))
+}
+
+/// Where and how often `watch` writes its metrics for a textfile collector.
+pub struct MetricsFile {
+    pub path: PathBuf,
+    pub interval: Duration,
+}
+
+/// Watches `local_dir` and uploads created or modified files to the same
+/// relative path under `prefix`, removing remote files whose local copy was
+/// deleted when `delete` is set. Paths `excludes` leaves out are ignored,
+/// with the ignore files as they were when the watch started. Files that
+/// settle together are handled smallest first with `small_first`, else in
+/// the order they changed, and the time until the first of them was visible
+/// remotely is printed. Runs until Ctrl-C, after which the change being
+/// transferred is finished before returning.
+pub fn watch(
+    prefix: &str,
+    local_dir: &Path,
+    debounce: Duration,
+    delete: bool,
+    small_first: bool,
+    excludes: &ExcludeOptions,
+    metrics_file: Option<&MetricsFile>,
+) -> Result<()> {
+    let local_dir = local_dir
+        .canonicalize()
+        .with_context(|| format!("cannot watch {:?}", local_dir))?;
+    let excludes = Excludes::load(&local_dir, excludes)?;
+    let metadata_clone_dir = clone_metadata()?;
+    let mut remote_files: BTreeSet<String> = list_file_metadata(&metadata_clone_dir, prefix)?
+        .into_keys()
+        .collect();
+    fs::remove_dir_all(&metadata_clone_dir)?;
+
+    let stop = Arc::new(AtomicBool::new(false));
+    let handler_stop = stop.clone();
+    ctrlc::set_handler(move || {
+        if handler_stop.swap(true, Ordering::SeqCst) {
+            eprintln!("--- watch: interrupted twice, exiting now");
+            std::process::exit(130);
+        }
+        eprintln!("--- watch: stopping after the current transfer, Ctrl-C again to force");
+    })?;
+    detach_children_from_sigint();
+
+    let (tx, rx) = mpsc::channel::<notify::Result<Event>>();
+    let mut watcher = notify::recommended_watcher(tx)?;
+    watcher.watch(&local_dir, RecursiveMode::Recursive)?;
+    eprintln!(
+        "--- watch: {} -> {} (debounce {:?})",
+        local_dir.display(),
+        if prefix.is_empty() { "/" } else { prefix },
+        debounce
+    );
+
+    let mut pending: HashMap<PathBuf, Pending> = HashMap::new();
+    // totals of every upload of this run
+    let mut session = TransferReport::default();
+    let mut metrics_written: Option<Instant> = None;
+    while !stop.load(Ordering::SeqCst) {
+        metrics::set_watch_pending(pending.len());
+        if let Some(file) = metrics_file {
+            if metrics_written.is_none_or(|at| at.elapsed() >= file.interval) {
+                if let Err(e) = metrics::write_file(&file.path) {
+                    eprintln!("--- watch: {e:#}");
+                }
+                metrics_written = Some(Instant::now());
+            }
+        }
+        match rx.recv_timeout(TICK) {
+            Ok(Ok(event)) => {
+                if matches!(event.kind, EventKind::Access(_)) {
+                    continue;
+                }
+                for path in event.paths {
+                    // files moved in with a new directory produce no events of their own
+                    let paths: Vec<PathBuf> = if path.is_dir() {
+                        WalkDir::new(&path)
+                            .into_iter()
+                            .filter_map(|e| e.ok())
+                            .filter(|e| !e.file_type().is_dir())
+                            .map(|e| e.into_path())
+                            .collect()
+                    } else {
+                        vec![path]
+                    };
+                    for path in paths {
+                        if let Some(reason) = excludes.excluded(&path, false) {
+                            if verbose() {
+                                eprintln!("--- watch: excluded {} ({})", path.display(), reason);
+                            }
+                            continue;
+                        }
+                        let state = file_state(&path, excludes.links);
+                        let special = fs::symlink_metadata(&path)
+                            .ok()
+                            .and_then(|m| special_kind(m.file_type()));
+                        if let (None, Some(kind)) = (state, special) {
+                            eprintln!("--- watch: skipping {}: {}", path.display(), kind);
+                            continue;
+                        }
+                        pending.insert(
+                            path,
+                            Pending {
+                                since: Instant::now(),
+                                state,
+                            },
+                        );
+                    }
+                }
+            }
+            Ok(Err(e)) => eprintln!("--- watch: watcher error: {e}"),
+            Err(mpsc::RecvTimeoutError::Timeout) => {}
+            Err(mpsc::RecvTimeoutError::Disconnected) => break,
+        }
+
+        let mut quiet: Vec<(&PathBuf, &Pending)> = pending
+            .iter()
+            .filter(|(_, p)| p.since.elapsed() >= debounce)
+            .collect();
+        if small_first {
+            // removals first, they transfer nothing
+            quiet.sort_by_key(|(path, p)| (p.state.map(|(size, _)| size), *path));
+        } else {
+            quiet.sort_by_key(|(path, p)| (p.since, *path));
+        }
+        let mut quiet: Vec<PathBuf> = quiet.into_iter().map(|(path, _)| path.clone()).collect();
+        let sizes: Vec<u64> = quiet
+            .iter()
+            .filter_map(|path| pending[path].state.map(|(size, _)| size))
+            .collect();
+        if sizes.len() > 1 {
+            if let Err(e) = api::check_batch_size(sizes.len(), sizes.iter().sum()) {
+                // each is checked alone as well, these wait until they change again
+                eprintln!("--- watch: skipping the uploads of this round: {e:#}");
+                quiet.retain(|path| {
+                    if pending[path].state.is_none() {
+                        return true;
+                    }
+                    pending.remove(path);
+                    false
+                });
+            }
+        }
+        let round_started = Instant::now();
+        let mut first_visible = None;
+        let mut uploaded = 0;
+        for path in quiet {
+            if stop.load(Ordering::SeqCst) {
+                break;
+            }
+            let state = file_state(&path, excludes.links);
+            let entry = pending.get_mut(&path).expect("quiet path is pending");
+            if state != entry.state {
+                // still being written, wait for another quiet period
+                entry.since = Instant::now();
+                entry.state = state;
+                continue;
+            }
+            pending.remove(&path);
+            let Ok(rel) = path.strip_prefix(&local_dir) else {
+                continue;
+            };
+            let rel = rel.to_string_lossy().into_owned();
+            let remote = if prefix.is_empty() {
+                rel.clone()
+            } else {
+                format!("{}/{}", prefix.trim_end_matches('/'), rel)
+            };
+            match state {
+                Some((size, _)) => {
+                    eprintln!("--- watch: uploading {} ({})", rel, human_size(size));
+                    let opts = UploadOptions {
+                        links: excludes.links,
+                        ..Default::default()
+                    };
+                    match api::upload(&remote, &path.to_string_lossy(), &opts) {
+                        Ok(report) => {
+                            remote_files.insert(rel);
+                            session.merge(&report);
+                            first_visible.get_or_insert(round_started.elapsed());
+                            uploaded += 1;
+                            eprintln!("--- watch: uploaded {}, {}", remote, report.savings());
+                        }
+                        Err(e) => eprintln!("--- watch: upload of {} failed: {e}", remote),
+                    }
+                }
+                None if delete && remote_files.contains(&rel) && !path.exists() => {
+                    eprintln!("--- watch: removing {}", remote);
+                    match api::remove(&remote) {
+                        Ok(_) => {
+                            remote_files.remove(&rel);
+                            eprintln!("--- watch: removed {}", remote);
+                        }
+                        Err(e) => eprintln!("--- watch: removal of {} failed: {e}", remote),
+                    }
+                }
+                None => {}
+            }
+        }
+        if let (Some(first), true) = (first_visible, uploaded > 1) {
+            eprintln!(
+                "--- watch: {} files uploaded in {:.1}s, the first visible after {:.1}s",
+                uploaded,
+                round_started.elapsed().as_secs_f64(),
+                first.as_secs_f64()
+            );
+        }
+    }
+    if session.bytes > 0 {
+        eprintln!("--- watch: this session {}", session.savings());
+    }
+    if let Some(file) = metrics_file {
+        metrics::write_file(&file.path)?;
+    }
+    Ok(())
+}
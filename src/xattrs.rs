@@ -0,0 +1,97 @@
+//! Extended attributes kept with `--xattrs`: Finder tags, quarantine flags
+//! and, on macOS, resource forks, which are the `com.apple.ResourceFork`
+//! attribute. Values are stored in base64 in the file's metadata. What
+//! cannot be read or set is skipped with a warning, the data is what counts.
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use crate::constants::MAX_XATTR_SIZE;
+use crate::utils::human_size;
+
+/// The extended attributes of `path` by name, values in base64, none when
+/// it has none or the platform has no extended attributes. Names that are
+/// not UTF-8 and values over `MAX_XATTR_SIZE` are left out.
+pub fn capture(path: &Path) -> Option<BTreeMap<String, String>> {
+    if !xattr::SUPPORTED_PLATFORM {
+        eprintln!("--- extended attributes are not supported on this platform, not storing them");
+        return None;
+    }
+    let names = match xattr::list(path) {
+        Ok(names) => names,
+        Err(e) => {
+            eprintln!(
+                "--- cannot list the extended attributes of {}: {}",
+                path.display(),
+                e
+            );
+            return None;
+        }
+    };
+    let mut attrs = BTreeMap::new();
+    for name in names {
+        let Some(name) = name.to_str().map(str::to_string) else {
+            eprintln!(
+                "--- skipping extended attribute {:?} of {}: not UTF-8",
+                name,
+                path.display()
+            );
+            continue;
+        };
+        let value = match xattr::get(path, &name) {
+            // gone since it was listed
+            Ok(None) => continue,
+            Ok(Some(value)) => value,
+            Err(e) => {
+                eprintln!(
+                    "--- skipping extended attribute {} of {}: {}",
+                    name,
+                    path.display(),
+                    e
+                );
+                continue;
+            }
+        };
+        if value.len() > MAX_XATTR_SIZE {
+            eprintln!(
+                "--- skipping extended attribute {} of {}: {} is over {}",
+                name,
+                path.display(),
+                human_size(value.len() as u64),
+                human_size(MAX_XATTR_SIZE as u64)
+            );
+            continue;
+        }
+        attrs.insert(name, STANDARD.encode(value));
+    }
+    (!attrs.is_empty()).then_some(attrs)
+}
+
+/// Sets the extended attributes `attrs` of `capture` on `path`, warning
+/// about each one the platform or the filesystem refuses.
+pub fn restore(path: &Path, attrs: &BTreeMap<String, String>) {
+    if !xattr::SUPPORTED_PLATFORM {
+        eprintln!(
+            "--- extended attributes are not supported on this platform, not restoring the {} of {}",
+            attrs.len(),
+            path.display()
+        );
+        return;
+    }
+    for (name, value) in attrs {
+        let res = match STANDARD.decode(value) {
+            Ok(value) => xattr::set(path, name, &value).map_err(|e| e.to_string()),
+            Err(e) => Err(format!("its stored value is not base64: {}", e)),
+        };
+        if let Err(e) = res {
+            eprintln!(
+                "--- cannot restore extended attribute {} of {}: {}",
+                name,
+                path.display(),
+                e
+            );
+        }
+    }
+}
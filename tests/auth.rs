@@ -0,0 +1,155 @@
+//! Credentials git cannot get without asking: the clone fails at once with
+//! what to check instead of waiting on a prompt nobody sees. And tokens in
+//! the config file, which stay out of sight of other users and of output.
+
+mod common;
+
+use common::Drive;
+use std::fs::OpenOptions;
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpListener;
+use std::process::{Output, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// `gidrive args` with stdin closed, failing the test if it is still
+/// running after a minute.
+fn run_without_prompt(drive: &Drive, args: &[&str]) -> Output {
+    let mut child = drive
+        .command(args)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("run gidrive");
+    let started = Instant::now();
+    while child.try_wait().unwrap().is_none() {
+        if started.elapsed() > Duration::from_secs(60) {
+            let _ = child.kill();
+            panic!("gidrive {:?} hangs", args);
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+    child.wait_with_output().unwrap()
+}
+
+/// Points git at `url` for the metadata repo of the drive.
+fn redirect_metadata(drive: &Drive, url: &str) {
+    let gitconfig = drive.files().parent().unwrap().join("home/.gitconfig");
+    let mut file = OpenOptions::new().append(true).open(gitconfig).unwrap();
+    write!(
+        file,
+        "[url \"{}\"]\n\tinsteadOf = https://github.com/tester/metadata\n",
+        url
+    )
+    .unwrap();
+}
+
+#[test]
+fn a_refused_token_fails_at_once_with_what_to_check() {
+    let drive = Drive::new();
+    // answers every request as if the token were wrong
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}/metadata", listener.local_addr().unwrap());
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut line = String::new();
+            while reader.read_line(&mut line).unwrap_or(0) > 2 {
+                line.clear();
+            }
+            let _ = stream.write_all(
+                b"HTTP/1.1 401 Unauthorized\r\n\
+                  WWW-Authenticate: Basic realm=\"GitHub\"\r\n\
+                  Content-Length: 0\r\nConnection: close\r\n\r\n",
+            );
+        }
+    });
+    redirect_metadata(&drive, &url);
+
+    let output = run_without_prompt(&drive, &["ls"]);
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("authentication required: the token was refused"),
+        "{}",
+        stderr
+    );
+}
+
+// libgit2 talks to the git daemon of the tests, it runs no ssh
+#[cfg(not(feature = "libgit2"))]
+#[test]
+fn ssh_runs_in_batch_mode_and_a_refused_key_says_to_check_the_agent() {
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+
+    let drive = Drive::new();
+    let root = drive.files().parent().unwrap().to_path_buf();
+    let key = root.join("home/id_test");
+    fs::write(&key, "not a key").unwrap();
+    // an ssh that logs how it was run and refuses the key, as GitHub does
+    // for one needing a passphrase when BatchMode keeps ssh from asking
+    let log = root.join("tmp/ssh.log");
+    let ssh = root.join("bin/ssh");
+    fs::write(
+        &ssh,
+        format!(
+            "#!/bin/sh\n\
+             echo \"$* prompt=$GIT_TERMINAL_PROMPT\" >> '{}'\n\
+             echo 'git@github.com: Permission denied (publickey).' >&2\n\
+             exit 255\n",
+            log.display()
+        ),
+    )
+    .unwrap();
+    fs::set_permissions(&ssh, fs::Permissions::from_mode(0o755)).unwrap();
+    drive.set("transport", "ssh");
+    drive.set("github.ssh_key", key.to_str().unwrap());
+    redirect_metadata(&drive, "git@github.com:tester/metadata");
+
+    let output = run_without_prompt(&drive, &["ls"]);
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let hint = format!(
+        "authentication required: is your ssh-agent running, or is {} passphrase-less?",
+        key.display()
+    );
+    assert!(stderr.contains(&hint), "{}", stderr);
+    let runs = fs::read_to_string(&log).unwrap();
+    assert_eq!(runs.lines().count(), 1, "retried: {}", runs);
+    assert!(runs.contains("-o BatchMode=yes"), "{}", runs);
+    assert!(runs.contains(&format!("-i {}", key.display())), "{}", runs);
+    assert!(runs.contains("prompt=0"), "{}", runs);
+}
+
+#[test]
+fn config_tokens_are_private_to_the_owner_and_masked_in_output() {
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+
+    let drive = Drive::empty();
+    let token = "ghp_secret123";
+    let set = drive.ok(&["config", "set", "github.token", token]);
+    assert!(!set.contains(token), "{}", set);
+    drive.ok(&["config", "set", "serve.token", token]);
+    let path = drive
+        .files()
+        .parent()
+        .unwrap()
+        .join("home/.config/gidrive/config.toml");
+    let mode = fs::metadata(&path).unwrap().permissions().mode();
+    assert_eq!(mode & 0o777, 0o600, "{:o}", mode);
+
+    for args in [
+        &["config", "list"][..],
+        &["config", "list", "--show-origin"],
+    ] {
+        let list = drive.ok(args);
+        assert!(!list.contains(token), "{}", list);
+        assert!(list.contains("github.token = ********"), "{}", list);
+        assert!(list.contains("serve.token = ********"), "{}", list);
+    }
+    assert_eq!(drive.ok(&["config", "get", "github.token"]).trim(), token);
+}
@@ -0,0 +1,90 @@
+//! `ls --checksums` manifests, checked by `verify --manifest` and by
+//! `sha256sum -c` itself.
+
+mod common;
+
+use common::{Drive, Tamper, CHUNK_SIZE};
+use std::fs;
+use std::process::Command;
+
+/// A drive holding files of awkward names, and the manifest `ls` writes.
+fn drive_with_manifest() -> (Drive, String) {
+    let drive = Drive::new();
+    for (name, size) in [
+        ("plain.bin", 2 * CHUNK_SIZE + 3),
+        ("with space.txt", 100),
+        ("back\\slash.txt", 10),
+        ("dir/empty.bin", 0),
+    ] {
+        let local = drive.fixture(name, size);
+        drive.ok(&["upload", name, local.to_str().unwrap()]);
+    }
+    let manifest = drive.ok(&["ls", "--checksums"]);
+    fs::write(drive.files().join("SHA256SUMS"), &manifest).unwrap();
+    (drive, manifest)
+}
+
+#[test]
+fn the_manifest_is_what_sha256sum_writes() {
+    let (drive, manifest) = drive_with_manifest();
+    assert_eq!(manifest.lines().count(), 4);
+    assert!(manifest.contains("\\slash.txt"), "{}", manifest);
+    let escaped = manifest.lines().find(|l| l.contains("slash")).unwrap();
+    assert!(escaped.starts_with('\\'), "{}", escaped);
+    assert!(escaped.ends_with("  back\\\\slash.txt"), "{}", escaped);
+    let checked = Command::new("sha256sum")
+        .args(["-c", "SHA256SUMS"])
+        .current_dir(drive.files())
+        .output()
+        .expect("run sha256sum");
+    assert!(
+        checked.status.success(),
+        "{}",
+        String::from_utf8_lossy(&checked.stdout)
+    );
+}
+
+#[test]
+fn verify_reports_every_line_and_fails_on_any_mismatch() {
+    let (drive, manifest) = drive_with_manifest();
+    let out = drive.ok(&["verify", "--manifest", "SHA256SUMS", "--deep"]);
+    assert_eq!(out.matches(": OK").count(), 4, "{}", out);
+
+    let mut bad = String::new();
+    for line in manifest.lines() {
+        match line.strip_suffix("  plain.bin") {
+            Some(sha) => {
+                let other = if sha.starts_with('0') { "1" } else { "0" };
+                bad.push_str(&format!("{}{}  plain.bin\n", other, &sha[1..]));
+            }
+            None => bad.push_str(&format!("{}\n", line)),
+        }
+    }
+    bad.push_str(&format!(
+        "{}  gone.bin\nnot a checksum line\n",
+        "a".repeat(64)
+    ));
+    fs::write(drive.files().join("BAD"), bad).unwrap();
+    let output = drive.gidrive(&["verify", "--manifest", "BAD"]);
+    assert!(!output.status.success());
+    let out = String::from_utf8_lossy(&output.stdout);
+    assert_eq!(out.matches(": FAILED\n").count(), 1, "{}", out);
+    assert!(out.contains("gone.bin: FAILED open or read"), "{}", out);
+    assert_eq!(out.matches(": OK").count(), 3, "{}", out);
+}
+
+#[test]
+fn a_deep_verify_fetches_the_damaged_chunk() {
+    let (drive, _) = drive_with_manifest();
+    let chunk = &drive.file_metadata("plain.bin")["chunks"][1];
+    drive.tamper(
+        chunk["repo"].as_str().unwrap(),
+        chunk["path"].as_str().unwrap(),
+        Tamper::Flip(7),
+    );
+    drive.ok(&["verify", "--manifest", "SHA256SUMS"]);
+    let output = drive.gidrive(&["verify", "--manifest", "SHA256SUMS", "--deep"]);
+    assert!(!output.status.success());
+    let out = String::from_utf8_lossy(&output.stdout);
+    assert!(out.contains("plain.bin: FAILED"), "{}", out);
+}
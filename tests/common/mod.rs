@@ -0,0 +1,524 @@
+//! A drive for integration tests that never reaches GitHub: the repos are
+//! bare git repos in a temporary dir, which https://github.com/ URLs are
+//! rewritten to, and a fake `gh` creates, lists and deletes them there.
+//! With libgit2, whose local transport runs no hooks, they are served by a
+//! `git daemon` of the drive instead. Every drive has a HOME of its own, so
+//! tests run in parallel.
+
+#![allow(dead_code)]
+
+use serde_json::Value;
+use std::fs;
+use std::net::{TcpListener, TcpStream};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Output, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::Duration;
+
+/// Account owning the metadata and storage repos of a test drive.
+pub const OWNER: &str = "tester";
+
+/// Chunk size of a test drive, small for files of many chunks to stay small.
+pub const CHUNK_SIZE: usize = 64 * 1024;
+
+const FAKE_GH: &str = r#"#!/bin/sh
+# the gh commands gidrive runs, on the bare repos of GH_ROOT; a repo
+# created runs the command in `race` once, in a work dir of its own, see
+# Drive::race_at_repo_create
+race="$GH_ROOT/../race"
+case "$1 $2" in
+ "repo create")
+  git init -q --bare -b main "$GH_ROOT/$3.git"
+  if [ -f "$race" ]; then
+    mv "$race" "$race.running"
+    set --
+    while IFS= read -r arg; do set -- "$@" "$arg"; done < "$race.running"
+    env -u GIDRIVE_WORK_DIR "$@" >&2
+    echo $? > "$race.status"
+  fi ;;
+ "repo list") printf '[%s]\n' "$(ls "$GH_ROOT/$3" | sed 's/\.git$//;s/.*/{"name":"&"}/' | paste -sd, -)" ;;
+ "repo view") test -d "$GH_ROOT/$3.git" ;;
+ "repo delete") rm -rf "$GH_ROOT/$3.git" ;;
+ "auth token") echo faketoken ;;
+ "api -i") printf 'HTTP/2 200\nX-OAuth-Scopes: repo, delete_repo\n\n{}\n' ;;
+ *) echo "fake gh: $*" >&2; exit 1 ;;
+esac
+"#;
+
+/// pre-receive hook of every bare repo, failing storage pushes the way
+/// GitHub does when it is down, or crashing the pushing gidrive: once the
+/// first number in `push-failures` of pushes passed, it fails as many as
+/// the second one says, as the third word says.
+const PRE_RECEIVE: &str = r#"#!/bin/sh
+case "$PWD" in */metadata.git) exit 0 ;; esac
+state="$GH_ROOT/../push-failures"
+[ -f "$state" ] || exit 0
+read pass fail how < "$state"
+if [ "$pass" -gt 0 ]; then
+  echo "$((pass - 1)) $fail $how" > "$state"
+  exit 0
+fi
+[ "$fail" -gt 0 ] || exit 0
+echo "0 $((fail - 1)) $how" > "$state"
+if [ "$how" = crash ]; then
+  # the gidrive of this drive, not a parent with git daemon in between
+  for environ in /proc/[0-9]*/environ; do
+    pid=${environ#/proc/}
+    pid=${pid%/environ}
+    [ "$(cat /proc/$pid/comm 2>/dev/null)" = gidrive ] || continue
+    if tr '\0' '\n' < "$environ" 2>/dev/null | grep -qxF "GH_ROOT=$GH_ROOT"; then
+      kill -9 "$pid"
+    fi
+  done
+fi
+echo "HTTP 503 Service Unavailable" >&2
+exit 1
+"#;
+
+/// How `Drive::tamper` damages a stored chunk.
+#[derive(Clone, Copy, Debug)]
+pub enum Tamper {
+    /// Drops the last byte
+    Truncate,
+    /// Flips the bits of the byte at this position, modulo the size
+    Flip(usize),
+    /// Deletes the chunk from the repo
+    Remove,
+}
+
+pub struct Drive {
+    root: PathBuf,
+    daemon: Option<Child>,
+}
+
+impl Drive {
+    /// A drive with an initialized metadata repo and no files.
+    pub fn new() -> Drive {
+        let drive = Drive::empty();
+        drive.ok(&["init"]);
+        drive
+    }
+
+    /// The repos, config and fake `gh` of a drive, without running
+    /// `gidrive init`.
+    pub fn empty() -> Drive {
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        let root = std::env::temp_dir().join(format!(
+            "gidrive-test-{}-{}",
+            std::process::id(),
+            NEXT.fetch_add(1, Ordering::Relaxed)
+        ));
+        let _ = fs::remove_dir_all(&root);
+        for dir in ["bin", "hooks", "home", "tmp", "files", "gh"] {
+            fs::create_dir_all(root.join(dir)).expect("create test drive dir");
+        }
+        fs::create_dir_all(root.join("gh").join(OWNER)).expect("create test account");
+        let gh = root.join("bin").join("gh");
+        fs::write(&gh, FAKE_GH).expect("write fake gh");
+        fs::set_permissions(&gh, fs::Permissions::from_mode(0o755)).expect("chmod fake gh");
+        let hook = root.join("hooks").join("pre-receive");
+        fs::write(&hook, PRE_RECEIVE).expect("write pre-receive hook");
+        fs::set_permissions(&hook, fs::Permissions::from_mode(0o755)).expect("chmod hook");
+        let gitconfig = root.join("home").join(".gitconfig");
+        let (daemon, base) = if cfg!(feature = "libgit2") {
+            let (daemon, port) = git_daemon(&root.join("gh"), &gitconfig);
+            (Some(daemon), format!("git://127.0.0.1:{}", port))
+        } else {
+            (None, root.join("gh").display().to_string())
+        };
+        // in HOME too, libgit2 does not read GIT_CONFIG_GLOBAL
+        fs::write(
+            &gitconfig,
+            format!(
+                "[url \"{}/\"]\n\tinsteadOf = https://github.com/\n\
+                 [user]\n\tname = test\n\temail = test@localhost\n\
+                 [init]\n\tdefaultBranch = main\n\
+                 [core]\n\thooksPath = {}\n",
+                base,
+                root.join("hooks").display()
+            ),
+        )
+        .expect("write gitconfig");
+        let config = root.join("home").join(".config").join("gidrive");
+        fs::create_dir_all(&config).expect("create config dir");
+        fs::write(
+            config.join("config.toml"),
+            format!(
+                "transport = \"https\"\nchunk_size = {}\n\
+                 [github]\nusername = \"{}\"\ntoken = \"faketoken\"\napi = \"gh\"\n",
+                CHUNK_SIZE, OWNER
+            ),
+        )
+        .expect("write config");
+        Drive { root, daemon }
+    }
+
+    /// `gidrive args`, run against this drive.
+    pub fn gidrive(&self, args: &[&str]) -> Output {
+        self.command(args).output().expect("run gidrive")
+    }
+
+    /// The command running `gidrive args` against this drive, for those
+    /// that keep running, like `serve`.
+    pub fn command(&self, args: &[&str]) -> Command {
+        let path = format!(
+            "{}:{}",
+            self.root.join("bin").display(),
+            std::env::var("PATH").unwrap_or_default()
+        );
+        let mut command = Command::new(env!("CARGO_BIN_EXE_gidrive"));
+        command
+            .args(args)
+            .current_dir(self.root.join("files"))
+            .env("PATH", path)
+            .env("HOME", self.root.join("home"))
+            .env("TMPDIR", self.root.join("tmp"))
+            .env("GH_ROOT", self.root.join("gh"))
+            .env(
+                "GIT_CONFIG_GLOBAL",
+                self.root.join("home").join(".gitconfig"),
+            )
+            .env("GIT_CONFIG_NOSYSTEM", "1")
+            .env("GIDRIVE_ASSUME_YES", "1")
+            .env_remove("XDG_CONFIG_HOME")
+            .env_remove("XDG_CACHE_HOME")
+            .env_remove("XDG_STATE_HOME")
+            .env_remove("GITHUB_TOKEN");
+        command
+    }
+
+    /// `sh script args` run in the files dir with the git config of this
+    /// drive, as a script gidrive wrote runs on a machine without it.
+    pub fn sh(&self, script: &Path, args: &[&str]) -> Output {
+        Command::new("sh")
+            .arg(script)
+            .args(args)
+            .current_dir(self.root.join("files"))
+            .env("HOME", self.root.join("home"))
+            .env("TMPDIR", self.root.join("tmp"))
+            .env(
+                "GIT_CONFIG_GLOBAL",
+                self.root.join("home").join(".gitconfig"),
+            )
+            .env("GIT_CONFIG_NOSYSTEM", "1")
+            .output()
+            .expect("run sh")
+    }
+
+    /// `gidrive config set key value`.
+    pub fn set(&self, key: &str, value: &str) {
+        self.ok(&["config", "set", key, value]);
+    }
+
+    /// Lets the next `pass` pushes to storage repos through, then refuses
+    /// `refuse` of them with an HTTP 503.
+    pub fn fail_pushes(&self, pass: usize, refuse: usize) {
+        self.push_failures(pass, refuse, "refuse");
+    }
+
+    /// Lets the next `pass` pushes to storage repos through, then kills the
+    /// gidrive making the next one, like a crash or a power cut would.
+    pub fn crash_at_push(&self, pass: usize) {
+        self.push_failures(pass, 1, "crash");
+    }
+
+    fn push_failures(&self, pass: usize, fail: usize, how: &str) {
+        fs::write(
+            self.root.join("push-failures"),
+            format!("{} {} {}\n", pass, fail, how),
+        )
+        .expect("write push-failures");
+    }
+
+    /// Runs `gidrive args` against this drive when the next gidrive creates
+    /// a storage repo, after it planned its upload from the metadata and
+    /// before it pushes the space it reserved there, like a second client
+    /// writing at the same time.
+    pub fn race_at_repo_create(&self, args: &[&str]) {
+        let mut lines = env!("CARGO_BIN_EXE_gidrive").to_string();
+        for arg in args {
+            lines.push('\n');
+            lines.push_str(arg);
+        }
+        lines.push('\n');
+        fs::write(self.root.join("race"), lines).expect("write race");
+    }
+
+    /// Exit code of the gidrive `race_at_repo_create` ran, none before it ran.
+    pub fn race_status(&self) -> Option<i32> {
+        fs::read_to_string(self.root.join("race.status"))
+            .ok()
+            .map(|status| status.trim().parse().expect("exit code"))
+    }
+
+    /// Lets every push through again.
+    pub fn heal_pushes(&self) {
+        let _ = fs::remove_file(self.root.join("push-failures"));
+    }
+
+    /// Stdout of `gidrive args`, failing the test with its stderr unless it
+    /// succeeds.
+    pub fn ok(&self, args: &[&str]) -> String {
+        let output = self.gidrive(args);
+        assert!(
+            output.status.success(),
+            "gidrive {:?} failed:\n{}",
+            args,
+            String::from_utf8_lossy(&output.stderr)
+        );
+        String::from_utf8_lossy(&output.stdout).into_owned()
+    }
+
+    /// Stderr of `gidrive args`, failing the test if it succeeds.
+    pub fn fails(&self, args: &[&str]) -> String {
+        let output = self.gidrive(args);
+        assert!(!output.status.success(), "gidrive {:?} succeeded", args);
+        String::from_utf8_lossy(&output.stderr).into_owned()
+    }
+
+    /// Dir of local files, the working dir of `gidrive`.
+    pub fn files(&self) -> PathBuf {
+        self.root.join("files")
+    }
+
+    /// Writes a local file `name` of `size` bytes, the same bytes for the
+    /// same name and size, and returns its path.
+    pub fn fixture(&self, name: &str, size: usize) -> PathBuf {
+        let path = self.files().join(name);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).expect("create fixture dir");
+        }
+        fs::write(&path, fixture_bytes(name, size)).expect("write fixture");
+        path
+    }
+
+    /// Uploads `local` to `remote` and downloads it again, asserting the
+    /// bytes are the same. Returns the metadata of `remote`.
+    pub fn round_trip(&self, remote: &str, local: &Path) -> Value {
+        let local = local.to_str().expect("UTF-8 fixture path");
+        self.ok(&["upload", remote, local]);
+        let back = self.files().join("downloaded");
+        let _ = fs::remove_file(&back);
+        self.ok(&["download", remote, back.to_str().unwrap()]);
+        assert!(
+            fs::read(local).unwrap() == fs::read(&back).unwrap(),
+            "{} came back different",
+            remote
+        );
+        self.file_metadata(remote)
+    }
+
+    /// Bare repo `name` of the test account.
+    pub fn repo(&self, name: &str) -> PathBuf {
+        self.root
+            .join("gh")
+            .join(OWNER)
+            .join(format!("{}.git", name))
+    }
+
+    /// The file `path` of the metadata repo as pushed, none if absent.
+    pub fn metadata_file(&self, path: &str) -> Option<String> {
+        let output = Command::new("git")
+            .arg("--git-dir")
+            .arg(self.repo("metadata"))
+            .args(["show", &format!("HEAD:{}", path)])
+            .output()
+            .expect("run git show");
+        output
+            .status
+            .success()
+            .then(|| String::from_utf8_lossy(&output.stdout).into_owned())
+    }
+
+    /// The pushed metadata JSON of `remote` in the default namespace.
+    pub fn file_metadata(&self, remote: &str) -> Value {
+        let data = self
+            .metadata_file(&format!("fs/default/{}.json", remote))
+            .unwrap_or_else(|| panic!("no metadata for {}", remote));
+        serde_json::from_str(&data).expect("parse file metadata")
+    }
+
+    /// The pushed repos.json.
+    pub fn repos(&self) -> Value {
+        let data = self.metadata_file("repos.json").expect("no repos.json");
+        serde_json::from_str(&data).expect("parse repos.json")
+    }
+
+    /// Commits on the main branch of the bare repo `name`.
+    pub fn commits(&self, name: &str) -> usize {
+        let output = Command::new("git")
+            .arg("--git-dir")
+            .arg(self.repo(name))
+            .args(["rev-list", "--count", "HEAD"])
+            .output()
+            .expect("run git rev-list");
+        String::from_utf8_lossy(&output.stdout)
+            .trim()
+            .parse()
+            .unwrap()
+    }
+
+    /// Size of the blob `path` on the main branch of the storage repo `repo`.
+    pub fn blob_size(&self, repo: &str, path: &str) -> Option<u64> {
+        let output = Command::new("git")
+            .arg("--git-dir")
+            .arg(self.repo(repo))
+            .args(["cat-file", "-s", &format!("HEAD:{}", path)])
+            .output()
+            .expect("run git cat-file");
+        output.status.success().then(|| {
+            String::from_utf8_lossy(&output.stdout)
+                .trim()
+                .parse()
+                .unwrap()
+        })
+    }
+
+    /// Commits `tamper` of the chunk `path` to the storage repo `repo`, as
+    /// if GitHub lost or damaged it.
+    pub fn tamper(&self, repo: &str, path: &str, tamper: Tamper) {
+        let spec = format!("HEAD:{}", path);
+        let mut data = Command::new("git")
+            .arg("--git-dir")
+            .arg(self.repo(repo))
+            .args(["cat-file", "blob", &spec])
+            .output()
+            .expect("run git cat-file")
+            .stdout;
+        match tamper {
+            Tamper::Truncate => {
+                data.pop();
+            }
+            Tamper::Flip(at) if !data.is_empty() => {
+                let at = at % data.len();
+                data[at] = !data[at];
+            }
+            Tamper::Flip(_) => data.push(0),
+            Tamper::Remove => {}
+        }
+        let data = match tamper {
+            Tamper::Remove => None,
+            _ => Some(data),
+        };
+        self.commit_files(repo, &[(path, data)], "tamper");
+    }
+
+    /// Commits `files` to the main branch of the bare repo `repo`, each
+    /// (path, content) written, or removed when the content is none, as if
+    /// another client or an older gidrive pushed them.
+    pub fn commit_files(&self, repo: &str, files: &[(&str, Option<Vec<u8>>)], message: &str) {
+        let git_dir = self.repo(repo);
+        let index = self.root.join("tmp").join("commit-index");
+        let _ = fs::remove_file(&index);
+        // update-index wants a work tree, though it reads none here
+        let work_tree = self.root.join("tmp").join("commit-tree");
+        fs::create_dir_all(&work_tree).expect("create commit work tree");
+        let git = |args: &[&str], stdin: Option<&[u8]>| -> String {
+            let mut child = Command::new("git")
+                .arg("--git-dir")
+                .arg(&git_dir)
+                .arg("--work-tree")
+                .arg(&work_tree)
+                .args(args)
+                .env("GIT_INDEX_FILE", &index)
+                .env(
+                    "GIT_CONFIG_GLOBAL",
+                    self.root.join("home").join(".gitconfig"),
+                )
+                .stdin(std::process::Stdio::piped())
+                .stdout(std::process::Stdio::piped())
+                .spawn()
+                .expect("run git");
+            if let Some(data) = stdin {
+                use std::io::Write;
+                child.stdin.take().unwrap().write_all(data).unwrap();
+            }
+            let output = child.wait_with_output().expect("run git");
+            assert!(output.status.success(), "git {:?} failed", args);
+            String::from_utf8_lossy(&output.stdout).trim().to_string()
+        };
+        git(&["read-tree", "HEAD"], None);
+        for (path, data) in files {
+            match data {
+                Some(data) => {
+                    let blob = git(&["hash-object", "-w", "--stdin"], Some(data));
+                    let info = format!("100644,{},{}", blob, path);
+                    git(&["update-index", "--add", "--cacheinfo", &info], None);
+                }
+                None => {
+                    git(&["update-index", "--force-remove", path], None);
+                }
+            }
+        }
+        let tree = git(&["write-tree"], None);
+        let commit = git(&["commit-tree", &tree, "-p", "HEAD", "-m", message], None);
+        git(&["update-ref", "HEAD", &commit], None);
+    }
+}
+
+impl Drop for Drive {
+    fn drop(&mut self) {
+        if let Some(daemon) = &mut self.daemon {
+            let _ = daemon.kill();
+            let _ = daemon.wait();
+        }
+        let _ = fs::remove_dir_all(&self.root);
+    }
+}
+
+/// A `git daemon` serving the repos of `gh` for fetches and pushes, with the
+/// hooks `gitconfig` sets, and the port it listens on once it accepts.
+fn git_daemon(gh: &Path, gitconfig: &Path) -> (Child, u16) {
+    let port = TcpListener::bind("127.0.0.1:0")
+        .and_then(|listener| listener.local_addr())
+        .expect("find a free port")
+        .port();
+    // git-daemon itself, `git daemon` would leave it running when killed
+    let exec_path = Command::new("git")
+        .arg("--exec-path")
+        .output()
+        .expect("run git --exec-path")
+        .stdout;
+    let exec_path = PathBuf::from(String::from_utf8_lossy(&exec_path).trim());
+    let mut daemon = Command::new(exec_path.join("git-daemon"))
+        .args(["--reuseaddr", "--export-all", "--enable=receive-pack"])
+        .arg("--listen=127.0.0.1")
+        .arg(format!("--port={}", port))
+        .arg(format!("--base-path={}", gh.display()))
+        .arg(gh)
+        .env("GH_ROOT", gh)
+        .env("GIT_CONFIG_GLOBAL", gitconfig)
+        .env("GIT_CONFIG_NOSYSTEM", "1")
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .expect("run git daemon");
+    for _ in 0..100 {
+        if TcpStream::connect(("127.0.0.1", port)).is_ok() {
+            return (daemon, port);
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+    let _ = daemon.kill();
+    let _ = daemon.wait();
+    panic!("git daemon does not listen on port {}", port);
+}
+
+/// `size` bytes that differ between names and chunks, so no two chunks of
+/// fixtures dedupe by accident.
+pub fn fixture_bytes(name: &str, size: usize) -> Vec<u8> {
+    let mut state = name.bytes().fold(0xcbf2_9ce4_8422_2325u64, |h, b| {
+        (h ^ b as u64).wrapping_mul(0x100_0000_01b3)
+    });
+    (0..size)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state as u8
+        })
+        .collect()
+}
@@ -0,0 +1,151 @@
+//! Two clients writing the metadata at the same time: the second to push
+//! makes its change again on top of the first one's instead of failing.
+
+mod common;
+
+use common::{Drive, CHUNK_SIZE};
+use std::fs;
+
+#[test]
+fn metadata_commits_of_two_clients_are_both_kept() {
+    let drive = Drive::new();
+    let first = drive.fixture("first.bin", CHUNK_SIZE);
+    let second = drive.fixture("second.bin", 2 * CHUNK_SIZE);
+    drive.race_at_repo_create(&["upload", "second.bin", second.to_str().unwrap()]);
+    let output = drive.gidrive(&["upload", "first.bin", first.to_str().unwrap()]);
+    let err = String::from_utf8_lossy(&output.stderr);
+    assert!(output.status.success(), "{}", err);
+    assert_eq!(drive.race_status(), Some(0));
+    assert!(
+        err.contains("another client changed the metadata first"),
+        "{}",
+        err
+    );
+
+    for (name, local) in [("first.bin", &first), ("second.bin", &second)] {
+        let back = drive.files().join(format!("back-{}", name));
+        drive.ok(&["download", name, back.to_str().unwrap()]);
+        assert!(
+            fs::read(&back).unwrap() == fs::read(local).unwrap(),
+            "{}",
+            name
+        );
+    }
+    let log = drive.metadata_file("clients.log").unwrap();
+    assert!(log.contains("Add metadata for first.bin"), "{}", log);
+    assert!(log.contains("Add metadata for second.bin"), "{}", log);
+    let repos = drive.repos();
+    let stored: u64 = repos["repos"]
+        .as_object()
+        .unwrap()
+        .values()
+        .map(|repo| repo["current_size"].as_u64().unwrap())
+        .sum();
+    assert_eq!(stored, 3 * CHUNK_SIZE as u64);
+    drive.ok(&["fsck"]);
+}
+
+#[test]
+fn reservations_racing_for_the_same_repo_are_placed_again() {
+    let drive = Drive::empty();
+    drive.set("max_repo_size", &(4 * CHUNK_SIZE).to_string());
+    drive.ok(&["init"]);
+    let half = drive.fixture("half.bin", 2 * CHUNK_SIZE);
+    drive.ok(&["upload", "half.bin", half.to_str().unwrap()]);
+    // both plan the two free chunks of the first repo, the second also
+    // needs a new repo for the rest and lets the first push its reservation
+    let racer = drive.fixture("racer.bin", 2 * CHUNK_SIZE);
+    drive.race_at_repo_create(&["upload", "racer.bin", racer.to_str().unwrap()]);
+    let big = drive.fixture("big.bin", 4 * CHUNK_SIZE);
+    let output = drive.gidrive(&["upload", "big.bin", big.to_str().unwrap()]);
+    let err = String::from_utf8_lossy(&output.stderr);
+    assert!(output.status.success(), "{}", err);
+    assert_eq!(drive.race_status(), Some(0));
+    assert!(err.contains("placing its chunks again"), "{}", err);
+
+    let repos = drive.repos();
+    let sizes: Vec<u64> = repos["repos"]
+        .as_object()
+        .unwrap()
+        .values()
+        .map(|repo| repo["current_size"].as_u64().unwrap())
+        .collect();
+    assert_eq!(sizes, [4 * CHUNK_SIZE as u64; 2], "{}", repos);
+    for (name, local) in [
+        ("half.bin", &half),
+        ("racer.bin", &racer),
+        ("big.bin", &big),
+    ] {
+        let back = drive.files().join(format!("back-{}", name));
+        drive.ok(&["download", name, back.to_str().unwrap()]);
+        assert!(
+            fs::read(&back).unwrap() == fs::read(local).unwrap(),
+            "{}",
+            name
+        );
+    }
+    drive.ok(&["fsck"]);
+}
+
+#[test]
+fn the_replay_works_in_a_work_dir_with_a_space() {
+    let drive = Drive::new();
+    let first = drive.fixture("first.bin", CHUNK_SIZE);
+    let second = drive.fixture("second.bin", CHUNK_SIZE);
+    let work = drive.files().join("work dir");
+    drive.race_at_repo_create(&["upload", "second.bin", second.to_str().unwrap()]);
+    let output = drive
+        .command(&["upload", "first.bin", first.to_str().unwrap()])
+        .env("GIDRIVE_WORK_DIR", &work)
+        .output()
+        .expect("run gidrive");
+    let err = String::from_utf8_lossy(&output.stderr);
+    assert!(output.status.success(), "{}", err);
+    assert_eq!(drive.race_status(), Some(0));
+    assert!(
+        err.contains("another client changed the metadata first"),
+        "{}",
+        err
+    );
+    assert!(!err.contains("could not cache the metadata"), "{}", err);
+    let log = drive.metadata_file("clients.log").unwrap();
+    assert!(log.contains("Add metadata for first.bin"), "{}", log);
+    assert!(log.contains("Add metadata for second.bin"), "{}", log);
+    drive.ok(&["fsck"]);
+}
+
+#[test]
+fn an_append_beaten_by_a_change_of_the_file_leaves_its_chunks_to_gc() {
+    let drive = Drive::new();
+    drive.set("max_repo_size", &CHUNK_SIZE.to_string());
+    let file = drive.fixture("file.bin", CHUNK_SIZE);
+    let tail = drive.fixture("tail.bin", CHUNK_SIZE);
+    let other = drive.fixture("other.bin", CHUNK_SIZE);
+    drive.ok(&["upload", "file.bin", file.to_str().unwrap()]);
+    drive.race_at_repo_create(&["upload", "file.bin", other.to_str().unwrap()]);
+    let output = drive.gidrive(&["append", "file.bin", tail.to_str().unwrap()]);
+    let err = String::from_utf8_lossy(&output.stderr);
+    assert!(!output.status.success(), "{}", err);
+    assert_eq!(drive.race_status(), Some(0));
+    assert!(err.contains("the appended chunks wait for gc"), "{}", err);
+
+    let back = drive.files().join("back.bin");
+    drive.ok(&["download", "file.bin", back.to_str().unwrap()]);
+    assert!(fs::read(&back).unwrap() == fs::read(&other).unwrap());
+    // the chunk of the replaced file and the appended one
+    let pending: serde_json::Value =
+        serde_json::from_str(&drive.metadata_file("pending_delete.json").unwrap()).unwrap();
+    assert_eq!(pending.as_array().unwrap().len(), 2, "{}", pending);
+    let repos = drive.repos();
+    let sum = |field: &str| -> u64 {
+        repos["repos"]
+            .as_object()
+            .unwrap()
+            .values()
+            .map(|repo| repo[field].as_u64().unwrap())
+            .sum()
+    };
+    assert_eq!(sum("current_size"), 3 * CHUNK_SIZE as u64);
+    assert_eq!(sum("reclaimable"), 2 * CHUNK_SIZE as u64);
+    drive.ok(&["fsck"]);
+}
@@ -0,0 +1,56 @@
+//! Restore scripts of `export-script`, run with only sh, git and coreutils
+//! against the storage repos.
+
+mod common;
+
+use common::{Drive, CHUNK_SIZE};
+use std::fs;
+
+/// Writes the restore script of `remote` and runs it into `out`, returning
+/// whether it succeeded.
+fn restore(drive: &Drive, remote: &str, out: &str) -> bool {
+    let script = drive.ok(&["export-script", remote]);
+    let path = drive.files().join("restore.sh");
+    fs::write(&path, script).unwrap();
+    let output = drive.sh(&path, &[out]);
+    if !output.status.success() {
+        eprintln!("{}", String::from_utf8_lossy(&output.stderr));
+    }
+    output.status.success()
+}
+
+#[test]
+fn the_restore_script_rebuilds_the_uploaded_bytes() {
+    let drive = Drive::new();
+    drive.set("max_repo_size", &(2 * CHUNK_SIZE).to_string());
+    for (name, size) in [
+        ("many-repos.bin", 5 * CHUNK_SIZE + 17),
+        ("it's small.txt", 100),
+    ] {
+        let local = drive.fixture(name, size);
+        drive.ok(&["upload", &format!("dir/{}", name), local.to_str().unwrap()]);
+        let out = format!("restored-{}", name);
+        assert!(restore(&drive, &format!("dir/{}", name), &out), "{}", name);
+        assert_eq!(
+            fs::read(drive.files().join(&out)).unwrap(),
+            fs::read(&local).unwrap(),
+            "{}",
+            name
+        );
+    }
+}
+
+#[test]
+fn the_restore_script_refuses_a_damaged_chunk() {
+    let drive = Drive::new();
+    let local = drive.fixture("file.bin", 2 * CHUNK_SIZE);
+    let meta = drive.round_trip("file.bin", &local);
+    let chunk = &meta["chunks"][1];
+    drive.tamper(
+        chunk["repo"].as_str().unwrap(),
+        chunk["path"].as_str().unwrap(),
+        common::Tamper::Flip(3),
+    );
+    assert!(!restore(&drive, "file.bin", "restored.bin"));
+    assert!(!drive.files().join("restored.bin").exists());
+}
@@ -0,0 +1,136 @@
+//! `gidrive fetch` against a local HTTP server that asks for basic auth,
+//! redirects, honors Range requests and can drop a transfer halfway.
+
+mod common;
+
+use common::{fixture_bytes, Drive, CHUNK_SIZE};
+use sha2::{Digest, Sha256};
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+
+const AUTH: &str = "Basic dXNlcjpwQHNz"; // user:p@ss
+
+/// Serves `body` at /file.bin to user:p@ss, redirecting /old.bin there. The
+/// first `drops` responses of the file stop after half of what they promise.
+/// Returns the base URL and the count of Range requests.
+fn serve(body: Vec<u8>, drops: usize) -> (String, Arc<AtomicUsize>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let base = format!("http://{}", listener.local_addr().unwrap());
+    let ranges = Arc::new(AtomicUsize::new(0));
+    let counted = ranges.clone();
+    let drops = Arc::new(AtomicUsize::new(drops));
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let (body, ranges, drops) = (body.clone(), counted.clone(), drops.clone());
+            thread::spawn(move || respond(stream.unwrap(), &body, &ranges, &drops));
+        }
+    });
+    (base, ranges)
+}
+
+fn respond(mut stream: TcpStream, body: &[u8], ranges: &AtomicUsize, drops: &AtomicUsize) {
+    let mut reader = BufReader::new(stream.try_clone().unwrap());
+    let mut request = String::new();
+    reader.read_line(&mut request).unwrap();
+    let (mut auth, mut from) = (None, None);
+    loop {
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        let (name, value) = line.split_once(": ").unwrap();
+        match name.to_ascii_lowercase().as_str() {
+            "authorization" => auth = Some(value.to_string()),
+            "range" => {
+                let start = value.strip_prefix("bytes=").unwrap().trim_end_matches('-');
+                from = Some(start.parse::<usize>().unwrap());
+            }
+            _ => {}
+        }
+    }
+    let path = request.split(' ').nth(1).unwrap();
+    let head = if auth.as_deref() != Some(AUTH) {
+        "HTTP/1.1 401 Unauthorized\r\nContent-Length: 0\r\n".to_string()
+    } else if path == "/old.bin" {
+        "HTTP/1.1 302 Found\r\nLocation: /file.bin\r\nContent-Length: 0\r\n".to_string()
+    } else if path != "/file.bin" {
+        "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n".to_string()
+    } else {
+        let from = from.unwrap_or(0);
+        if from > 0 {
+            ranges.fetch_add(1, Ordering::SeqCst);
+        }
+        let rest = &body[from..];
+        let status = if from > 0 {
+            format!(
+                "206 Partial Content\r\nContent-Range: bytes {}-{}/{}",
+                from,
+                body.len() - 1,
+                body.len()
+            )
+        } else {
+            "200 OK".to_string()
+        };
+        let head = format!(
+            "HTTP/1.1 {}\r\nContent-Length: {}\r\nETag: \"v1\"\r\nConnection: close\r\n\r\n",
+            status,
+            rest.len()
+        );
+        stream.write_all(head.as_bytes()).unwrap();
+        let dropped = drops
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1))
+            .is_ok();
+        let sent = if dropped { rest.len() / 2 } else { rest.len() };
+        let _ = stream.write_all(&rest[..sent]);
+        return;
+    };
+    let _ = stream.write_all(format!("{}Connection: close\r\n\r\n", head).as_bytes());
+}
+
+#[test]
+fn fetch_follows_the_redirect_and_records_the_url_without_credentials() {
+    let drive = Drive::new();
+    let body = fixture_bytes("fetched.bin", 3 * CHUNK_SIZE + 17);
+    let (base, _) = serve(body.clone(), 0);
+    let url = base.replace("http://", "http://user:p%40ss@") + "/old.bin";
+    let sha = hex::encode(Sha256::digest(&body));
+    drive.ok(&["fetch", &url, "web/fetched.bin", "--expected-sha256", &sha]);
+    let meta = drive.file_metadata("web/fetched.bin");
+    assert_eq!(meta["source_url"], format!("{}/old.bin", base).as_str());
+    assert_eq!(meta["checksum"], sha.as_str());
+    let back = drive.files().join("back.bin");
+    drive.ok(&["download", "web/fetched.bin", back.to_str().unwrap()]);
+    assert!(std::fs::read(&back).unwrap() == body);
+}
+
+#[test]
+fn a_dropped_transfer_resumes_with_a_range_request() {
+    let drive = Drive::new();
+    let body = fixture_bytes("resumed.bin", 2 * CHUNK_SIZE + 5);
+    let (base, ranges) = serve(body.clone(), 1);
+    let url = base.replace("http://", "http://user:p%40ss@") + "/file.bin";
+    drive.ok(&["fetch", &url, "resumed.bin"]);
+    assert_eq!(ranges.load(Ordering::SeqCst), 1);
+    let back = drive.files().join("back.bin");
+    drive.ok(&["download", "resumed.bin", back.to_str().unwrap()]);
+    assert!(std::fs::read(&back).unwrap() == body);
+}
+
+#[test]
+fn a_checksum_mismatch_or_missing_credentials_record_nothing() {
+    let drive = Drive::new();
+    let (base, _) = serve(fixture_bytes("bad.bin", CHUNK_SIZE + 1), 0);
+    let url = base.replace("http://", "http://user:p%40ss@") + "/file.bin";
+    let wrong = "0".repeat(64);
+    let err = drive.fails(&["fetch", &url, "bad.bin", "--expected-sha256", &wrong]);
+    assert!(err.contains("not the expected"), "{}", err);
+    assert!(drive.metadata_file("fs/default/bad.bin.json").is_none());
+    let err = drive.fails(&["fetch", &format!("{}/file.bin", base), "bad.bin"]);
+    assert!(err.contains("401"), "{}", err);
+    assert!(drive.metadata_file("fs/default/bad.bin.json").is_none());
+}
@@ -0,0 +1,137 @@
+//! Commands of the `[hooks]` config around transfers: their arguments, the
+//! `GIDRIVE_HOOK_*` environment and what a failing one does.
+
+mod common;
+
+use common::Drive;
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::PathBuf;
+
+/// A hook script exiting with `code` that appends its arguments,
+/// `GIDRIVE_HOOK_*` environment and any credential variable, as `leak=`
+/// lines, to hooks.log, one block per run.
+fn hook_script(drive: &Drive, name: &str, code: i32) -> PathBuf {
+    let log = drive.files().join("hooks.log");
+    let script = drive.files().join(name);
+    fs::write(
+        &script,
+        format!(
+            "#!/bin/sh\n\
+             {{\n\
+             for arg in \"$@\"; do printf 'arg=%s\\n' \"$arg\"; done\n\
+             env | grep '^GIDRIVE_HOOK' | sort\n\
+             env | grep -e '^GH_TOKEN=' -e ASKPASS -e faketoken | sed 's/^/leak=/'\n\
+             echo ---\n\
+             }} >> '{}'\n\
+             exit {}\n",
+            log.display(),
+            code
+        ),
+    )
+    .unwrap();
+    fs::set_permissions(&script, fs::Permissions::from_mode(0o755)).unwrap();
+    script
+}
+
+/// The runs hooks.log recorded, each as its lines.
+fn runs(drive: &Drive) -> Vec<Vec<String>> {
+    let log = fs::read_to_string(drive.files().join("hooks.log")).unwrap_or_default();
+    log.split_terminator("---\n")
+        .map(|run| run.lines().map(str::to_string).collect())
+        .collect()
+}
+
+fn has(run: &[String], line: &str) -> bool {
+    run.iter().any(|l| l == line)
+}
+
+#[test]
+fn hooks_get_their_arguments_and_the_transfer_in_the_environment() {
+    let drive = Drive::new();
+    let script = hook_script(&drive, "hook.sh", 0);
+    let command = format!("{} one 'two words'", script.display());
+    drive.set("hooks.post_upload", &command);
+    drive.set("hooks.post_download", &command);
+    let local = drive.fixture("file.bin", 1000);
+    let sha = hex::encode(Sha256::digest(fs::read(&local).unwrap()));
+    drive.ok(&["upload", "dir/file.bin", local.to_str().unwrap()]);
+    let back = drive.files().join("back.bin");
+    drive.ok(&["download", "dir/file.bin", back.to_str().unwrap()]);
+
+    let runs = runs(&drive);
+    assert_eq!(runs.len(), 2, "{:?}", runs);
+    let (upload, download) = (&runs[0], &runs[1]);
+    for run in &runs {
+        assert_eq!(run[..2], ["arg=one", "arg=two words"], "{:?}", run);
+        assert!(has(run, "GIDRIVE_HOOK_REMOTE=dir/file.bin"), "{:?}", run);
+        assert!(has(run, "GIDRIVE_HOOK_SIZE=1000"), "{:?}", run);
+        assert!(
+            has(run, &format!("GIDRIVE_HOOK_CHECKSUM={}", sha)),
+            "{:?}",
+            run
+        );
+        assert!(run.iter().any(|l| l.starts_with("GIDRIVE_HOOK_DURATION=")));
+        assert!(!run.iter().any(|l| l.starts_with("GIDRIVE_HOOK_ERROR=")));
+        // the token of the drive is only handed to git and gh
+        assert!(!run.iter().any(|l| l.starts_with("leak=")), "{:?}", run);
+    }
+    assert!(has(upload, "GIDRIVE_HOOK=post_upload"), "{:?}", upload);
+    assert!(has(upload, "GIDRIVE_HOOK_OPERATION=upload"));
+    let local_line = format!("GIDRIVE_HOOK_LOCAL={}", local.display());
+    assert!(has(upload, &local_line), "{:?}", upload);
+    assert!(
+        has(download, "GIDRIVE_HOOK=post_download"),
+        "{:?}",
+        download
+    );
+    assert!(has(download, "GIDRIVE_HOOK_OPERATION=download"));
+    let back_line = format!("GIDRIVE_HOOK_LOCAL={}", back.display());
+    assert!(has(download, &back_line), "{:?}", download);
+}
+
+#[test]
+fn a_failing_hook_only_warns_unless_pre_upload_is_required() {
+    let drive = Drive::new();
+    let failing = hook_script(&drive, "failing.sh", 3);
+    let on_error = hook_script(&drive, "on-error.sh", 0);
+    drive.set("hooks.pre_upload", failing.to_str().unwrap());
+    drive.set("hooks.post_upload", failing.to_str().unwrap());
+    drive.set("hooks.on_error", on_error.to_str().unwrap());
+    let local = drive.fixture("file.bin", 100);
+
+    let output = drive.gidrive(&["upload", "warned.bin", local.to_str().unwrap()]);
+    assert!(output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("--- pre_upload hook failed"), "{}", stderr);
+    assert!(stderr.contains("--- post_upload hook failed"), "{}", stderr);
+    assert!(stderr.contains("exit status: 3"), "{}", stderr);
+    assert!(drive.metadata_file("fs/default/warned.bin.json").is_some());
+    assert_eq!(runs(&drive).len(), 2);
+
+    drive.set("hooks.pre_upload_required", "true");
+    let stderr = drive.fails(&["upload", "refused.bin", local.to_str().unwrap()]);
+    assert!(stderr.contains("pre_upload hook failed"), "{}", stderr);
+    assert!(drive.metadata_file("fs/default/refused.bin.json").is_none());
+    let runs = runs(&drive);
+    let on_error = runs.last().unwrap();
+    assert!(has(on_error, "GIDRIVE_HOOK=on_error"), "{:?}", runs);
+    assert!(has(on_error, "GIDRIVE_HOOK_REMOTE=refused.bin"));
+    let error = on_error
+        .iter()
+        .find_map(|l| l.strip_prefix("GIDRIVE_HOOK_ERROR="))
+        .expect("no GIDRIVE_HOOK_ERROR");
+    assert!(error.contains("pre_upload hook failed"), "{}", error);
+
+    drive.set("hooks.post_upload", "/nonexistent/hook");
+    drive.set("hooks.pre_upload_required", "false");
+    let output = drive.gidrive(&["upload", "missing.bin", local.to_str().unwrap()]);
+    assert!(output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("Failed to run /nonexistent/hook"),
+        "{}",
+        stderr
+    );
+}
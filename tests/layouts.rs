@@ -0,0 +1,135 @@
+//! Metadata repos as each gidrive version left them, under
+//! tests/fixtures/layouts: every file must still parse, and write back
+//! unchanged but for the defaults of the fields added since.
+
+use gidrive::metadata::{file_meta_relpath, shard_count};
+use gidrive::models::{FileMetadata, NamespaceStats, PendingDelete, ReposMetadata};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::fmt::Debug;
+use std::fs;
+use std::path::{Path, PathBuf};
+use walkdir::WalkDir;
+
+/// Fields added after the first format, as writing them with the value an
+/// older file implies by leaving them out spells them.
+const DEFAULTS: &[(&str, &str)] = &[
+    ("checksum_algo", "\"sha256\""),
+    ("public", "false"),
+    ("retired", "false"),
+    ("reclaimable", "0"),
+];
+
+fn layout(name: &str) -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/fixtures/layouts")
+        .join(name)
+}
+
+/// Parses `data` as a `T`, checks it reads the same once written back and
+/// returns the JSON it is written as.
+fn reserialize<T: Serialize + DeserializeOwned + PartialEq + Debug>(data: &str) -> String {
+    let parsed: T = serde_json::from_str(data).unwrap();
+    let written = serde_json::to_string_pretty(&parsed).unwrap();
+    assert_eq!(serde_json::from_str::<T>(&written).unwrap(), parsed);
+    written
+}
+
+/// Every JSON file of the layout `name`, with the JSON gidrive writes it
+/// back as.
+fn round_trips(name: &str) -> Vec<(PathBuf, String, String)> {
+    let root = layout(name);
+    let mut files = Vec::new();
+    for entry in WalkDir::new(&root).sort_by_file_name() {
+        let entry = entry.unwrap();
+        let rel = entry.path().strip_prefix(&root).unwrap().to_path_buf();
+        if !entry.file_type().is_file() || rel.extension().is_none_or(|e| e != "json") {
+            continue;
+        }
+        let data = fs::read_to_string(entry.path()).unwrap();
+        let written = match rel.to_str().unwrap() {
+            "repos.json" => reserialize::<ReposMetadata>(&data),
+            "pending_delete.json" => reserialize::<Vec<PendingDelete>>(&data),
+            "namespaces.json" => reserialize::<BTreeMap<String, NamespaceStats>>(&data),
+            _ if rel.starts_with("fs") => reserialize::<FileMetadata>(&data),
+            other => panic!("no type for {}", other),
+        };
+        files.push((rel, data, written));
+    }
+    assert!(!files.is_empty(), "no files in layout {}", name);
+    files
+}
+
+/// Asserts `written` holds everything `original` does, and otherwise only
+/// fields of DEFAULTS set to their default.
+fn assert_same_but_defaults(original: &Value, written: &Value, at: &str) {
+    match (original, written) {
+        (Value::Object(original), Value::Object(written)) => {
+            for (key, value) in original {
+                let field = format!("{}.{}", at, key);
+                let Some(back) = written.get(key) else {
+                    panic!("{} is dropped", field);
+                };
+                assert_same_but_defaults(value, back, &field);
+            }
+            for (key, value) in written {
+                if original.contains_key(key) {
+                    continue;
+                }
+                let default = DEFAULTS.iter().find(|(name, _)| name == key);
+                let Some((_, default)) = default else {
+                    panic!("{}.{} is added", at, key);
+                };
+                assert_eq!(value.to_string(), *default, "{}.{}", at, key);
+            }
+        }
+        (Value::Array(original), Value::Array(written)) => {
+            assert_eq!(original.len(), written.len(), "{}", at);
+            for (i, (value, back)) in original.iter().zip(written).enumerate() {
+                assert_same_but_defaults(value, back, &format!("{}[{}]", at, i));
+            }
+        }
+        _ => assert_eq!(original, written, "{}", at),
+    }
+}
+
+fn assert_older_layout(name: &str) {
+    for (rel, data, written) in round_trips(name) {
+        let original: Value = serde_json::from_str(&data).unwrap();
+        let written: Value = serde_json::from_str(&written).unwrap();
+        assert_same_but_defaults(&original, &written, &rel.display().to_string());
+    }
+}
+
+#[test]
+fn the_baseline_layout_round_trips() {
+    assert_older_layout("baseline");
+}
+
+#[test]
+fn the_layout_before_namespaces_round_trips() {
+    assert_older_layout("pre-namespace");
+}
+
+#[test]
+fn the_layout_before_owners_round_trips() {
+    assert_older_layout("pre-owner");
+}
+
+#[test]
+fn the_sharded_layout_round_trips_byte_for_byte() {
+    for (rel, data, written) in round_trips("sharded") {
+        assert_eq!(written, data.trim_end(), "{}", rel.display());
+    }
+}
+
+#[test]
+fn the_sharded_layout_is_where_gidrive_looks() {
+    let root = layout("sharded");
+    let shards = shard_count(&root).unwrap();
+    assert_eq!(shards, 64);
+    let rel = file_meta_relpath("docs/report.pdf", shards).unwrap();
+    assert!(root.join(&rel).is_file(), "{} is missing", rel.display());
+}
@@ -0,0 +1,280 @@
+//! The two `ls` formats pinned: the aligned table, and the porcelain lines
+//! scripts parse, which must not change from release to release; and the
+//! order and JSON of the listing.
+
+mod common;
+
+use common::{Drive, CHUNK_SIZE};
+use gidrive::api::{sort_entries, LsOptions, LsSort};
+use gidrive::listing::{porcelain_line, table};
+use gidrive::models::{ArchiveInfo, ChecksumAlgo, FileEntry};
+
+fn entry(path: &str, size: u64, chunks: usize, mtime: Option<u64>) -> FileEntry {
+    FileEntry {
+        path: path.to_string(),
+        size,
+        checksum: format!("{:064x}", size),
+        checksum_algo: ChecksumAlgo::Sha256,
+        mtime,
+        chunks,
+        archive: None,
+        symlink_target: None,
+        source_url: None,
+    }
+}
+
+fn entries() -> Vec<FileEntry> {
+    let mut archive = entry("backups/home.tar.zst", 5 << 20, 3, Some(1714564800));
+    archive.archive = Some(ArchiveInfo {
+        format: "tar+zstd".to_string(),
+        entries: 12,
+        bytes: None,
+    });
+    let mut link = entry("latest", 0, 0, Some(1714568400));
+    link.symlink_target = Some("backups/home.tar.zst".to_string());
+    let mut odd = entry("odd\tname\\x.bin", 7, 1, None);
+    odd.checksum_algo = ChecksumAlgo::Blake3;
+    vec![
+        archive,
+        link,
+        entry("notes.txt", 1536, 1, Some(1717200000)),
+        odd,
+    ]
+}
+
+#[test]
+fn the_table_is_aligned_after_all_rows_are_known() {
+    let expected = [
+        "PATH                                SIZE  CHUNKS  MODIFIED",
+        "backups/home.tar.zst            5.00 MiB       3  2024-05-01 12:00:00Z  tar+zstd archive, 12 entries",
+        "latest -> backups/home.tar.zst         -       -  2024-05-01 13:00:00Z",
+        "notes.txt                       1.50 KiB       1  2024-06-01 00:00:00Z",
+        "odd\tname\\x.bin                       7 B       1  -",
+    ];
+    assert_eq!(table(&entries()), expected);
+    let expected = ["PATH   SIZE  CHUNKS", "a.bin   7 B       1"];
+    assert_eq!(table(&[entry("a.bin", 7, 1, None)]), expected);
+}
+
+#[test]
+fn the_porcelain_format_does_not_change() {
+    let lines: Vec<String> = entries().iter().map(porcelain_line).collect();
+    let expected = [
+        format!(
+            "archive\t5242880\t3\t1714564800\tsha256\t{:064x}\tbackups/home.tar.zst\t",
+            5 << 20
+        ),
+        format!(
+            "symlink\t0\t0\t1714568400\tsha256\t{:064x}\tlatest\tbackups/home.tar.zst",
+            0
+        ),
+        format!(
+            "file\t1536\t1\t1717200000\tsha256\t{:064x}\tnotes.txt\t",
+            1536
+        ),
+        format!("file\t7\t1\t-\tblake3\t{:064x}\todd\\tname\\\\x.bin\t", 7),
+    ];
+    assert_eq!(lines, expected);
+    for line in &lines {
+        assert_eq!(line.split('\t').count(), 8, "{}", line);
+    }
+}
+
+/// The paths of `files` in the order `sort_entries` leaves them.
+fn sorted(files: &[FileEntry], opts: &LsOptions) -> Vec<String> {
+    let mut files = files.to_vec();
+    sort_entries(&mut files, opts);
+    files.into_iter().map(|entry| entry.path).collect()
+}
+
+#[test]
+fn ls_orders_by_the_sort_key_then_by_path() {
+    let files = [
+        entry("b.txt", 10, 1, Some(3)),
+        entry("a/z.txt", 10, 1, None),
+        entry("a.txt", 5, 1, Some(1)),
+        entry("a/b/c.txt", 30, 1, Some(3)),
+        entry("c/d.txt", 5, 1, None),
+    ];
+    let by = |sort, reverse, group_dirs| LsOptions {
+        sort,
+        reverse,
+        group_dirs,
+        ..Default::default()
+    };
+    assert_eq!(
+        sorted(&files, &by(LsSort::Name, false, false)),
+        ["a.txt", "a/b/c.txt", "a/z.txt", "b.txt", "c/d.txt"]
+    );
+    assert_eq!(
+        sorted(&files, &by(LsSort::Name, false, true)),
+        ["a/b/c.txt", "a/z.txt", "c/d.txt", "a.txt", "b.txt"]
+    );
+    assert_eq!(
+        sorted(&files, &by(LsSort::Size, false, false)),
+        ["a.txt", "c/d.txt", "a/z.txt", "b.txt", "a/b/c.txt"]
+    );
+    assert_eq!(
+        sorted(&files, &by(LsSort::Size, true, false)),
+        ["a/b/c.txt", "b.txt", "a/z.txt", "c/d.txt", "a.txt"]
+    );
+    assert_eq!(
+        sorted(&files, &by(LsSort::Mtime, false, false)),
+        ["a/z.txt", "c/d.txt", "a.txt", "a/b/c.txt", "b.txt"]
+    );
+    assert_eq!(
+        sorted(&files, &by(LsSort::Name, true, true)),
+        ["b.txt", "a.txt", "c/d.txt", "a/z.txt", "a/b/c.txt"]
+    );
+}
+
+#[test]
+fn ls_prints_the_table_and_the_porcelain_lines_of_the_drive() {
+    let drive = Drive::new();
+    let local = drive.fixture("big.bin", 2 * CHUNK_SIZE + 1);
+    drive.ok(&["upload", "big.bin", local.to_str().unwrap()]);
+    let meta = drive.file_metadata("big.bin");
+    let out = drive.ok(&["ls"]);
+    let lines: Vec<&str> = out.lines().collect();
+    assert_eq!(lines.len(), 2, "{}", out);
+    assert!(lines[0].starts_with("PATH"), "{}", out);
+    assert!(lines[1].starts_with("big.bin"), "{}", out);
+    let porcelain = drive.ok(&["ls", "--porcelain"]);
+    let expected = format!(
+        "file\t{}\t3\t{}\tsha256\t{}\tbig.bin\t\n",
+        2 * CHUNK_SIZE + 1,
+        meta["mtime"],
+        meta["checksum"].as_str().unwrap()
+    );
+    assert_eq!(porcelain, expected);
+}
+
+#[test]
+fn a_corrupt_metadata_file_is_skipped_failed_on_with_strict_and_reported_by_fsck() {
+    let drive = Drive::new();
+    let local = drive.fixture("good.bin", 100);
+    drive.ok(&["upload", "good.bin", local.to_str().unwrap()]);
+    let bad = "fs/default/bad.bin.json";
+    drive.commit_files(
+        "metadata",
+        &[(bad, Some(b"{not json".to_vec()))],
+        "poison bad.bin",
+    );
+
+    let output = drive.gidrive(&["ls", "--porcelain"]);
+    assert!(output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert_eq!(stdout.lines().count(), 1, "{}", stdout);
+    assert!(stdout.contains("\tgood.bin\t"), "{}", stdout);
+    assert!(
+        stderr.contains("--- skipping corrupt metadata") && stderr.contains(bad),
+        "{}",
+        stderr
+    );
+
+    let stderr = drive.fails(&["ls", "--strict"]);
+    assert!(stderr.contains("corrupt metadata"), "{}", stderr);
+    assert!(stderr.contains(bad), "{}", stderr);
+
+    let output = drive.gidrive(&["fsck"]);
+    assert!(!output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains(&format!("corrupt {}", bad)), "{}", stdout);
+    assert!(
+        stdout.contains("1 files ok, 1 corrupt metadata files"),
+        "{}",
+        stdout
+    );
+}
+
+#[test]
+fn ls_json_is_an_array_of_the_entries_in_the_asked_order() {
+    let drive = Drive::new();
+    for (remote, size) in [("b.bin", 300), ("dir/a.bin", 100), ("c.bin", 200)] {
+        let local = drive.fixture(remote.rsplit('/').next().unwrap(), size);
+        drive.ok(&["upload", remote, local.to_str().unwrap()]);
+    }
+    let entries = |args: &[&str]| -> Vec<FileEntry> {
+        let out = drive.ok(&[&["ls", "--json"], args].concat());
+        serde_json::from_str(&out).unwrap_or_else(|e| panic!("{}: {}", e, out))
+    };
+    let paths = |entries: Vec<FileEntry>| -> Vec<String> {
+        entries.into_iter().map(|entry| entry.path).collect()
+    };
+
+    let listed = entries(&[]);
+    assert_eq!(listed.len(), 3);
+    let a = listed
+        .iter()
+        .find(|entry| entry.path == "dir/a.bin")
+        .unwrap();
+    assert_eq!((a.size, a.chunks), (100, 1));
+    assert_eq!(a.checksum, drive.file_metadata("dir/a.bin")["checksum"]);
+    assert_eq!(paths(listed), ["b.bin", "c.bin", "dir/a.bin"]);
+    assert_eq!(
+        paths(entries(&["--group-dirs"])),
+        ["dir/a.bin", "b.bin", "c.bin"]
+    );
+    assert_eq!(
+        paths(entries(&["--sort", "size"])),
+        ["dir/a.bin", "c.bin", "b.bin"]
+    );
+    assert_eq!(
+        paths(entries(&["--sort", "size", "--reverse"])),
+        ["b.bin", "c.bin", "dir/a.bin"]
+    );
+}
+
+#[test]
+fn remote_paths_complete_from_the_metadata_cache() {
+    let drive = Drive::new();
+    let local = drive.fixture("file.bin", 100);
+    for remote in [
+        "photos/a.jpg",
+        "photos/b c.jpg",
+        "photos/été.jpg",
+        "notes.txt",
+    ] {
+        drive.ok(&["upload", remote, local.to_str().unwrap()]);
+    }
+    // the cache holds the metadata as of the last clone
+    drive.ok(&["ls"]);
+    // GitHub gone, the cache answers
+    std::fs::remove_dir_all(drive.repo("metadata")).unwrap();
+    let listed = drive.ok(&["__complete-remote", "photos/"]);
+    assert_eq!(
+        listed.lines().collect::<Vec<_>>(),
+        ["photos/a.jpg", "photos/b c.jpg", "photos/été.jpg"]
+    );
+
+    let script = drive.ok(&["completions", "bash"]);
+    let bin = std::path::Path::new(env!("CARGO_BIN_EXE_gidrive"))
+        .parent()
+        .unwrap();
+    let output = drive
+        .command(&[])
+        .get_envs()
+        .fold(std::process::Command::new("bash"), |mut bash, (key, value)| {
+            match value {
+                Some(value) => bash.env(key, value),
+                None => bash.env_remove(key),
+            };
+            bash
+        })
+        .env("PATH", format!("{}:{}", bin.display(), std::env::var("PATH").unwrap()))
+        .arg("-c")
+        .arg(format!(
+            "{}\nCOMP_WORDS=(gidrive rm no); COMP_CWORD=2\n_gidrive_remote\nprintf '%s\\n' \"${{COMPREPLY[@]}}\"",
+            script
+        ))
+        .output()
+        .expect("run bash");
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert_eq!(
+        stdout,
+        "notes.txt\n",
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+}
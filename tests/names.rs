@@ -0,0 +1,91 @@
+//! Remote names meant to break out of a command line or the terminal: each
+//! upload either round-trips or fails with an error, and runs nothing.
+
+mod common;
+
+use common::Drive;
+use std::fs;
+use std::process::Command;
+
+/// Subjects and bodies of the metadata commits, newest first.
+fn metadata_log(drive: &Drive) -> Vec<(String, String)> {
+    let output = Command::new("git")
+        .arg("--git-dir")
+        .arg(drive.repo("metadata"))
+        .args(["log", "--format=%s%x00%b%x00"])
+        .output()
+        .expect("run git log");
+    assert!(output.status.success());
+    let log = String::from_utf8(output.stdout).unwrap();
+    let mut fields = log.split('\0').map(|field| field.trim().to_string());
+    let mut commits = Vec::new();
+    while let (Some(subject), Some(body)) = (fields.next(), fields.next()) {
+        commits.push((subject, body));
+    }
+    commits
+}
+
+#[test]
+fn shell_syntax_in_remote_names_reaches_git_verbatim() {
+    let drive = Drive::new();
+    let home = drive.files().parent().unwrap().join("home");
+    let canary = home.join("canary");
+    fs::write(&canary, "still here").unwrap();
+    let local = drive.fixture("file.bin", 100);
+    for remote in [
+        "\"; rm -rf ~;",
+        "$(touch pwned)",
+        "`touch pwned`.bin",
+        "it's 'quoted' \\ too",
+        "dir; touch pwned/x.bin",
+    ] {
+        drive.round_trip(remote, &local);
+        let (subject, body) = &metadata_log(&drive)[0];
+        assert_eq!(subject, "Add metadata for 1 file");
+        assert_eq!(body, remote);
+    }
+    assert!(canary.exists(), "~ was removed");
+    assert!(!drive.files().join("pwned").exists(), "a command ran");
+    assert!(!home.join("pwned").exists(), "a command ran");
+}
+
+#[test]
+fn a_10_kb_remote_name_fails_before_anything_is_pushed() {
+    let drive = Drive::new();
+    let local = drive.fixture("file.bin", 100);
+    let commits = drive.commits("metadata");
+    let long = "x".repeat(10 * 1024);
+    let stderr = drive.fails(&["upload", &long, local.to_str().unwrap()]);
+    assert!(stderr.contains("invalid remote name"), "{}", stderr);
+    assert!(stderr.contains("limited to 250 bytes"), "{}", stderr);
+    assert!(stderr.len() < 2048, "the name is echoed whole: {}", stderr);
+    assert_eq!(drive.commits("metadata"), commits);
+
+    // as long in parts a file name can hold, it is only the commit message
+    // that is cut
+    let nested = vec!["y".repeat(200); 10].join("/");
+    drive.round_trip(&nested, &local);
+    let (_, body) = &metadata_log(&drive)[0];
+    assert!(body.chars().count() < 300, "{}", body);
+    assert!(body.ends_with("..."), "{}", body);
+}
+
+#[test]
+fn bidi_overrides_and_control_characters_stay_out_of_the_commit_messages() {
+    let drive = Drive::new();
+    let local = drive.fixture("file.bin", 100);
+    let remote = "invoice\u{202e}fdp.exe\u{2066}\tnew\nline";
+    let meta = drive.round_trip(remote, &local);
+    assert_eq!(meta["size"], 100);
+    let listed = drive.ok(&["ls", "--json"]);
+    let listed: serde_json::Value = serde_json::from_str(&listed).unwrap();
+    assert_eq!(listed[0]["path"], remote);
+    let (_, body) = &metadata_log(&drive)[0];
+    assert_eq!(body, "invoice?fdp.exe? new line");
+    let clients = drive.metadata_file("clients.log").unwrap();
+    assert!(
+        clients.contains("Add metadata for invoice?fdp.exe? new line"),
+        "{}",
+        clients
+    );
+}
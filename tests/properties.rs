@@ -0,0 +1,370 @@
+//! Properties of chunking, planning and reassembly over generated inputs:
+//! the planner accounts for every byte within the repo limits, split chunks
+//! add up to the input, a short staged chunk is caught before it is written,
+//! git progress is read in any wording, and a download either returns the
+//! uploaded bytes or fails, whatever pushes fail or chunks GitHub damages on
+//! the way.
+//! Cases that run gidrive are few by default, `PROPTEST_CASES=500 cargo
+//! test --release --test properties` runs a long session.
+
+mod common;
+
+use common::{fixture_bytes, Drive, Tamper};
+use gidrive::chunks::{copy_staged, split_into_chunks, ShortChunk};
+use gidrive::config::{AccountPolicy, PlacementStrategy};
+use gidrive::git::parse_progress;
+use gidrive::metadata::{plan_upload, PlanOptions, StorageExhausted};
+use gidrive::models::{ChecksumAlgo, ChunkInfo, RepoInfo, ReposMetadata};
+use gidrive::utils::{checksum_hex, Hasher};
+use proptest::prelude::*;
+use proptest::test_runner::FileFailurePersistence;
+use std::collections::BTreeMap;
+use std::fs;
+
+/// `cases` cases, or as many as `PROPTEST_CASES` says.
+fn cases(cases: u32) -> ProptestConfig {
+    let cases = std::env::var("PROPTEST_CASES")
+        .ok()
+        .and_then(|n| n.parse().ok())
+        .unwrap_or(cases);
+    ProptestConfig {
+        cases,
+        failure_persistence: Some(Box::new(FileFailurePersistence::Direct(
+            "tests/properties.proptest-regressions",
+        ))),
+        ..ProptestConfig::default()
+    }
+}
+
+fn strategy() -> impl Strategy<Value = PlacementStrategy> {
+    prop_oneof![
+        Just(PlacementStrategy::BestFit),
+        Just(PlacementStrategy::Striped)
+    ]
+}
+
+/// Chunk size, file size of up to 200 chunks, and the size of a repo.
+fn sizes() -> impl Strategy<Value = (u64, u64, u64)> {
+    (1u64..1 << 20).prop_flat_map(|chunk_size| {
+        (
+            Just(chunk_size),
+            (0u64..200, 0..chunk_size).prop_map(move |(full, tail)| full * chunk_size + tail),
+            chunk_size..=chunk_size * 40,
+        )
+    })
+}
+
+/// Repos holding up to `max_repo_size` each, public, retired or not.
+fn repos(max_repo_size: u64) -> impl Strategy<Value = ReposMetadata> {
+    prop::collection::vec((0..=max_repo_size, any::<bool>(), any::<bool>()), 0..6).prop_map(
+        |repos| ReposMetadata {
+            next_id: 0,
+            repos: repos
+                .into_iter()
+                .enumerate()
+                .map(|(i, (current_size, public, retired))| {
+                    let name = format!("storage-{:08x}", i);
+                    let repo = RepoInfo {
+                        name: name.clone(),
+                        current_size,
+                        public,
+                        retired,
+                        reclaimable: 0,
+                        owner: Some("tester".to_string()),
+                    };
+                    (name, repo)
+                })
+                .collect(),
+        },
+    )
+}
+
+proptest! {
+    #![proptest_config(cases(256))]
+
+    #[test]
+    fn plans_account_for_every_byte_within_the_repo_limits(
+        ((chunk_size, file_size, max_repo_size), existing) in sizes()
+            .prop_flat_map(|sizes| (Just(sizes), repos(sizes.2))),
+        strategy in strategy(),
+        public in any::<bool>(),
+        max_repos in prop::option::of(1usize..12),
+        seed in any::<u64>(),
+    ) {
+        let opts = PlanOptions {
+            chunk_size,
+            max_repo_size,
+            repo_prefix: "storage-".to_string(),
+            public,
+            strategy,
+            seed,
+            accounts: vec!["tester".to_string()],
+            account_policy: AccountPolicy::RoundRobin,
+            max_repos,
+        };
+        let plan = match plan_upload(file_size, &existing, &opts) {
+            Ok(plan) => plan,
+            Err(e) => {
+                prop_assert!(max_repos.is_some(), "no limit on repos, yet {:#}", e);
+                prop_assert!(e.is::<StorageExhausted>(), "{:#}", e);
+                return Ok(());
+            }
+        };
+        let mut assigned: BTreeMap<&str, u64> = BTreeMap::new();
+        let mut total = 0;
+        for (i, (index, repo, size)) in plan.assignments.iter().enumerate() {
+            prop_assert_eq!(*index, i);
+            prop_assert_eq!(*size, chunk_size.min(file_size - total));
+            total += size;
+            *assigned.entry(repo.as_str()).or_default() += size;
+        }
+        prop_assert_eq!(total, file_size);
+        for (name, repo) in &plan.repos.repos {
+            let before = existing.repos.get(name).map_or(0, |r| r.current_size);
+            let added = assigned.get(name.as_str()).copied().unwrap_or(0);
+            prop_assert_eq!(repo.current_size, before + added);
+            if added > 0 {
+                prop_assert!(!repo.retired, "{} is retired", name);
+                prop_assert_eq!(repo.public, public);
+                prop_assert!(repo.current_size <= max_repo_size, "{} overflows", name);
+            }
+        }
+        for name in &plan.new_repos {
+            prop_assert!(!existing.repos.contains_key(name));
+            prop_assert!(assigned.contains_key(name.as_str()), "{} is empty", name);
+        }
+        if let Some(max) = max_repos {
+            prop_assert!(plan.new_repos.is_empty() || plan.repos.repos.len() <= max);
+        }
+    }
+
+    #[test]
+    fn split_chunks_add_up_to_the_input(
+        (seed, len) in (any::<u64>(), 0..200_000usize),
+        cuts in prop::collection::vec(any::<prop::sample::Index>(), 0..20),
+        blake3 in any::<bool>(),
+    ) {
+        let data = fixture_bytes(&seed.to_string(), len);
+        let algo = if blake3 { ChecksumAlgo::Blake3 } else { ChecksumAlgo::Sha256 };
+        let mut bounds: Vec<usize> = cuts.iter().map(|cut| cut.index(data.len() + 1)).collect();
+        bounds.extend([0, data.len()]);
+        bounds.sort_unstable();
+        bounds.dedup();
+        let chunk_sizes: Vec<u64> = bounds.windows(2).map(|w| (w[1] - w[0]) as u64).collect();
+        let dir = std::env::temp_dir().join(format!(
+            "gidrive-split-{}-{:?}",
+            std::process::id(),
+            std::thread::current().id()
+        ));
+        fs::create_dir_all(&dir).unwrap();
+        let mut whole = Hasher::new(algo);
+        let mut chunks = Vec::new();
+        split_into_chunks(&mut data.as_slice(), &chunk_sizes, &dir, algo, &mut whole, |chunk| {
+            chunks.push((chunk.index, fs::read(&chunk.path)?, chunk.checksum));
+            Ok(())
+        })
+        .unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        prop_assert_eq!(whole.finalize_hex(), checksum_hex(algo, &data));
+        prop_assert_eq!(chunks.len(), chunk_sizes.len());
+        for (i, (index, content, checksum)) in chunks.iter().enumerate() {
+            prop_assert_eq!(*index, i);
+            let expected = &data[bounds[i]..bounds[i + 1]];
+            prop_assert_eq!(content.as_slice(), expected);
+            prop_assert_eq!(checksum, &checksum_hex(algo, expected));
+        }
+    }
+
+    #[test]
+    fn git_progress_is_read_in_any_wording(
+        phase in "[A-Z][a-z]{1,9}( [a-z]{1,9})?",
+        (done, total) in (0u64..1 << 40).prop_flat_map(|total| (0..=total, Just(total))),
+        remote in any::<bool>(),
+        end in prop_oneof![Just("\r"), Just("\n")],
+        other in "\\PC*",
+    ) {
+        let prefix = if remote { "remote: " } else { "" };
+        let percent = (done * 100).checked_div(total).unwrap_or(100);
+        let line = format!(
+            "{}{}: {:3}% ({}/{}), 1.50 MiB | 2.00 MiB/s{}",
+            prefix, phase, percent, done, total, end
+        );
+        let progress = parse_progress(&line);
+        prop_assert!(progress.is_some(), "{:?}", line);
+        let progress = progress.unwrap();
+        prop_assert_eq!(&progress.phase, &phase);
+        prop_assert_eq!((progress.done, progress.total), (Some(done), Some(total)));
+        prop_assert_eq!(progress.bytes, Some(3 << 19));
+        let counted = parse_progress(&format!("{}{}: {}, done.\n", prefix, phase, done));
+        prop_assert_eq!(counted.map(|p| (p.done, p.total)), Some((Some(done), None)));
+
+        // a line git redraws is progress whatever it says, others are not
+        let redrawn = parse_progress(&format!("{}{}\r", prefix, other));
+        prop_assert_eq!(redrawn.is_some(), !other.trim().is_empty());
+        for message in [
+            format!("fatal: {}\n", other),
+            format!("ERROR: Repository {} not found.\n", other),
+            format!("To github.com:tester/{}.git\n", other),
+        ] {
+            prop_assert!(parse_progress(&message).is_none(), "{:?}", message);
+        }
+    }
+
+    #[test]
+    fn a_short_staged_chunk_is_named_before_anything_is_written(
+        (len, cut) in (1..100_000usize).prop_flat_map(|len| (Just(len), 0..len)),
+        index in 0..10_000usize,
+        repo in 0..1000u32,
+    ) {
+        let data = fixture_bytes(&len.to_string(), len);
+        let chunk = ChunkInfo {
+            repo: format!("storage-{:08x}", repo),
+            path: "chunk".to_string(),
+            size: len as u64,
+            index,
+            offset: 0,
+            checksum: None,
+            owner: None,
+        };
+        let path = std::env::temp_dir().join(format!(
+            "gidrive-staged-{}-{:?}",
+            std::process::id(),
+            std::thread::current().id()
+        ));
+        fs::write(&path, &data).unwrap();
+        let mut output = Vec::new();
+        prop_assert_eq!(copy_staged(&path, &chunk, &mut output).unwrap(), len as u64);
+        prop_assert!(output == data);
+
+        fs::write(&path, &data[..cut]).unwrap();
+        let mut output = Vec::new();
+        let err = copy_staged(&path, &chunk, &mut output).unwrap_err();
+        fs::remove_file(&path).unwrap();
+        prop_assert!(output.is_empty(), "wrote {} bytes", output.len());
+        let short = err.downcast_ref::<ShortChunk>();
+        prop_assert!(short.is_some(), "{:#}", err);
+        prop_assert_eq!(short.unwrap().staged, cut as u64);
+        let named = format!("chunk {} ({})", index, chunk.repo);
+        prop_assert!(err.to_string().starts_with(&named), "{:#}", err);
+    }
+}
+
+/// Chunk size of the drives below, small for files of many chunks.
+const CHUNK: usize = 4096;
+
+/// A drive of `CHUNK` chunks, `max_repo_chunks` to a repo, pushing
+/// `batch` chunks at once with `placement`, one transfer at a time so the
+/// pushes fail in a known order.
+fn drive(placement: PlacementStrategy, max_repo_chunks: usize, batch: usize) -> Drive {
+    let drive = Drive::empty();
+    drive.set("chunk_size", &CHUNK.to_string());
+    drive.set("max_repo_size", &(CHUNK * max_repo_chunks).to_string());
+    drive.set("push_batch_chunks", &batch.to_string());
+    drive.set("transfer_concurrency", "1");
+    let placement = match placement {
+        PlacementStrategy::BestFit => "best-fit",
+        PlacementStrategy::Striped => "striped",
+    };
+    drive.set("placement", placement);
+    drive.set("retry.push.base_delay", "0s");
+    drive.set("retry.push.jitter", "0");
+    drive.ok(&["init"]);
+    drive
+}
+
+/// Sizes of files up to 12 chunks, around the chunk boundaries.
+fn file_size() -> impl Strategy<Value = usize> {
+    prop_oneof![
+        Just(0),
+        1..CHUNK * 12,
+        (1..=12usize, -1..=1isize).prop_map(|(n, d)| (n * CHUNK).saturating_add_signed(d)),
+    ]
+}
+
+proptest! {
+    #![proptest_config(cases(4))]
+
+    #[test]
+    fn pushes_failing_then_passing_are_retried_to_the_same_bytes(
+        size in file_size(),
+        placement in strategy(),
+        max_repo_chunks in 1..6usize,
+        batch in 1..4usize,
+        pass in 0..6usize,
+        refuse in 0..3usize,
+    ) {
+        let drive = drive(placement, max_repo_chunks, batch);
+        drive.set("retry.push.max_attempts", &(refuse + 1).to_string());
+        drive.fail_pushes(pass, refuse);
+        let local = drive.fixture("file.bin", size);
+        let meta = drive.round_trip("file.bin", &local);
+        prop_assert_eq!(meta["size"].as_u64(), Some(size as u64));
+    }
+
+    #[test]
+    fn a_failed_upload_leaves_no_file_and_can_be_repeated(
+        size in file_size(),
+        placement in strategy(),
+        max_repo_chunks in 1..6usize,
+        batch in 1..4usize,
+        pass in 0..8usize,
+    ) {
+        let drive = drive(placement, max_repo_chunks, batch);
+        drive.set("retry.push.max_attempts", "1");
+        drive.fail_pushes(pass, 1);
+        let local = drive.fixture("file.bin", size);
+        let uploaded = drive.gidrive(&["upload", "file.bin", local.to_str().unwrap()]);
+        drive.heal_pushes();
+        if !uploaded.status.success() {
+            prop_assert!(drive.metadata_file("fs/default/file.bin.json").is_none());
+        }
+        drive.round_trip("file.bin", &local);
+    }
+
+    #[test]
+    fn an_upload_killed_after_any_batch_resumes_to_the_same_bytes(
+        size in file_size(),
+        placement in strategy(),
+        max_repo_chunks in 1..6usize,
+        batch in 1..4usize,
+        pass in 0..8usize,
+    ) {
+        let drive = drive(placement, max_repo_chunks, batch);
+        drive.crash_at_push(pass);
+        let local = drive.fixture("file.bin", size);
+        let uploaded = drive.gidrive(&["upload", "file.bin", local.to_str().unwrap()]);
+        drive.heal_pushes();
+        if !uploaded.status.success() {
+            drive.ok(&["resume"]);
+        }
+        let back = drive.files().join("back.bin");
+        drive.ok(&["download", "file.bin", back.to_str().unwrap()]);
+        prop_assert!(fs::read(&back).unwrap() == fixture_bytes("file.bin", size));
+    }
+
+    #[test]
+    fn a_damaged_chunk_fails_the_download_without_a_short_file(
+        size in 1..CHUNK * 8,
+        placement in strategy(),
+        chunk in any::<prop::sample::Index>(),
+        tamper in prop_oneof![
+            Just(Tamper::Truncate),
+            any::<usize>().prop_map(Tamper::Flip),
+            Just(Tamper::Remove),
+        ],
+    ) {
+        let drive = drive(placement, 3, 2);
+        let local = drive.fixture("file.bin", size);
+        let meta = drive.round_trip("file.bin", &local);
+        let chunks = meta["chunks"].as_array().unwrap();
+        let damaged = &chunks[chunk.index(chunks.len())];
+        drive.tamper(
+            damaged["repo"].as_str().unwrap(),
+            damaged["path"].as_str().unwrap(),
+            tamper,
+        );
+        let back = drive.files().join("back.bin");
+        drive.fails(&["download", "file.bin", back.to_str().unwrap()]);
+        prop_assert!(!back.exists(), "a failed download left {}", back.display());
+    }
+}
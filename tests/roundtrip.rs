@@ -0,0 +1,552 @@
+//! Uploads and downloads against bare repos standing in for GitHub,
+//! checking the bytes, the file metadata and the accounting of repos.json.
+
+mod common;
+
+use common::{Drive, CHUNK_SIZE};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use std::fs;
+
+/// Checks that the metadata of a file of `size` bytes has the chunks it
+/// should, each stored at its size in its repo, and the file's checksum.
+fn assert_chunked(drive: &Drive, meta: &Value, local: &std::path::Path, size: usize) {
+    assert_eq!(meta["size"], size as u64);
+    let sha = hex::encode(Sha256::digest(fs::read(local).unwrap()));
+    assert_eq!(meta["checksum"], sha.as_str());
+    let chunks = meta["chunks"].as_array().unwrap();
+    assert_eq!(chunks.len(), size.div_ceil(CHUNK_SIZE));
+    let mut total = 0;
+    for (i, chunk) in chunks.iter().enumerate() {
+        assert_eq!(chunk["index"], i as u64);
+        let chunk_size = chunk["size"].as_u64().unwrap();
+        assert_eq!(chunk_size, CHUNK_SIZE.min(size - total) as u64);
+        let stored = drive.blob_size(
+            chunk["repo"].as_str().unwrap(),
+            chunk["path"].as_str().unwrap(),
+        );
+        assert_eq!(stored, Some(chunk_size), "chunk {} is not stored", i);
+        total += chunk_size as usize;
+    }
+}
+
+#[test]
+fn files_of_every_shape_round_trip() {
+    let drive = Drive::new();
+    for (name, size) in [
+        ("empty.bin", 0),
+        ("small.txt", 1000),
+        ("one-chunk.bin", CHUNK_SIZE),
+        ("two-chunks.bin", 2 * CHUNK_SIZE),
+        ("many-chunks.bin", 7 * CHUNK_SIZE + 123),
+    ] {
+        let local = drive.fixture(name, size);
+        let remote = format!("shapes/{}", name);
+        let meta = drive.round_trip(&remote, &local);
+        assert_chunked(&drive, &meta, &local, size);
+    }
+}
+
+#[test]
+fn repos_json_accounts_for_every_chunk() {
+    let drive = Drive::new();
+    let a = drive.fixture("a.bin", 3 * CHUNK_SIZE + 1);
+    let b = drive.fixture("b.bin", 5000);
+    drive.round_trip("a.bin", &a);
+    drive.round_trip("dir/b.bin", &b);
+    let repos = drive.repos();
+    let stored: u64 = repos["repos"]
+        .as_object()
+        .unwrap()
+        .values()
+        .map(|repo| repo["current_size"].as_u64().unwrap())
+        .sum();
+    assert_eq!(stored, (3 * CHUNK_SIZE + 1 + 5000) as u64);
+    for repo in repos["repos"].as_object().unwrap().keys() {
+        assert!(drive.repo(repo).exists(), "{} was never created", repo);
+    }
+}
+
+#[test]
+fn identical_content_is_stored_once() {
+    let drive = Drive::new();
+    let local = drive.fixture("same.bin", 2 * CHUNK_SIZE);
+    let first = drive.round_trip("one/same.bin", &local);
+    let second = drive.round_trip("two/same.bin", &local);
+    assert_eq!(first["chunks"], second["chunks"]);
+    assert_eq!(stored_bytes(&drive), (2 * CHUNK_SIZE) as u64);
+}
+
+/// Sum of `current_size` over repos.json.
+fn stored_bytes(drive: &Drive) -> u64 {
+    drive.repos()["repos"]
+        .as_object()
+        .unwrap()
+        .values()
+        .map(|repo| repo["current_size"].as_u64().unwrap())
+        .sum()
+}
+
+#[test]
+fn chunks_a_repo_has_for_another_file_are_not_reserved_twice() {
+    let drive = Drive::new();
+    let local = drive.fixture("same.bin", 2 * CHUNK_SIZE);
+    let first = drive.round_trip("one/same.bin", &local);
+    // chunks.idx of a version that recorded no content, so the second upload
+    // pushes the chunks again and finds them in the repo
+    let index = drive.metadata_file("chunks.idx").unwrap();
+    let legacy: String = index
+        .lines()
+        .map(|line| {
+            let (rest, _content) = line.rsplit_once('\t').unwrap();
+            format!("{}\t-\n", rest)
+        })
+        .collect();
+    drive.commit_files(
+        "metadata",
+        &[("chunks.idx", Some(legacy.into_bytes()))],
+        "Index without content",
+    );
+    let second = drive.round_trip("two/same.bin", &local);
+    assert_eq!(first["chunks"], second["chunks"]);
+    assert_eq!(stored_bytes(&drive), (2 * CHUNK_SIZE) as u64);
+}
+
+#[test]
+fn chunks_a_failed_upload_left_in_a_repo_stay_reserved_by_the_next() {
+    let drive = Drive::new();
+    drive.set("push_batch_chunks", "1");
+    drive.set("retry.push.max_attempts", "1");
+    drive.fail_pushes(1, 1);
+    let local = drive.fixture("again.bin", 2 * CHUNK_SIZE);
+    drive.fails(&["upload", "again.bin", local.to_str().unwrap()]);
+    assert_eq!(stored_bytes(&drive), 0);
+    drive.heal_pushes();
+    let meta = drive.round_trip("again.bin", &local);
+    let repo = meta["chunks"][0]["repo"].as_str().unwrap();
+    // one commit per chunk, the first from the failed upload
+    assert_eq!(drive.commits(repo), 2);
+    assert_eq!(stored_bytes(&drive), (2 * CHUNK_SIZE) as u64);
+}
+
+#[test]
+fn remove_drops_the_metadata() {
+    let drive = Drive::new();
+    let local = drive.fixture("gone.txt", 10);
+    drive.round_trip("gone.txt", &local);
+    drive.ok(&["clean", "--file", "gone.txt"]);
+    assert!(drive.metadata_file("fs/default/gone.txt.json").is_none());
+    let back = drive.files().join("back.txt");
+    drive.fails(&["download", "gone.txt", back.to_str().unwrap()]);
+    assert!(!back.exists());
+}
+
+#[test]
+fn clean_deletes_the_repos_of_every_storage_account_before_the_metadata() {
+    let drive = Drive::new();
+    let local = drive.fixture("spread.bin", 3 * CHUNK_SIZE);
+    // an account of its own, reached with the token of the drive: an
+    // account token would name it in the URL, past the test's URL rewrite
+    drive.set("accounts.other.ssh_key", local.to_str().unwrap());
+    drive.set("max_repo_size", &CHUNK_SIZE.to_string());
+    drive.round_trip("spread.bin", &local);
+    let gh = drive.files().parent().unwrap().join("gh");
+    let repos_of = |account: &str| -> Vec<String> {
+        let mut names: Vec<String> = fs::read_dir(gh.join(account))
+            .map(|dir| {
+                dir.map(|e| e.unwrap().file_name().to_string_lossy().into_owned())
+                    .collect()
+            })
+            .unwrap_or_default();
+        names.sort();
+        names
+    };
+    let other = repos_of("other");
+    assert!(!other.is_empty(), "no repo on the second account");
+    let unrelated = gh.join("other").join("unrelated.git");
+    fs::create_dir_all(&unrelated).unwrap();
+
+    let plan = drive.ok(&["clean", "--all", "--dry-run"]);
+    for repo in &other {
+        let repo = format!("other/{}", repo.trim_end_matches(".git"));
+        assert!(plan.contains(&repo), "{} not in {}", repo, plan);
+    }
+    assert!(
+        plan.contains("skip 1 repos not in repos.json: other/unrelated"),
+        "{}",
+        plan
+    );
+
+    drive.ok(&["clean", "--all"]);
+    assert_eq!(repos_of("other"), ["unrelated.git"]);
+    assert!(
+        repos_of(common::OWNER).is_empty(),
+        "{:?}",
+        repos_of(common::OWNER)
+    );
+}
+
+#[test]
+fn chunks_at_the_4_digit_paths_of_older_versions_download() {
+    let drive = Drive::new();
+    let local = drive.fixture("legacy.bin", 2 * CHUNK_SIZE + 5);
+    let mut meta = drive.round_trip("legacy.bin", &local);
+    let repo = meta["chunks"][0]["repo"].as_str().unwrap().to_string();
+    let mut moves = Vec::new();
+    for chunk in meta["chunks"].as_array_mut().unwrap() {
+        let path = chunk["path"].as_str().unwrap().to_string();
+        let index = chunk["index"].as_u64().unwrap();
+        assert!(path.ends_with(&format!("_{:08}.chunk", index)), "{}", path);
+        assert_eq!(chunk["repo"], repo.as_str());
+        let legacy = path.replace(&format!("{:08}", index), &format!("{:04}", index));
+        chunk["path"] = Value::from(legacy.clone());
+        moves.push((path, legacy));
+    }
+    let data = |path: &str| {
+        let spec = format!("HEAD:{}", path);
+        let output = std::process::Command::new("git")
+            .arg("--git-dir")
+            .arg(drive.repo(&repo))
+            .args(["cat-file", "blob", &spec])
+            .output()
+            .unwrap();
+        output.stdout
+    };
+    let mut changes = Vec::new();
+    for (path, legacy) in &moves {
+        changes.push((legacy.as_str(), Some(data(path))));
+        changes.push((path.as_str(), None));
+    }
+    drive.commit_files(&repo, &changes, "Chunks as an older gidrive named them");
+    let json = serde_json::to_vec_pretty(&meta).unwrap();
+    drive.commit_files(
+        "metadata",
+        &[("fs/default/legacy.bin.json", Some(json))],
+        "Metadata as an older gidrive wrote it",
+    );
+
+    let back = drive.files().join("legacy-back.bin");
+    drive.ok(&["download", "legacy.bin", back.to_str().unwrap()]);
+    assert_eq!(fs::read(&back).unwrap(), fs::read(&local).unwrap());
+}
+
+#[test]
+fn the_storage_map_lists_every_chunk_of_the_namespace() {
+    let drive = Drive::new();
+    let big = drive.fixture("big.bin", 2 * CHUNK_SIZE + 1);
+    let small = drive.fixture("small, \"quoted\".txt", 10);
+    let meta = drive.round_trip("dir/big.bin", &big);
+    drive.round_trip("small, \"quoted\".txt", &small);
+    let other = big.to_str().unwrap();
+    drive.ok(&["--namespace", "other", "upload", "elsewhere.bin", other]);
+
+    let csv = drive.ok(&["export-map"]);
+    let lines: Vec<&str> = csv.lines().collect();
+    assert_eq!(
+        lines[0],
+        "path,chunk_index,owner,repo,chunk_path,offset,size,checksum,public,encrypted,compressed"
+    );
+    assert_eq!(lines.len(), 1 + 3 + 1, "{}", csv);
+    assert!(
+        csv.contains("\n\"small, \"\"quoted\"\".txt\",0,tester,"),
+        "{}",
+        csv
+    );
+    assert!(!csv.contains("elsewhere.bin"), "{}", csv);
+
+    let json = drive.ok(&[
+        "--offline",
+        "export-map",
+        "--prefix",
+        "dir/",
+        "--format",
+        "json",
+    ]);
+    let rows: Value = serde_json::from_str(&json).unwrap();
+    let rows = rows.as_array().unwrap();
+    assert_eq!(rows.len(), 3);
+    for (row, chunk) in rows.iter().zip(meta["chunks"].as_array().unwrap()) {
+        assert_eq!(row["path"], "dir/big.bin");
+        assert_eq!(row["chunk_index"], chunk["index"]);
+        assert_eq!(row["repo"], chunk["repo"]);
+        assert_eq!(row["chunk_path"], chunk["path"]);
+        assert_eq!(row["size"], chunk["size"]);
+        assert_eq!(row["checksum"], chunk["checksum"]);
+        assert_eq!(row["encrypted"], false);
+    }
+    let ndjson = drive.ok(&["--namespace", "other", "export-map", "--format", "ndjson"]);
+    assert_eq!(ndjson.lines().count(), 3, "{}", ndjson);
+    assert!(ndjson
+        .lines()
+        .all(|line| line.contains("\"path\":\"elsewhere.bin\"")));
+}
+
+/// A drive holding photos/2019/{a,b,c}.jpg and photos/2020/d.jpg.
+fn photos() -> Drive {
+    let drive = Drive::new();
+    for name in ["2019/a.jpg", "2019/b.jpg", "2019/c.jpg", "2020/d.jpg"] {
+        let local = drive.fixture(name, CHUNK_SIZE + 1);
+        drive.ok(&[
+            "upload",
+            &format!("photos/{}", name),
+            local.to_str().unwrap(),
+        ]);
+    }
+    drive
+}
+
+#[test]
+fn rm_r_removes_a_directory_in_one_commit_and_leaves_chunks_to_gc() {
+    let drive = photos();
+    let commits = drive.commits("metadata");
+    let listed = drive.ok(&["rm", "-r", "photos/2019/", "--dry-run"]);
+    assert_eq!(
+        listed.matches("would remove photos/2019/").count(),
+        3,
+        "{}",
+        listed
+    );
+    assert_eq!(drive.commits("metadata"), commits);
+    drive.fails(&["rm", "photos/2019"]);
+    drive.ok(&["rm", "-r", "photos/2019"]);
+    assert_eq!(drive.commits("metadata"), commits + 1);
+    for name in ["a", "b", "c"] {
+        let path = format!("fs/default/photos/2019/{}.jpg.json", name);
+        assert!(drive.metadata_file(&path).is_none());
+    }
+    assert!(drive
+        .metadata_file("fs/default/photos/2020/d.jpg.json")
+        .is_some());
+    let pending: Value =
+        serde_json::from_str(&drive.metadata_file("pending_delete.json").unwrap()).unwrap();
+    assert_eq!(pending.as_array().unwrap().len(), 3 * 2, "{}", pending);
+    drive.fails(&["rm", "-r", "/"]);
+    assert!(drive
+        .metadata_file("fs/default/photos/2020/d.jpg.json")
+        .is_some());
+}
+
+#[test]
+fn gc_deletes_released_chunks_after_the_grace_period_or_with_now() {
+    let drive = Drive::new();
+    let local = drive.fixture("old.bin", 2 * CHUNK_SIZE);
+    let meta = drive.round_trip("old.bin", &local);
+    let chunks = meta["chunks"].as_array().unwrap().clone();
+    let repo = chunks[0]["repo"].as_str().unwrap();
+    drive.ok(&["rm", "old.bin"]);
+    assert_eq!(drive.repos()["repos"][repo]["reclaimable"], 2 * CHUNK_SIZE);
+
+    let out = drive.ok(&["gc"]);
+    assert!(out.contains("deleted 0 chunks"), "{}", out);
+    assert!(out.contains("2 chunks (128.00 KiB) wait"), "{}", out);
+    for chunk in &chunks {
+        let path = chunk["path"].as_str().unwrap();
+        assert!(drive.blob_size(repo, path).is_some(), "{}", path);
+    }
+
+    // the first chunk was released eight days ago
+    let mut pending: Value =
+        serde_json::from_str(&drive.metadata_file("pending_delete.json").unwrap()).unwrap();
+    let since = pending[0]["since"].as_u64().unwrap();
+    pending[0]["since"] = (since - 8 * 86_400).into();
+    drive.commit_files(
+        "metadata",
+        &[(
+            "pending_delete.json",
+            Some(serde_json::to_vec_pretty(&pending).unwrap()),
+        )],
+        "Release a week ago",
+    );
+    let out = drive.ok(&["gc"]);
+    assert!(out.contains("deleted 1 chunks"), "{}", out);
+    assert!(out.contains("1 chunks (64.00 KiB) wait"), "{}", out);
+    let first = pending[0]["path"].as_str().unwrap();
+    assert!(drive.blob_size(repo, first).is_none());
+    assert_eq!(drive.repos()["repos"][repo]["reclaimable"], CHUNK_SIZE);
+
+    let out = drive.ok(&["gc", "--now", "--dry-run"]);
+    assert!(out.contains("would delete 1 chunks"), "{}", out);
+    let out = drive.ok(&["gc", "--now"]);
+    assert!(out.contains("deleted 1 chunks"), "{}", out);
+    assert!(!out.contains("wait"), "{}", out);
+    for chunk in &chunks {
+        let path = chunk["path"].as_str().unwrap();
+        assert!(drive.blob_size(repo, path).is_none(), "{}", path);
+    }
+    assert_eq!(drive.repos()["repos"][repo]["reclaimable"], 0);
+    assert_eq!(stored_bytes(&drive), 0);
+}
+
+#[test]
+fn mv_renames_a_directory_in_one_commit() {
+    let drive = photos();
+    let commits = drive.commits("metadata");
+    let before = drive.file_metadata("photos/2019/a.jpg");
+    let listed = drive.ok(&["mv", "photos/2019/", "archive/2019/", "--dry-run"]);
+    assert!(
+        listed.contains("would move photos/2019/a.jpg -> archive/2019/a.jpg"),
+        "{}",
+        listed
+    );
+    drive.ok(&["mv", "photos/2019/", "archive/2019/"]);
+    assert_eq!(drive.commits("metadata"), commits + 1);
+    assert_eq!(drive.file_metadata("archive/2019/a.jpg"), before);
+    assert!(drive
+        .metadata_file("fs/default/photos/2019/a.jpg.json")
+        .is_none());
+    let back = drive.files().join("back.jpg");
+    drive.ok(&["download", "archive/2019/c.jpg", back.to_str().unwrap()]);
+
+    drive.ok(&["mv", "photos/2020/d.jpg", "archive/2019/"]);
+    assert!(drive
+        .metadata_file("fs/default/archive/2019/d.jpg.json")
+        .is_some());
+    drive.fails(&["mv", "archive/2019/a.jpg", "archive/2019/b.jpg"]);
+    drive.fails(&["mv", "archive/", "archive/inner/"]);
+    drive.fails(&["mv", "/", "elsewhere/"]);
+    assert_eq!(drive.commits("metadata"), commits + 2);
+}
+
+#[test]
+fn git_progress_is_shown_per_repo_and_raw_only_at_vv() {
+    let drive = Drive::new();
+    let local = drive.fixture("a.bin", 3 * CHUNK_SIZE);
+    let output = drive.gidrive(&["-v", "upload", "a.bin", local.to_str().unwrap()]);
+    assert!(output.status.success());
+    let err = String::from_utf8_lossy(&output.stderr);
+    assert!(err.contains("--- push tester/storage-"), "{}", err);
+    assert!(
+        err.contains("tester/metadata: Writing objects 100%"),
+        "{}",
+        err
+    );
+    assert!(
+        !err.contains('\r') && !err.contains("Writing objects:"),
+        "{}",
+        err
+    );
+
+    // libgit2 has no raw git output to pass through
+    if cfg!(not(feature = "libgit2")) {
+        let local = drive.fixture("b.bin", CHUNK_SIZE);
+        let output = drive.gidrive(&["-vv", "upload", "b.bin", local.to_str().unwrap()]);
+        let err = String::from_utf8_lossy(&output.stderr);
+        assert!(err.contains("Writing objects: 100%"), "{}", err);
+        assert!(!err.contains("--- push tester/"), "{}", err);
+    }
+
+    let local = drive.fixture("c.bin", CHUNK_SIZE);
+    let args = [
+        "--progress",
+        "json",
+        "upload",
+        "c.bin",
+        local.to_str().unwrap(),
+    ];
+    let output = drive.gidrive(&args);
+    let events: Vec<Value> = String::from_utf8_lossy(&output.stderr)
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    let pushed = events.iter().find(|e| {
+        e["event"] == "git_transfer"
+            && e["direction"] == "push"
+            && e["objects"] == e["total_objects"]
+    });
+    assert!(pushed.is_some(), "{:?}", events);
+}
+
+#[test]
+fn a_striped_file_is_written_while_its_repos_are_fetched_in_chunk_order() {
+    let drive = Drive::new();
+    // three full repos, then room for three more chunks in each
+    drive.set("max_repo_size", &(3 * CHUNK_SIZE).to_string());
+    let filler = drive.fixture("filler.bin", 9 * CHUNK_SIZE);
+    drive.ok(&["upload", "filler.bin", filler.to_str().unwrap()]);
+    drive.set("max_repo_size", &(6 * CHUNK_SIZE).to_string());
+    drive.set("placement", "striped");
+    let local = drive.fixture("striped.bin", 9 * CHUNK_SIZE);
+    let meta = drive.round_trip("striped.bin", &local);
+    let repos: Vec<&str> = meta["chunks"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|chunk| chunk["repo"].as_str().unwrap())
+        .collect();
+    assert_ne!(repos[0], repos[1]);
+    assert_ne!(repos[1], repos[2]);
+    assert_ne!(repos[0], repos[2]);
+    assert_eq!(repos[..3].repeat(3), repos, "not striped");
+
+    drive.set("transfer_concurrency", "1");
+    let output = drive.gidrive(&["-vv", "cat", "striped.bin"]);
+    assert!(output.status.success());
+    assert!(output.stdout == fs::read(&local).unwrap(), "cat differs");
+    let err = String::from_utf8_lossy(&output.stderr);
+    let starts: Vec<&str> = err
+        .lines()
+        .filter_map(|line| line.strip_prefix("schedule: starting "))
+        .collect();
+    let expected: Vec<String> = (0..3)
+        .map(|i| format!("{}, lowest outstanding chunk {}", repos[i], i))
+        .collect();
+    assert_eq!(starts, expected, "{}", err);
+    // one repo fetched at a time, the writer only ever waits for the chunk
+    // the repo being fetched delivers next: it catches up with each chunk of
+    // the last repo instead of writing the file once all are in
+    let waits: Vec<&str> = err
+        .lines()
+        .filter_map(|line| line.strip_prefix("schedule: writer waiting for chunk "))
+        .collect();
+    let expected = [
+        format!("1 from {}", repos[1]),
+        format!("2 from {}", repos[2]),
+        format!("5 from {}", repos[2]),
+        format!("8 from {}", repos[2]),
+    ];
+    assert_eq!(waits, expected, "{}", err);
+}
+
+// libgit2 commits without running hooks
+#[cfg(not(feature = "libgit2"))]
+#[test]
+fn a_refused_commit_fails_the_upload() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let drive = Drive::new();
+    let local = drive.fixture("refused.bin", CHUNK_SIZE);
+    // a pre-commit hook refusing every commit, like a failing gpg signature
+    let hook = drive.files().parent().unwrap().join("hooks/pre-commit");
+    fs::write(&hook, "#!/bin/sh\necho 'commit refused' >&2\nexit 1\n").unwrap();
+    fs::set_permissions(&hook, fs::Permissions::from_mode(0o755)).unwrap();
+
+    let stderr = drive.fails(&["upload", "refused.bin", local.to_str().unwrap()]);
+    assert!(stderr.contains("Failed to git commit"), "{}", stderr);
+    assert!(stderr.contains("commit refused"), "{}", stderr);
+    assert!(drive.metadata_file("fs/default/refused.bin.json").is_none());
+}
+
+#[test]
+fn diff_without_checksum_warns_that_it_compared_sizes_only() {
+    let drive = Drive::new();
+    let local = drive.fixture("file.bin", 100);
+    drive.ok(&["upload", "file.bin", local.to_str().unwrap()]);
+    // same size, other content
+    let mut data = fs::read(&local).unwrap();
+    data[0] ^= 0xff;
+    fs::write(&local, data).unwrap();
+
+    let output = drive.gidrive(&["diff", "file.bin", local.to_str().unwrap()]);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(output.status.success(), "{}", stderr);
+    assert!(
+        stderr.contains("1 files on both sides compared by size only"),
+        "{}",
+        stderr
+    );
+    let output = drive.gidrive(&["diff", "--checksum", "file.bin", local.to_str().unwrap()]);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(!output.status.success(), "{}", stderr);
+    assert!(!stderr.contains("compared by size only"), "{}", stderr);
+    assert!(String::from_utf8_lossy(&output.stdout).contains("checksum"));
+}
@@ -0,0 +1,200 @@
+//! `serve` over HTTP: the WebDAV mount answering PROPFIND, GET and ranges
+//! from the test repos.
+
+mod common;
+
+use common::{Drive, CHUNK_SIZE};
+use std::io::Read;
+use std::net::{TcpListener, TcpStream};
+use std::process::{Child, Stdio};
+use std::thread;
+use std::time::Duration;
+
+const TOKEN: &str = "secret";
+
+/// A running `gidrive serve`, killed when dropped.
+struct Server {
+    child: Child,
+    base: String,
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+fn serve(drive: &Drive) -> Server {
+    drive.set("serve.token", TOKEN);
+    let port = TcpListener::bind("127.0.0.1:0")
+        .and_then(|listener| listener.local_addr())
+        .expect("find a free port")
+        .port();
+    let listen = format!("127.0.0.1:{}", port);
+    let child = drive
+        .command(&["serve", "--listen", &listen])
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .expect("run gidrive serve");
+    let server = Server {
+        child,
+        base: format!("http://{}", listen),
+    };
+    for _ in 0..100 {
+        if TcpStream::connect(("127.0.0.1", port)).is_ok() {
+            return server;
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+    panic!("gidrive serve does not listen on {}", listen);
+}
+
+impl Server {
+    fn request(&self, method: &str, path: &str) -> ureq::Request {
+        ureq::request(method, &format!("{}{}", self.base, path))
+            .set("Authorization", &format!("Bearer {}", TOKEN))
+    }
+
+    /// Status and body of `method path` with `headers`.
+    fn call(&self, method: &str, path: &str, headers: &[(&str, &str)]) -> (u16, Vec<u8>) {
+        let mut request = self.request(method, path);
+        for (name, value) in headers {
+            request = request.set(name, value);
+        }
+        let response = match request.call() {
+            Ok(response) => response,
+            Err(ureq::Error::Status(_, response)) => response,
+            Err(e) => panic!("{} {} failed: {}", method, path, e),
+        };
+        let status = response.status();
+        let mut body = Vec::new();
+        response.into_reader().read_to_end(&mut body).unwrap();
+        (status, body)
+    }
+
+    fn propfind(&self, path: &str, depth: &str) -> (u16, String) {
+        let (status, body) = self.call("PROPFIND", path, &[("Depth", depth)]);
+        (status, String::from_utf8(body).unwrap())
+    }
+}
+
+/// A drive holding docs/report.bin over three chunks, docs/a b.txt and
+/// top.txt, with the report's bytes.
+fn drive_with_files() -> (Drive, Vec<u8>) {
+    let drive = Drive::new();
+    let report = drive.fixture("report.bin", 2 * CHUNK_SIZE + 10);
+    drive.ok(&["upload", "docs/report.bin", report.to_str().unwrap()]);
+    let small = drive.fixture("a b.txt", 100);
+    drive.ok(&["upload", "docs/a b.txt", small.to_str().unwrap()]);
+    drive.ok(&["upload", "top.txt", small.to_str().unwrap()]);
+    (drive, std::fs::read(report).unwrap())
+}
+
+#[test]
+fn propfind_lists_the_tree_one_level_at_a_time() {
+    let (drive, report) = drive_with_files();
+    let server = serve(&drive);
+
+    let (status, root) = server.propfind("/dav/", "1");
+    assert_eq!(status, 207, "{}", root);
+    assert!(root.contains("<D:href>/dav/docs/</D:href>"), "{}", root);
+    assert!(root.contains("<D:href>/dav/top.txt</D:href>"), "{}", root);
+    assert!(!root.contains("report.bin"), "{}", root);
+
+    let (status, docs) = server.propfind("/dav/docs", "1");
+    assert_eq!(status, 207);
+    assert!(
+        docs.contains("<D:href>/dav/docs/a%20b.txt</D:href>"),
+        "{}",
+        docs
+    );
+    let length = format!("<D:getcontentlength>{}</D:getcontentlength>", report.len());
+    assert!(docs.contains(&length), "{}", docs);
+
+    let (status, file) = server.propfind("/dav/docs/report.bin", "0");
+    assert_eq!(status, 207);
+    assert_eq!(file.matches("<D:response>").count(), 1);
+    assert!(file.contains("<D:resourcetype/>"), "{}", file);
+
+    assert_eq!(server.propfind("/dav/", "infinity").0, 403);
+    assert_eq!(server.propfind("/dav/missing.txt", "0").0, 404);
+}
+
+#[test]
+fn get_serves_whole_files_and_ranges_across_chunks() {
+    let (drive, report) = drive_with_files();
+    let server = serve(&drive);
+
+    let (status, body) = server.call("GET", "/dav/docs/report.bin", &[]);
+    assert_eq!(status, 200);
+    assert!(body == report, "the served file differs");
+
+    let start = CHUNK_SIZE - 5;
+    let range = format!("bytes={}-{}", start, start + 9);
+    let response = server
+        .request("GET", "/dav/docs/report.bin")
+        .set("Range", &range)
+        .call()
+        .unwrap();
+    assert_eq!(response.status(), 206);
+    assert_eq!(
+        response.header("Content-Range"),
+        Some(format!("bytes {}-{}/{}", start, start + 9, report.len()).as_str())
+    );
+    let mut body = Vec::new();
+    response.into_reader().read_to_end(&mut body).unwrap();
+    assert_eq!(body, &report[start..start + 10]);
+
+    let (status, tail) = server.call("GET", "/dav/docs/report.bin", &[("Range", "bytes=-7")]);
+    assert_eq!(status, 206);
+    assert_eq!(tail, &report[report.len() - 7..]);
+    let past = format!("bytes={}-", report.len());
+    let (status, _) = server.call("GET", "/dav/docs/report.bin", &[("Range", &past)]);
+    assert_eq!(status, 416);
+
+    assert_eq!(server.call("GET", "/dav/docs", &[]).0, 405);
+    assert_eq!(server.call("PUT", "/dav/new.txt", &[]).0, 405);
+    assert_eq!(server.call("GET", "/dav/docs/..%2Ftop.txt", &[]).0, 400);
+}
+
+#[test]
+fn webdav_clients_authenticate_with_the_token_as_basic_password() {
+    let (drive, _) = drive_with_files();
+    let server = serve(&drive);
+    let url = format!("{}/dav/top.txt", server.base);
+    let status = |request: ureq::Request| match request.call() {
+        Ok(response) => response.status(),
+        Err(ureq::Error::Status(status, _)) => status,
+        Err(e) => panic!("GET top.txt failed: {}", e),
+    };
+    assert_eq!(status(ureq::get(&url)), 401);
+    // "user:secret" and "user:wrong"
+    assert_eq!(
+        status(ureq::get(&url).set("Authorization", "Basic dXNlcjpzZWNyZXQ=")),
+        200
+    );
+    assert_eq!(
+        status(ureq::get(&url).set("Authorization", "Basic dXNlcjp3cm9uZw==")),
+        401
+    );
+    let (status, options) = {
+        let response = server.request("OPTIONS", "/dav/").call().unwrap();
+        (
+            response.status(),
+            response.header("DAV").map(str::to_string),
+        )
+    };
+    assert_eq!((status, options.as_deref()), (200, Some("1")));
+}
+
+#[test]
+fn an_empty_token_refuses_to_serve() {
+    let drive = Drive::new();
+    for token in ["", "  "] {
+        drive.set("serve.token", token);
+        let stderr = drive.fails(&["serve", "--listen", "127.0.0.1:0"]);
+        assert!(stderr.contains("serve needs a bearer token"), "{}", stderr);
+    }
+}
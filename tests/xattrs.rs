@@ -0,0 +1,99 @@
+//! `upload --xattrs` and `download --xattrs`: extended attributes kept in the
+//! metadata and set again on the downloaded file.
+#![cfg(unix)]
+
+mod common;
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use common::Drive;
+use std::path::Path;
+
+/// The extended attributes of `path`, by name.
+fn attrs(path: &Path) -> Vec<(String, Vec<u8>)> {
+    let mut attrs: Vec<(String, Vec<u8>)> = xattr::list(path)
+        .unwrap()
+        .map(|name| {
+            let value = xattr::get(path, &name).unwrap().unwrap();
+            (name.to_string_lossy().into_owned(), value)
+        })
+        .collect();
+    attrs.sort();
+    attrs
+}
+
+#[test]
+fn extended_attributes_round_trip_with_xattrs() {
+    let drive = Drive::new();
+    let local = drive.fixture("tagged.bin", 1000);
+    if let Err(e) = xattr::set(&local, "user.gidrive.tag", b"red") {
+        eprintln!("skipping, the test dir has no extended attributes: {}", e);
+        return;
+    }
+    let binary: Vec<u8> = (0..=255).collect();
+    xattr::set(&local, "user.gidrive.binary", &binary).unwrap();
+    let expected = vec![
+        ("user.gidrive.binary".to_string(), binary.clone()),
+        ("user.gidrive.tag".to_string(), b"red".to_vec()),
+    ];
+    assert_eq!(attrs(&local), expected);
+
+    drive.ok(&["upload", "plain.bin", local.to_str().unwrap()]);
+    let plain = drive.file_metadata("plain.bin");
+    assert!(plain.get("xattrs").is_none(), "{}", plain);
+
+    drive.ok(&["upload", "--xattrs", "tagged.bin", local.to_str().unwrap()]);
+    let meta = drive.file_metadata("tagged.bin");
+    assert_eq!(meta["xattrs"]["user.gidrive.tag"], STANDARD.encode("red"));
+    assert_eq!(
+        meta["xattrs"]["user.gidrive.binary"],
+        STANDARD.encode(&binary)
+    );
+
+    let without = drive.files().join("without.bin");
+    drive.ok(&["download", "tagged.bin", without.to_str().unwrap()]);
+    assert!(attrs(&without).is_empty());
+    let back = drive.files().join("back.bin");
+    drive.ok(&["download", "--xattrs", "tagged.bin", back.to_str().unwrap()]);
+    assert_eq!(attrs(&back), expected);
+    assert_eq!(
+        std::fs::read(&back).unwrap(),
+        std::fs::read(&local).unwrap()
+    );
+}
+
+#[test]
+fn an_attribute_that_cannot_be_set_only_warns() {
+    let drive = Drive::new();
+    let local = drive.fixture("file.bin", 100);
+    if let Err(e) = xattr::set(&local, "user.gidrive.tag", b"red") {
+        eprintln!("skipping, the test dir has no extended attributes: {}", e);
+        return;
+    }
+    drive.ok(&["upload", "--xattrs", "file.bin", local.to_str().unwrap()]);
+    let mut meta = drive.file_metadata("file.bin");
+    meta["xattrs"]["user.gidrive.broken"] = "not base64!".into();
+    drive.commit_files(
+        "metadata",
+        &[(
+            "fs/default/file.bin.json",
+            Some(serde_json::to_vec_pretty(&meta).unwrap()),
+        )],
+        "Unsettable attributes",
+    );
+
+    let back = drive.files().join("back.bin");
+    let output = drive.gidrive(&["download", "--xattrs", "file.bin", back.to_str().unwrap()]);
+    assert!(output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("cannot restore extended attribute user.gidrive.broken"),
+        "{}",
+        stderr
+    );
+    assert!(stderr.contains("not base64"), "{}", stderr);
+    assert_eq!(
+        attrs(&back),
+        [("user.gidrive.tag".to_string(), b"red".to_vec())]
+    );
+}